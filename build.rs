@@ -0,0 +1,81 @@
+//! Packs the `initrd` directory into a ustar archive, which the kernel embeds
+//! as its initial ramdisk.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-changed=initrd");
+
+    let mut archive = Vec::new();
+    append_dir(&mut archive, Path::new("initrd"), "")?;
+    // The end of the archive is marked by two zero blocks.
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    fs::write(out_dir.join("initrd.tar"), archive)
+}
+
+/// Appends the contents of `dir`, stored in the archive under `prefix`.
+fn append_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    // Sort so that the archive does not depend on the order of the host's
+    // directory listing.
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_str().expect("initrd file names must be UTF-8");
+        let path = format!("{}{}", prefix, name);
+        if entry.file_type()?.is_dir() {
+            append_header(archive, &format!("{}/", path), b'5', 0o755, 0);
+            append_dir(archive, &entry.path(), &format!("{}/", path))?;
+        } else {
+            let data = fs::read(entry.path())?;
+            append_header(archive, &path, b'0', 0o644, data.len());
+            archive.extend_from_slice(&data);
+            archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+    }
+    Ok(())
+}
+
+fn append_header(archive: &mut Vec<u8>, path: &str, kind: u8, mode: u32, size: usize) {
+    let mut header = [0u8; BLOCK_SIZE];
+    // Long paths are split between the prefix and name fields.
+    let (prefix, name) = match path.len() {
+        0..=100 => ("", path),
+        _ => path[..path.len() - 1]
+            .rmatch_indices('/')
+            .map(|(index, _)| (&path[..index], &path[index + 1..]))
+            .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+            .unwrap_or_else(|| panic!("initrd path is too long: {}", path)),
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    write_octal(&mut header[148..155], checksum.into());
+
+    archive.extend_from_slice(&header);
+}
+
+/// Writes `value` as zero padded octal digits followed by a NUL.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
@@ -0,0 +1,270 @@
+//! The fixed ACPI description table (FADT), whose signature is `FACP`.
+//!
+//! The FADT gives the I/O ports of the fixed power management registers,
+//! how to hand them from the firmware to the OS, the register which resets
+//! the machine and the address of the differentiated system description
+//! table (DSDT). The sleep types to write
+//! to those registers to enter the soft-off state S5 are only found in the
+//! DSDT's AML, in the package named `_S5_`.
+//!
+//! See: https://wiki.osdev.org/FADT and https://wiki.osdev.org/Shutdown
+
+use super::{find_table, table_at};
+
+// Offsets of fields in the table's contents, which follow the header.
+const DSDT: usize = 4;
+const SMI_COMMAND: usize = 12;
+const ACPI_ENABLE: usize = 16;
+const PM1A_CONTROL: usize = 28;
+const PM1B_CONTROL: usize = 32;
+const FLAGS: usize = 76;
+const RESET_REGISTER: usize = 80;
+const RESET_VALUE: usize = 92;
+const X_DSDT: usize = 104;
+
+/// Bit of the flags set if the reset register is supported.
+const FLAG_RESET_REGISTER: u32 = 1 << 10;
+
+// Address spaces of generic addresses.
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+
+// AML opcodes found around the `_S5_` package.
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+
+/// The fields of the FADT needed to power the machine off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Physical address of the DSDT.
+    pub dsdt: u64,
+    /// Port to which [acpi_enable](Self::acpi_enable) is written to hand the
+    /// power management registers to the OS, or 0 if they already are.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    /// Port of the PM1a control register.
+    pub pm1a_control: u16,
+    /// Port of the PM1b control register, or 0 if there is none.
+    pub pm1b_control: u16,
+    /// The register to which [reset_value](Self::reset_value) is written to
+    /// reset the machine, if supported.
+    pub reset_register: Option<ResetRegister>,
+    pub reset_value: u8,
+}
+
+/// Where the reset register is. Registers in PCI configuration space are not
+/// supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetRegister {
+    /// A byte at a physical address.
+    Memory(u64),
+    /// An I/O port.
+    Io(u16),
+}
+
+/// The values of the `SLP_TYP` fields of the PM1a and PM1b control registers
+/// which select a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Returns the FADT, or `None` if there is no FADT.
+pub fn find() -> Option<Fadt> {
+    find_table(b"FACP").and_then(|fadt| parse(fadt.data()))
+}
+
+fn parse(data: &[u8]) -> Option<Fadt> {
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    // Prefer the 64-bit address of the DSDT if the table is new enough to
+    // have it.
+    let x_dsdt = data
+        .get(X_DSDT..X_DSDT + 8)
+        .map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+    let dsdt = match x_dsdt {
+        0 => u32_at(DSDT)? as u64,
+        x_dsdt => x_dsdt,
+    };
+    // The reset register is a generic address: its address space, three
+    // bytes describing its width and the address.
+    let flags = u32_at(FLAGS).unwrap_or(0);
+    let reset_register = data
+        .get(RESET_REGISTER..RESET_REGISTER + 12)
+        .filter(|_| flags & FLAG_RESET_REGISTER != 0)
+        .and_then(|register| {
+            let address = u64::from_le_bytes(register[4..].try_into().unwrap());
+            match register[0] {
+                ADDRESS_SPACE_MEMORY => Some(ResetRegister::Memory(address)),
+                ADDRESS_SPACE_IO => Some(ResetRegister::Io(address as u16)),
+                _ => None,
+            }
+        });
+    Some(Fadt {
+        dsdt,
+        smi_command: u32_at(SMI_COMMAND)? as u16,
+        acpi_enable: *data.get(ACPI_ENABLE)?,
+        pm1a_control: u32_at(PM1A_CONTROL)? as u16,
+        pm1b_control: u32_at(PM1B_CONTROL)? as u16,
+        reset_register,
+        reset_value: data.get(RESET_VALUE).copied().unwrap_or(0),
+    })
+}
+
+/// Returns the sleep type of the soft-off state S5, or `None` if the DSDT
+/// does not define it.
+pub fn s5_sleep_type(fadt: &Fadt) -> Option<SleepType> {
+    let dsdt = table_at(fadt.dsdt)?;
+    if &dsdt.signature != b"DSDT" {
+        return None;
+    }
+    find_s5(dsdt.data())
+}
+
+/// Searches AML for the definition of the `_S5_` package.
+///
+/// Parsing AML properly takes an interpreter. The package is almost always
+/// defined at the top level with constant elements though, so looking for
+/// its name is enough.
+fn find_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(_, name)| name == b"_S5_")
+        .find_map(|(start, _)| {
+            // The name is preceded by the name opcode, possibly followed by
+            // the root prefix.
+            let named = match start.checked_sub(1).map(|index| aml[index]) {
+                Some(AML_NAME) => true,
+                Some(b'\\') => start >= 2 && aml[start - 2] == AML_NAME,
+                _ => false,
+            };
+            named.then(|| parse_s5_package(&aml[start + 4..])).flatten()
+        })
+}
+
+fn parse_s5_package(aml: &[u8]) -> Option<SleepType> {
+    if *aml.first()? != AML_PACKAGE {
+        return None;
+    }
+    // The top two bits of the first byte of the package length count the
+    // bytes following it. The element count follows the length.
+    let length_bytes = (*aml.get(1)? >> 6) as usize;
+    let mut elements = aml.get(3 + length_bytes..)?;
+    let mut next = || {
+        let (value, len) = match *elements.first()? {
+            AML_ZERO => (0, 1),
+            AML_ONE => (1, 1),
+            AML_BYTE_PREFIX => (*elements.get(1)?, 2),
+            // The sleep types are 3 bits, so the high byte is ignored.
+            AML_WORD_PREFIX => (*elements.get(1)?, 3),
+            _ => return None,
+        };
+        elements = elements.get(len..)?;
+        Some(value)
+    };
+    let a = next()?;
+    // Some firmware gives only one value for both registers.
+    let b = next().unwrap_or(a);
+    Some(SleepType { a, b })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse() {
+        let mut data = alloc::vec![0; 116];
+        data[DSDT..DSDT + 4].copy_from_slice(&0x7fe0_0040u32.to_le_bytes());
+        data[SMI_COMMAND..SMI_COMMAND + 4].copy_from_slice(&0xb2u32.to_le_bytes());
+        data[ACPI_ENABLE] = 0xf1;
+        data[PM1A_CONTROL..PM1A_CONTROL + 4].copy_from_slice(&0x604u32.to_le_bytes());
+        let fadt = parse(&data).unwrap();
+        assert_eq!(
+            fadt,
+            Fadt {
+                dsdt: 0x7fe0_0040,
+                smi_command: 0xb2,
+                acpi_enable: 0xf1,
+                pm1a_control: 0x604,
+                pm1b_control: 0,
+                reset_register: None,
+                reset_value: 0,
+            }
+        );
+
+        // The reset register is only used if the flags say it is supported.
+        data[RESET_REGISTER] = ADDRESS_SPACE_IO;
+        data[RESET_REGISTER + 4..RESET_REGISTER + 12].copy_from_slice(&0xcf9u64.to_le_bytes());
+        data[RESET_VALUE] = 0x06;
+        assert_eq!(parse(&data).unwrap().reset_register, None);
+        data[FLAGS..FLAGS + 4].copy_from_slice(&FLAG_RESET_REGISTER.to_le_bytes());
+        let fadt = parse(&data).unwrap();
+        assert_eq!(fadt.reset_register, Some(ResetRegister::Io(0xcf9)));
+        assert_eq!(fadt.reset_value, 0x06);
+
+        // The 64-bit address of the DSDT wins, and a truncated table is
+        // rejected.
+        data[X_DSDT..X_DSDT + 8].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        assert_eq!(parse(&data).unwrap().dsdt, 0x1_0000_0000);
+        assert_eq!(parse(&data[..PM1B_CONTROL]), None);
+    }
+
+    #[test_case]
+    fn test_find_s5() {
+        // Name (_S5_, Package (0x04) { 0x05, Zero, Zero, Zero }), following
+        // an unrelated use of the name.
+        let aml = [
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            0x10, //
+            AML_NAME,
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            AML_PACKAGE,
+            0x06,
+            0x04, //
+            AML_BYTE_PREFIX,
+            0x05,
+            AML_ZERO,
+            AML_ZERO,
+            AML_ZERO,
+        ];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 5, b: 0 }));
+
+        // The name may be prefixed with the root and the values bare.
+        let aml = [
+            AML_NAME,
+            b'\\',
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            AML_PACKAGE,
+            0x05,
+            0x02, //
+            AML_ZERO,
+            AML_ONE,
+        ];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 0, b: 1 }));
+        assert_eq!(find_s5(b"_S5_"), None);
+    }
+
+    #[test_case]
+    fn test_s5_sleep_type() {
+        let fadt = find().unwrap();
+        assert!(fadt.pm1a_control != 0);
+        assert!(s5_sleep_type(&fadt).is_some());
+    }
+}
@@ -0,0 +1,88 @@
+//! The multiple APIC description table (MADT), which lists the processors and
+//! interrupt controllers in the system.
+//!
+//! See: https://wiki.osdev.org/MADT
+
+use alloc::vec::Vec;
+
+use super::find_table;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+/// The processor is ready to use.
+const FLAG_ENABLED: u32 = 1 << 0;
+/// The processor is disabled but may be enabled at runtime.
+const FLAG_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A processor described by the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// The processor's ID in the ACPI namespace.
+    pub acpi_id: u32,
+    pub apic_id: u32,
+    /// `false` if the firmware has disabled the processor, in which case it
+    /// must not be started.
+    pub enabled: bool,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Returns the processors listed in the MADT, or an empty list if there is no
+/// MADT.
+///
+/// Processors which are neither enabled nor online capable are omitted.
+pub fn processors() -> Vec<Processor> {
+    let mut processors = Vec::new();
+    let Some(madt) = find_table(b"APIC") else {
+        return processors;
+    };
+
+    // The entries follow the local APIC address and flags.
+    let mut entries = madt.data().get(8..).unwrap_or(&[]);
+    while entries.len() >= 2 {
+        let (kind, len) = (entries[0], entries[1] as usize);
+        if len < 2 || len > entries.len() {
+            break;
+        }
+        let entry = &entries[..len];
+
+        let processor = match kind {
+            ENTRY_LOCAL_APIC if len >= 8 => {
+                Some((entry[2] as u32, entry[3] as u32, read_u32(entry, 4)))
+            }
+            ENTRY_LOCAL_X2APIC if len >= 16 => {
+                Some((read_u32(entry, 12), read_u32(entry, 4), read_u32(entry, 8)))
+            }
+            _ => None,
+        };
+        if let Some((acpi_id, apic_id, flags)) = processor {
+            if flags & (FLAG_ENABLED | FLAG_ONLINE_CAPABLE) != 0 {
+                processors.push(Processor {
+                    acpi_id,
+                    apic_id,
+                    enabled: flags & FLAG_ENABLED != 0,
+                });
+            }
+        }
+
+        entries = &entries[len..];
+    }
+
+    processors
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_bsp_is_listed() {
+        let bsp = crate::cpu::cpuid(1, 0).ebx >> 24;
+        assert!(processors()
+            .iter()
+            .any(|processor| processor.enabled && processor.apic_id == bsp));
+    }
+}
@@ -0,0 +1,68 @@
+//! The memory-mapped configuration table (MCFG), which locates the PCI
+//! Express enhanced configuration access mechanism (ECAM) regions.
+//!
+//! See: https://wiki.osdev.org/PCI_Express
+
+use alloc::vec::Vec;
+
+use super::find_table;
+
+/// Length of an entry, which follows 8 reserved bytes at the start of the
+/// table's contents.
+const ENTRY_LENGTH: usize = 16;
+
+/// A range of buses whose configuration space is mapped to memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigRegion {
+    /// Physical address of the configuration space of bus 0, even if the
+    /// region starts at a later bus.
+    pub base: u64,
+    /// The PCI segment group, which is 0 on most machines.
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Returns the regions listed in the MCFG, or an empty list if there is no
+/// MCFG.
+pub fn regions() -> Vec<ConfigRegion> {
+    find_table(b"MCFG").map_or_else(Vec::new, |mcfg| parse(mcfg.data()))
+}
+
+fn parse(data: &[u8]) -> Vec<ConfigRegion> {
+    data.get(8..)
+        .unwrap_or(&[])
+        .chunks_exact(ENTRY_LENGTH)
+        .map(|entry| ConfigRegion {
+            base: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+            segment: u16::from_le_bytes([entry[8], entry[9]]),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse() {
+        let mut data = alloc::vec![0; 8];
+        data.extend_from_slice(&0xb000_0000u64.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0xff, 0, 0, 0, 0]);
+        // A truncated entry is ignored.
+        data.extend_from_slice(&[1, 2, 3]);
+
+        assert_eq!(
+            parse(&data),
+            [ConfigRegion {
+                base: 0xb000_0000,
+                segment: 0,
+                start_bus: 0,
+                end_bus: 0xff,
+            }]
+        );
+        assert!(parse(&[]).is_empty());
+    }
+}
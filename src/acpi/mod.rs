@@ -0,0 +1,171 @@
+//! Discovery of ACPI system description tables.
+//!
+//! The firmware leaves a root system description pointer (RSDP) in low
+//! memory which leads to the root (RSDT) or extended (XSDT) system description
+//! table. That table in turn lists the physical addresses of every other
+//! table, each of which starts with a common [SdtHeader].
+//!
+//! Tables are read in place through the physical memory mapping set up by
+//! [crate::mem::init].
+//!
+//! See: https://wiki.osdev.org/RSDP
+
+pub mod fadt;
+pub mod madt;
+pub mod mcfg;
+
+use core::{mem::size_of, slice, str};
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::mem::phys_to_virt;
+
+/// Root system description pointer.
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The remaining fields are only present from revision 2 onwards.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Length of the revision 1 part of the [Rsdp].
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Header common to all system description tables.
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the table's signature, such as `"APIC"` for the MADT.
+    pub fn signature(&self) -> &str {
+        str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// Returns the whole table, including the header.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
+    /// Returns the table's contents following the header.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+
+    fn is_valid(&self) -> bool {
+        self.length as usize >= size_of::<SdtHeader>() && checksum(self.bytes())
+    }
+}
+
+/// Where the root table lists its entries, which are 32-bit addresses in the
+/// RSDT and 64-bit addresses in the XSDT.
+#[derive(Clone, Copy)]
+enum Root {
+    Rsdt(&'static SdtHeader),
+    Xsdt(&'static SdtHeader),
+}
+
+static ROOT: Once<Option<Root>> = Once::new();
+
+/// Returns `true` if the bytes sum to zero, as required of every ACPI
+/// structure.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Maps a table at the given physical address, returning `None` if its
+/// checksum is wrong.
+fn table_at(addr: u64) -> Option<&'static SdtHeader> {
+    let header = unsafe { &*phys_to_virt(PhysAddr::new(addr)).as_ptr::<SdtHeader>() };
+    header.is_valid().then_some(header)
+}
+
+/// Searches `len` bytes of physical memory starting at `start` for the RSDP,
+/// which is always 16 byte aligned.
+fn search_rsdp(start: u64, len: u64) -> Option<&'static Rsdp> {
+    (start..start + len).step_by(16).find_map(|addr| {
+        let ptr = phys_to_virt(PhysAddr::new(addr)).as_ptr::<u8>();
+        let bytes = unsafe { slice::from_raw_parts(ptr, RSDP_V1_LENGTH) };
+        if &bytes[..8] != b"RSD PTR " || !checksum(bytes) {
+            return None;
+        }
+        Some(unsafe { &*(ptr as *const Rsdp) })
+    })
+}
+
+fn find_root() -> Option<Root> {
+    // The RSDP is either in the first KiB of the extended BIOS data area,
+    // whose segment is stored at 0x40e, or in the BIOS ROM area.
+    let ebda = unsafe { *phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>() } as u64 * 16;
+    let rsdp = (ebda != 0)
+        .then(|| search_rsdp(ebda, 1024))
+        .flatten()
+        .or_else(|| search_rsdp(0xe0000, 0x20000))?;
+
+    if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        if let Some(xsdt) = table_at(rsdp.xsdt_address) {
+            return Some(Root::Xsdt(xsdt));
+        }
+    }
+    table_at(rsdp.rsdt_address as u64).map(Root::Rsdt)
+}
+
+/// Returns an iterator over every valid table listed by the root table.
+///
+/// # Panics
+///
+/// Panics if called before [crate::mem::init].
+pub fn tables() -> impl Iterator<Item = &'static SdtHeader> {
+    let root = *ROOT.call_once(find_root);
+    let (data, entry_size): (&[u8], usize) = match root {
+        Some(Root::Rsdt(rsdt)) => (rsdt.data(), 4),
+        Some(Root::Xsdt(xsdt)) => (xsdt.data(), 8),
+        None => (&[], 4),
+    };
+
+    data.chunks_exact(entry_size).filter_map(|entry| {
+        let mut addr = [0; 8];
+        addr[..entry.len()].copy_from_slice(entry);
+        table_at(u64::from_le_bytes(addr))
+    })
+}
+
+/// Returns the first table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature)
+}
+
+/// Returns `true` if the firmware provides ACPI tables.
+pub fn is_present() -> bool {
+    ROOT.call_once(find_root).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_tables_have_valid_checksums() {
+        assert!(is_present());
+        for table in tables() {
+            assert!(checksum(table.bytes()), "{}", table.signature());
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use linked_list_allocator::LockedHeap;
 use x86_64::{
     structures::paging::{
@@ -6,12 +8,39 @@ use x86_64::{
     VirtAddr,
 };
 
+use crate::{early_println, early_trace};
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
 
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+/// Set once [init_heap] has handed the heap to the allocator.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Bytes of the kernel heap in use and free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Returns how much of the kernel heap is in use.
+pub fn usage() -> HeapUsage {
+    let heap = ALLOCATOR.lock();
+    HeapUsage {
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
+
+/// Returns `true` once the heap can be allocated from, for code which also
+/// runs earlier during boot.
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -24,19 +53,33 @@ pub fn init_heap(
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    early_trace!(
+        "heap: mapping {} KiB at {:#x}",
+        HEAP_SIZE / 1024,
+        HEAP_START
+    );
     for page in page_range {
-        let frame = frame_allocator
+        let mapped = frame_allocator
             .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+            });
+        match mapped {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                early_println!("heap: cannot map {:?}: {:?}", page, error);
+                return Err(error);
+            }
         }
     }
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
     }
+    INITIALIZED.store(true, Ordering::Release);
+    early_trace!("heap: ready");
 
     Ok(())
 }
@@ -0,0 +1,254 @@
+//! A write-back cache of blocks.
+//!
+//! [BlockCache] wraps a block device and is a block device itself, so it
+//! fits between a filesystem and the device the filesystem is on. Reads are
+//! served from memory when possible; writes only go to memory and mark the
+//! block dirty. Dirty blocks reach the device when they are evicted, which
+//! happens to the least recently used block once the cache is full, or when
+//! [BlockCache::sync] is called.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec};
+
+use spin::Mutex;
+
+use super::{check_range, BlockDevice, Error};
+
+/// Counters describing how well a cache works.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Blocks read from the cache.
+    pub hits: u64,
+    /// Blocks read from the device.
+    pub misses: u64,
+    /// Dirty blocks written to the device.
+    pub writebacks: u64,
+}
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Value of [State::clock] when the block was last used.
+    last_used: u64,
+}
+
+struct State {
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// Incremented on every access, to order blocks by use.
+    clock: u64,
+    stats: Stats,
+}
+
+/// A block device caching the blocks of another.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    /// Maximum number of blocks kept.
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl BlockCache {
+    /// Creates a cache of up to `capacity` blocks of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        assert!(capacity > 0, "block cache needs room for a block");
+        BlockCache {
+            device,
+            capacity,
+            state: Mutex::new(State {
+                blocks: BTreeMap::new(),
+                clock: 0,
+                stats: Stats::default(),
+            }),
+        }
+    }
+
+    /// Writes every dirty block to the device.
+    pub fn sync(&self) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        for (&block, cached) in state.blocks.iter_mut().filter(|(_, cached)| cached.dirty) {
+            self.device.write_blocks(block, &cached.data)?;
+            cached.dirty = false;
+            state.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.state.lock().stats
+    }
+
+    /// Returns the cached copy of `block`, reading it from the device first
+    /// unless `overwrite` is set, in which case the caller replaces all of
+    /// it.
+    fn get<'a>(
+        &self,
+        state: &'a mut State,
+        block: u64,
+        overwrite: bool,
+    ) -> Result<&'a mut CachedBlock, Error> {
+        state.clock += 1;
+        let clock = state.clock;
+        if state.blocks.contains_key(&block) {
+            state.stats.hits += 1;
+        } else {
+            if state.blocks.len() >= self.capacity {
+                self.evict(state)?;
+            }
+            let mut data = vec![0; self.device.block_size()].into_boxed_slice();
+            if !overwrite {
+                self.device.read_blocks(block, &mut data)?;
+                state.stats.misses += 1;
+            }
+            state.blocks.insert(
+                block,
+                CachedBlock {
+                    data,
+                    dirty: false,
+                    last_used: clock,
+                },
+            );
+        }
+        let cached = state.blocks.get_mut(&block).unwrap();
+        cached.last_used = clock;
+        Ok(cached)
+    }
+
+    /// Drops the least recently used block, writing it back if dirty.
+    fn evict(&self, state: &mut State) -> Result<(), Error> {
+        let (&block, cached) = state
+            .blocks
+            .iter()
+            .min_by_key(|(_, cached)| cached.last_used)
+            .unwrap();
+        if cached.dirty {
+            self.device.write_blocks(block, &cached.data)?;
+            state.stats.writebacks += 1;
+        }
+        state.blocks.remove(&block);
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(self, block, buf.len())?;
+        let mut state = self.state.lock();
+        for (block, buf) in (block..).zip(buf.chunks_exact_mut(self.block_size())) {
+            buf.copy_from_slice(&self.get(&mut state, block, false)?.data);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), Error> {
+        check_range(self, block, data.len())?;
+        let mut state = self.state.lock();
+        for (block, data) in (block..).zip(data.chunks_exact(self.block_size())) {
+            let cached = self.get(&mut state, block, true)?;
+            cached.data.copy_from_slice(data);
+            cached.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.sync()?;
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{RamDisk, SECTOR_SIZE};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// Counts the blocks written to a [RamDisk].
+    struct CountingDisk {
+        disk: RamDisk,
+        writes: AtomicU64,
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn block_count(&self) -> u64 {
+            self.disk.block_count()
+        }
+
+        fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), Error> {
+            self.disk.read_blocks(block, buf)
+        }
+
+        fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), Error> {
+            self.writes
+                .fetch_add((data.len() / SECTOR_SIZE) as u64, Ordering::Relaxed);
+            self.disk.write_blocks(block, data)
+        }
+    }
+
+    fn cache(capacity: usize) -> (Arc<CountingDisk>, BlockCache) {
+        let disk = Arc::new(CountingDisk {
+            disk: RamDisk::new(8),
+            writes: AtomicU64::new(0),
+        });
+        let cache = BlockCache::new(disk.clone(), capacity);
+        (disk, cache)
+    }
+
+    #[test_case]
+    fn test_read_hits() {
+        let (_, cache) = cache(4);
+        let mut buf = [0; 2 * SECTOR_SIZE];
+        cache.read_blocks(0, &mut buf).unwrap();
+        cache.read_blocks(1, &mut buf).unwrap();
+        assert_eq!(
+            cache.stats(),
+            Stats {
+                hits: 1,
+                misses: 3,
+                writebacks: 0,
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_write_back() {
+        let (disk, cache) = cache(4);
+        cache.write_blocks(3, &[9; SECTOR_SIZE]).unwrap();
+        cache.write_blocks(3, &[7; SECTOR_SIZE]).unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 0);
+
+        let mut buf = [0; SECTOR_SIZE];
+        cache.read_blocks(3, &mut buf).unwrap();
+        assert_eq!(buf, [7; SECTOR_SIZE]);
+
+        cache.sync().unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+        disk.read_blocks(3, &mut buf).unwrap();
+        assert_eq!(buf, [7; SECTOR_SIZE]);
+        cache.sync().unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn test_evicts_least_recently_used() {
+        let (disk, cache) = cache(2);
+        let mut buf = [0; SECTOR_SIZE];
+        cache.write_blocks(0, &[1; SECTOR_SIZE]).unwrap();
+        cache.read_blocks(1, &mut buf).unwrap();
+        cache.read_blocks(0, &mut buf).unwrap();
+        // Evicts block 1, which is clean.
+        cache.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 0);
+        // Evicts block 0, which is dirty.
+        cache.read_blocks(1, &mut buf).unwrap();
+        assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.stats().misses, 3);
+    }
+}
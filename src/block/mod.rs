@@ -0,0 +1,216 @@
+//! Block devices.
+//!
+//! A [BlockDevice] is storage addressed in fixed-size blocks, such as a disk.
+//! Filesystems read and write whole blocks through the trait, or arbitrary
+//! byte ranges through [BlockDevice::read_at], without knowing which driver
+//! is behind it.
+//!
+//! Drivers [register] their devices under a name like `ata0`, which is how
+//! the rest of the kernel finds them. Filesystems with scattered metadata,
+//! like FAT, should sit on a [BlockCache] rather than directly on the device.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+
+use spin::Mutex;
+
+pub mod cache;
+
+pub use cache::BlockCache;
+
+/// Size of a block of most devices, and the default of [BlockDevice::block_size].
+pub const SECTOR_SIZE: usize = 512;
+
+/// Reasons block device operations fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The blocks are past the end of the device.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    Misaligned,
+    /// The device cannot be written to.
+    ReadOnly,
+    /// The device reported an error.
+    Io,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::OutOfRange => "block out of range",
+            Error::Misaligned => "buffer is not a whole number of blocks",
+            Error::ReadOnly => "device is read-only",
+            Error::Io => "input/output error",
+        })
+    }
+}
+
+/// Storage addressed in blocks.
+pub trait BlockDevice: Send + Sync {
+    /// Returns the size of a block in bytes.
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    /// Returns the number of blocks of the device.
+    fn block_count(&self) -> u64;
+
+    /// Reads the blocks starting at `block` into `buf`, whose length must be
+    /// a multiple of the block size.
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes `data`, whose length must be a multiple of the block size, to
+    /// the blocks starting at `block`.
+    fn write_blocks(&self, _block: u64, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Waits until every write reached the storage.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes starting at byte `offset`, which need not be
+    /// aligned to blocks.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let block_size = self.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + buf.len() as u64).div_ceil(block_size);
+        let skip = (offset % block_size) as usize;
+        if skip == 0 && (buf.len() as u64).is_multiple_of(block_size) {
+            return self.read_blocks(first, buf);
+        }
+
+        let mut blocks = vec![0; ((last - first) * block_size) as usize];
+        self.read_blocks(first, &mut blocks)?;
+        buf.copy_from_slice(&blocks[skip..skip + buf.len()]);
+        Ok(())
+    }
+}
+
+/// Checks that a transfer of `len` bytes starting at `block` fits `device`,
+/// for drivers to call before touching the hardware.
+pub fn check_range(device: &dyn BlockDevice, block: u64, len: usize) -> Result<(), Error> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(Error::Misaligned);
+    }
+    let count = (len / device.block_size()) as u64;
+    match block.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(()),
+        _ => Err(Error::OutOfRange),
+    }
+}
+
+/// A block device backed by memory.
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk of `blocks` sectors.
+    pub fn new(blocks: usize) -> Self {
+        RamDisk::from_image(vec![0; blocks * SECTOR_SIZE])
+    }
+
+    /// Creates a disk holding `image`, padded with zeroes to whole sectors.
+    pub fn from_image(mut image: Vec<u8>) -> Self {
+        image.resize(image.len().next_multiple_of(SECTOR_SIZE), 0);
+        RamDisk {
+            data: Mutex::new(image),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(self, block, buf.len())?;
+        let start = block as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), Error> {
+        check_range(self, block, data.len())?;
+        let start = block as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Registered block devices, by name.
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Makes `device` available under `name`, replacing any device previously
+/// registered under it.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().insert(name.to_string(), device);
+}
+
+/// Returns the device registered under `name`.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// Returns the registered devices, ordered by name.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
+
+/// Flushes every registered device, writing back what caches hold.
+pub fn sync() -> Result<(), Error> {
+    for (_, device) in devices() {
+        device.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_ram_disk() {
+        let disk = RamDisk::new(4);
+        assert_eq!(disk.block_count(), 4);
+        disk.write_blocks(1, &[7; SECTOR_SIZE]).unwrap();
+
+        let mut buf = [0; SECTOR_SIZE];
+        disk.read_blocks(1, &mut buf).unwrap();
+        assert_eq!(buf, [7; SECTOR_SIZE]);
+        assert_eq!(disk.read_blocks(4, &mut buf), Err(Error::OutOfRange));
+        assert_eq!(disk.read_blocks(0, &mut buf[1..]), Err(Error::Misaligned));
+    }
+
+    #[test_case]
+    fn test_read_at() {
+        let image: Vec<u8> = (0..3 * SECTOR_SIZE).map(|i| i as u8).collect();
+        let disk = RamDisk::from_image(image.clone());
+
+        let mut buf = [0; 600];
+        disk.read_at(500, &mut buf).unwrap();
+        assert_eq!(buf[..], image[500..1100]);
+        assert_eq!(disk.read_at(1000, &mut buf), Err(Error::OutOfRange));
+    }
+
+    #[test_case]
+    fn test_registry() {
+        register("test-ram0", Arc::new(RamDisk::new(1)));
+        assert_eq!(get("test-ram0").unwrap().block_count(), 1);
+        assert!(get("test-missing").is_none());
+        assert!(devices().iter().any(|(name, _)| name == "test-ram0"));
+    }
+}
@@ -0,0 +1,68 @@
+//! The kernel command line.
+//!
+//! The bootloader passes no command line, so the kernel takes its arguments
+//! from the SMBIOS [OEM strings](smbios::oem_strings) starting with
+//! `toyos:`, which QEMU sets with:
+//!
+//! ```text
+//! -smbios type=11,value="toyos:log=warn quiet"
+//! ```
+//!
+//! Arguments are separated by spaces, and are either flags like `quiet` or
+//! settings like `log=warn`. A setting given more than once takes its last
+//! value.
+
+use alloc::vec::Vec;
+
+use spin::Once;
+
+use crate::smbios;
+
+/// The prefix of the OEM strings holding arguments.
+const PREFIX: &str = "toyos:";
+
+static ARGS: Once<Vec<&'static str>> = Once::new();
+
+/// Returns the arguments in the order they were given in.
+///
+/// # Panics
+///
+/// Panics if called before [crate::mem::init].
+pub fn args() -> &'static [&'static str] {
+    ARGS.call_once(|| parse(&smbios::oem_strings()))
+}
+
+/// Returns the value of the last setting of `key`, or `None` if it is not
+/// set.
+pub fn get(key: &str) -> Option<&'static str> {
+    args()
+        .iter()
+        .rev()
+        .find_map(|arg| arg.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Returns `true` if the flag is given.
+pub fn has(flag: &str) -> bool {
+    args().contains(&flag)
+}
+
+/// Splits the OEM strings with the prefix into arguments.
+fn parse(strings: &[&'static str]) -> Vec<&'static str> {
+    strings
+        .iter()
+        .filter_map(|string| string.strip_prefix(PREFIX))
+        .flat_map(|args| args.split_ascii_whitespace())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse() {
+        let args = parse(&["vendor string", "toyos:log=info quiet", "toyos: log=warn "]);
+        assert_eq!(args, ["log=info", "quiet", "log=warn"]);
+        assert!(parse(&["log=debug"]).is_empty());
+    }
+}
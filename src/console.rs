@@ -0,0 +1,167 @@
+//! Consoles which kernel output is printed to.
+//!
+//! A [Console] is anything text can be written to: the [VGA writer](vga),
+//! a [serial port](serial) or the [debug console](debugcon). Text printed
+//! with [print](crate::print) and [println](crate::println) goes to every
+//! registered console, which at boot is the VGA writer alone. Registering a
+//! serial port as well captures the kernel's output on machines without a
+//! screen. Building with the `serial-console` feature registers COM1 from
+//! the start, so that not even the output of early boot is lost.
+//!
+//! ```no_run
+//! toyos::console::register(&toyos::serial::COM1);
+//! ```
+
+use core::fmt;
+
+use crate::{serial, sync::IrqSpinlock, vga, vga::Color};
+
+/// Maximum number of registered consoles.
+pub const MAX_CONSOLES: usize = 4;
+
+/// A sink for kernel output.
+pub trait Console: Sync {
+    /// Returns the name of the console, which is unique among registered
+    /// consoles.
+    fn name(&self) -> &str;
+
+    /// Writes formatted text.
+    fn print(&self, args: fmt::Arguments);
+
+    /// Writes formatted text in the given foreground color. Consoles without
+    /// colors write the text as it is.
+    fn print_colored(&self, _color: Color, args: fmt::Arguments) {
+        self.print(args);
+    }
+}
+
+/// The registered consoles. Empty slots come after the others.
+static CONSOLES: IrqSpinlock<[Option<&'static dyn Console>; MAX_CONSOLES]> =
+    IrqSpinlock::new(if cfg!(feature = "serial-console") {
+        [Some(&vga::WRITER), Some(&serial::COM1), None, None]
+    } else {
+        [Some(&vga::WRITER), None, None, None]
+    });
+
+/// Adds a console for printed text to go to. Returns `false` if a console
+/// with the same name is registered already, or there are [MAX_CONSOLES].
+pub fn register(console: &'static dyn Console) -> bool {
+    let mut consoles = CONSOLES.lock();
+    if consoles
+        .iter()
+        .flatten()
+        .any(|c| c.name() == console.name())
+    {
+        return false;
+    }
+    match consoles.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(console);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes the console with the given name. Returns `false` if there is no
+/// such console.
+pub fn unregister(name: &str) -> bool {
+    let mut consoles = CONSOLES.lock();
+    let Some(index) = consoles.iter().flatten().position(|c| c.name() == name) else {
+        return false;
+    };
+    consoles[index..].rotate_left(1);
+    consoles[MAX_CONSOLES - 1] = None;
+    true
+}
+
+/// Returns whether a console with the given name is registered.
+pub fn is_registered(name: &str) -> bool {
+    CONSOLES.lock().iter().flatten().any(|c| c.name() == name)
+}
+
+/// Calls `f` with each registered console, without holding the registry's
+/// lock while printing.
+fn for_each(mut f: impl FnMut(&dyn Console)) {
+    let consoles = *CONSOLES.lock();
+    for console in consoles.iter().flatten() {
+        f(*console);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    for_each(|console| console.print(args));
+}
+
+#[doc(hidden)]
+pub fn _cprint(color: Color, args: fmt::Arguments) {
+    for_each(|console| console.print_colored(color, args));
+}
+
+/// Prints to every registered console.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+/// Prints to every registered console, appending a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Prints in the given foreground color, then restores the previous colors.
+#[macro_export]
+macro_rules! cprint {
+    ($color:expr, $($arg:tt)*) => ($crate::console::_cprint($color, format_args!($($arg)*)));
+}
+
+/// Prints in the given foreground color with a newline, then restores the
+/// previous colors.
+#[macro_export]
+macro_rules! cprintln {
+    ($color:expr) => ($crate::cprint!($color, "\n"));
+    ($color:expr, $($arg:tt)*) => ($crate::cprint!($color, "{}\n", format_args!($($arg)*)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(AtomicUsize);
+
+    impl Console for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn print(&self, _args: fmt::Arguments) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    static COUNTER: Counter = Counter(AtomicUsize::new(0));
+
+    #[test_case]
+    fn test_serial_console() {
+        assert_eq!(is_registered("COM1"), cfg!(feature = "serial-console"));
+    }
+
+    #[test_case]
+    fn test_register() {
+        assert!(is_registered("vga"));
+        assert!(register(&COUNTER));
+        assert!(!register(&COUNTER));
+        crate::println!("counted");
+        crate::cprintln!(Color::Green, "counted");
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 2);
+
+        assert!(unregister("counter"));
+        assert!(!unregister("counter"));
+        crate::println!("not counted");
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,94 @@
+//! The `cpu` module provides helpers for querying processor features.
+//!
+//! See: https://wiki.osdev.org/CPUID
+
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+
+/// First CPUID leaf of the extended function range.
+const EXTENDED_LEAF_BASE: u32 = 0x8000_0000;
+
+/// Executes the `cpuid` instruction for the given leaf and sub-leaf.
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    __cpuid_count(leaf, subleaf)
+}
+
+/// Returns the highest supported standard CPUID leaf.
+pub fn max_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+/// Returns the highest supported extended CPUID leaf.
+pub fn max_extended_leaf() -> u32 {
+    cpuid(EXTENDED_LEAF_BASE, 0).eax
+}
+
+/// Returns `true` if the processor has a local APIC.
+pub fn has_apic() -> bool {
+    const APIC: u32 = 1 << 9;
+    cpuid(1, 0).edx & APIC != 0
+}
+
+/// Returns `true` if the processor's local APIC supports x2APIC mode.
+pub fn has_x2apic() -> bool {
+    const X2APIC: u32 = 1 << 21;
+    cpuid(1, 0).ecx & X2APIC != 0
+}
+
+/// Returns `true` if the processor supports the machine check exception and
+/// the machine check architecture MSRs.
+pub fn has_machine_check() -> bool {
+    const MCE: u32 = 1 << 7;
+    const MCA: u32 = 1 << 14;
+
+    cpuid(1, 0).edx & (MCE | MCA) == MCE | MCA
+}
+
+/// Returns `true` if the processor has the `xsave` family of instructions.
+pub fn has_xsave() -> bool {
+    const XSAVE: u32 = 1 << 26;
+    cpuid(1, 0).ecx & XSAVE != 0
+}
+
+/// Returns `true` if the processor supports AVX.
+pub fn has_avx() -> bool {
+    const AVX: u32 = 1 << 28;
+    cpuid(1, 0).ecx & AVX != 0
+}
+
+/// Returns `true` if the processor has the `rdrand` instruction.
+pub fn has_rdrand() -> bool {
+    const RDRAND: u32 = 1 << 30;
+    cpuid(1, 0).ecx & RDRAND != 0
+}
+
+/// Returns `true` if the processor has the `rdseed` instruction.
+pub fn has_rdseed() -> bool {
+    const EXTENDED_FEATURES_LEAF: u32 = 7;
+    const RDSEED: u32 = 1 << 18;
+
+    max_leaf() >= EXTENDED_FEATURES_LEAF && cpuid(EXTENDED_FEATURES_LEAF, 0).ebx & RDSEED != 0
+}
+
+/// Returns `true` if the time stamp counter runs at a constant rate regardless
+/// of power state or frequency scaling.
+pub fn has_invariant_tsc() -> bool {
+    const ADVANCED_POWER_MANAGEMENT_LEAF: u32 = 0x8000_0007;
+    const INVARIANT_TSC: u32 = 1 << 8;
+
+    max_extended_leaf() >= ADVANCED_POWER_MANAGEMENT_LEAF
+        && cpuid(ADVANCED_POWER_MANAGEMENT_LEAF, 0).edx & INVARIANT_TSC != 0
+}
+
+/// Returns `true` if the processor supports `monitor`/`mwait` and lets
+/// interrupts end an `mwait` even while they are masked.
+pub fn has_mwait() -> bool {
+    const MONITOR: u32 = 1 << 3;
+    const MWAIT_LEAF: u32 = 5;
+    const EXTENSIONS: u32 = 1 << 0;
+    const INTERRUPT_BREAK: u32 = 1 << 1;
+
+    cpuid(1, 0).ecx & MONITOR != 0
+        && max_leaf() >= MWAIT_LEAF
+        && cpuid(MWAIT_LEAF, 0).ecx & (EXTENSIONS | INTERRUPT_BREAK) == EXTENSIONS | INTERRUPT_BREAK
+}
@@ -0,0 +1,68 @@
+//! The debug console of Bochs and QEMU.
+//!
+//! Every byte written to port 0xe9 appears on the host at once, without the
+//! emulation of a UART in between, which makes it faster than a serial port
+//! and leaves the serial ports free for data. QEMU shows it when started
+//! with a `-debugcon` option, such as `-debugcon stdio`.
+//!
+//! See: https://wiki.osdev.org/QEMU#Debugging
+
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+use crate::{
+    console::Console,
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+};
+
+pub const PORT: u16 = 0xe9;
+
+/// Keeps lines written by different CPUs from interleaving.
+static DEBUGCON: IrqSpinlock<DebugCon> = IrqSpinlock::new(DebugCon(()));
+
+/// Writes to the debug console.
+pub struct DebugCon(());
+
+impl DebugCon {
+    pub fn send(&mut self, byte: u8) {
+        unsafe { Port::new(PORT).write(byte) };
+    }
+}
+
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+impl Console for IrqSpinlock<DebugCon> {
+    fn name(&self) -> &str {
+        "debugcon"
+    }
+
+    fn print(&self, args: fmt::Arguments) {
+        use fmt::Write;
+
+        _ = self.lock().write_fmt(args);
+    }
+}
+
+/// Returns the debug console as a [Console], to register.
+pub fn console() -> &'static dyn Console {
+    &DEBUGCON
+}
+
+/// Locks the debug console for writing.
+pub fn lock() -> IrqSpinlockGuard<'static, DebugCon> {
+    DEBUGCON.lock()
+}
+
+/// Returns `true` if the emulator has a debug console, which reads as the
+/// number of its port.
+pub fn is_present() -> bool {
+    unsafe { Port::<u8>::new(PORT).read() == PORT as u8 }
+}
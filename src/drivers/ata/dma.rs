@@ -0,0 +1,181 @@
+//! Bus-master DMA through the IDE controller.
+//!
+//! The controller's bus-master registers, found through BAR4 of the PCI
+//! function, take the physical address of a table of physical region
+//! descriptors (PRDs). Each describes a block of memory the controller moves
+//! sectors to or from on its own once the drive is sent a DMA command, after
+//! which the drive raises its interrupt a single time, however many sectors
+//! were transferred.
+//!
+//! Each channel has a table of [BUFFER_FRAMES] descriptors, each covering a
+//! frame of its own. Sectors are copied between the frames and the caller's
+//! buffer, whose physical memory need not be contiguous or even mapped
+//! below 4 GiB, which the 32-bit addresses in descriptors require.
+//!
+//! See: https://wiki.osdev.org/ATA/ATAPI_using_DMA
+
+use x86_64::{
+    instructions::port::Port,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+};
+
+use crate::{
+    block::{Error, SECTOR_SIZE},
+    mem::{phys_to_virt, GlobalFrameAllocator},
+};
+
+// Registers relative to the bus-master base of a channel.
+const REG_COMMAND: u16 = 0;
+const REG_STATUS: u16 = 2;
+const REG_PRDT: u16 = 4;
+
+const COMMAND_START: u8 = 1 << 0;
+/// Bit of the command register making the controller write to memory, which
+/// is the direction of a read from the disk.
+const COMMAND_READ: u8 = 1 << 3;
+
+const STATUS_ERROR: u8 = 1 << 1;
+const STATUS_INTERRUPT: u8 = 1 << 2;
+
+/// Bit of a descriptor marking the end of the table.
+const PRD_END: u64 = 1 << 63;
+
+const FRAME_SIZE: usize = 4096;
+
+/// Number of frames sectors are transferred through.
+const BUFFER_FRAMES: usize = 16;
+
+/// Most sectors moved by a single command.
+pub(super) const MAX_SECTORS: usize = BUFFER_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+
+/// The bus-master registers and memory of a channel.
+pub(super) struct Dma {
+    base: u16,
+    prdt: PhysFrame,
+    buffers: [PhysFrame; BUFFER_FRAMES],
+}
+
+impl Dma {
+    /// Allocates the descriptor table and buffers for the channel whose
+    /// bus-master registers start at `base`. Returns `None` if frames run
+    /// out or one lies above 4 GiB.
+    pub(super) fn new(base: u16) -> Option<Dma> {
+        let mut frames = [None; BUFFER_FRAMES + 1];
+        for index in 0..frames.len() {
+            frames[index] = GlobalFrameAllocator.allocate_frame();
+            let reachable = frames[index]
+                .is_some_and(|frame| frame.start_address().as_u64() <= u32::MAX as u64);
+            if !reachable {
+                for frame in frames.iter().flatten() {
+                    unsafe { GlobalFrameAllocator.deallocate_frame(*frame) };
+                }
+                return None;
+            }
+        }
+        let frames = frames.map(Option::unwrap);
+        Some(Dma {
+            base,
+            prdt: frames[0],
+            buffers: frames[1..].try_into().unwrap(),
+        })
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Returns the `index`th buffer frame.
+    fn buffer(&self, index: usize) -> *mut u8 {
+        phys_to_virt(self.buffers[index].start_address()).as_mut_ptr()
+    }
+
+    /// Copies `data`, at most [MAX_SECTORS] sectors, into the buffers to be
+    /// written to the disk.
+    pub(super) fn copy_in(&self, data: &[u8]) {
+        for (index, chunk) in data.chunks(FRAME_SIZE).enumerate() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), self.buffer(index), chunk.len())
+            };
+        }
+    }
+
+    /// Copies the sectors read from the disk out of the buffers into `buf`.
+    pub(super) fn copy_out(&self, buf: &mut [u8]) {
+        for (index, chunk) in buf.chunks_mut(FRAME_SIZE).enumerate() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(self.buffer(index), chunk.as_mut_ptr(), chunk.len())
+            };
+        }
+    }
+
+    /// Sets up the controller to transfer `len` bytes from the disk if
+    /// `read` is set, or to it otherwise. Must be called before the drive is
+    /// sent the command.
+    pub(super) fn prepare(&self, len: usize, read: bool) {
+        let table = phys_to_virt(self.prdt.start_address()).as_mut_ptr::<u64>();
+        for (index, entry) in prd_entries(&self.buffers, len).enumerate() {
+            unsafe { table.add(index).write_volatile(entry) };
+        }
+        let prdt = self.prdt.start_address().as_u64() as u32;
+        unsafe { Port::new(self.base + REG_PRDT).write(prdt) };
+        self.write(REG_COMMAND, if read { COMMAND_READ } else { 0 });
+        // The error and interrupt bits are cleared by writing ones.
+        self.write(REG_STATUS, STATUS_ERROR | STATUS_INTERRUPT);
+    }
+
+    /// Starts the transfer, once the drive has been sent the command.
+    pub(super) fn start(&self) {
+        let command = self.read(REG_COMMAND);
+        self.write(REG_COMMAND, command | COMMAND_START);
+    }
+
+    /// Stops the controller after the drive has completed the command, and
+    /// checks that the transfer did not fail.
+    pub(super) fn finish(&self) -> Result<(), Error> {
+        let command = self.read(REG_COMMAND);
+        self.write(REG_COMMAND, command & !COMMAND_START);
+        let status = self.read(REG_STATUS);
+        self.write(REG_STATUS, STATUS_ERROR | STATUS_INTERRUPT);
+        if status & STATUS_ERROR != 0 {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the descriptors covering the first `len` bytes of `frames`, the
+/// last marked as the end of the table.
+fn prd_entries(frames: &[PhysFrame], len: usize) -> impl Iterator<Item = u64> + '_ {
+    let count = len.div_ceil(FRAME_SIZE);
+    frames[..count]
+        .iter()
+        .enumerate()
+        .map(move |(index, frame)| {
+            let size = (len - index * FRAME_SIZE).min(FRAME_SIZE) as u64;
+            let end = if index + 1 == count { PRD_END } else { 0 };
+            frame.start_address().as_u64() | size << 32 | end
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use x86_64::PhysAddr;
+
+    use super::*;
+
+    #[test_case]
+    fn test_prd_entries() {
+        let frames =
+            [0x1000, 0x5000, 0x3000].map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
+        let entries: Vec<u64> = prd_entries(&frames, FRAME_SIZE + SECTOR_SIZE).collect();
+        assert_eq!(entries, [0x1000_0000_1000, 0x8000_0200_0000_5000]);
+        let entries: Vec<u64> = prd_entries(&frames, SECTOR_SIZE).collect();
+        assert_eq!(entries, [0x8000_0200_0000_1000]);
+    }
+}
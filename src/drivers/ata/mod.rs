@@ -0,0 +1,574 @@
+//! Driver for ATA disks on the legacy IDE channels, using bus-master DMA
+//! where the controller and drive support it and programmed I/O (PIO)
+//! otherwise.
+//!
+//! There are two channels, each with up to two drives, a master and a slave.
+//! [init] sends IDENTIFY to all four and registers every ATA disk found as a
+//! [block device](crate::block) named `ata0` to `ata3`, in the order primary
+//! master, primary slave, secondary master, secondary slave.
+//!
+//! Sectors are addressed with 28-bit LBAs, or 48-bit LBAs past the first 128
+//! GiB on disks supporting them. The drive raises IRQ14 or IRQ15, for the
+//! primary or secondary channel, and the requesting thread is parked until
+//! then rather than spinning, leaving the CPU to other tasks.
+//!
+//! With PIO, every sector moves through the data port, and the drive raises
+//! its interrupt whenever it has a sector ready to be read or has taken one
+//! written. With [DMA](dma), the IDE controller found on the PCI bus moves
+//! the sectors of a whole command, and the drive raises its interrupt once
+//! they have all been moved.
+//!
+//! See: https://wiki.osdev.org/ATA_PIO_Mode
+
+mod dma;
+
+use alloc::{format, string::String, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use self::dma::Dma;
+use crate::{
+    block::{self, check_range, BlockDevice, Error, SECTOR_SIZE},
+    interrupts,
+    pci::{self, Bar},
+    task::thread::WaitQueue,
+    time::{self, Duration},
+};
+
+// Registers relative to the I/O base of a channel.
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+/// Reading the status register acknowledges the interrupt.
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+/// Status read from a channel with no drives, whose lines float high.
+const STATUS_FLOATING: u8 = 0xff;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_READ_DMA: u8 = 0xc8;
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_WRITE_DMA: u8 = 0xca;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// Bit of the drive register selecting LBA rather than CHS addressing.
+const DRIVE_LBA: u8 = 1 << 6;
+/// Bits of the drive register which are always set.
+const DRIVE_FIXED: u8 = 0xa0;
+/// Bit of the drive register selecting the slave.
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+/// PCI class and subclass of IDE controllers.
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_IDE: u8 = 0x01;
+/// Bit of the programming interface of an IDE controller set if it can act
+/// as a bus master.
+const PROG_IF_BUS_MASTER: u8 = 1 << 7;
+/// Offset of the bus-master registers of the secondary channel.
+const SECONDARY_BUS_MASTER: u16 = 8;
+
+/// Highest sector reachable with a 28-bit LBA, plus one.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Most sectors moved by a single command. A sector count of 0 stands for
+/// 256 in 28-bit commands, so larger transfers are split.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+/// How long a drive may take to complete a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An IDE channel.
+struct Channel {
+    /// First of the eight command block registers.
+    base: u16,
+    /// The device control and alternate status register.
+    control: u16,
+    /// The IRQ of the legacy PIC the channel raises.
+    irq: u8,
+    /// Set while a thread issues commands to one of the channel's drives.
+    busy: AtomicBool,
+    /// Threads waiting for the channel to be free.
+    idle: WaitQueue,
+    /// Set by the interrupt handler, cleared before issuing a command.
+    interrupted: AtomicBool,
+    /// The thread waiting for the interrupt.
+    interrupt: WaitQueue,
+    /// Set up by [init] if the controller supports bus-master DMA.
+    dma: Once<Dma>,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel::new(0x1f0, 0x3f6, 14),
+    Channel::new(0x170, 0x376, 15),
+];
+
+impl Channel {
+    const fn new(base: u16, control: u16, irq: u8) -> Self {
+        Channel {
+            base,
+            control,
+            irq,
+            busy: AtomicBool::new(false),
+            idle: WaitQueue::new(),
+            interrupted: AtomicBool::new(false),
+            interrupt: WaitQueue::new(),
+            dma: Once::new(),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt.
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Waits about 400ns, as drives need after being selected or sent a
+    /// command before their status is meaningful.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Blocks until no other thread uses the channel, and returns a guard
+    /// which frees it when dropped.
+    fn lock(&self) -> ChannelGuard<'_> {
+        self.idle.wait_until(|| {
+            self.busy
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        ChannelGuard(self)
+    }
+
+    fn select(&self, slave: bool, bits: u8) {
+        let slave = if slave { DRIVE_SLAVE } else { 0 };
+        self.write(REG_DRIVE, DRIVE_FIXED | slave | bits);
+        self.delay();
+    }
+
+    /// Waits until the drive is no longer busy and returns its status.
+    fn wait_not_busy(&self) -> Result<u8, Error> {
+        let deadline = time::ticks() + time::duration_to_ticks(COMMAND_TIMEOUT);
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if time::ticks() >= deadline {
+                return Err(Error::Io);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits for the drive to raise its interrupt, then checks that the
+    /// command did not fail.
+    ///
+    /// Should the interrupt not arrive, the status is polled instead, so the
+    /// driver also works with the IRQ masked.
+    fn wait_interrupt(&self) -> Result<u8, Error> {
+        self.interrupt.wait_until_timeout(
+            || self.interrupted.swap(false, Ordering::Acquire),
+            Duration::from_millis(100),
+        );
+        self.wait_not_busy()?;
+        let status = self.read(REG_STATUS);
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(Error::Io);
+        }
+        Ok(status)
+    }
+
+    /// Sets up the registers for a transfer of `count` sectors starting at
+    /// `lba` and sends `command`.
+    fn start(&self, slave: bool, lba: u64, count: usize, lba48: bool, command: u8) {
+        self.interrupted.store(false, Ordering::Relaxed);
+        if lba48 {
+            self.select(slave, DRIVE_LBA);
+            // The high bytes go first, through the same registers.
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(slave, DRIVE_LBA | (lba >> 24) as u8 & 0xf);
+        }
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+        self.write(REG_COMMAND, command);
+        self.delay();
+    }
+
+    fn read_sector(&self, buf: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buf: &[u8]) {
+        let mut data = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+}
+
+/// Frees a [Channel] when dropped.
+struct ChannelGuard<'a>(&'a Channel);
+
+impl Drop for ChannelGuard<'_> {
+    fn drop(&mut self) {
+        self.0.busy.store(false, Ordering::Release);
+        self.0.idle.wake_one();
+    }
+}
+
+/// What a drive reports about itself in response to IDENTIFY.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub model: String,
+    /// Number of addressable sectors.
+    pub sectors: u64,
+    /// Whether the drive supports 48-bit LBAs.
+    pub lba48: bool,
+    /// Whether the drive supports DMA.
+    pub dma: bool,
+}
+
+impl Identity {
+    /// Decodes the 256 words returned by IDENTIFY.
+    fn parse(words: &[u16; 256]) -> Identity {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let dma = words[49] & (1 << 8) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, i| {
+                sectors | (words[100 + i] as u64) << (16 * i)
+            })
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        // Strings hold two characters per word, the first in the high byte.
+        let model: String = words[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(char::from)
+            .collect();
+        Identity {
+            model: String::from(model.trim_end()),
+            sectors,
+            lba48,
+            dma,
+        }
+    }
+}
+
+/// An ATA disk.
+pub struct Drive {
+    channel: &'static Channel,
+    slave: bool,
+    identity: Identity,
+}
+
+impl Drive {
+    /// Sends IDENTIFY to a drive, returning `None` if there is no drive or
+    /// it is not an ATA disk, like an ATAPI CD-ROM.
+    fn identify(channel: &'static Channel, slave: bool) -> Option<Drive> {
+        let _guard = channel.lock();
+        if channel.alternate_status() == STATUS_FLOATING {
+            return None;
+        }
+        channel.select(slave, 0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            channel.write(register, 0);
+        }
+        channel.write(REG_COMMAND, COMMAND_IDENTIFY);
+        channel.delay();
+        if channel.alternate_status() == 0 {
+            return None;
+        }
+        channel.wait_not_busy().ok()?;
+        // Other kinds of devices set these to their signature and abort the
+        // command.
+        if channel.read(REG_LBA_MID) != 0 || channel.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        let status = channel.wait_not_busy().ok()?;
+        if status & STATUS_ERR != 0 || status & STATUS_DRQ == 0 {
+            return None;
+        }
+
+        let mut bytes = [0; SECTOR_SIZE];
+        channel.read_sector(&mut bytes);
+        // Acknowledge the interrupt raised by IDENTIFY.
+        channel.read(REG_STATUS);
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Some(Drive {
+            channel,
+            slave,
+            identity: Identity::parse(&words),
+        })
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Returns whether a transfer ending before `end` needs 48-bit LBAs.
+    fn needs_lba48(&self, end: u64) -> bool {
+        self.identity.lba48 && end > LBA28_LIMIT
+    }
+
+    /// Returns the DMA registers and memory of the channel, if both the
+    /// controller and the drive support DMA.
+    fn dma(&self) -> Option<&'static Dma> {
+        self.channel.dma.get().filter(|_| self.identity.dma)
+    }
+
+    fn sectors_per_command(&self) -> usize {
+        if self.dma().is_some() {
+            dma::MAX_SECTORS
+        } else {
+            MAX_SECTORS_PER_COMMAND
+        }
+    }
+
+    /// Starts a transfer of the sectors of `len` bytes from `lba`, with
+    /// `command` or, if 48-bit LBAs are needed, `command_ext`.
+    fn start(&self, lba: u64, len: usize, command: u8, command_ext: u8) {
+        let count = len / SECTOR_SIZE;
+        let lba48 = self.needs_lba48(lba + count as u64);
+        let command = if lba48 { command_ext } else { command };
+        self.channel.start(self.slave, lba, count, lba48, command);
+    }
+
+    fn read_pio(&self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.start(
+            lba,
+            buf.len(),
+            COMMAND_READ_SECTORS,
+            COMMAND_READ_SECTORS_EXT,
+        );
+        for sector in buf.chunks_exact_mut(SECTOR_SIZE) {
+            if self.channel.wait_interrupt()? & STATUS_DRQ == 0 {
+                return Err(Error::Io);
+            }
+            self.channel.read_sector(sector);
+        }
+        Ok(())
+    }
+
+    fn write_pio(&self, lba: u64, data: &[u8]) -> Result<(), Error> {
+        self.start(
+            lba,
+            data.len(),
+            COMMAND_WRITE_SECTORS,
+            COMMAND_WRITE_SECTORS_EXT,
+        );
+        // The drive asks for the first sector without an interrupt, and
+        // raises one after taking each sector.
+        let status = self.channel.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 || status & STATUS_DRQ == 0 {
+            return Err(Error::Io);
+        }
+        for sector in data.chunks_exact(SECTOR_SIZE) {
+            self.channel.write_sector(sector);
+            self.channel.wait_interrupt()?;
+        }
+        Ok(())
+    }
+
+    fn read_dma(&self, dma: &Dma, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        dma.prepare(buf.len(), true);
+        self.start(lba, buf.len(), COMMAND_READ_DMA, COMMAND_READ_DMA_EXT);
+        dma.start();
+        let status = self.channel.wait_interrupt();
+        dma.finish()?;
+        status?;
+        dma.copy_out(buf);
+        Ok(())
+    }
+
+    fn write_dma(&self, dma: &Dma, lba: u64, data: &[u8]) -> Result<(), Error> {
+        dma.copy_in(data);
+        dma.prepare(data.len(), false);
+        self.start(lba, data.len(), COMMAND_WRITE_DMA, COMMAND_WRITE_DMA_EXT);
+        dma.start();
+        let status = self.channel.wait_interrupt();
+        dma.finish()?;
+        status.map(|_| ())
+    }
+}
+
+impl BlockDevice for Drive {
+    fn block_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(self, block, buf.len())?;
+        let _guard = self.channel.lock();
+        let dma = self.dma();
+        let sectors = self.sectors_per_command();
+        let chunks = buf.chunks_mut(sectors * SECTOR_SIZE);
+        for (lba, chunk) in (block..).step_by(sectors).zip(chunks) {
+            match dma {
+                Some(dma) => self.read_dma(dma, lba, chunk)?,
+                None => self.read_pio(lba, chunk)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), Error> {
+        check_range(self, block, data.len())?;
+        let _guard = self.channel.lock();
+        let dma = self.dma();
+        let sectors = self.sectors_per_command();
+        let chunks = data.chunks(sectors * SECTOR_SIZE);
+        for (lba, chunk) in (block..).step_by(sectors).zip(chunks) {
+            match dma {
+                Some(dma) => self.write_dma(dma, lba, chunk)?,
+                None => self.write_pio(lba, chunk)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the drive to write its cache to the disk.
+    fn flush(&self) -> Result<(), Error> {
+        let _guard = self.channel.lock();
+        let command = if self.identity.lba48 {
+            COMMAND_CACHE_FLUSH_EXT
+        } else {
+            COMMAND_CACHE_FLUSH
+        };
+        self.channel.interrupted.store(false, Ordering::Relaxed);
+        self.channel.select(self.slave, 0);
+        self.channel.write(REG_COMMAND, command);
+        self.channel.delay();
+        self.channel.wait_interrupt().map(|_| ())
+    }
+}
+
+/// Acknowledges the interrupt of the primary (0) or secondary (1) channel
+/// and wakes the thread waiting for it. Called by the interrupt handlers.
+pub fn handle_interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    channel.read(REG_STATUS);
+    channel.interrupted.store(true, Ordering::Release);
+    channel.interrupt.wake_all();
+}
+
+/// Detects the drives on both channels and registers the ATA disks among
+/// them. Returns the number of disks found.
+pub fn init() -> usize {
+    init_dma();
+    let mut found = 0;
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        interrupts::enable_irq(channel.irq);
+        for slave in [false, true] {
+            if let Some(drive) = Drive::identify(channel, slave) {
+                let name = format!("ata{}", index * 2 + slave as usize);
+                block::register(&name, Arc::new(drive));
+                found += 1;
+            }
+        }
+    }
+    found
+}
+
+/// Sets up DMA on both channels if the IDE controller can act as a bus
+/// master. The channels are assumed to be at their legacy ports, as they are
+/// on controllers which can.
+fn init_dma() {
+    let Some(controller) = pci::find_class(CLASS_STORAGE, SUBCLASS_IDE)
+        .find(|device| device.class.prog_if & PROG_IF_BUS_MASTER != 0)
+    else {
+        return;
+    };
+    let Some(Bar::Io { port, .. }) = controller.bars[4] else {
+        return;
+    };
+    controller.enable_bus_mastering();
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        if channel.dma.get().is_some() {
+            continue;
+        }
+        if let Some(dma) = Dma::new(port + index as u16 * SECONDARY_BUS_MASTER) {
+            channel.dma.call_once(|| dma);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_identity() {
+        let mut words = [0; 256];
+        words[27] = u16::from_be_bytes(*b"QE");
+        words[28] = u16::from_be_bytes(*b"MU");
+        words[29] = u16::from_be_bytes(*b"  ");
+        words[60] = 0x0000;
+        words[61] = 0x0010;
+        assert_eq!(
+            Identity::parse(&words),
+            Identity {
+                model: String::from("QEMU"),
+                sectors: 0x10_0000,
+                lba48: false,
+                dma: false,
+            }
+        );
+
+        words[83] = 1 << 10;
+        words[100] = 0x1234;
+        words[102] = 0x0001;
+        assert_eq!(Identity::parse(&words).sectors, 0x1_0000_0000_1234);
+    }
+
+    #[test_case]
+    fn test_boot_disk() {
+        // QEMU attaches the boot image as the primary master.
+        init();
+        let disk = block::get("ata0").expect("no boot disk");
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read_blocks(0, &mut sector).unwrap();
+        assert_eq!(sector[510..], [0x55, 0xaa]);
+        assert_eq!(
+            disk.read_blocks(disk.block_count(), &mut sector),
+            Err(Error::OutOfRange)
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! Driver for Intel 8254x and 82574 gigabit Ethernet controllers, QEMU's
+//! `e1000` and `e1000e`, the former being its default NIC.
+//!
+//! The controller's registers are memory-mapped through BAR0. Its MAC
+//! address is read from the EEPROM. Frames move through two rings of
+//! descriptors in memory, one for receiving and one for transmitting, each
+//! pointing at a buffer of its own. The controller owns the descriptors
+//! between the ring's head, which it advances, and its tail, which the
+//! driver advances.
+//!
+//! The controller interrupts when it has received frames, and frames are
+//! taken from the receive ring by [NetworkDevice::recv_frame].
+//!
+//! See: https://wiki.osdev.org/Intel_Ethernet_i217 and Intel's "PCI/PCI-X
+//! Family of Gigabit Ethernet Controllers Software Developer's Manual".
+
+use alloc::{sync::Arc, vec::Vec};
+
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    mem::{phys_to_virt, DmaRegion},
+    net::{self, Error, MacAddress, NetworkDevice, MAX_FRAME_SIZE},
+    pci::{self, Bar},
+    sync::IrqSpinlock,
+    time::{self, Duration},
+};
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+/// Device IDs of the supported controllers: the 82540EM, the 82545EM and the
+/// 82574L.
+const DEVICE_IDS: [u16; 3] = [0x100e, 0x100f, 0x10d3];
+/// Device ID of the 82574L, whose EEPROM read register is laid out
+/// differently.
+const DEVICE_82574: u16 = 0x10d3;
+
+const REG_CTRL: usize = 0x0000;
+const REG_EERD: usize = 0x0014;
+/// Reading the interrupt cause acknowledges the interrupt.
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDT: usize = 0x3818;
+/// The multicast table, 128 registers.
+const REG_MTA: usize = 0x5200;
+/// The low and high halves of the first receive address.
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const EERD_START: u32 = 1 << 0;
+
+/// Interrupt causes of received frames: the receive timer, the ring
+/// running low on descriptors and the ring overflowing.
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_RECEIVE: u32 = INT_RXDMT0 | INT_RXO | INT_RXT0;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Accept broadcast frames.
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the checksum from received frames. Buffers are 2048 bytes with the
+/// size bits left zeroed.
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short frames to the minimum length.
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// The inter-packet gap recommended for copper links.
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+/// Bit of a descriptor's status set once the controller is done with it.
+const STATUS_DD: u8 = 1 << 0;
+/// Bit of a receive descriptor's status set on the last of a frame.
+const STATUS_EOP: u8 = 1 << 1;
+
+const CMD_EOP: u8 = 1 << 0;
+/// Append the checksum.
+const CMD_IFCS: u8 = 1 << 1;
+/// Report the status once sent.
+const CMD_RS: u8 = 1 << 3;
+
+/// Number of descriptors of each ring. The size of a ring must be a
+/// multiple of 128 bytes.
+const RING_SIZE: usize = 32;
+
+/// Size of each buffer, the default receive buffer size.
+const BUFFER_SIZE: usize = 2048;
+
+/// How long the controller may take to reset or read the EEPROM.
+const TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A descriptor of either ring. Receive and transmit descriptors share the
+/// address, length and status; the other fields differ.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Descriptor {
+    address: u64,
+    len: u16,
+    /// The checksum of a received frame, or the checksum offset and the
+    /// command of a frame to transmit.
+    checksum_or_command: [u8; 2],
+    status: u8,
+    /// The errors of a received frame, or the checksum start of a frame to
+    /// transmit.
+    errors: u8,
+    special: u16,
+}
+
+/// A ring of descriptors and their buffers.
+struct Ring {
+    descriptors: DmaRegion,
+    buffers: DmaRegion,
+    /// The next descriptor for the driver to look at.
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Option<Ring> {
+        let ring = Ring {
+            descriptors: DmaRegion::new(RING_SIZE * size_of::<Descriptor>())?,
+            buffers: DmaRegion::new(RING_SIZE * BUFFER_SIZE)?,
+            next: 0,
+        };
+        for index in 0..RING_SIZE {
+            let address = ring.buffers.phys_addr() + (index * BUFFER_SIZE) as u64;
+            ring.write(
+                index,
+                Descriptor {
+                    address: address.as_u64(),
+                    ..Default::default()
+                },
+            );
+        }
+        Some(ring)
+    }
+
+    fn descriptor(&self, index: usize) -> *mut Descriptor {
+        unsafe { self.descriptors.as_mut_ptr::<Descriptor>().add(index) }
+    }
+
+    fn read(&self, index: usize) -> Descriptor {
+        unsafe { self.descriptor(index).read_volatile() }
+    }
+
+    fn write(&self, index: usize, descriptor: Descriptor) {
+        unsafe { self.descriptor(index).write_volatile(descriptor) }
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        unsafe { self.buffers.as_mut_ptr::<u8>().add(index * BUFFER_SIZE) }
+    }
+}
+
+/// The memory-mapped registers of a controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read(&self, register: usize) -> u32 {
+        unsafe {
+            (self.base + register as u64)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe {
+            (self.base + register as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    /// Polls until `done` holds for the value of `register`, returning the
+    /// value, or `None` after [TIMEOUT].
+    fn poll(&self, register: usize, done: impl Fn(u32) -> bool) -> Option<u32> {
+        let deadline = time::ticks() + time::duration_to_ticks(TIMEOUT);
+        loop {
+            let value = self.read(register);
+            if done(value) {
+                return Some(value);
+            }
+            if time::ticks() > deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Points the controller at `ring`, through the registers starting with
+    /// `base_low`, and sets its tail.
+    fn set_ring(&self, base_low: usize, ring: &Ring, tail: usize) {
+        let address = ring.descriptors.phys_addr().as_u64();
+        // The high half of the base, the length, the head and the tail
+        // follow the low half of the base.
+        self.write(base_low, address as u32);
+        self.write(base_low + 4, (address >> 32) as u32);
+        self.write(base_low + 8, (RING_SIZE * size_of::<Descriptor>()) as u32);
+        self.write(base_low + 0x10, 0);
+        self.write(base_low + 0x18, tail as u32);
+    }
+}
+
+/// An Intel gigabit Ethernet controller.
+pub struct E1000 {
+    registers: Registers,
+    mac: MacAddress,
+    receive: Mutex<Ring>,
+    transmit: Mutex<Ring>,
+}
+
+impl E1000 {
+    /// Resets the controller and sets up its rings, returning `None` if it
+    /// cannot be driven.
+    fn new(device: &pci::Device) -> Option<E1000> {
+        let Some(Bar::Memory { address, .. }) = device.bars[0] else {
+            return None;
+        };
+        device.enable_bus_mastering();
+        let registers = Registers {
+            base: phys_to_virt(PhysAddr::new(address)),
+        };
+
+        registers.write(REG_IMC, u32::MAX);
+        registers.write(REG_CTRL, registers.read(REG_CTRL) | CTRL_RST);
+        registers.poll(REG_CTRL, |ctrl| ctrl & CTRL_RST == 0)?;
+        registers.write(REG_IMC, u32::MAX);
+        registers.read(REG_ICR);
+        registers.write(REG_CTRL, registers.read(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        let mac = read_mac(registers, device.device_id == DEVICE_82574);
+        for index in 0..128 {
+            registers.write(REG_MTA + 4 * index, 0);
+        }
+
+        let receive = Ring::new()?;
+        let transmit = Ring::new()?;
+        // Every transmit descriptor starts out done with.
+        for index in 0..RING_SIZE {
+            let mut descriptor = transmit.read(index);
+            descriptor.status = STATUS_DD;
+            transmit.write(index, descriptor);
+        }
+        // The controller fills every receive descriptor but the one before
+        // its head, so that a full ring is told apart from an empty one.
+        registers.set_ring(REG_RDBAL, &receive, RING_SIZE - 1);
+        registers.set_ring(REG_TDBAL, &transmit, 0);
+        registers.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+        registers.write(REG_TIPG, TIPG_DEFAULT);
+        registers.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        Some(E1000 {
+            registers,
+            mac,
+            receive: Mutex::new(receive),
+            transmit: Mutex::new(transmit),
+        })
+    }
+}
+
+/// Reads the MAC address from the EEPROM, or from the receive address the
+/// controller loaded from it if the EEPROM does not respond.
+fn read_mac(registers: Registers, is_82574: bool) -> MacAddress {
+    let (done, address_shift) = if is_82574 { (1 << 1, 2) } else { (1 << 4, 8) };
+    let read_word = |word: u32| {
+        registers.write(REG_EERD, EERD_START | word << address_shift);
+        let value = registers.poll(REG_EERD, |eerd| eerd & done != 0)?;
+        Some((value >> 16) as u16)
+    };
+    let words = [read_word(0), read_word(1), read_word(2)];
+    if let [Some(a), Some(b), Some(c)] = words {
+        let [a0, a1] = a.to_le_bytes();
+        let [b0, b1] = b.to_le_bytes();
+        let [c0, c1] = c.to_le_bytes();
+        return MacAddress([a0, a1, b0, b1, c0, c1]);
+    }
+    let low = registers.read(REG_RAL).to_le_bytes();
+    let high = registers.read(REG_RAH).to_le_bytes();
+    MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+}
+
+impl NetworkDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut transmit = self.transmit.lock();
+        let index = transmit.next;
+        let mut descriptor = transmit.read(index);
+        if descriptor.status & STATUS_DD == 0 {
+            return Err(Error::Busy);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), transmit.buffer(index), frame.len())
+        };
+        descriptor.len = frame.len() as u16;
+        descriptor.checksum_or_command = [0, CMD_EOP | CMD_IFCS | CMD_RS];
+        descriptor.status = 0;
+        transmit.write(index, descriptor);
+        transmit.next = (index + 1) % RING_SIZE;
+        self.registers.write(REG_TDT, transmit.next as u32);
+        Ok(())
+    }
+
+    fn recv_frame(&self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let mut receive = self.receive.lock();
+        let index = receive.next;
+        let mut descriptor = receive.read(index);
+        if descriptor.status & STATUS_DD == 0 {
+            return Ok(None);
+        }
+        let len = descriptor.len as usize;
+        // Frames never span buffers, as they are at most 1518 bytes.
+        let result = if descriptor.status & STATUS_EOP == 0 || descriptor.errors != 0 {
+            Err(Error::Io)
+        } else if len > buf.len() {
+            Err(Error::TooLarge)
+        } else {
+            unsafe { core::ptr::copy_nonoverlapping(receive.buffer(index), buf.as_mut_ptr(), len) };
+            Ok(Some(len))
+        };
+
+        descriptor.status = 0;
+        receive.write(index, descriptor);
+        receive.next = (index + 1) % RING_SIZE;
+        self.registers.write(REG_RDT, index as u32);
+        result
+    }
+}
+
+/// The controllers set up by [init], and their IRQs.
+static DEVICES: IrqSpinlock<Vec<(Registers, u8)>> = IrqSpinlock::new(Vec::new());
+
+/// Acknowledges the interrupts of the controllers on the IRQ, and wakes the
+/// threads waiting for frames.
+fn handle_interrupt() {
+    let mut received = false;
+    for (registers, _) in DEVICES.lock().iter() {
+        received |= registers.read(REG_ICR) & INT_RECEIVE != 0;
+    }
+    if received {
+        net::frame_received();
+    }
+}
+
+static FOUND: Once<usize> = Once::new();
+
+/// Sets up every supported controller and registers it as a
+/// [network device](crate::net). Returns the number of controllers found.
+pub fn init() -> usize {
+    *FOUND.call_once(|| {
+        let mut found = 0;
+        let devices = pci::devices().iter().filter(|device| {
+            device.vendor_id == VENDOR_INTEL && DEVICE_IDS.contains(&device.device_id)
+        });
+        for device in devices {
+            let Some(nic) = E1000::new(device) else {
+                continue;
+            };
+            net::add_controller(
+                "e1000",
+                &DEVICES,
+                nic.registers,
+                device.interrupt_line,
+                handle_interrupt,
+            );
+            nic.registers.write(REG_IMS, INT_RECEIVE);
+            net::register_ethernet(Arc::new(nic));
+            found += 1;
+        }
+        found
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::MIN_FRAME_SIZE;
+
+    /// Address QEMU's user networking gives the guest, and that of its
+    /// gateway.
+    const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+    const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+    #[test_case]
+    fn test_arp() {
+        // QEMU attaches an 82540EM with user networking unless told not to.
+        pci::init();
+        if init() == 0 {
+            return;
+        }
+        let (_, nic) = net::devices()
+            .into_iter()
+            .find(|(name, _)| name.starts_with("eth"))
+            .unwrap();
+        let mac = nic.mac_address();
+        assert_eq!(mac.0[..3], [0x52, 0x54, 0x00]);
+
+        // Ask the gateway for its address.
+        let mut request = Vec::new();
+        request.extend_from_slice(&MacAddress::BROADCAST.0);
+        request.extend_from_slice(&mac.0);
+        request.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+        request.extend_from_slice(&mac.0);
+        request.extend_from_slice(&GUEST_IP);
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&GATEWAY_IP);
+        request.resize(MIN_FRAME_SIZE, 0);
+        nic.send_frame(&request).unwrap();
+
+        let deadline = time::ticks() + time::duration_to_ticks(Duration::from_secs(1));
+        let mut frame = [0; MAX_FRAME_SIZE];
+        while time::ticks() < deadline {
+            let Some(len) = nic.recv_frame(&mut frame).unwrap() else {
+                core::hint::spin_loop();
+                continue;
+            };
+            // An ARP reply to the guest, from the gateway.
+            if len >= 42 && frame[12..14] == [0x08, 0x06] && frame[20..22] == [0, 2] {
+                assert_eq!(frame[..6], mac.0);
+                assert_eq!(frame[28..32], GATEWAY_IP);
+                return;
+            }
+        }
+        panic!("no ARP reply");
+    }
+}
@@ -0,0 +1,11 @@
+//! Drivers for devices which are not needed to boot the kernel.
+//!
+//! Each driver has an `init` function which finds its devices and makes them
+//! available to the rest of the kernel, for example by registering them as
+//! [block devices](crate::block).
+
+pub mod ata;
+pub mod e1000;
+pub mod rtc;
+pub mod rtl8139;
+pub mod virtio;
@@ -0,0 +1,310 @@
+//! Driver for the real-time clock (RTC) in the CMOS of PC compatibles.
+//!
+//! The RTC keeps the date and time while the machine is off. Its registers
+//! are read through an index and a data port. They may be in BCD or binary
+//! and the hour in 12 or 24 hour format, as status register B says, and
+//! reading them while the RTC updates them once a second may give a mix of
+//! the old and the new time, so [read] reads them until two reads agree.
+//!
+//! [init] seeds the kernel's [wall-clock time](crate::time::wall_clock) from
+//! the RTC. The RTC can also raise IRQ8 periodically, see
+//! [enable_periodic_interrupt].
+//!
+//! See: https://wiki.osdev.org/CMOS and https://wiki.osdev.org/RTC
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    interrupts,
+    sync::IrqSpinlock,
+    time::{self, Duration},
+};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+/// Reading status register C acknowledges the interrupt.
+const REG_STATUS_C: u8 = 0x0c;
+/// The century on QEMU and most firmware, though not in any standard.
+const REG_CENTURY: u8 = 0x32;
+
+/// Bit of status register A set while the RTC updates the time.
+const STATUS_A_UPDATING: u8 = 1 << 7;
+/// Bits of status register A selecting the rate of the periodic interrupt.
+const STATUS_A_RATE: u8 = 0x0f;
+/// Bit of status register B set if hours are in 24 hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Bit of status register B set if values are binary rather than BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Bit of status register B enabling the periodic interrupt.
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+
+/// Bit of the hour set for PM in 12 hour format.
+const HOUR_PM: u8 = 0x80;
+
+const IRQ: u8 = 8;
+
+/// Frequency the periodic interrupt rates divide.
+const BASE_FREQUENCY: u32 = 32768;
+
+/// Serializes access to the index and data ports.
+static CMOS: IrqSpinlock<()> = IrqSpinlock::new(());
+
+/// Set once the handler for IRQ8 has been added.
+static HANDLER: Once<()> = Once::new();
+
+/// Number of periodic interrupts since [enable_periodic_interrupt].
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A date and time in UTC, as kept by the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// From 1 to 12.
+    pub month: u8,
+    /// From 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the time since the Unix epoch.
+    pub fn to_unix(&self) -> Duration {
+        Duration::from_secs(
+            days_from_epoch(self.year, self.month, self.day) as u64 * 86400
+                + self.hour as u64 * 3600
+                + self.minute as u64 * 60
+                + self.second as u64,
+        )
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats the date and time as in ISO 8601, like
+    /// `2024-02-29T13:05:09Z`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Returns the number of days from 1970-01-01 to the given date.
+///
+/// See: https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_epoch(year: u16, month: u8, day: u8) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads a register. The caller must hold [CMOS].
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).read()
+    }
+}
+
+/// Writes a register. The caller must hold [CMOS].
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).write(value);
+    }
+}
+
+/// The raw registers making up the date and time.
+type Registers = [u8; 7];
+
+/// Reads the date and time registers once no update is in progress.
+fn read_registers() -> Registers {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        REG_SECONDS,
+        REG_MINUTES,
+        REG_HOURS,
+        REG_DAY,
+        REG_MONTH,
+        REG_YEAR,
+        REG_CENTURY,
+    ]
+    .map(read_register)
+}
+
+/// Decodes the date and time registers in the format given by status
+/// register B.
+fn decode(registers: Registers, status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year, century] = registers;
+    let convert = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    let hour = if status_b & STATUS_B_24_HOUR != 0 {
+        convert(hour)
+    } else {
+        // 12 AM is midnight and 12 PM is noon.
+        let pm = if hour & HOUR_PM != 0 { 12 } else { 0 };
+        convert(hour & !HOUR_PM) % 12 + pm
+    };
+    let century = match convert(century) {
+        century @ 19..=21 => century,
+        // Assume this century if the register holds anything else.
+        _ => 20,
+    };
+    DateTime {
+        year: century as u16 * 100 + convert(year) as u16,
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Reads the current date and time.
+pub fn read() -> DateTime {
+    let _cmos = CMOS.lock();
+    let mut registers = read_registers();
+    loop {
+        let again = read_registers();
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    decode(registers, read_register(REG_STATUS_B))
+}
+
+/// Makes the RTC raise IRQ8 at about `frequency` Hz, rounded down to a
+/// power of two between 2 and 8192 Hz. Returns the frequency set.
+pub fn enable_periodic_interrupt(frequency: u32) -> u32 {
+    // The frequency is 32768 >> (rate - 1), and rates 1 and 2 do not work.
+    let rate = (3..=15)
+        .find(|&rate| BASE_FREQUENCY >> (rate - 1) <= frequency)
+        .unwrap_or(15);
+    {
+        let _cmos = CMOS.lock();
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & !STATUS_A_RATE) | rate as u8);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        // Acknowledge anything pending, or the RTC never interrupts again.
+        read_register(REG_STATUS_C);
+    }
+    HANDLER.call_once(|| {
+        interrupts::add_irq_handler(IRQ, handle_interrupt);
+    });
+    BASE_FREQUENCY >> (rate - 1)
+}
+
+/// Returns the number of periodic interrupts raised since
+/// [enable_periodic_interrupt] was called.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Handler for IRQ8.
+fn handle_interrupt() {
+    let _cmos = CMOS.lock();
+    read_register(REG_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Sets the kernel's wall-clock time from the RTC and returns the time read.
+pub fn init() -> DateTime {
+    let now = read();
+    time::set_wall_clock(now.to_unix());
+    now
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_to_unix() {
+        let epoch = DateTime {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        assert_eq!(epoch.to_unix(), Duration::ZERO);
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 5,
+            second: 9,
+        };
+        assert_eq!(leap_day.to_unix().as_secs(), 1_709_211_909);
+        assert_eq!(alloc::format!("{}", leap_day), "2024-02-29T13:05:09Z");
+    }
+
+    #[test_case]
+    fn test_decode() {
+        // 1:05:09 PM on 2024-02-29, in BCD and 12 hour format.
+        let registers = [0x09, 0x05, 0x81, 0x29, 0x02, 0x24, 0x20];
+        let time = decode(registers, 0);
+        assert_eq!((time.year, time.month, time.day), (2024, 2, 29));
+        assert_eq!((time.hour, time.minute, time.second), (13, 5, 9));
+
+        // 12 AM is midnight; binary and 24 hour format are taken as is.
+        assert_eq!(decode([0, 0, 0x12, 1, 1, 0x24, 0x20], 0).hour, 0);
+        let binary = decode([9, 5, 13, 29, 2, 24, 0], STATUS_B_BINARY | STATUS_B_24_HOUR);
+        assert_eq!(binary, time);
+    }
+
+    #[test_case]
+    fn test_periodic_interrupt() {
+        assert_eq!(enable_periodic_interrupt(1000), 512);
+        let start = periodic_ticks();
+        while periodic_ticks() == start {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    #[test_case]
+    fn test_wall_clock() {
+        let now = init();
+        assert!(now.year >= 2024);
+        let wall_clock = time::wall_clock().unwrap();
+        assert!(wall_clock >= now.to_unix());
+    }
+}
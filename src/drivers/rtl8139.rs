@@ -0,0 +1,351 @@
+//! Driver for Realtek RTL8139 Fast Ethernet controllers, QEMU's `rtl8139`.
+//!
+//! The controller's registers are I/O ports at BAR0. It writes received
+//! frames one after another into a single ring buffer, each preceded by a
+//! header holding its status and length, and the driver tells it how far it
+//! has read. Frames are sent from four transmit slots, used in turn, each
+//! with a buffer of its own.
+//!
+//! The controller interrupts when it has received frames, and frames are
+//! taken from the ring buffer by [NetworkDevice::recv_frame].
+//!
+//! See: https://wiki.osdev.org/RTL8139 and Realtek's "RTL8139(A/B)
+//! Programming Guide".
+
+use alloc::{sync::Arc, vec::Vec};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+
+use crate::{
+    mem::DmaRegion,
+    net::{self, Error, MacAddress, NetworkDevice, MAX_FRAME_SIZE, MIN_FRAME_SIZE},
+    pci::{self, Bar},
+    sync::IrqSpinlock,
+    time::{self, Duration},
+};
+
+const VENDOR_REALTEK: u16 = 0x10ec;
+const DEVICE_RTL8139: u16 = 0x8139;
+
+const REG_IDR: u16 = 0x00;
+/// Status of the first transmit slot, followed by those of the others.
+const REG_TSD: u16 = 0x10;
+/// Address of the buffer of the first transmit slot, followed by those of
+/// the others.
+const REG_TSAD: u16 = 0x20;
+const REG_RBSTART: u16 = 0x30;
+const REG_CR: u16 = 0x37;
+/// Where the driver has read the receive buffer up to, less 16 bytes.
+const REG_CAPR: u16 = 0x38;
+/// Where the controller has written the receive buffer up to.
+const REG_CBR: u16 = 0x3a;
+const REG_IMR: u16 = 0x3c;
+/// Interrupt causes are acknowledged by writing them back.
+const REG_ISR: u16 = 0x3e;
+const REG_RCR: u16 = 0x44;
+const REG_CONFIG1: u16 = 0x52;
+
+const CR_BUFE: u8 = 1 << 0;
+const CR_TE: u8 = 1 << 2;
+const CR_RE: u8 = 1 << 3;
+const CR_RST: u8 = 1 << 4;
+
+/// Bit of a transmit status set once the frame has been moved out of the
+/// slot's buffer.
+const TSD_OWN: u32 = 1 << 13;
+
+const INT_ROK: u16 = 1 << 0;
+const INT_RER: u16 = 1 << 1;
+const INT_RXOVW: u16 = 1 << 4;
+const INT_RECEIVE: u16 = INT_ROK | INT_RER | INT_RXOVW;
+
+/// Accept frames to the controller's address, multicast and broadcast
+/// frames.
+const RCR_APM: u32 = 1 << 1;
+const RCR_AM: u32 = 1 << 2;
+const RCR_AB: u32 = 1 << 3;
+/// Write frames past the end of the ring buffer rather than wrapping them
+/// around to its start.
+const RCR_WRAP: u32 = 1 << 7;
+
+/// Bit of a received frame's status set if it was received without error.
+const RX_ROK: u16 = 1 << 0;
+
+/// Size of the ring buffer, with the length bits of the receive
+/// configuration left zeroed.
+const RX_RING_SIZE: usize = 8192;
+/// Size of the memory of the ring buffer: the ring, 16 bytes the controller
+/// needs past it and room for a frame written past its end.
+const RX_BUFFER_SIZE: usize = RX_RING_SIZE + 16 + 2048;
+/// Size of the header preceding each received frame.
+const RX_HEADER_SIZE: usize = 4;
+/// Size of the checksum following each received frame.
+const CHECKSUM_SIZE: usize = 4;
+
+const TX_SLOTS: usize = 4;
+/// Size of the buffer of each transmit slot.
+const TX_BUFFER_SIZE: usize = 2048;
+
+/// How long the controller may take to reset.
+const RESET_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// The I/O ports of a controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    base: u16,
+}
+
+impl Registers {
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u8(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+}
+
+struct Receive {
+    buffer: DmaRegion,
+    /// Offset in the ring of the next frame's header.
+    offset: usize,
+}
+
+struct Transmit {
+    buffers: DmaRegion,
+    /// The next slot to send from.
+    next: usize,
+    /// Which slots were sent from since the controller was reset.
+    used: [bool; TX_SLOTS],
+}
+
+/// A Realtek RTL8139 controller.
+pub struct Rtl8139 {
+    registers: Registers,
+    mac: MacAddress,
+    receive: Mutex<Receive>,
+    transmit: Mutex<Transmit>,
+}
+
+impl Rtl8139 {
+    /// Resets the controller and sets up its buffers, returning `None` if it
+    /// cannot be driven.
+    fn new(device: &pci::Device) -> Option<Rtl8139> {
+        let Some(Bar::Io { port, .. }) = device.bars[0] else {
+            return None;
+        };
+        device.enable_bus_mastering();
+        let registers = Registers { base: port };
+        // The buffers' addresses are 32 bits wide.
+        let receive = DmaRegion::new(RX_BUFFER_SIZE)?;
+        let transmit = DmaRegion::new(TX_SLOTS * TX_BUFFER_SIZE)?;
+        let reachable = |region: &DmaRegion| {
+            region.phys_addr().as_u64() + region.size() as u64 <= u32::MAX as u64
+        };
+        if !reachable(&receive) || !reachable(&transmit) {
+            return None;
+        }
+
+        // Power the controller on, then reset it.
+        registers.write_u8(REG_CONFIG1, 0);
+        registers.write_u8(REG_CR, CR_RST);
+        let deadline = time::ticks() + time::duration_to_ticks(RESET_TIMEOUT);
+        while registers.read_u8(REG_CR) & CR_RST != 0 {
+            if time::ticks() > deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+
+        registers.write_u32(REG_RBSTART, receive.phys_addr().as_u64() as u32);
+        for slot in 0..TX_SLOTS {
+            let address = transmit.phys_addr().as_u64() as usize + slot * TX_BUFFER_SIZE;
+            registers.write_u32(REG_TSAD + 4 * slot as u16, address as u32);
+        }
+        registers.write_u8(REG_CR, CR_RE | CR_TE);
+        registers.write_u32(REG_RCR, RCR_APM | RCR_AM | RCR_AB | RCR_WRAP);
+
+        Some(Rtl8139 {
+            registers,
+            mac: MacAddress(core::array::from_fn(|i| {
+                registers.read_u8(REG_IDR + i as u16)
+            })),
+            receive: Mutex::new(Receive {
+                buffer: receive,
+                offset: 0,
+            }),
+            transmit: Mutex::new(Transmit {
+                buffers: transmit,
+                next: 0,
+                used: [false; TX_SLOTS],
+            }),
+        })
+    }
+
+    /// Tells the controller the ring buffer has been read up to `offset`.
+    fn set_read_offset(&self, offset: usize) {
+        // The register lags 16 bytes behind, for historical reasons.
+        self.registers
+            .write_u16(REG_CAPR, (offset as u16).wrapping_sub(16));
+    }
+}
+
+impl NetworkDevice for Rtl8139 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut transmit = self.transmit.lock();
+        let slot = transmit.next;
+        let status_register = REG_TSD + 4 * slot as u16;
+        if transmit.used[slot] && self.registers.read_u32(status_register) & TSD_OWN == 0 {
+            return Err(Error::Busy);
+        }
+        // The controller does not pad short frames itself.
+        let len = frame.len().max(MIN_FRAME_SIZE);
+        unsafe {
+            let buffer = transmit
+                .buffers
+                .as_mut_ptr::<u8>()
+                .add(slot * TX_BUFFER_SIZE);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len());
+            core::ptr::write_bytes(buffer.add(frame.len()), 0, len - frame.len());
+        }
+        // Writing the length clears the OWN bit, sending the frame.
+        self.registers.write_u32(status_register, len as u32);
+        transmit.used[slot] = true;
+        transmit.next = (slot + 1) % TX_SLOTS;
+        Ok(())
+    }
+
+    fn recv_frame(&self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let mut receive = self.receive.lock();
+        if self.registers.read_u8(REG_CR) & CR_BUFE != 0 {
+            return Ok(None);
+        }
+        let offset = receive.offset;
+        let header = unsafe { receive.buffer.as_mut_ptr::<u8>().add(offset) };
+        let (status, len) = unsafe {
+            let header = header.cast::<u16>();
+            (
+                header.read_volatile(),
+                header.add(1).read_volatile() as usize,
+            )
+        };
+        let valid =
+            status & RX_ROK != 0 && (CHECKSUM_SIZE..=MAX_FRAME_SIZE + CHECKSUM_SIZE).contains(&len);
+        let frame_len = len.saturating_sub(CHECKSUM_SIZE);
+        let result = if !valid {
+            Err(Error::Io)
+        } else if frame_len > buf.len() {
+            Err(Error::TooLarge)
+        } else {
+            // Frames are never wrapped around the end of the ring.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    header.add(RX_HEADER_SIZE),
+                    buf.as_mut_ptr(),
+                    frame_len,
+                )
+            };
+            Ok(Some(frame_len))
+        };
+
+        receive.offset = if valid {
+            next_frame(offset, len)
+        } else {
+            // Nothing after a bad header can be trusted, so skip everything
+            // the controller has written.
+            self.registers.read_u16(REG_CBR) as usize % RX_RING_SIZE
+        };
+        self.set_read_offset(receive.offset);
+        result
+    }
+}
+
+/// Returns the offset in the ring of the frame following the one at
+/// `offset`, whose length including the checksum is `len`. Frames start on
+/// 4-byte boundaries.
+fn next_frame(offset: usize, len: usize) -> usize {
+    (offset + RX_HEADER_SIZE + len).next_multiple_of(4) % RX_RING_SIZE
+}
+
+/// The controllers set up by [init], and their IRQs.
+static DEVICES: IrqSpinlock<Vec<(Registers, u8)>> = IrqSpinlock::new(Vec::new());
+
+/// Acknowledges the interrupts of the controllers on the IRQ, and wakes the
+/// threads waiting for frames.
+fn handle_interrupt() {
+    let mut received = false;
+    for (registers, _) in DEVICES.lock().iter() {
+        let causes = registers.read_u16(REG_ISR);
+        registers.write_u16(REG_ISR, causes);
+        received |= causes & INT_RECEIVE != 0;
+    }
+    if received {
+        net::frame_received();
+    }
+}
+
+static FOUND: Once<usize> = Once::new();
+
+/// Sets up every RTL8139 and registers it as a
+/// [network device](crate::net). Returns the number of controllers found.
+pub fn init() -> usize {
+    *FOUND.call_once(|| {
+        let mut found = 0;
+        let devices = pci::devices().iter().filter(|device| {
+            (device.vendor_id, device.device_id) == (VENDOR_REALTEK, DEVICE_RTL8139)
+        });
+        for device in devices {
+            let Some(nic) = Rtl8139::new(device) else {
+                continue;
+            };
+            net::add_controller(
+                "rtl8139",
+                &DEVICES,
+                nic.registers,
+                device.interrupt_line,
+                handle_interrupt,
+            );
+            nic.registers.write_u16(REG_IMR, INT_RECEIVE);
+            net::register_ethernet(Arc::new(nic));
+            found += 1;
+        }
+        found
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_next_frame() {
+        assert_eq!(next_frame(0, 64), 68);
+        assert_eq!(next_frame(68, 61), 136);
+        // Frames written past the end continue at the start of the ring.
+        assert_eq!(next_frame(RX_RING_SIZE - 8, 64), 60);
+    }
+}
@@ -0,0 +1,328 @@
+//! The virtio transport over PCI, for the paravirtualized devices of QEMU.
+//!
+//! Devices are driven through their legacy interface: a block of I/O ports
+//! at BAR0 holding the feature bits, device status and queue registers,
+//! followed by configuration specific to the kind of device. Data moves
+//! through [virtqueues](Virtqueue), rings of buffers in memory the device
+//! reads and writes through DMA.
+//!
+//! See: https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html,
+//! sections 2.6 and 4.1.4.8.
+
+pub mod net;
+pub mod rng;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    mem::DmaRegion,
+    pci::{Bar, Device},
+};
+
+/// Vendor ID of virtio devices.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// Registers relative to the I/O base of a device.
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_DRIVER_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+/// Reading the ISR status acknowledges the interrupt.
+const REG_ISR: u16 = 0x13;
+/// Start of the configuration of the device, while MSI-X is disabled.
+const REG_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+/// Bit of the ISR status set when a queue has used buffers.
+const ISR_QUEUE: u8 = 1;
+
+/// Alignment of the used ring, and unit of the queue address.
+const QUEUE_ALIGN: usize = 4096;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// The legacy interface of a virtio device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transport {
+    base: u16,
+}
+
+impl Transport {
+    /// Resets `device` and acknowledges it, returning `None` if it has no
+    /// legacy interface. The driver must then [negotiate](Self::negotiate)
+    /// features, set up its queues and [start](Self::start) the device.
+    pub fn new(device: &Device) -> Option<Transport> {
+        let Some(Bar::Io { port, .. }) = device.bars[0] else {
+            return None;
+        };
+        device.enable_bus_mastering();
+        let transport = Transport { base: port };
+        transport.write_u8(REG_STATUS, 0);
+        transport.write_u8(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Some(transport)
+    }
+
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u8(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Accepts the features among `wanted` which the device offers, and
+    /// returns them.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = self.read_u32(REG_DEVICE_FEATURES) & wanted;
+        self.write_u32(REG_DRIVER_FEATURES, features);
+        features
+    }
+
+    /// Reads the byte at `offset` of the device's configuration.
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        self.read_u8(REG_CONFIG + offset)
+    }
+
+    /// Tells the device the driver is ready.
+    pub fn start(&self) {
+        let status = self.read_u8(REG_STATUS);
+        self.write_u8(REG_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        let status = self.read_u8(REG_STATUS);
+        self.write_u8(REG_STATUS, status | STATUS_FAILED);
+    }
+
+    /// Acknowledges an interrupt, returning whether the device raised it
+    /// because a queue has used buffers.
+    pub fn acknowledge_interrupt(&self) -> bool {
+        self.read_u8(REG_ISR) & ISR_QUEUE != 0
+    }
+
+    /// Tells the device the queue has new available buffers.
+    fn notify(&self, queue: u16) {
+        self.write_u16(REG_QUEUE_NOTIFY, queue);
+    }
+}
+
+/// A buffer in a chain added to a [Virtqueue].
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer rather than reads it.
+    pub device_writes: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Byte offsets of the rings of a queue of `size` buffers, and the size of
+/// the memory they take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    available: usize,
+    used: usize,
+    size: usize,
+}
+
+impl Layout {
+    fn new(size: u16) -> Layout {
+        let size = size as usize;
+        let available = 16 * size;
+        // Flags, index, the ring and the used event.
+        let used = (available + 6 + 2 * size).next_multiple_of(QUEUE_ALIGN);
+        Layout {
+            available,
+            used,
+            size: (used + 6 + 8 * size).next_multiple_of(QUEUE_ALIGN),
+        }
+    }
+}
+
+/// A queue of buffers shared with a device.
+///
+/// The driver adds chains of buffers to the available ring and notifies the
+/// device, which moves each chain to the used ring when done with it.
+pub struct Virtqueue {
+    transport: Transport,
+    index: u16,
+    size: u16,
+    layout: Layout,
+    memory: DmaRegion,
+    /// Descriptors not part of a chain.
+    free: Vec<u16>,
+    /// Index of the next entry of the available ring.
+    next_available: u16,
+    /// Index of the next entry of the used ring to take.
+    next_used: u16,
+}
+
+impl Virtqueue {
+    /// Sets up the queue `index` of the device, returning `None` if it does
+    /// not exist or memory ran out.
+    pub fn new(transport: Transport, index: u16) -> Option<Virtqueue> {
+        transport.write_u16(REG_QUEUE_SELECT, index);
+        let size = transport.read_u16(REG_QUEUE_SIZE);
+        if size == 0 {
+            return None;
+        }
+        let layout = Layout::new(size);
+        let memory = DmaRegion::new(layout.size)?;
+        let frame = memory.phys_addr().as_u64() / QUEUE_ALIGN as u64;
+        transport.write_u32(REG_QUEUE_ADDRESS, frame as u32);
+        Some(Virtqueue {
+            transport,
+            index,
+            size,
+            layout,
+            memory,
+            free: (0..size).rev().collect(),
+            next_available: 0,
+            next_used: 0,
+        })
+    }
+
+    /// Returns the number of buffers the queue holds.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { self.memory.as_mut_ptr::<Descriptor>().add(index as usize) }
+    }
+
+    /// Returns a pointer to the 16-bit field at `offset` of the memory.
+    fn field(&self, offset: usize) -> *mut u16 {
+        unsafe { self.memory.as_mut_ptr::<u8>().add(offset).cast() }
+    }
+
+    /// Adds a chain of `buffers` for the device to use, without notifying
+    /// it. Returns the ID of the chain, or `None` if the queue is full.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let indices = self.free.split_off(self.free.len() - buffers.len());
+        for (position, (buffer, &index)) in buffers.iter().zip(&indices).enumerate() {
+            let next = indices.get(position + 1).copied();
+            let mut flags = if next.is_some() { DESC_F_NEXT } else { 0 };
+            if buffer.device_writes {
+                flags |= DESC_F_WRITE;
+            }
+            let descriptor = Descriptor {
+                address: buffer.address.as_u64(),
+                len: buffer.len,
+                flags,
+                next: next.unwrap_or(0),
+            };
+            unsafe { self.descriptor(index).write_volatile(descriptor) };
+        }
+
+        let head = indices[0];
+        let slot = self.layout.available + 4 + 2 * (self.next_available % self.size) as usize;
+        unsafe { self.field(slot).write_volatile(head) };
+        self.next_available = self.next_available.wrapping_add(1);
+        // The device must see the ring entry before the new index.
+        fence(Ordering::SeqCst);
+        unsafe {
+            self.field(self.layout.available + 2)
+                .write_volatile(self.next_available)
+        };
+        Some(head)
+    }
+
+    /// Tells the device about the chains added since the last call.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        self.transport.notify(self.index);
+    }
+
+    /// Takes the oldest chain the device is done with, freeing its
+    /// descriptors. Returns its ID and the number of bytes the device wrote
+    /// to it.
+    pub fn take_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe { self.field(self.layout.used + 2).read_volatile() };
+        if used == self.next_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = self.layout.used + 4 + 8 * (self.next_used % self.size) as usize;
+        let (head, len) = unsafe {
+            let entry = self.field(slot).cast::<u32>();
+            (entry.read_volatile() as u16, entry.add(1).read_volatile())
+        };
+        self.next_used = self.next_used.wrapping_add(1);
+
+        let mut index = head;
+        loop {
+            self.free.push(index);
+            let descriptor = unsafe { self.descriptor(index).read_volatile() };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+        }
+        Some((head, len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_layout() {
+        assert_eq!(
+            Layout::new(256),
+            Layout {
+                available: 0x1000,
+                used: 0x2000,
+                size: 0x3000,
+            }
+        );
+        assert_eq!(
+            Layout::new(16),
+            Layout {
+                available: 0x100,
+                used: 0x1000,
+                size: 0x2000,
+            }
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Driver for virtio network devices, QEMU's `virtio-net-pci`.
+//!
+//! The device has a receive queue, kept full of empty buffers for the device
+//! to write arriving frames to, and a transmit queue of frames to send. Each
+//! frame is preceded by a header describing checksum and segmentation
+//! offloads, none of which are negotiated, so it is left zeroed.
+//!
+//! The device interrupts when it has used buffers of either queue. Frames are
+//! taken from the receive queue by [NetworkDevice::recv_frame], and buffers
+//! of sent frames are reclaimed before sending another.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use spin::{Mutex, Once};
+
+use super::{Buffer, Transport, Virtqueue, VENDOR_ID};
+use crate::{
+    mem::DmaRegion,
+    net::{self, Error, MacAddress, NetworkDevice, MAX_FRAME_SIZE},
+    pci,
+    sync::IrqSpinlock,
+};
+
+/// Device ID of network devices with a legacy interface.
+const DEVICE_ID: u16 = 0x1000;
+
+/// Feature bit of devices with a MAC address in their configuration.
+const FEATURE_MAC: u32 = 1 << 5;
+/// Feature bit of devices accepting the header and frame in one buffer.
+const FEATURE_ANY_LAYOUT: u32 = 1 << 27;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// Size of the header preceding every frame.
+const HEADER_SIZE: usize = 10;
+
+/// Size of each buffer, enough for the header and the largest frame.
+const BUFFER_SIZE: usize = 2048;
+
+/// Most buffers given to each queue.
+const MAX_BUFFERS: usize = 32;
+
+/// A queue and the buffers handed to it.
+struct Queue {
+    queue: Virtqueue,
+    buffers: DmaRegion,
+    /// Index of the buffer of each chain in the queue, by chain ID.
+    owners: Vec<usize>,
+    /// Buffers not in the queue.
+    free: Vec<usize>,
+}
+
+impl Queue {
+    fn new(transport: Transport, index: u16) -> Option<Queue> {
+        let queue = Virtqueue::new(transport, index)?;
+        let count = MAX_BUFFERS.min(queue.size() as usize);
+        Some(Queue {
+            owners: vec![0; queue.size() as usize],
+            free: (0..count).rev().collect(),
+            buffers: DmaRegion::new(count * BUFFER_SIZE)?,
+            queue,
+        })
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        unsafe { self.buffers.as_mut_ptr::<u8>().add(index * BUFFER_SIZE) }
+    }
+
+    /// Adds the buffer `index` to the queue, holding `len` bytes for the
+    /// device to read, or empty for it to write to if `len` is `None`.
+    fn add(&mut self, index: usize, len: Option<usize>) {
+        let address = self.buffers.phys_addr() + (index * BUFFER_SIZE) as u64;
+        let buffer = Buffer {
+            address,
+            len: len.unwrap_or(BUFFER_SIZE) as u32,
+            device_writes: len.is_none(),
+        };
+        // There are no more buffers than room in the queue.
+        let head = self.queue.add(&[buffer]).unwrap();
+        self.owners[head as usize] = index;
+    }
+
+    /// Takes the oldest buffer the device is done with, returning its index
+    /// and the number of bytes the device wrote to it.
+    fn take_used(&mut self) -> Option<(usize, usize)> {
+        let (head, len) = self.queue.take_used()?;
+        Some((self.owners[head as usize], len as usize))
+    }
+}
+
+/// A virtio network device.
+pub struct VirtioNet {
+    transport: Transport,
+    mac: MacAddress,
+    receive: Mutex<Queue>,
+    transmit: Mutex<Queue>,
+}
+
+impl VirtioNet {
+    /// Sets up the device and fills its receive queue, returning `None` if
+    /// it cannot be driven.
+    fn new(device: &pci::Device) -> Option<VirtioNet> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(FEATURE_MAC | FEATURE_ANY_LAYOUT);
+        let queues = Queue::new(transport, RECEIVE_QUEUE)
+            .zip(Queue::new(transport, TRANSMIT_QUEUE))
+            .filter(|_| features & FEATURE_MAC != 0);
+        let Some((mut receive, transmit)) = queues else {
+            transport.fail();
+            return None;
+        };
+
+        while let Some(index) = receive.free.pop() {
+            receive.add(index, None);
+        }
+        transport.start();
+        receive.queue.notify();
+        Some(VirtioNet {
+            transport,
+            mac: MacAddress(core::array::from_fn(|i| transport.config_u8(i as u16))),
+            receive: Mutex::new(receive),
+            transmit: Mutex::new(transmit),
+        })
+    }
+}
+
+impl NetworkDevice for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut transmit = self.transmit.lock();
+        while let Some((index, _)) = transmit.take_used() {
+            transmit.free.push(index);
+        }
+        let index = transmit.free.pop().ok_or(Error::Busy)?;
+        unsafe {
+            let buffer = transmit.buffer(index);
+            core::ptr::write_bytes(buffer, 0, HEADER_SIZE);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer.add(HEADER_SIZE), frame.len());
+        }
+        transmit.add(index, Some(HEADER_SIZE + frame.len()));
+        transmit.queue.notify();
+        Ok(())
+    }
+
+    fn recv_frame(&self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let mut receive = self.receive.lock();
+        let Some((index, len)) = receive.take_used() else {
+            return Ok(None);
+        };
+        let len = len.saturating_sub(HEADER_SIZE);
+        let fits = len <= buf.len();
+        if fits {
+            unsafe {
+                let frame = receive.buffer(index).add(HEADER_SIZE);
+                core::ptr::copy_nonoverlapping(frame, buf.as_mut_ptr(), len);
+            }
+        }
+        receive.add(index, None);
+        receive.queue.notify();
+        if !fits {
+            return Err(Error::TooLarge);
+        }
+        Ok(Some(len))
+    }
+}
+
+/// The devices set up by [init], and their IRQs.
+static DEVICES: IrqSpinlock<Vec<(Transport, u8)>> = IrqSpinlock::new(Vec::new());
+
+/// Acknowledges the interrupts of the devices on the IRQ, and wakes the
+/// threads waiting for frames.
+fn handle_interrupt() {
+    let mut received = false;
+    for (transport, _) in DEVICES.lock().iter() {
+        received |= transport.acknowledge_interrupt();
+    }
+    if received {
+        net::frame_received();
+    }
+}
+
+static FOUND: Once<usize> = Once::new();
+
+/// Sets up every virtio network device and registers it as a
+/// [network device](crate::net). Returns the number of devices found.
+pub fn init() -> usize {
+    *FOUND.call_once(|| {
+        let mut found = 0;
+        let devices = pci::devices()
+            .iter()
+            .filter(|device| (device.vendor_id, device.device_id) == (VENDOR_ID, DEVICE_ID));
+        for device in devices {
+            let Some(nic) = VirtioNet::new(device) else {
+                continue;
+            };
+            net::add_controller(
+                "virtio-net",
+                &DEVICES,
+                nic.transport,
+                device.interrupt_line,
+                handle_interrupt,
+            );
+            net::register_ethernet(Arc::new(nic));
+            found += 1;
+        }
+        found
+    })
+}
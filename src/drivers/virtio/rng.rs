@@ -0,0 +1,165 @@
+//! Driver for virtio entropy devices, QEMU's `virtio-rng-pci`.
+//!
+//! The device has a single queue of empty buffers, which it fills with
+//! random bytes gathered by the host. Each filled buffer is mixed into the
+//! [kernel's generator](crate::rand) and handed back to the device, so the
+//! generator keeps receiving entropy from outside the guest even when the
+//! CPU has neither `rdseed` nor `rdrand`.
+//!
+//! The device interrupts when it has filled a buffer, and deferred work mixes
+//! the bytes in, as reseeding takes too long for an interrupt handler.
+
+use alloc::vec::Vec;
+
+use conquer_once::spin::OnceCell;
+use spin::Once;
+
+use super::{Buffer, Transport, Virtqueue, VENDOR_ID};
+use crate::{
+    interrupts::{
+        self,
+        deferred::{self, Work},
+    },
+    mem::DmaRegion,
+    pci, rand,
+    sync::IrqSpinlock,
+};
+
+/// Device ID of entropy devices with a legacy interface.
+const DEVICE_ID: u16 = 0x1005;
+
+const REQUEST_QUEUE: u16 = 0;
+
+/// Number of bytes requested from the device at a time.
+const BUFFER_SIZE: usize = 64;
+
+/// Number of times the queue is polled for the first buffer during [init].
+const POLL_LIMIT: usize = 1_000_000;
+
+/// A virtio entropy device.
+struct VirtioRng {
+    transport: Transport,
+    irq: u8,
+    queue: Virtqueue,
+    buffer: DmaRegion,
+}
+
+impl VirtioRng {
+    /// Sets up the device and requests its first bytes, returning `None` if
+    /// it cannot be driven.
+    fn new(device: &pci::Device) -> Option<VirtioRng> {
+        let transport = Transport::new(device)?;
+        transport.negotiate(0);
+        let queue = Virtqueue::new(transport, REQUEST_QUEUE);
+        let Some((queue, buffer)) = queue.zip(DmaRegion::new(BUFFER_SIZE)) else {
+            transport.fail();
+            return None;
+        };
+        let mut rng = VirtioRng {
+            transport,
+            irq: device.interrupt_line,
+            queue,
+            buffer,
+        };
+        transport.start();
+        rng.request();
+        Some(rng)
+    }
+
+    /// Hands the buffer to the device to fill.
+    fn request(&mut self) {
+        let buffer = Buffer {
+            address: self.buffer.phys_addr(),
+            len: BUFFER_SIZE as u32,
+            device_writes: true,
+        };
+        // The buffer is the only one ever in the queue.
+        self.queue.add(&[buffer]).unwrap();
+        self.queue.notify();
+    }
+
+    /// Copies the bytes the device filled the buffer with to `bytes`, and
+    /// requests more. Returns the number of bytes copied, or `None` if the
+    /// device has not filled the buffer yet.
+    fn take(&mut self, bytes: &mut [u8; BUFFER_SIZE]) -> Option<usize> {
+        let (_, len) = self.queue.take_used()?;
+        let len = (len as usize).min(BUFFER_SIZE);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.buffer.as_mut_ptr(), bytes.as_mut_ptr(), len)
+        };
+        self.request();
+        Some(len)
+    }
+}
+
+/// The devices set up by [init].
+static DEVICES: IrqSpinlock<Vec<VirtioRng>> = IrqSpinlock::new(Vec::new());
+
+/// Deferred work raised by the interrupt handler when a device may have
+/// filled its buffer.
+static FILLED_WORK: OnceCell<Work> = OnceCell::uninit();
+
+/// Acknowledges the interrupts of the devices on the IRQ.
+fn handle_interrupt() {
+    let mut filled = false;
+    for device in DEVICES.lock().iter() {
+        filled |= device.transport.acknowledge_interrupt();
+    }
+    if filled {
+        if let Ok(work) = FILLED_WORK.try_get() {
+            work.raise();
+        }
+    }
+}
+
+/// Deferred half of the interrupt handler: mixes the bytes of every filled
+/// buffer into the kernel's generator. Returns whether there were any.
+///
+/// Each device is only looked at once, as it may refill its buffer as soon as
+/// it is handed back.
+fn buffers_filled() -> bool {
+    let mut bytes = [0; BUFFER_SIZE];
+    let mut found = false;
+    for index in 0..DEVICES.lock().len() {
+        // The generator is not reseeded with the devices locked.
+        let Some(len) = DEVICES.lock()[index].take(&mut bytes) else {
+            continue;
+        };
+        rand::add_entropy(&bytes[..len]);
+        found = true;
+    }
+    found
+}
+
+static FOUND: Once<usize> = Once::new();
+
+/// Sets up every virtio entropy device to feed the kernel's generator.
+/// Returns the number of devices found.
+///
+/// The first bytes of the devices are waited for, so that the generator has
+/// been reseeded with them by the time this returns.
+pub fn init() -> usize {
+    *FOUND.call_once(|| {
+        FILLED_WORK.init_once(|| deferred::register(|| _ = buffers_filled()));
+        let devices = pci::devices()
+            .iter()
+            .filter(|device| (device.vendor_id, device.device_id) == (VENDOR_ID, DEVICE_ID));
+        for device in devices {
+            let Some(rng) = VirtioRng::new(device) else {
+                continue;
+            };
+            let irq = rng.irq;
+            let shared = DEVICES.lock().iter().any(|device| device.irq == irq);
+            DEVICES.lock().push(rng);
+            if !shared && !interrupts::add_irq_handler(irq, handle_interrupt) {
+                crate::println!("virtio-rng: cannot handle IRQ {}", irq);
+            }
+        }
+
+        let found = DEVICES.lock().len();
+        if found != 0 {
+            (0..POLL_LIMIT).find(|_| buffers_filled());
+        }
+        found
+    })
+}
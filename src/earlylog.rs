@@ -0,0 +1,52 @@
+//! Logging for the first moments of boot.
+//!
+//! [early_print] and [early_println] write straight to the registers of
+//! [COM1](serial::COM1) without taking its lock or allocating, so they work
+//! from the first instruction of the kernel, before the heap, the
+//! [logger](crate::logger) and the consoles are set up, and even while the
+//! port's lock is held. Bytes may interleave with other output to COM1 at
+//! worst.
+//!
+//! [toyos::init](crate::init) and the memory setup report their failures
+//! this way. Building with the `early-log` feature also traces every step
+//! they take with [early_trace], so that the last line shows where boot
+//! stopped.
+
+use core::fmt::{self, Write};
+
+use crate::serial;
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    // SAFETY: Writing while the lock is held interleaves bytes at worst.
+    _ = unsafe { serial::COM1.unlocked() }.write_fmt(args);
+}
+
+/// Prints to COM1 without locking or allocating.
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => {
+        $crate::earlylog::_print(format_args!($($arg)*))
+    };
+}
+
+/// Prints to COM1 without locking or allocating, appending a newline.
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($fmt:expr) => ($crate::early_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::early_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Prints a step of boot to COM1 like [early_println], if the kernel is
+/// built with the `early-log` feature. Without it, the arguments are not
+/// evaluated.
+#[macro_export]
+macro_rules! early_trace {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "early-log") {
+            $crate::early_println!($($arg)*);
+        }
+    };
+}
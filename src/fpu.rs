@@ -0,0 +1,163 @@
+//! The state of the x87 FPU, SSE and AVX.
+//!
+//! [init] enables the FPU, SSE and, where supported, AVX on the calling CPU.
+//! The kernel itself is built without them, so only code run by threads, in
+//! particular user code, uses their registers. Each thread keeps their
+//! contents in an [FpuState], which the scheduler saves and restores when it
+//! switches threads. `xsave` is used where the processor has it, and `fxsave`
+//! otherwise.
+//!
+//! See: https://wiki.osdev.org/SSE and https://wiki.osdev.org/FPU
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+use crate::cpu;
+
+/// Size of an [FpuState], which holds the x87, SSE and AVX state saved by
+/// `xsave` or the smaller state saved by `fxsave`.
+const AREA_SIZE: usize = 1024;
+
+/// Leaf of `cpuid` describing the state saved by `xsave`.
+const XSAVE_LEAF: u32 = 0xd;
+
+/// Offsets of the x87 control word and of MXCSR in the saved state.
+const CONTROL_WORD_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+/// The x87 control word after `fninit`, masking every exception.
+const DEFAULT_CONTROL_WORD: u16 = 0x037f;
+/// The initial value of MXCSR, masking every exception.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Set by [init] if the state is saved with `xsave`.
+static XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// Enables the FPU, SSE and AVX on the calling CPU and resets their state.
+///
+/// Must be called on every CPU.
+pub fn init() {
+    let xsave = cpu::has_xsave();
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+        if xsave {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+            let mut features = XCr0Flags::X87 | XCr0Flags::SSE;
+            if cpu::has_avx() {
+                features |= XCr0Flags::AVX;
+            }
+            XCr0::write(features);
+            assert!(
+                cpu::cpuid(XSAVE_LEAF, 0).ebx as usize <= AREA_SIZE,
+                "xsave area too large"
+            );
+        }
+
+        asm!("fninit", options(nomem, nostack));
+    }
+    XSAVE.store(xsave, Ordering::Relaxed);
+}
+
+/// The saved contents of the FPU, SSE and AVX registers.
+#[repr(C, align(64))]
+pub struct FpuState([u8; AREA_SIZE]);
+
+impl FpuState {
+    /// Returns the state after [init]: empty registers with every exception
+    /// masked.
+    pub const fn new() -> Self {
+        let mut area = [0; AREA_SIZE];
+        let control_word = DEFAULT_CONTROL_WORD.to_le_bytes();
+        area[CONTROL_WORD_OFFSET] = control_word[0];
+        area[CONTROL_WORD_OFFSET + 1] = control_word[1];
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        let mut index = 0;
+        while index < mxcsr.len() {
+            area[MXCSR_OFFSET + index] = mxcsr[index];
+            index += 1;
+        }
+        FpuState(area)
+    }
+
+    /// Saves the registers of the calling CPU.
+    pub fn save(&mut self) {
+        let area = self.0.as_mut_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags),
+                );
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Loads the registers of the calling CPU.
+    pub fn restore(&self) {
+        let area = self.0.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags),
+                );
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        FpuState::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::thread;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn test_state_is_kept_per_thread() {
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+
+        for value in [1u64, 2] {
+            thread::spawn(move || {
+                unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+                for _ in 0..10 {
+                    thread::yield_now();
+                }
+                let kept: u64;
+                unsafe { asm!("movq {}, xmm0", out(reg) kept, options(nomem, nostack)) };
+                assert_eq!(kept, value);
+                DONE.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        while DONE.load(Ordering::Relaxed) < 2 {
+            thread::yield_now();
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! The Bochs display adapter, QEMU's `-vga std` and `bochs-display`.
+//!
+//! Besides VGA text mode, the adapter has a linear framebuffer at its first
+//! BAR, which it shows once a mode is set through its "DISPI" registers.
+//!
+//! See: https://wiki.osdev.org/Bochs_VBE_Extensions
+
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use super::{Framebuffer, Info, PixelFormat};
+use crate::{
+    mem::phys_to_virt,
+    pci::{self, Bar},
+};
+
+const VENDOR_ID: u16 = 0x1234;
+const DEVICE_ID: u16 = 0x1111;
+
+/// Port selecting a DISPI register.
+const INDEX_PORT: u16 = 0x01ce;
+
+/// Port reading and writing the selected DISPI register.
+const DATA_PORT: u16 = 0x01cf;
+
+const REG_ID: u16 = 0;
+const REG_XRES: u16 = 1;
+const REG_YRES: u16 = 2;
+const REG_BPP: u16 = 3;
+const REG_ENABLE: u16 = 4;
+const REG_VIRT_WIDTH: u16 = 6;
+
+/// The first version of the DISPI interface with a linear framebuffer.
+const ID_LINEAR_FRAMEBUFFER: u16 = 0xb0c2;
+
+const ENABLE_DISPLAY: u16 = 1 << 0;
+const ENABLE_LINEAR_FRAMEBUFFER: u16 = 1 << 6;
+
+/// Bits per pixel of the modes set by [init].
+const BITS_PER_PIXEL: u16 = 32;
+
+fn read(register: u16) -> u16 {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).read()
+    }
+}
+
+fn write(register: u16, value: u16) {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).write(value);
+    }
+}
+
+/// Returns `true` if there is an adapter with a linear framebuffer.
+pub fn is_present() -> bool {
+    pci::find(VENDOR_ID, DEVICE_ID).is_some() && read(REG_ID) >= ID_LINEAR_FRAMEBUFFER
+}
+
+/// Switches the adapter from text mode to a graphics mode of the given size,
+/// and returns its framebuffer. Returns `None` if there is no adapter, or
+/// its memory is too small for the mode.
+///
+/// Nothing written to the VGA text buffer is seen once this returns.
+pub fn init(width: u16, height: u16) -> Option<Framebuffer> {
+    if !is_present() {
+        return None;
+    }
+    let device = pci::find(VENDOR_ID, DEVICE_ID)?;
+    let Some(Bar::Memory { address, size, .. }) = device.bars[0] else {
+        return None;
+    };
+    let info = Info {
+        width: width as usize,
+        height: height as usize,
+        stride: width as usize,
+        bytes_per_pixel: BITS_PER_PIXEL as usize / 8,
+        format: PixelFormat::Bgr,
+    };
+    if (info.stride * info.height * info.bytes_per_pixel) as u64 > size {
+        return None;
+    }
+
+    write(REG_ENABLE, 0);
+    write(REG_XRES, width);
+    write(REG_YRES, height);
+    write(REG_BPP, BITS_PER_PIXEL);
+    write(REG_VIRT_WIDTH, width);
+    write(REG_ENABLE, ENABLE_DISPLAY | ENABLE_LINEAR_FRAMEBUFFER);
+
+    let address = phys_to_virt(PhysAddr::new(address));
+    Some(unsafe { Framebuffer::new(address, info) })
+}
@@ -0,0 +1,355 @@
+//! Text consoles on pixel framebuffers.
+//!
+//! A [Framebuffer] is a linear array of pixels in memory, as set up by UEFI
+//! and newer bootloaders in place of VGA text mode, or by the
+//! [Bochs display adapter](bochs) on request. A [TextConsole] draws text on
+//! it with the bitmap [font], and [init] makes it the console which printed
+//! text goes to instead of the VGA writer.
+//!
+//! ```no_run
+//! if let Some(framebuffer) = toyos::framebuffer::bochs::init(1024, 768) {
+//!     toyos::framebuffer::init(framebuffer);
+//! }
+//! ```
+
+pub mod bochs;
+pub mod font;
+
+use core::fmt::{self, Write};
+
+use spin::Once;
+use x86_64::VirtAddr;
+
+use crate::{console, sync::IrqSpinlock, vga::Color, vga::TAB_WIDTH};
+
+/// The order of the color components of a pixel in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+}
+
+/// The layout of a framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Info {
+    /// Width of the picture in pixels.
+    pub width: usize,
+    /// Height of the picture in pixels.
+    pub height: usize,
+    /// Number of pixels from the start of one row to the start of the next.
+    pub stride: usize,
+    /// Size of a pixel, 3 or 4 bytes.
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+/// The color of a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Rgb {
+        Rgb { red, green, blue }
+    }
+}
+
+impl From<Color> for Rgb {
+    /// Returns the color of the standard VGA palette.
+    fn from(color: Color) -> Rgb {
+        match color {
+            Color::Black => Rgb::new(0x00, 0x00, 0x00),
+            Color::Blue => Rgb::new(0x00, 0x00, 0xaa),
+            Color::Green => Rgb::new(0x00, 0xaa, 0x00),
+            Color::Cyan => Rgb::new(0x00, 0xaa, 0xaa),
+            Color::Red => Rgb::new(0xaa, 0x00, 0x00),
+            Color::Magenta => Rgb::new(0xaa, 0x00, 0xaa),
+            Color::Brown => Rgb::new(0xaa, 0x55, 0x00),
+            Color::LightGray => Rgb::new(0xaa, 0xaa, 0xaa),
+            Color::DarkGray => Rgb::new(0x55, 0x55, 0x55),
+            Color::LightBlue => Rgb::new(0x55, 0x55, 0xff),
+            Color::LightGreen => Rgb::new(0x55, 0xff, 0x55),
+            Color::LightCyan => Rgb::new(0x55, 0xff, 0xff),
+            Color::LightRed => Rgb::new(0xff, 0x55, 0x55),
+            Color::Pink => Rgb::new(0xff, 0x55, 0xff),
+            Color::Yellow => Rgb::new(0xff, 0xff, 0x55),
+            Color::White => Rgb::new(0xff, 0xff, 0xff),
+        }
+    }
+}
+
+/// A linear framebuffer.
+pub struct Framebuffer {
+    info: Info,
+    bytes: &'static mut [u8],
+}
+
+impl Framebuffer {
+    /// Creates a framebuffer from the address of its first pixel.
+    ///
+    /// # Safety
+    ///
+    /// `address` must be mapped to a framebuffer with the layout of `info`,
+    /// which nothing else writes to.
+    pub unsafe fn new(address: VirtAddr, info: Info) -> Framebuffer {
+        let len = info.stride * info.height * info.bytes_per_pixel;
+        let bytes = core::slice::from_raw_parts_mut(address.as_mut_ptr(), len);
+        Framebuffer { info, bytes }
+    }
+
+    pub fn info(&self) -> Info {
+        self.info
+    }
+
+    /// Sets the color of a pixel. Pixels outside the picture are ignored.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        self.bytes[offset..offset + 3].copy_from_slice(&encode(self.info.format, color));
+    }
+
+    /// Sets the colors of a run of pixels in a row, starting at `x`. The
+    /// pixels past the right edge are ignored.
+    pub fn write_pixels(&mut self, x: usize, y: usize, pixels: &[Rgb]) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+        let len = pixels.len().min(self.info.width - x);
+        let start = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        let end = start + len * self.info.bytes_per_pixel;
+        let chunks = self.bytes[start..end].chunks_exact_mut(self.info.bytes_per_pixel);
+        let format = self.info.format;
+        for (bytes, &color) in chunks.zip(pixels) {
+            bytes[..3].copy_from_slice(&encode(format, color));
+        }
+    }
+
+    /// Fills a rectangle with a color, clipped to the picture.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let right = (x + width).min(self.info.width);
+        let bottom = (y + height).min(self.info.height);
+        for y in y..bottom {
+            for x in x..right {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Moves the picture up by `rows` rows of pixels, filling the rows which
+    /// come free at the bottom with `color`.
+    pub fn scroll_up(&mut self, rows: usize, color: Rgb) {
+        let rows = rows.min(self.info.height);
+        let row_len = self.info.stride * self.info.bytes_per_pixel;
+        self.bytes.copy_within(rows * row_len.., 0);
+        let width = self.info.width;
+        let height = self.info.height;
+        self.fill_rect(0, height - rows, width, rows, color);
+    }
+}
+
+/// Returns the bytes of a pixel of the given color.
+fn encode(format: PixelFormat, color: Rgb) -> [u8; 3] {
+    match format {
+        PixelFormat::Rgb => [color.red, color.green, color.blue],
+        PixelFormat::Bgr => [color.blue, color.green, color.red],
+    }
+}
+
+/// Draws text on a framebuffer, scrolling it up when the bottom is full.
+///
+/// Like the [VGA writer](crate::vga::Writer), it understands `\n`, `\t`,
+/// `\r` and `\x08`.
+pub struct TextConsole {
+    framebuffer: Framebuffer,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: Rgb,
+    background: Rgb,
+}
+
+impl TextConsole {
+    /// Clears the framebuffer and starts writing at the top left.
+    pub fn new(framebuffer: Framebuffer) -> TextConsole {
+        let info = framebuffer.info();
+        let mut console = TextConsole {
+            framebuffer,
+            columns: info.width / font::WIDTH,
+            rows: info.height / font::HEIGHT,
+            column: 0,
+            row: 0,
+            foreground: Color::Yellow.into(),
+            background: Color::Black.into(),
+        };
+        let background = console.background;
+        console
+            .framebuffer
+            .fill_rect(0, 0, info.width, info.height, background);
+        console
+    }
+
+    /// Returns the number of columns and rows of text.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Returns the row and column of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    /// Sets the colors of the text written from now on.
+    pub fn set_color(&mut self, foreground: Rgb, background: Rgb) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// Draws `c` in the cell at the given column and row.
+    fn draw(&mut self, c: char, column: usize, row: usize) {
+        let glyph = font::glyph(c);
+        let (left, top) = (column * font::WIDTH, row * font::HEIGHT);
+        for (y, bits) in glyph.iter().enumerate() {
+            for x in 0..font::WIDTH {
+                let color = if bits & (0x80 >> x) != 0 {
+                    self.foreground
+                } else {
+                    self.background
+                };
+                self.framebuffer.set_pixel(left + x, top + y, color);
+            }
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column = 0,
+            '\t' => {
+                let next_stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < next_stop.min(self.columns) {
+                    self.write_char(' ');
+                }
+            }
+            '\x08' => {
+                if self.column > 0 {
+                    self.column -= 1;
+                    self.draw(' ', self.column.min(self.columns - 1), self.row);
+                }
+            }
+            c => {
+                if self.column >= self.columns {
+                    self.new_line();
+                }
+                self.draw(c, self.column, self.row);
+                self.column += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        let background = self.background;
+        self.framebuffer.scroll_up(font::HEIGHT, background);
+    }
+}
+
+impl Write for TextConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        Ok(())
+    }
+}
+
+impl console::Console for IrqSpinlock<TextConsole> {
+    fn name(&self) -> &str {
+        "framebuffer"
+    }
+
+    fn print(&self, args: fmt::Arguments) {
+        _ = self.lock().write_fmt(args);
+    }
+
+    fn print_colored(&self, color: Color, args: fmt::Arguments) {
+        let mut console = self.lock();
+        let previous = console.foreground;
+        console.foreground = color.into();
+        _ = console.write_fmt(args);
+        console.foreground = previous;
+    }
+}
+
+static CONSOLE: Once<IrqSpinlock<TextConsole>> = Once::new();
+
+/// Draws printed text on `framebuffer` from now on, in place of the VGA
+/// writer, which is no longer on the screen. Returns `false` if there is a
+/// framebuffer console already.
+pub fn init(framebuffer: Framebuffer) -> bool {
+    if CONSOLE.is_completed() {
+        return false;
+    }
+    let console = CONSOLE.call_once(|| IrqSpinlock::new(TextConsole::new(framebuffer)));
+    console::unregister("vga");
+    console::register(console)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{boxed::Box, vec};
+
+    fn framebuffer(width: usize, height: usize) -> Framebuffer {
+        let info = Info {
+            width,
+            height,
+            stride: width,
+            bytes_per_pixel: 4,
+            format: PixelFormat::Bgr,
+        };
+        let bytes = Box::leak(vec![0u8; width * height * 4].into_boxed_slice());
+        unsafe { Framebuffer::new(VirtAddr::from_ptr(bytes.as_mut_ptr()), info) }
+    }
+
+    fn pixel(framebuffer: &Framebuffer, x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * framebuffer.info.stride + x) * 4;
+        framebuffer.bytes[offset..offset + 3].try_into().unwrap()
+    }
+
+    #[test_case]
+    fn test_set_pixel() {
+        let mut framebuffer = framebuffer(4, 4);
+        framebuffer.set_pixel(1, 2, Rgb::new(1, 2, 3));
+        framebuffer.set_pixel(4, 0, Rgb::new(1, 2, 3));
+        assert_eq!(pixel(&framebuffer, 1, 2), [3, 2, 1]);
+        assert_eq!(pixel(&framebuffer, 3, 0), [0, 0, 0]);
+    }
+
+    #[test_case]
+    fn test_text_console() {
+        let mut console = TextConsole::new(framebuffer(2 * font::WIDTH, 2 * font::HEIGHT));
+        assert_eq!(console.size(), (2, 2));
+        let yellow = Rgb::from(Color::Yellow);
+        let yellow = [yellow.blue, yellow.green, yellow.red];
+
+        // The stem of '|' is the fourth pixel of every row but the last.
+        write!(console, "|").unwrap();
+        assert_eq!(pixel(&console.framebuffer, 3, 0), yellow);
+        assert_eq!(pixel(&console.framebuffer, 2, 0), [0, 0, 0]);
+
+        // Wrapping off the bottom scrolls the '|' up off the screen.
+        write!(console, " \n\n").unwrap();
+        assert_eq!(console.cursor(), (1, 0));
+        assert_eq!(pixel(&console.framebuffer, 3, 0), [0, 0, 0]);
+    }
+}
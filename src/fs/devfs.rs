@@ -0,0 +1,295 @@
+//! A filesystem of devices, mounted at `/dev` during boot.
+//!
+//! Every entry is a file whose reads and writes go to a device rather than
+//! to stored bytes. The character devices are:
+//!
+//! - `console`, which prints writes to the
+//!   [shell terminal](crate::vt::SHELL). Reading is not supported yet and
+//!   always hits end of file.
+//! - `serial0`, which sends writes through the first serial port. Reading
+//!   hits end of file as well.
+//! - `null`, which discards writes and is always at end of file.
+//! - `zero`, which reads as an endless run of zeroes and discards writes.
+//! - `random`, which reads as an endless run of bytes from the kernel's
+//!   [random number generator](crate::rand).
+//!
+//! Every registered [block device](block) appears under the name it was
+//! registered with, as a file the size of the device.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use super::{Dir, DirEntry, Error, File, FileSystem, FileType, Inode, Metadata};
+use crate::{
+    block::{self, BlockDevice},
+    rand, serial, vt,
+};
+
+/// The device filesystem.
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        DevFs {
+            root: Arc::new(DevDir),
+        }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+/// The only directory, listing the devices present when it is read.
+struct DevDir;
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: (CharDevice::ALL.len() + block::devices().len()) as u64,
+        }
+    }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        Some(self)
+    }
+}
+
+impl Dir for DevDir {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        if let Some(device) = CharDevice::ALL.iter().find(|device| device.name() == name) {
+            return Ok(Arc::new(*device));
+        }
+        let device = block::get(name).ok_or(Error::NotFound)?;
+        Ok(Arc::new(BlockFile { device }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        let char_devices = CharDevice::ALL
+            .iter()
+            .map(|device| device.name().to_string());
+        let block_devices = block::devices().into_iter().map(|(name, _)| name);
+        Ok(char_devices
+            .chain(block_devices)
+            .map(|name: String| DirEntry {
+                name,
+                kind: FileType::File,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharDevice {
+    Console,
+    Serial,
+    Null,
+    Zero,
+    Random,
+}
+
+impl CharDevice {
+    const ALL: [CharDevice; 5] = [
+        CharDevice::Console,
+        CharDevice::Serial,
+        CharDevice::Null,
+        CharDevice::Zero,
+        CharDevice::Random,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            CharDevice::Console => "console",
+            CharDevice::Serial => "serial0",
+            CharDevice::Null => "null",
+            CharDevice::Zero => "zero",
+            CharDevice::Random => "random",
+        }
+    }
+}
+
+impl Inode for CharDevice {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: 0,
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for CharDevice {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            CharDevice::Console | CharDevice::Serial | CharDevice::Null => Ok(0),
+            CharDevice::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            CharDevice::Random => {
+                rand::fill(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, Error> {
+        match self {
+            CharDevice::Console => {
+                vt::print(vt::SHELL, format_args!("{}", String::from_utf8_lossy(data)))
+            }
+            CharDevice::Serial => {
+                // Bytes are sent as they are, so binary data survives.
+                let mut port = serial::COM1.lock();
+                for &byte in data {
+                    port.send(byte);
+                }
+            }
+            CharDevice::Null | CharDevice::Zero | CharDevice::Random => {}
+        }
+        Ok(data.len())
+    }
+
+    /// Devices have no contents to truncate, so opening them with
+    /// [OpenFlags::TRUNCATE](super::OpenFlags::TRUNCATE) is harmless.
+    fn truncate(&self, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A block device as a file.
+struct BlockFile {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockFile {
+    fn size(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+}
+
+impl Inode for BlockFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.size(),
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for BlockFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = (buf.len() as u64).min(self.size().saturating_sub(offset)) as usize;
+        if len > 0 {
+            self.device.read_at(offset, &mut buf[..len])?;
+        }
+        Ok(len)
+    }
+
+    /// Writes within the device, reading the blocks only partly covered by
+    /// `data` first. Writes past the end fail with [Error::NoSpace].
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let len = (data.len() as u64).min(self.size().saturating_sub(offset)) as usize;
+        if len == 0 {
+            return Err(Error::NoSpace);
+        }
+        let data = &data[..len];
+
+        let block_size = self.device.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + len as u64).div_ceil(block_size);
+        let skip = (offset % block_size) as usize;
+        let mut blocks = vec![0; ((last - first) * block_size) as usize];
+        if skip != 0 || !(len as u64).is_multiple_of(block_size) {
+            self.device.read_blocks(first, &mut blocks)?;
+        }
+        blocks[skip..skip + len].copy_from_slice(data);
+        self.device
+            .write_blocks(first, &blocks)
+            .map_err(|error| match error {
+                block::Error::ReadOnly => Error::ReadOnly,
+                error => error.into(),
+            })?;
+        Ok(len)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{RamDisk, SECTOR_SIZE};
+
+    #[test_case]
+    fn test_char_devices() {
+        let dev = DevFs::new().root();
+        let mut buf = [0xff; 16];
+        let zero = dev.lookup("zero").unwrap().as_file().unwrap();
+        assert_eq!(zero.read_at(0, &mut buf), Ok(16));
+        assert_eq!(buf, [0; 16]);
+
+        let null = dev.lookup("null").unwrap().as_file().unwrap();
+        assert_eq!(null.write_at(0, b"gone"), Ok(4));
+        assert_eq!(null.read_at(0, &mut buf), Ok(0));
+
+        let random = dev.lookup("random").unwrap().as_file().unwrap();
+        assert_eq!(random.read_at(0, &mut buf), Ok(16));
+        assert_ne!(buf, [0; 16]);
+        assert_eq!(dev.lookup("missing").err(), Some(Error::NotFound));
+    }
+
+    #[test_case]
+    fn test_block_devices() {
+        block::register("test-devfs0", Arc::new(RamDisk::new(2)));
+        let dev = DevFs::new().root();
+        assert!(dev
+            .read_dir()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.name == "test-devfs0"));
+
+        let disk = dev.lookup("test-devfs0").unwrap().as_file().unwrap();
+        assert_eq!(disk.metadata().size, 2 * SECTOR_SIZE as u64);
+        assert_eq!(disk.write_at(510, b"abcd"), Ok(4));
+        assert_eq!(disk.write_at(1022, b"xyz"), Ok(2));
+        assert_eq!(disk.write_at(1024, b"x"), Err(Error::NoSpace));
+
+        let mut buf = [0; 8];
+        assert_eq!(disk.read_at(508, &mut buf), Ok(8));
+        assert_eq!(&buf, b"\0\0abcd\0\0");
+        assert_eq!(disk.read_at(1020, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"\0\0xy");
+    }
+}
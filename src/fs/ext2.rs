@@ -0,0 +1,571 @@
+//! Read-only ext2 driver.
+//!
+//! An ext2 volume is divided into blocks, and the blocks into groups. The
+//! superblock, 1024 bytes into the volume, describes the sizes of both. Each
+//! group has a descriptor, stored in the blocks after the superblock, which
+//! says where the group's table of inodes is. Inodes hold the type and size
+//! of a file and the numbers of its first 12 blocks, followed by a singly, a
+//! doubly and a triply indirect block: blocks holding numbers of blocks, or
+//! numbers of blocks holding numbers of blocks and so on. Directories are
+//! files holding a list of variable length entries mapping names to inode
+//! numbers.
+//!
+//! Volumes using features which change how files are stored, like the
+//! extents of ext4, are rejected. Entries other than files and directories,
+//! such as symbolic links, are left out.
+//!
+//! See: https://www.nongnu.org/ext2-doc/ext2.html
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use super::{Dir, DirEntry, Error, File, FileSystem, FileType, Inode, Metadata};
+use crate::block::BlockDevice;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+/// Directory entries record the type of the inode.
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Incompatible features which do not change how files are read.
+const SUPPORTED_INCOMPAT: u32 = FEATURE_INCOMPAT_FILETYPE;
+/// Regular files may be larger than 4 GiB.
+const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+
+/// Number of block numbers stored in the inode itself.
+const DIRECT_BLOCKS: u64 = 12;
+
+/// An ext2 filesystem on a block device.
+pub struct Ext2 {
+    volume: Arc<Volume>,
+}
+
+impl Ext2 {
+    /// Reads the superblock of the volume on `device`. Fails with
+    /// [Error::Corrupted] if it is not an ext2 volume and with
+    /// [Error::NotSupported] if it needs features this driver lacks.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let mut superblock = [0; 1024];
+        device.read_at(SUPERBLOCK_OFFSET, &mut superblock)?;
+        let volume = Volume::parse(device, &superblock)?;
+        // Fail now rather than on first access if the root is unreadable.
+        let root = volume.inode(ROOT_INODE)?;
+        if root.kind() != Some(FileType::Directory) {
+            return Err(Error::Corrupted);
+        }
+        Ok(Ext2 {
+            volume: Arc::new(volume),
+        })
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        // The root inode was checked when mounting. Should the device fail
+        // now, the root appears empty.
+        let inode = self.volume.inode(ROOT_INODE).unwrap_or_default();
+        Arc::new(Ext2Dir {
+            volume: self.volume.clone(),
+            inode,
+        })
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Layout of a volume.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Block holding the first group descriptor.
+    descriptor_block: u64,
+    /// Directory entries record the type of the inode.
+    file_types: bool,
+    large_files: bool,
+}
+
+impl Volume {
+    fn parse(device: Arc<dyn BlockDevice>, superblock: &[u8; 1024]) -> Result<Self, Error> {
+        if u16_at(superblock, 56) != MAGIC {
+            return Err(Error::Corrupted);
+        }
+        let log_block_size = u32_at(superblock, 24);
+        let inodes_per_group = u32_at(superblock, 40);
+        let (inode_size, incompat, ro_compat) = match u32_at(superblock, 76) {
+            // Revision 0 predates these fields.
+            0 => (128, 0, 0),
+            _ => (
+                u16_at(superblock, 88),
+                u32_at(superblock, 96),
+                u32_at(superblock, 100),
+            ),
+        };
+        if log_block_size > 6
+            || inodes_per_group == 0
+            || !inode_size.is_power_of_two()
+            || inode_size < 128
+        {
+            return Err(Error::Corrupted);
+        }
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(Error::NotSupported);
+        }
+
+        let block_size = 1024 << log_block_size;
+        Ok(Volume {
+            device,
+            block_size,
+            inode_count: u32_at(superblock, 0),
+            inodes_per_group,
+            inode_size: inode_size.into(),
+            // The descriptors follow the block holding the superblock.
+            descriptor_block: SUPERBLOCK_OFFSET / block_size + 1,
+            file_types: incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            large_files: ro_compat & FEATURE_RO_COMPAT_LARGE_FILE != 0,
+        })
+    }
+
+    fn read_block(&self, block: u32, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.device
+            .read_at(u64::from(block) * self.block_size + offset, buf)?;
+        Ok(())
+    }
+
+    /// Reads the inode numbered `number`, counting from 1.
+    fn inode(&self, number: u32) -> Result<RawInode, Error> {
+        if number == 0 || number > self.inode_count {
+            return Err(Error::Corrupted);
+        }
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+
+        let mut descriptor = [0; 32];
+        self.device.read_at(
+            self.descriptor_block * self.block_size + u64::from(group) * 32,
+            &mut descriptor,
+        )?;
+        let inode_table = u32_at(&descriptor, 8);
+
+        let mut inode = [0; 128];
+        self.read_block(inode_table, u64::from(index) * self.inode_size, &mut inode)?;
+        let mode = u16_at(&inode, 0);
+        let mut size = u64::from(u32_at(&inode, 4));
+        if self.large_files && mode & MODE_TYPE_MASK == MODE_REGULAR {
+            size |= u64::from(u32_at(&inode, 108)) << 32;
+        }
+        let mut blocks = [0; 15];
+        for (index, block) in blocks.iter_mut().enumerate() {
+            *block = u32_at(&inode, 40 + index * 4);
+        }
+        Ok(RawInode { mode, size, blocks })
+    }
+
+    /// Returns the number of the `index`th block of `inode`, or 0 for a hole.
+    fn block_of(&self, inode: &RawInode, index: u64) -> Result<u32, Error> {
+        let per_block = self.block_size / 4;
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+
+        // Find which indirect block the index is under, and its index among
+        // the blocks reachable from there.
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        let mut level = 1;
+        while index >= span {
+            index -= span;
+            span *= per_block;
+            level += 1;
+            if level > 3 {
+                return Err(Error::Corrupted);
+            }
+        }
+
+        let mut block = inode.blocks[DIRECT_BLOCKS as usize + level - 1];
+        for _ in 0..level {
+            if block == 0 {
+                return Ok(0);
+            }
+            span /= per_block;
+            let mut entry = [0; 4];
+            self.read_block(block, index / span * 4, &mut entry)?;
+            block = u32::from_le_bytes(entry);
+            index %= span;
+        }
+        Ok(block)
+    }
+
+    /// Reads from `inode` starting at `offset`, filling holes with zeroes.
+    /// Returns the number of bytes read.
+    fn read(&self, inode: &RawInode, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let end = inode.size.min(offset.saturating_add(buf.len() as u64));
+        let mut position = offset;
+        while position < end {
+            let start = position % self.block_size;
+            let len = (self.block_size - start).min(end - position);
+            let copied = (position - offset) as usize;
+            let buf = &mut buf[copied..copied + len as usize];
+            match self.block_of(inode, position / self.block_size)? {
+                0 => buf.fill(0),
+                block => self.read_block(block, start, buf)?,
+            }
+            position += len;
+        }
+        Ok(position.saturating_sub(offset) as usize)
+    }
+}
+
+/// The fields of an on-disk inode the driver uses.
+#[derive(Default)]
+struct RawInode {
+    mode: u16,
+    size: u64,
+    /// Numbers of the direct blocks, then of the singly, doubly and triply
+    /// indirect blocks.
+    blocks: [u32; 15],
+}
+
+impl RawInode {
+    fn kind(&self) -> Option<FileType> {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_REGULAR => Some(FileType::File),
+            MODE_DIRECTORY => Some(FileType::Directory),
+            _ => None,
+        }
+    }
+}
+
+/// A directory entry as stored on disk.
+struct RawEntry {
+    inode: u32,
+    name: String,
+    /// The type recorded in the entry, if the volume records types and it
+    /// is a file or directory.
+    kind: Option<FileType>,
+}
+
+/// Parses the entries of a directory, skipping `.`, `..` and unused entries.
+fn parse_directory(data: &[u8], file_types: bool) -> Result<Vec<RawEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let inode = u32_at(data, offset);
+        let record_len = usize::from(u16_at(data, offset + 4));
+        let name_len = match file_types {
+            true => usize::from(data[offset + 6]),
+            false => usize::from(u16_at(data, offset + 6)),
+        };
+        if record_len < 8 || offset + record_len > data.len() || 8 + name_len > record_len {
+            return Err(Error::Corrupted);
+        }
+
+        let name = &data[offset + 8..offset + 8 + name_len];
+        if inode != 0 && name != b"." && name != b".." {
+            let kind = match data[offset + 7] {
+                1 if file_types => Some(FileType::File),
+                2 if file_types => Some(FileType::Directory),
+                _ => None,
+            };
+            entries.push(RawEntry {
+                inode,
+                name: String::from_utf8_lossy(name).into_owned(),
+                kind,
+            });
+        }
+        offset += record_len;
+    }
+    Ok(entries)
+}
+
+struct Ext2Dir {
+    volume: Arc<Volume>,
+    inode: RawInode,
+}
+
+impl Ext2Dir {
+    /// Reads the entries a block at a time, as they never span blocks, so a
+    /// corrupt size cannot make the driver allocate the whole of it.
+    fn entries(&self) -> Result<Vec<RawEntry>, Error> {
+        let mut entries = Vec::new();
+        let mut block = vec![0; self.volume.block_size as usize];
+        let mut offset = 0;
+        while offset < self.inode.size {
+            let len = self.volume.read(&self.inode, offset, &mut block)?;
+            entries.extend(parse_directory(&block[..len], self.volume.file_types)?);
+            offset += len as u64;
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Ext2Dir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: self.entries().map_or(0, |entries| entries.len() as u64),
+        }
+    }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        Some(self)
+    }
+}
+
+impl Dir for Ext2Dir {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        let entry = self
+            .entries()?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(Error::NotFound)?;
+        let volume = self.volume.clone();
+        let inode = volume.inode(entry.inode)?;
+        Ok(match inode.kind() {
+            Some(FileType::Directory) => Arc::new(Ext2Dir { volume, inode }),
+            Some(FileType::File) => Arc::new(Ext2File { volume, inode }),
+            None => return Err(Error::NotSupported),
+        })
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        let mut entries = Vec::new();
+        for entry in self.entries()? {
+            // Volumes without types in entries need the inode read.
+            let kind = match entry.kind {
+                Some(kind) => Some(kind),
+                None => self.volume.inode(entry.inode)?.kind(),
+            };
+            if let Some(kind) = kind {
+                entries.push(DirEntry {
+                    name: entry.name,
+                    kind,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+struct Ext2File {
+    volume: Arc<Volume>,
+    inode: RawInode,
+}
+
+impl Inode for Ext2File {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.inode.size,
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for Ext2File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        self.volume.read(&self.inode, offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const BLOCK: usize = 1024;
+    const INODE_TABLE: usize = 5;
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_inode(image: &mut [u8], number: usize, mode: u16, size: u32, blocks: &[u32]) {
+        let start = INODE_TABLE * BLOCK + (number - 1) * 128;
+        put_u16(image, start, mode);
+        put_u32(image, start + 4, size);
+        for (index, &block) in blocks.iter().enumerate() {
+            put_u32(image, start + 40 + index * 4, block);
+        }
+    }
+
+    /// Writes directory entries into `block`, the last one spanning the rest
+    /// of the block.
+    fn put_directory(image: &mut [u8], block: usize, entries: &[(u32, &str, u8)]) {
+        let mut offset = block * BLOCK;
+        for (index, &(inode, name, kind)) in entries.iter().enumerate() {
+            let record_len = match index == entries.len() - 1 {
+                true => (block + 1) * BLOCK - offset,
+                false => (8 + name.len()).next_multiple_of(4),
+            };
+            put_u32(image, offset, inode);
+            put_u16(image, offset + 4, record_len as u16);
+            image[offset + 6] = name.len() as u8;
+            image[offset + 7] = kind;
+            image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+            offset += record_len;
+        }
+    }
+
+    /// Builds a volume of 1 KiB blocks with this layout:
+    ///
+    /// ```text
+    /// /               inode 2, block 7
+    /// /hello.txt      inode 11, block 9
+    /// /docs/          inode 12, block 8
+    ///     big.bin     inode 13, 14 blocks with a hole, singly indirect
+    /// /link           inode 14, a symbolic link
+    /// ```
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 32 * BLOCK];
+
+        let superblock = &mut image[BLOCK..2 * BLOCK];
+        put_u32(superblock, 0, 16);
+        put_u32(superblock, 4, 32);
+        put_u32(superblock, 20, 1);
+        put_u32(superblock, 32, 8192);
+        put_u32(superblock, 40, 16);
+        put_u16(superblock, 56, MAGIC);
+        put_u32(superblock, 76, 1);
+        put_u16(superblock, 88, 128);
+        put_u32(superblock, 96, FEATURE_INCOMPAT_FILETYPE);
+
+        put_u32(&mut image, 2 * BLOCK + 8, INODE_TABLE as u32);
+
+        put_inode(&mut image, 2, MODE_DIRECTORY | 0o755, BLOCK as u32, &[7]);
+        put_directory(
+            &mut image,
+            7,
+            &[
+                (2, ".", 2),
+                (2, "..", 2),
+                (11, "hello.txt", 1),
+                (12, "docs", 2),
+                (14, "link", 7),
+            ],
+        );
+
+        put_inode(&mut image, 11, MODE_REGULAR | 0o644, 5, &[9]);
+        image[9 * BLOCK..9 * BLOCK + 5].copy_from_slice(b"hello");
+
+        put_inode(&mut image, 12, MODE_DIRECTORY | 0o755, BLOCK as u32, &[8]);
+        put_directory(
+            &mut image,
+            8,
+            &[
+                (12, ".", 2),
+                (2, "..", 2),
+                (0, "unused", 1),
+                (13, "big.bin", 1),
+            ],
+        );
+
+        // Logical blocks 0 to 11 are blocks 10 to 21, except for a hole at
+        // 5. Blocks 12 and 13 are 23 and 24, listed in indirect block 22.
+        let mut blocks: Vec<u32> = (10..22).collect();
+        blocks[5] = 0;
+        blocks.push(22);
+        put_inode(
+            &mut image,
+            13,
+            MODE_REGULAR | 0o644,
+            13 * BLOCK as u32 + 100,
+            &blocks,
+        );
+        put_u32(&mut image, 22 * BLOCK, 23);
+        put_u32(&mut image, 22 * BLOCK + 4, 24);
+        for (logical, &block) in blocks[..12].iter().chain(&[23, 24]).enumerate() {
+            if block != 0 {
+                let start = block as usize * BLOCK;
+                image[start..start + BLOCK].fill(logical as u8 + 1);
+            }
+        }
+
+        put_inode(&mut image, 14, 0xa000 | 0o777, 9, &[]);
+        image
+    }
+
+    fn mount(image: Vec<u8>) -> Result<Arc<dyn Dir>, Error> {
+        Ok(Ext2::new(Arc::new(RamDisk::from_image(image)))?.root())
+    }
+
+    #[test_case]
+    fn test_read_dir() {
+        let root = mount(image()).unwrap();
+        assert_eq!(
+            root.read_dir().unwrap(),
+            [
+                DirEntry {
+                    name: "hello.txt".into(),
+                    kind: FileType::File,
+                },
+                DirEntry {
+                    name: "docs".into(),
+                    kind: FileType::Directory,
+                },
+            ]
+        );
+        assert_eq!(root.lookup("link").err(), Some(Error::NotSupported));
+        assert_eq!(root.lookup("HELLO.TXT").err(), Some(Error::NotFound));
+    }
+
+    #[test_case]
+    fn test_read_file() {
+        let root = mount(image()).unwrap();
+        let hello = root.lookup("hello.txt").unwrap().as_file().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(hello.read_at(1, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"ello");
+
+        let docs = root.lookup("docs").unwrap().as_dir().unwrap();
+        let big = docs.lookup("big.bin").unwrap().as_file().unwrap();
+        assert_eq!(big.metadata().size, 13 * BLOCK as u64 + 100);
+        let mut buf = vec![0; 2 * BLOCK];
+        assert_eq!(big.read_at(5 * BLOCK as u64, &mut buf), Ok(2 * BLOCK));
+        assert!(buf[..BLOCK].iter().all(|&byte| byte == 0));
+        assert!(buf[BLOCK..].iter().all(|&byte| byte == 7));
+        assert_eq!(big.read_at(13 * BLOCK as u64, &mut buf), Ok(100));
+        assert_eq!(buf[..100], [14; 100]);
+    }
+
+    #[test_case]
+    fn test_rejected_volumes() {
+        assert_eq!(mount(vec![0; 4 * BLOCK]).err(), Some(Error::Corrupted));
+        let mut image = image();
+        // Extents, as used by ext4.
+        put_u32(&mut image, BLOCK + 96, 0x0040 | FEATURE_INCOMPAT_FILETYPE);
+        assert_eq!(mount(image).err(), Some(Error::NotSupported));
+    }
+
+    #[test_case]
+    fn test_directory_larger_than_its_blocks() {
+        let mut image = image();
+        put_inode(&mut image, 2, MODE_DIRECTORY | 0o755, u32::MAX, &[7]);
+        let root = mount(image).unwrap();
+        // The block after the first is a hole, which holds no valid entry.
+        assert_eq!(root.read_dir().err(), Some(Error::Corrupted));
+    }
+}
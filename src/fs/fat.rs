@@ -0,0 +1,674 @@
+//! Read-only FAT32 driver.
+//!
+//! A FAT volume starts with the BIOS parameter block (BPB), which describes
+//! the layout of the rest: a number of reserved sectors, the file allocation
+//! tables and then the data area, divided into clusters. The allocation
+//! table holds, for every cluster, the number of the next cluster of the
+//! same file, so files and directories are linked lists of clusters called
+//! cluster chains. Directories are arrays of 32 byte entries, with long file
+//! names stored in extra entries in front of the 8.3 entry they belong to.
+//!
+//! Only FAT32 volumes are supported; FAT12 and FAT16 keep their root
+//! directory outside of the data area. Names are matched case-insensitively,
+//! as on other systems.
+//!
+//! See: https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use super::{Dir, DirEntry, Error, File, FileSystem, FileType, Inode, Metadata};
+use crate::block::BlockDevice;
+
+/// Cluster numbers at or above this end a chain.
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// The upper 4 bits of allocation table entries are reserved.
+const ENTRY_MASK: u32 = 0x0fff_ffff;
+/// Size of a directory entry in bytes.
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Attributes marking a long file name entry.
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// Set on the first, which is the last part, of a long file name.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// First byte of the name of a deleted entry.
+const DELETED: u8 = 0xe5;
+/// Case flags of the 8.3 name, set by Windows NT and later.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// A FAT32 filesystem on a block device.
+pub struct Fat32 {
+    volume: Arc<Volume>,
+}
+
+impl Fat32 {
+    /// Reads the BPB of the volume on `device`. Fails with
+    /// [Error::Corrupted] if it is not a FAT32 volume.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let mut bpb = [0; 512];
+        device.read_at(0, &mut bpb)?;
+        let volume = Volume::parse(device, &bpb)?;
+        Ok(Fat32 {
+            volume: Arc::new(volume),
+        })
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        Arc::new(FatDir {
+            volume: self.volume.clone(),
+            first: self.volume.root_cluster,
+        })
+    }
+}
+
+/// Layout of a volume.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    /// Byte offset of the first allocation table.
+    fat_start: u64,
+    /// Byte offset of cluster 2, the first cluster.
+    data_start: u64,
+    cluster_size: u64,
+    /// Number of clusters in the data area.
+    cluster_count: u32,
+    root_cluster: u32,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    fn parse(device: Arc<dyn BlockDevice>, bpb: &[u8; 512]) -> Result<Self, Error> {
+        let bytes_per_sector = u16_at(bpb, 11);
+        let sectors_per_cluster = bpb[13];
+        let reserved_sectors = u16_at(bpb, 14);
+        let fat_count = bpb[16];
+        let root_entry_count = u16_at(bpb, 17);
+        let fat_size_16 = u16_at(bpb, 22);
+        let total_sectors = match u16_at(bpb, 19) {
+            0 => u32_at(bpb, 32),
+            sectors => sectors.into(),
+        };
+        let fat_size = u32_at(bpb, 36);
+        let root_cluster = u32_at(bpb, 44);
+
+        let valid = u16_at(bpb, 510) == 0xaa55
+            && bytes_per_sector.is_power_of_two()
+            && (512..=4096).contains(&bytes_per_sector)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fat_count > 0
+            // These are only used by FAT12 and FAT16.
+            && root_entry_count == 0
+            && fat_size_16 == 0
+            && fat_size > 0;
+        if !valid {
+            return Err(Error::Corrupted);
+        }
+
+        let bytes_per_sector = u64::from(bytes_per_sector);
+        let data_sector = u64::from(reserved_sectors) + u64::from(fat_count) * u64::from(fat_size);
+        let data_sectors = u64::from(total_sectors)
+            .checked_sub(data_sector)
+            .ok_or(Error::Corrupted)?;
+        // Limited by the size of the volume and by the number of entries
+        // the allocation table has room for, minus the 2 reserved ones.
+        let cluster_count = (data_sectors / u64::from(sectors_per_cluster))
+            .min(u64::from(fat_size) * bytes_per_sector / 4 - 2)
+            .min(u64::from(ENTRY_MASK)) as u32;
+
+        let volume = Volume {
+            device,
+            fat_start: u64::from(reserved_sectors) * bytes_per_sector,
+            data_start: data_sector * bytes_per_sector,
+            cluster_size: u64::from(sectors_per_cluster) * bytes_per_sector,
+            cluster_count,
+            root_cluster,
+        };
+        if !volume.is_valid_cluster(root_cluster) {
+            return Err(Error::Corrupted);
+        }
+        Ok(volume)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    /// Returns the cluster following `cluster` in its chain, or `None` at
+    /// the end of the chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Error> {
+        let mut entry = [0; 4];
+        self.device
+            .read_at(self.fat_start + u64::from(cluster) * 4, &mut entry)?;
+        match u32::from_le_bytes(entry) & ENTRY_MASK {
+            next if next >= END_OF_CHAIN => Ok(None),
+            next if self.is_valid_cluster(next) => Ok(Some(next)),
+            // Free or bad clusters are never part of a chain.
+            _ => Err(Error::Corrupted),
+        }
+    }
+
+    /// Returns the clusters of the chain starting at `first`, which is 0 for
+    /// empty files.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, Error> {
+        if first == 0 {
+            return Ok(Vec::new());
+        }
+        if !self.is_valid_cluster(first) {
+            return Err(Error::Corrupted);
+        }
+        let mut chain = vec![first];
+        while let Some(next) = self.next_cluster(*chain.last().unwrap())? {
+            // A longer chain must contain a loop.
+            if chain.len() >= self.cluster_count as usize {
+                return Err(Error::Corrupted);
+            }
+            chain.push(next);
+        }
+        Ok(chain)
+    }
+
+    /// Reads from `cluster`, starting `offset` bytes into it.
+    fn read_cluster(&self, cluster: u32, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let start = self.data_start + u64::from(cluster - 2) * self.cluster_size;
+        self.device.read_at(start + offset, buf)?;
+        Ok(())
+    }
+}
+
+/// A directory entry as stored on disk, with its long name resolved.
+struct RawEntry {
+    name: String,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl RawEntry {
+    fn kind(&self) -> FileType {
+        match self.attributes & ATTR_DIRECTORY {
+            0 => FileType::File,
+            _ => FileType::Directory,
+        }
+    }
+}
+
+/// Checksum of an 8.3 name stored in the long name entries belonging to it.
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Returns the 8.3 name `name` as `BASE.EXT`, lowercasing parts as `case`
+/// says.
+fn short_name(name: &[u8], case: u8) -> String {
+    let part = |bytes: &[u8], lowercase: bool| {
+        let mut part: String = bytes
+            .iter()
+            .map(|&byte| char::from(byte))
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        if lowercase {
+            part.make_ascii_lowercase();
+        }
+        part
+    };
+    let mut base = name[..8].to_vec();
+    // 0xe5 is a valid first character in some character sets.
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+    let base = part(&base, case & LOWERCASE_BASE != 0);
+    let extension = part(&name[8..11], case & LOWERCASE_EXTENSION != 0);
+    match extension.is_empty() {
+        true => base,
+        false => alloc::format!("{}.{}", base, extension),
+    }
+}
+
+/// Collects the parts of a long file name, which are stored last part first.
+#[derive(Default)]
+struct LongName {
+    parts: Vec<Option<[u16; 13]>>,
+    checksum: u8,
+}
+
+impl LongName {
+    fn push(&mut self, entry: &[u8]) {
+        let sequence = entry[0];
+        let index = usize::from(sequence & 0x1f);
+        if sequence & LAST_LONG_ENTRY != 0 {
+            self.parts = vec![None; index];
+            self.checksum = entry[13];
+        }
+        if index == 0 || index > self.parts.len() || entry[13] != self.checksum {
+            self.parts.clear();
+            return;
+        }
+        let mut chars = [0; 13];
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (char, offset) in chars.iter_mut().zip(offsets) {
+            *char = u16_at(entry, offset);
+        }
+        self.parts[index - 1] = Some(chars);
+    }
+
+    /// Returns the long name of the 8.3 entry `short_name` and forgets it.
+    fn take(&mut self, short_name: &[u8]) -> Option<String> {
+        let parts = core::mem::take(&mut self.parts);
+        if parts.is_empty() || self.checksum != short_name_checksum(short_name) {
+            return None;
+        }
+        let mut units = Vec::with_capacity(parts.len() * 13);
+        for part in parts {
+            units.extend_from_slice(&part?);
+        }
+        let len = units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(units.len());
+        Some(
+            char::decode_utf16(units[..len].iter().copied())
+                .map(|char| char.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+/// Parses the entries in one cluster of a directory into `entries`, skipping
+/// `.`, `..`, deleted entries and the volume label. Long names may continue
+/// from the previous cluster in `long_name`. Returns `true` if the cluster
+/// holds the end of the directory.
+fn parse_directory(data: &[u8], long_name: &mut LongName, entries: &mut Vec<RawEntry>) -> bool {
+    for entry in data.chunks_exact(DIR_ENTRY_SIZE) {
+        let attributes = entry[11];
+        match entry[0] {
+            0 => return true,
+            DELETED => {
+                long_name.parts.clear();
+                continue;
+            }
+            _ if attributes & 0x3f == ATTR_LONG_NAME => {
+                long_name.push(entry);
+                continue;
+            }
+            _ => {}
+        }
+
+        let long = long_name.take(&entry[..11]);
+        if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+            continue;
+        }
+        entries.push(RawEntry {
+            name: long.unwrap_or_else(|| short_name(&entry[..11], entry[12])),
+            attributes,
+            cluster: u32::from(u16_at(entry, 20)) << 16 | u32::from(u16_at(entry, 26)),
+            size: u32_at(entry, 28),
+        });
+    }
+    false
+}
+
+struct FatDir {
+    volume: Arc<Volume>,
+    /// The first cluster of the directory, or 0 if it has none.
+    first: u32,
+}
+
+impl FatDir {
+    /// Reads the entries a cluster at a time while following the chain, so
+    /// a corrupt chain cannot make the driver allocate the whole of it.
+    fn entries(&self) -> Result<Vec<RawEntry>, Error> {
+        let volume = &self.volume;
+        let mut entries = Vec::new();
+        if self.first == 0 {
+            return Ok(entries);
+        }
+        if !volume.is_valid_cluster(self.first) {
+            return Err(Error::Corrupted);
+        }
+
+        let mut long_name = LongName::default();
+        let mut data = vec![0; volume.cluster_size as usize];
+        let mut cluster = Some(self.first);
+        let mut visited = 0;
+        while let Some(current) = cluster {
+            // A longer chain must contain a loop.
+            if visited == volume.cluster_count {
+                return Err(Error::Corrupted);
+            }
+            visited += 1;
+            volume.read_cluster(current, 0, &mut data)?;
+            if parse_directory(&data, &mut long_name, &mut entries) {
+                break;
+            }
+            cluster = volume.next_cluster(current)?;
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for FatDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: self.entries().map_or(0, |entries| entries.len() as u64),
+        }
+    }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        Some(self)
+    }
+}
+
+impl Dir for FatDir {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        let entry = self
+            .entries()?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .ok_or(Error::NotFound)?;
+        let volume = self.volume.clone();
+        Ok(match entry.kind() {
+            FileType::Directory => Arc::new(FatDir {
+                volume,
+                first: entry.cluster,
+            }),
+            FileType::File => {
+                let chain = volume.chain(entry.cluster)?;
+                if (chain.len() as u64) < u64::from(entry.size).div_ceil(volume.cluster_size) {
+                    return Err(Error::Corrupted);
+                }
+                Arc::new(FatFile {
+                    volume,
+                    chain,
+                    size: entry.size.into(),
+                })
+            }
+        })
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|entry| DirEntry {
+                kind: entry.kind(),
+                name: entry.name,
+            })
+            .collect())
+    }
+}
+
+struct FatFile {
+    volume: Arc<Volume>,
+    chain: Vec<u32>,
+    size: u64,
+}
+
+impl Inode for FatFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.size,
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for FatFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let cluster_size = self.volume.cluster_size;
+        let end = self.size.min(offset.saturating_add(buf.len() as u64));
+        let mut position = offset;
+        while position < end {
+            let cluster = self.chain[(position / cluster_size) as usize];
+            let start = position % cluster_size;
+            let len = (cluster_size - start).min(end - position);
+            let copied = (position - offset) as usize;
+            self.volume
+                .read_cluster(cluster, start, &mut buf[copied..copied + len as usize])?;
+            position += len;
+        }
+        Ok(position.saturating_sub(offset) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const SECTOR: usize = 512;
+
+    fn dir_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+        let mut entry = [0; 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// Returns the long name entries for `name`, in the order they are
+    /// stored.
+    fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        units.push(0);
+        units.resize(units.len().next_multiple_of(13), 0xffff);
+        let parts = units.len() / 13;
+        (0..parts)
+            .rev()
+            .map(|index| {
+                let mut entry = [0; 32];
+                entry[0] = index as u8 + 1;
+                if index == parts - 1 {
+                    entry[0] |= LAST_LONG_ENTRY;
+                }
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = short_name_checksum(short_name);
+                let offsets = (1..11)
+                    .step_by(2)
+                    .chain((14..26).step_by(2))
+                    .chain((28..32).step_by(2));
+                for (unit, offset) in units[index * 13..].iter().zip(offsets) {
+                    entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+                }
+                entry
+            })
+            .collect()
+    }
+
+    /// Builds a volume with one sector per cluster and this layout:
+    ///
+    /// ```text
+    /// /                       cluster 2
+    /// /README.TXT             cluster 3, "hello"
+    /// /A Long Directory Name/ cluster 4
+    ///     big.bin             clusters 5 and 7, 700 bytes
+    /// /empty                  no clusters
+    /// ```
+    fn image() -> Vec<u8> {
+        let (reserved, fat_size, clusters) = (1, 1, 16);
+        let total_sectors = reserved + fat_size + clusters;
+        let mut image = vec![0; total_sectors * SECTOR];
+
+        let bpb = &mut image[..SECTOR];
+        bpb[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        bpb[13] = 1;
+        bpb[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        bpb[16] = 1;
+        bpb[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+        bpb[36..40].copy_from_slice(&(fat_size as u32).to_le_bytes());
+        bpb[44..48].copy_from_slice(&2u32.to_le_bytes());
+        bpb[510..512].copy_from_slice(&0xaa55u16.to_le_bytes());
+
+        let fat = &mut image[reserved * SECTOR..];
+        let mut link = |cluster: usize, next: u32| {
+            fat[cluster * 4..cluster * 4 + 4].copy_from_slice(&next.to_le_bytes())
+        };
+        link(2, ENTRY_MASK);
+        link(3, ENTRY_MASK);
+        link(4, ENTRY_MASK);
+        link(5, 7);
+        link(7, ENTRY_MASK);
+
+        let cluster = |image: &mut Vec<u8>, cluster: usize, entries: &[[u8; 32]]| {
+            let start = (reserved + fat_size + cluster - 2) * SECTOR;
+            for (index, entry) in entries.iter().enumerate() {
+                image[start + index * 32..start + index * 32 + 32].copy_from_slice(entry);
+            }
+        };
+        let mut root = vec![dir_entry(b"TOYOS      ", ATTR_VOLUME_ID, 0, 0)];
+        root.push(dir_entry(b"README  TXT", 0, 3, 5));
+        root.extend(long_name_entries("A Long Directory Name", b"ALONGD~1   "));
+        root.push(dir_entry(b"ALONGD~1   ", ATTR_DIRECTORY, 4, 0));
+        let mut deleted = dir_entry(b"GONE    TXT", 0, 0, 0);
+        deleted[0] = DELETED;
+        root.push(deleted);
+        let mut empty = dir_entry(b"EMPTY      ", 0, 0, 0);
+        empty[12] = LOWERCASE_BASE;
+        root.push(empty);
+        cluster(&mut image, 2, &root);
+
+        cluster(
+            &mut image,
+            4,
+            &[
+                dir_entry(b".          ", ATTR_DIRECTORY, 4, 0),
+                dir_entry(b"..         ", ATTR_DIRECTORY, 0, 0),
+                dir_entry(b"BIG     BIN", 0, 5, 700),
+            ],
+        );
+
+        let start = (reserved + fat_size + 1) * SECTOR;
+        image[start..start + 5].copy_from_slice(b"hello");
+        for (cluster, byte) in [(5, 1), (7, 2)] {
+            let start = (reserved + fat_size + cluster - 2) * SECTOR;
+            image[start..start + SECTOR].fill(byte);
+        }
+        image
+    }
+
+    fn mount() -> Arc<dyn Dir> {
+        let disk = RamDisk::from_image(image());
+        Fat32::new(Arc::new(disk)).unwrap().root()
+    }
+
+    #[test_case]
+    fn test_read_dir() {
+        let root = mount();
+        let entries = root.read_dir().unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["README.TXT", "A Long Directory Name", "empty"]);
+        assert_eq!(entries[1].kind, FileType::Directory);
+
+        let dir = root.lookup("a long directory name").unwrap();
+        let dir = dir.as_dir().unwrap();
+        let entries = dir.read_dir().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "BIG.BIN");
+    }
+
+    #[test_case]
+    fn test_read_file() {
+        let root = mount();
+        let readme = root.lookup("readme.txt").unwrap().as_file().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(readme.read_at(0, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+
+        let dir = root.lookup("A Long Directory Name").unwrap();
+        let big = dir.as_dir().unwrap().lookup("big.bin").unwrap();
+        let big = big.as_file().unwrap();
+        assert_eq!(big.metadata().size, 700);
+        let mut buf = [0; 1024];
+        assert_eq!(big.read_at(500, &mut buf), Ok(200));
+        assert_eq!(buf[..12], [1; 12]);
+        assert_eq!(buf[12..200], [2; 188]);
+
+        let empty = root.lookup("empty").unwrap().as_file().unwrap();
+        assert_eq!(empty.read_at(0, &mut buf), Ok(0));
+        assert_eq!(root.lookup("gone.txt").err(), Some(Error::NotFound));
+    }
+
+    #[test_case]
+    fn test_not_fat32() {
+        let mut image = image();
+        image[22] = 1;
+        let disk = Arc::new(RamDisk::from_image(image));
+        assert_eq!(Fat32::new(disk).err(), Some(Error::Corrupted));
+        let disk = Arc::new(RamDisk::new(1));
+        assert_eq!(Fat32::new(disk).err(), Some(Error::Corrupted));
+    }
+
+    #[test_case]
+    fn test_chain_loop() {
+        let mut image = image();
+        // Make cluster 7 point back to cluster 5.
+        image[SECTOR + 7 * 4..SECTOR + 7 * 4 + 4].copy_from_slice(&5u32.to_le_bytes());
+        let root = Fat32::new(Arc::new(RamDisk::from_image(image)))
+            .unwrap()
+            .root();
+        let dir = root.lookup("A Long Directory Name").unwrap();
+        assert_eq!(
+            dir.as_dir().unwrap().lookup("big.bin").err(),
+            Some(Error::Corrupted)
+        );
+    }
+
+    #[test_case]
+    fn test_directory_chain_loop() {
+        let mut image = image();
+        // Fill the directory at cluster 4 with deleted entries, so that it
+        // has no end, and make the cluster its own successor.
+        let start = 4 * SECTOR;
+        image[start..start + SECTOR].fill(DELETED);
+        image[SECTOR + 4 * 4..SECTOR + 4 * 4 + 4].copy_from_slice(&4u32.to_le_bytes());
+        let root = Fat32::new(Arc::new(RamDisk::from_image(image)))
+            .unwrap()
+            .root();
+        let dir = root.lookup("A Long Directory Name").unwrap();
+        assert_eq!(
+            dir.as_dir().unwrap().read_dir().err(),
+            Some(Error::Corrupted)
+        );
+    }
+
+    #[test_case]
+    fn test_short_name() {
+        assert_eq!(short_name(b"README  TXT", 0), "README.TXT");
+        assert_eq!(
+            short_name(b"README  TXT", LOWERCASE_EXTENSION),
+            "README.txt"
+        );
+        assert_eq!(short_name(b"\x05BC       ", 0), "\u{e5}BC");
+    }
+}
@@ -0,0 +1,276 @@
+//! The initial ramdisk.
+//!
+//! The initrd is a read-only filesystem mounted at `/` during boot, holding
+//! the first programs to run and their configuration. The build script packs
+//! the `initrd` directory of the source tree into a [ustar] archive, which is
+//! embedded into the kernel image since the bootloader has no way of loading
+//! extra files alongside the kernel. Files are served straight from the
+//! archive without copying.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{Dir, DirEntry, Error, File, FileSystem, FileType, Inode, Metadata};
+
+pub mod ustar;
+
+/// The archive built from the `initrd` directory.
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+/// Builds the initrd from the embedded archive and mounts it at `/`.
+pub fn init() {
+    let mut builder = Builder::new();
+    builder.ustar(ARCHIVE).expect("malformed initrd archive");
+    super::mount("/", Arc::new(builder.build())).expect("failed to mount initrd");
+}
+
+/// A read-only filesystem of static data.
+pub struct Initrd {
+    root: Arc<Directory>,
+}
+
+impl FileSystem for Initrd {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+/// Collects the entries of an [Initrd].
+pub struct Builder {
+    root: Node,
+}
+
+enum Node {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Node>),
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder {
+            root: Node::Directory(BTreeMap::new()),
+        }
+    }
+
+    /// Adds a file at `path`, relative to the root, creating any missing
+    /// parent directories. Replaces a file already at `path`.
+    pub fn file(&mut self, path: &str, data: &'static [u8]) -> Result<(), Error> {
+        let (parent, name) = match path.trim_matches('/').rsplit_once('/') {
+            Some((parent, name)) => (self.dir_node(parent)?, name),
+            None => (self.dir_node("")?, path.trim_matches('/')),
+        };
+        if !valid_name(name) {
+            return Err(Error::InvalidPath);
+        }
+        match parent.get(name) {
+            Some(Node::Directory(_)) => Err(Error::IsADirectory),
+            _ => {
+                parent.insert(name.to_string(), Node::File(data));
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds a directory at `path`, relative to the root, creating any missing
+    /// parent directories.
+    pub fn dir(&mut self, path: &str) -> Result<(), Error> {
+        self.dir_node(path).map(|_| ())
+    }
+
+    /// Adds the files and directories of a ustar `archive`. Members other
+    /// than files and directories are skipped.
+    pub fn ustar(&mut self, archive: &'static [u8]) -> Result<(), ustar::Error> {
+        for entry in ustar::entries(archive) {
+            let entry = entry?;
+            let result = match entry.kind {
+                ustar::EntryType::File => self.file(&entry.path, entry.data),
+                ustar::EntryType::Directory => self.dir(&entry.path),
+                ustar::EntryType::Other(_) => Ok(()),
+            };
+            result.map_err(|_| ustar::Error::BadPath)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the entries of the directory at `path`, creating it and its
+    /// parents if needed.
+    fn dir_node(&mut self, path: &str) -> Result<&mut BTreeMap<String, Node>, Error> {
+        let mut node = &mut self.root;
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            if !valid_name(name) {
+                return Err(Error::InvalidPath);
+            }
+            let Node::Directory(entries) = node else {
+                return Err(Error::NotADirectory);
+            };
+            node = entries
+                .entry(name.to_string())
+                .or_insert_with(|| Node::Directory(BTreeMap::new()));
+        }
+        match node {
+            Node::Directory(entries) => Ok(entries),
+            Node::File(_) => Err(Error::NotADirectory),
+        }
+    }
+
+    pub fn build(self) -> Initrd {
+        let Node::Directory(entries) = self.root else {
+            unreachable!("the root is a directory");
+        };
+        Initrd {
+            root: Directory::new(entries),
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && name.len() <= super::MAX_NAME_LEN
+}
+
+struct Directory {
+    entries: BTreeMap<String, Arc<dyn Inode>>,
+}
+
+impl Directory {
+    fn new(nodes: BTreeMap<String, Node>) -> Arc<Self> {
+        let entries = nodes
+            .into_iter()
+            .map(|(name, node)| {
+                let inode: Arc<dyn Inode> = match node {
+                    Node::File(data) => Arc::new(StaticFile { data }),
+                    Node::Directory(nodes) => Directory::new(nodes),
+                };
+                (name, inode)
+            })
+            .collect();
+        Arc::new(Directory { entries })
+    }
+}
+
+impl Inode for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: self.entries.len() as u64,
+        }
+    }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        Some(self)
+    }
+}
+
+impl Dir for Directory {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.entries.get(name).cloned().ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                kind: inode.metadata().kind,
+            })
+            .collect())
+    }
+}
+
+struct StaticFile {
+    data: &'static [u8],
+}
+
+impl Inode for StaticFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.data.len() as u64,
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for StaticFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let start = offset.min(self.data.len() as u64) as usize;
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::{self, OpenFlags};
+
+    #[test_case]
+    fn test_embedded_files() {
+        assert_eq!(fs::read("/etc/hostname").unwrap(), b"toyos\n");
+        assert_eq!(fs::metadata("/etc").unwrap().kind, FileType::Directory);
+        assert!(fs::mounts().contains(&("/".to_string(), "initrd")));
+    }
+
+    #[test_case]
+    fn test_archive() {
+        let entries: Vec<_> = ustar::entries(ARCHIVE)
+            .map(|entry| entry.map(|entry| (entry.path, entry.kind)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(entries.contains(&("etc".to_string(), ustar::EntryType::Directory)));
+        assert!(entries.contains(&("etc/motd".to_string(), ustar::EntryType::File)));
+    }
+
+    #[test_case]
+    fn test_read_only() {
+        assert_eq!(fs::write("/etc/hostname", b"x"), Err(Error::ReadOnly));
+        assert_eq!(fs::create_dir("/etc/new"), Err(Error::ReadOnly));
+        assert!(fs::open("/etc/motd", OpenFlags::READ).is_ok());
+    }
+
+    #[test_case]
+    fn test_builder() {
+        let mut builder = Builder::new();
+        builder.file("/bin/init", b"init").unwrap();
+        builder.dir("usr/share").unwrap();
+        assert_eq!(builder.file("bin", b""), Err(Error::IsADirectory));
+        assert_eq!(builder.dir("bin/init/x"), Err(Error::NotADirectory));
+        assert_eq!(builder.file("../x", b""), Err(Error::InvalidPath));
+
+        let root = builder.build().root();
+        let names: Vec<_> = root
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["bin", "usr"]);
+
+        let init = root.lookup("bin").unwrap().as_dir().unwrap();
+        let init = init.lookup("init").unwrap().as_file().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(init.read_at(1, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"nit");
+    }
+}
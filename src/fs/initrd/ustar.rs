@@ -0,0 +1,244 @@
+//! Parser for ustar archives.
+//!
+//! An archive is a sequence of 512 byte blocks. Each member starts with a
+//! header block holding its path, type and size in octal, followed by its
+//! data padded to a whole block. The archive ends with zero blocks.
+//!
+//! See: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html#tag_20_92_13_06
+
+use alloc::string::String;
+use core::{fmt, str};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Reasons an archive is malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The archive ends in the middle of a member.
+    Truncated,
+    /// A header is not in ustar format.
+    BadMagic,
+    /// A header does not match its checksum.
+    BadChecksum,
+    /// A numeric header field is not an octal number.
+    BadNumber,
+    /// A path is not UTF-8 or cannot be stored in the filesystem.
+    BadPath,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::Truncated => "archive is truncated",
+            Error::BadMagic => "not a ustar archive",
+            Error::BadChecksum => "header checksum mismatch",
+            Error::BadNumber => "invalid numeric field",
+            Error::BadPath => "invalid path",
+        })
+    }
+}
+
+/// Kinds of archive members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    File,
+    Directory,
+    /// Links, devices and other members the initrd does not support, with
+    /// their type flag.
+    Other(u8),
+}
+
+/// A member of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Path of the member, without a trailing `/` for directories.
+    pub path: String,
+    pub kind: EntryType,
+    pub data: &'a [u8],
+}
+
+/// Returns an iterator over the members of `archive`. The iterator stops
+/// after the first error.
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        remaining: archive,
+        failed: false,
+    }
+}
+
+/// Iterator returned by [entries].
+pub struct Entries<'a> {
+    remaining: &'a [u8],
+    failed: bool,
+}
+
+impl<'a> Entries<'a> {
+    fn parse_next(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        // Archives may end without the end of archive marker.
+        if self.remaining.is_empty() {
+            return Ok(None);
+        }
+        let header = self.remaining.get(..BLOCK_SIZE).ok_or(Error::Truncated)?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        if &header[257..262] != b"ustar" {
+            return Err(Error::BadMagic);
+        }
+        let expected = octal(&header[148..156])?;
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| match index {
+                148..=155 => u64::from(b' '),
+                _ => u64::from(byte),
+            })
+            .sum();
+        if checksum != expected {
+            return Err(Error::BadChecksum);
+        }
+
+        let size = usize::try_from(octal(&header[124..136])?).map_err(|_| Error::Truncated)?;
+        let kind = match header[156] {
+            b'0' | b'\0' => EntryType::File,
+            b'5' => EntryType::Directory,
+            flag => EntryType::Other(flag),
+        };
+        let mut path = String::new();
+        let prefix = string(&header[345..500])?;
+        if !prefix.is_empty() {
+            path.push_str(prefix);
+            path.push('/');
+        }
+        path.push_str(string(&header[..100])?);
+        while path.ends_with('/') {
+            path.pop();
+        }
+
+        let padded = size
+            .checked_next_multiple_of(BLOCK_SIZE)
+            .ok_or(Error::Truncated)?;
+        let body = &self.remaining[BLOCK_SIZE..];
+        if body.len() < padded {
+            return Err(Error::Truncated);
+        }
+        self.remaining = &body[padded..];
+        Ok(Some(Entry {
+            path,
+            kind,
+            data: &body[..size],
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.parse_next().transpose();
+        self.failed = matches!(result, Some(Err(_)) | None);
+        result
+    }
+}
+
+/// Parses a NUL or space terminated octal number.
+fn octal(field: &[u8]) -> Result<u64, Error> {
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+    let mut value: u64 = 0;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return Err(Error::BadNumber);
+        }
+        value = value
+            .checked_mul(8)
+            .ok_or(Error::BadNumber)?
+            .checked_add(u64::from(digit - b'0'))
+            .ok_or(Error::BadNumber)?;
+    }
+    Ok(value)
+}
+
+/// Returns the contents of a NUL terminated string field.
+fn string(field: &[u8]) -> Result<&str, Error> {
+    let len = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| Error::BadPath)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn header(path: &str, kind: u8, size: usize) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        header[124..135].copy_from_slice(alloc::format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..155].copy_from_slice(alloc::format!("{:06o}\0", checksum).as_bytes());
+        header
+    }
+
+    fn archive(members: &[(&str, u8, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for &(path, kind, data) in members {
+            archive.extend_from_slice(&header(path, kind, data.len()));
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+        archive
+    }
+
+    #[test_case]
+    fn test_entries() {
+        let archive = archive(&[
+            ("bin/", b'5', b""),
+            ("bin/init", b'0', b"hello"),
+            ("bin/sh", b'2', b""),
+        ]);
+        let entries: Vec<_> = entries(&archive).collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, "bin");
+        assert_eq!(entries[0].kind, EntryType::Directory);
+        assert_eq!(entries[1].path, "bin/init");
+        assert_eq!(entries[1].kind, EntryType::File);
+        assert_eq!(entries[1].data, b"hello");
+        assert_eq!(entries[2].kind, EntryType::Other(b'2'));
+    }
+
+    #[test_case]
+    fn test_malformed() {
+        let mut archive = archive(&[("file", b'0', &[1; 600])]);
+        assert_eq!(
+            entries(&archive[..1000]).next(),
+            Some(Err(Error::Truncated))
+        );
+
+        archive[0] = b'g';
+        let mut iter = entries(&archive);
+        assert_eq!(iter.next(), Some(Err(Error::BadChecksum)));
+        assert_eq!(iter.next(), None);
+
+        archive[257] = b'x';
+        assert_eq!(entries(&archive).next(), Some(Err(Error::BadMagic)));
+    }
+
+    #[test_case]
+    fn test_octal() {
+        assert_eq!(octal(b"0000644\0"), Ok(0o644));
+        assert_eq!(octal(b"  17 \0"), Ok(0o17));
+        assert_eq!(octal(b"0009\0"), Err(Error::BadNumber));
+    }
+}
@@ -0,0 +1,943 @@
+//! The virtual filesystem (VFS).
+//!
+//! Concrete filesystems implement [FileSystem], which hands out the root
+//! [Dir] of the filesystem. Every node in a filesystem is an [Inode], which
+//! is either a [File] holding bytes or a [Dir] holding named entries.
+//!
+//! Filesystems are attached to the single directory tree with [mount]. Paths
+//! are always absolute; they are resolved by normalizing away `.` and `..`
+//! components, picking the mount with the longest matching prefix and then
+//! looking up the remaining components one directory at a time starting at
+//! the root of the mounted filesystem. Mount points need not exist in the
+//! filesystem they are mounted over.
+//!
+//! [open] returns an [OpenFile], which keeps the position of a sequence of
+//! reads and writes like a Unix file description. Functions like [read] and
+//! [read_dir] cover the common cases without opening anything.
+//!
+//! File contents can also be read and written asynchronously, with
+//! [File::read_async] and [File::write_async] or the methods of the same name
+//! of [OpenFile], so that async tasks do not hold up the executor while a
+//! disk transfers data. The synchronous operations of an [OpenFile] wait for
+//! the asynchronous ones with [thread::block_on], which only blocks the
+//! calling kernel thread.
+//!
+//! The root of the tree is the read-only [initrd], mounted during [init].
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{any::Any, fmt, future::Future, ops::BitOr, pin::Pin};
+
+use spin::Mutex;
+
+use crate::{
+    block,
+    task::{sync::AsyncMutex, thread},
+};
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+pub mod procfs;
+pub mod ramfs;
+
+/// Mounts the filesystems present from boot: the [initrd] at `/`, the
+/// [devfs] at `/dev`, the [procfs] at `/proc` and a [ramfs] at `/tmp`.
+pub fn init() {
+    initrd::init();
+    mount("/dev", Arc::new(devfs::DevFs::new())).expect("failed to mount /dev");
+    mount("/proc", Arc::new(procfs::ProcFs::new())).expect("failed to mount /proc");
+    mount("/tmp", Arc::new(ramfs::RamFs::new())).expect("failed to mount /tmp");
+}
+
+/// Maximum length of a single path component in bytes.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Reasons filesystem operations fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No entry exists at the path.
+    NotFound,
+    /// A component of the path, or the target, is not a directory.
+    NotADirectory,
+    /// The target is a directory, but the operation needs a file.
+    IsADirectory,
+    /// An entry with the name already exists.
+    AlreadyExists,
+    /// The directory still has entries.
+    NotEmpty,
+    /// The path is relative or has a component which is too long.
+    InvalidPath,
+    /// An offset is out of range, such as a position before the start of
+    /// a file.
+    InvalidArgument,
+    /// The filesystem or the open file does not allow writing.
+    ReadOnly,
+    /// The open file does not allow reading.
+    WriteOnly,
+    /// The filesystem does not support the operation.
+    NotSupported,
+    /// The filesystem is full.
+    NoSpace,
+    /// The mount point is in use.
+    Busy,
+    /// The operation would move an entry to another filesystem.
+    CrossDevice,
+    /// The on-disk structures are invalid or of an unsupported format.
+    Corrupted,
+    /// The underlying device failed.
+    Io,
+}
+
+impl From<block::Error> for Error {
+    fn from(_: block::Error) -> Self {
+        Error::Io
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::NotFound => "no such file or directory",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::AlreadyExists => "file exists",
+            Error::NotEmpty => "directory not empty",
+            Error::InvalidPath => "invalid path",
+            Error::InvalidArgument => "invalid argument",
+            Error::ReadOnly => "read-only",
+            Error::WriteOnly => "write-only",
+            Error::NotSupported => "operation not supported",
+            Error::NoSpace => "no space left on device",
+            Error::Busy => "resource busy",
+            Error::CrossDevice => "cross-device link",
+            Error::Corrupted => "corrupted filesystem",
+            Error::Io => "input/output error",
+        })
+    }
+}
+
+/// The kinds of inodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+/// Information about an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// Size in bytes of a file, or the number of entries of a directory.
+    pub size: u64,
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+/// A node in a filesystem.
+///
+/// Inodes are [Any] so that filesystems can recognize their own inodes among
+/// those passed to them, as [Dir::rename] needs.
+pub trait Inode: Any + Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Returns the inode as a file, or `None` if it is not one.
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        None
+    }
+
+    /// Returns the inode as a directory, or `None` if it is not one.
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        None
+    }
+}
+
+/// Future returned by the asynchronous operations of a [File].
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
+
+/// An inode holding bytes.
+///
+/// The write operations fail with [Error::ReadOnly] unless implemented.
+///
+/// The asynchronous operations complete immediately with the result of the
+/// synchronous ones unless implemented. Filesystems on devices which signal
+/// completion with an interrupt implement them instead, completing the
+/// future from the interrupt, and implement the synchronous operations by
+/// waiting for them with [thread::block_on].
+pub trait File: Inode {
+    /// Copies bytes starting at `offset` into `buf`. Returns the number of
+    /// bytes read, which is 0 at or past the end of the file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Writes `data` at `offset`, growing the file if needed. Returns the
+    /// number of bytes written.
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Shrinks or zero-extends the file to `len` bytes.
+    fn truncate(&self, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Like [File::read_at], but without blocking while waiting for a device.
+    fn read_async<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> IoFuture<'a, usize> {
+        Box::pin(core::future::ready(self.read_at(offset, buf)))
+    }
+
+    /// Like [File::write_at], but without blocking while waiting for a
+    /// device.
+    fn write_async<'a>(&'a self, offset: u64, data: &'a [u8]) -> IoFuture<'a, usize> {
+        Box::pin(core::future::ready(self.write_at(offset, data)))
+    }
+}
+
+/// An inode holding named entries.
+///
+/// The write operations fail with [Error::ReadOnly] unless implemented.
+pub trait Dir: Inode {
+    /// Returns the entry called `name`.
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error>;
+
+    /// Returns every entry, excluding `.` and `..`.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error>;
+
+    /// Creates an empty entry of the given kind called `name`.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Removes the entry called `name`. Directories must be empty.
+    fn remove(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Moves the entry called `name` to `target`, a directory of the same
+    /// filesystem, as `new_name`. Replaces an existing entry of the same kind
+    /// at `new_name`, as long as it is not a directory with entries.
+    fn rename(&self, _name: &str, _target: &dyn Dir, _new_name: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+}
+
+/// A filesystem which can be mounted.
+pub trait FileSystem: Send + Sync {
+    /// Returns a short name for the kind of filesystem, such as `ramfs`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Dir>;
+}
+
+struct Mount {
+    /// Normalized components of the mount point.
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// Mounted filesystems, longest mount point first so that the first match is
+/// the most specific one.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Splits an absolute path into its components, resolving `.` and `..`
+/// lexically. `..` at the root stays at the root.
+fn components(path: &str) -> Result<Vec<&str>, Error> {
+    if !path.starts_with('/') {
+        return Err(Error::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name if name.len() > MAX_NAME_LEN => return Err(Error::InvalidPath),
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// Returns `path` in normal form: absolute, without `.` and `..` components,
+/// repeated separators or a trailing separator.
+pub fn normalize(path: &str) -> Result<String, Error> {
+    let components = components(path)?;
+    if components.is_empty() {
+        return Ok("/".to_string());
+    }
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    Ok(normalized)
+}
+
+/// Attaches `fs` to the directory tree at `path`.
+///
+/// Fails with [Error::Busy] if a filesystem is already mounted there.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Error> {
+    let path: Vec<String> = components(path)?.into_iter().map(String::from).collect();
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(Error::Busy);
+    }
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path.len() < path.len())
+        .unwrap_or(mounts.len());
+    mounts.insert(index, Mount { path, fs });
+    Ok(())
+}
+
+/// Detaches the filesystem mounted at `path` and returns it. Files opened
+/// through it stay usable.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, Error> {
+    let path = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(Error::NotFound)?;
+    Ok(mounts.remove(index).fs)
+}
+
+/// Number of blocks cached for a filesystem mounted with [mount_device].
+const DEVICE_CACHE_BLOCKS: usize = 64;
+
+/// Mounts the filesystem on `device` at `path`, trying [fat] and then
+/// [ext2]. The device is read through a [BlockCache](block::BlockCache).
+/// Returns the name of the filesystem.
+pub fn mount_device(
+    path: &str,
+    device: Arc<dyn block::BlockDevice>,
+) -> Result<&'static str, Error> {
+    let cache: Arc<dyn block::BlockDevice> =
+        Arc::new(block::BlockCache::new(device, DEVICE_CACHE_BLOCKS));
+    let fs: Arc<dyn FileSystem> = match fat::Fat32::new(cache.clone()) {
+        Ok(fat) => Arc::new(fat),
+        Err(_) => Arc::new(ext2::Ext2::new(cache)?),
+    };
+    let name = fs.name();
+    mount(path, fs)?;
+    Ok(name)
+}
+
+/// Returns the mount points and the names of the filesystems mounted there.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    let mut mounts: Vec<_> = MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| {
+            let path = match mount.path.is_empty() {
+                true => "/".to_string(),
+                false => mount
+                    .path
+                    .iter()
+                    .flat_map(|component| ["/", component.as_str()])
+                    .collect(),
+            };
+            (path, mount.fs.name())
+        })
+        .collect();
+    mounts.sort();
+    mounts
+}
+
+/// Returns the filesystem `components` are in and the number of components
+/// naming its mount point.
+fn find_mount(components: &[&str]) -> Result<(Arc<dyn FileSystem>, usize), Error> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|mount| {
+            mount.path.len() <= components.len()
+                && mount.path.iter().zip(components).all(|(a, b)| a == b)
+        })
+        .map(|mount| (mount.fs.clone(), mount.path.len()))
+        .ok_or(Error::NotFound)
+}
+
+/// Returns the inode at `path`.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, Error> {
+    let components = components(path)?;
+    let (fs, mount_len) = find_mount(&components)?;
+
+    let mut inode: Arc<dyn Inode> = fs.root();
+    for name in &components[mount_len..] {
+        let dir = inode.as_dir().ok_or(Error::NotADirectory)?;
+        inode = dir.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+/// Returns the directory holding the entry at `path` and the entry's name.
+fn lookup_parent(path: &str) -> Result<(Arc<dyn Dir>, String), Error> {
+    let normalized = normalize(path)?;
+    let (parent, name) = normalized.rsplit_once('/').unwrap();
+    if name.is_empty() {
+        // The root has no parent.
+        return Err(Error::InvalidPath);
+    }
+    let parent = lookup(if parent.is_empty() { "/" } else { parent })?;
+    let parent = parent.as_dir().ok_or(Error::NotADirectory)?;
+    Ok((parent, name.to_string()))
+}
+
+/// Moves the file or directory at `from` to `to`, replacing a file or empty
+/// directory already at `to`. Both paths must be in the same filesystem.
+pub fn rename(from: &str, to: &str) -> Result<(), Error> {
+    let from_components = components(from)?;
+    let to_components = components(to)?;
+    if from_components.is_empty() || to_components.is_empty() {
+        return Err(Error::Busy);
+    }
+    // A directory can neither be moved into itself nor replace one of its
+    // ancestors, which is never empty.
+    if to_components.starts_with(&from_components) && to_components != from_components {
+        return Err(Error::InvalidPath);
+    }
+    if from_components.starts_with(&to_components) && to_components != from_components {
+        return Err(Error::NotEmpty);
+    }
+
+    let (from_fs, from_mount_len) = find_mount(&from_components)?;
+    let (to_fs, to_mount_len) = find_mount(&to_components)?;
+    if from_mount_len == from_components.len() || to_mount_len == to_components.len() {
+        return Err(Error::Busy);
+    }
+    if !Arc::ptr_eq(&from_fs, &to_fs) {
+        return Err(Error::CrossDevice);
+    }
+
+    let (from_parent, from_name) = lookup_parent(from)?;
+    let (to_parent, to_name) = lookup_parent(to)?;
+    from_parent.rename(&from_name, &*to_parent, &to_name)
+}
+
+/// Returns information about the inode at `path`.
+pub fn metadata(path: &str) -> Result<Metadata, Error> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// How [open] opens a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// Allows reading.
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    /// Allows writing.
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Creates the file if it does not exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Empties the file when opening it for writing.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Makes every write go to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub const fn empty() -> Self {
+        OpenFlags(0)
+    }
+
+    pub const fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// Reference point of [OpenFile::seek].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// The inode an [OpenFile] refers to.
+#[derive(Clone)]
+pub enum Node {
+    File(Arc<dyn File>),
+    Dir(Arc<dyn Dir>),
+}
+
+impl Node {
+    fn new(inode: Arc<dyn Inode>) -> Self {
+        match inode.metadata().kind {
+            FileType::File => Node::File(inode.as_file().expect("file inode is not a File")),
+            FileType::Directory => Node::Dir(inode.as_dir().expect("directory inode is not a Dir")),
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        match self {
+            Node::File(file) => file.metadata(),
+            Node::Dir(dir) => dir.metadata(),
+        }
+    }
+}
+
+/// A file or directory opened with [open], with a position for reading and
+/// writing.
+pub struct OpenFile {
+    node: Node,
+    flags: OpenFlags,
+    /// Locked for the whole of a read or write, so that those of different
+    /// threads and tasks happen one after another.
+    position: AsyncMutex<u64>,
+}
+
+impl OpenFile {
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.node.metadata()
+    }
+
+    /// Reads from the current position into `buf` and advances the position
+    /// past the bytes read. Returns 0 at the end of the file, and fails with
+    /// [Error::InvalidArgument] if the position could overflow.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        thread::block_on(self.read_async(buf))
+    }
+
+    /// Like [OpenFile::read], but without blocking while waiting for a
+    /// device.
+    pub async fn read_async(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(Error::WriteOnly);
+        }
+        let file = match &self.node {
+            Node::File(file) => file,
+            Node::Dir(_) => return Err(Error::IsADirectory),
+        };
+        let mut position = self.position.lock().await;
+        // Character devices accept any offset, so only this keeps the
+        // position from overflowing.
+        if position.checked_add(buf.len() as u64).is_none() {
+            return Err(Error::InvalidArgument);
+        }
+        let len = file.read_async(*position, buf).await?;
+        *position += len as u64;
+        Ok(len)
+    }
+
+    /// Writes `data` at the current position, or at the end of the file if
+    /// opened with [OpenFlags::APPEND], and advances the position past it.
+    /// Fails like [OpenFile::read] if the position could overflow.
+    pub fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        thread::block_on(self.write_async(data))
+    }
+
+    /// Like [OpenFile::write], but without blocking while waiting for a
+    /// device.
+    pub async fn write_async(&self, data: &[u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(Error::ReadOnly);
+        }
+        let file = match &self.node {
+            Node::File(file) => file,
+            Node::Dir(_) => return Err(Error::IsADirectory),
+        };
+        let mut position = self.position.lock().await;
+        if self.flags.contains(OpenFlags::APPEND) {
+            *position = file.metadata().size;
+        }
+        if position.checked_add(data.len() as u64).is_none() {
+            return Err(Error::InvalidArgument);
+        }
+        let len = file.write_async(*position, data).await?;
+        *position += len as u64;
+        Ok(len)
+    }
+
+    /// Moves the position and returns the new one. Positions past the end of
+    /// a file are allowed; writing there zero-fills the gap.
+    pub fn seek(&self, from: SeekFrom) -> Result<u64, Error> {
+        let mut position = thread::block_on(self.position.lock());
+        let new = match from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.metadata().size.checked_add_signed(delta),
+        };
+        *position = new.ok_or(Error::InvalidArgument)?;
+        Ok(*position)
+    }
+
+    /// Returns the entries of an open directory.
+    pub fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        match &self.node {
+            Node::Dir(dir) => dir.read_dir(),
+            Node::File(_) => Err(Error::NotADirectory),
+        }
+    }
+}
+
+/// Opens the file or directory at `path`.
+///
+/// Directories can only be opened for reading, which allows
+/// [OpenFile::read_dir].
+pub fn open(path: &str, flags: OpenFlags) -> Result<OpenFile, Error> {
+    let inode = match lookup(path) {
+        Err(Error::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = lookup_parent(path)?;
+            match parent.create(&name, FileType::File) {
+                // Another thread created it first.
+                Err(Error::AlreadyExists) => parent.lookup(&name)?,
+                result => result?,
+            }
+        }
+        result => result?,
+    };
+
+    let node = Node::new(inode);
+    match &node {
+        Node::Dir(_) if flags.contains(OpenFlags::WRITE) => return Err(Error::IsADirectory),
+        Node::File(file) if flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE) => {
+            file.truncate(0)?
+        }
+        _ => {}
+    }
+    Ok(OpenFile {
+        node,
+        flags,
+        position: AsyncMutex::new(0),
+    })
+}
+
+/// Returns the contents of the file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let file = lookup(path)?.as_file().ok_or(Error::IsADirectory)?;
+    let mut data = alloc::vec![0; file.metadata().size as usize];
+    let mut len = 0;
+    while len < data.len() {
+        match file.read_at(len as u64, &mut data[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Replaces the contents of the file at `path` with `data`, creating the
+/// file if needed.
+pub fn write(path: &str, data: &[u8]) -> Result<(), Error> {
+    let file = open(
+        path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+    )?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            0 => return Err(Error::NoSpace),
+            len => written += len,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the entries of the directory at `path`.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Error> {
+    lookup(path)?
+        .as_dir()
+        .ok_or(Error::NotADirectory)?
+        .read_dir()
+}
+
+/// Creates an empty directory at `path`.
+pub fn create_dir(path: &str) -> Result<(), Error> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.create(&name, FileType::Directory).map(|_| ())
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn remove(path: &str) -> Result<(), Error> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.remove(&name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{collections::BTreeMap, vec};
+
+    /// A minimal in-memory filesystem.
+    struct TestFs(Arc<TestDir>);
+
+    struct TestDir(Mutex<BTreeMap<String, Arc<dyn Inode>>>);
+
+    struct TestFile(Mutex<Vec<u8>>);
+
+    impl FileSystem for TestFs {
+        fn name(&self) -> &'static str {
+            "testfs"
+        }
+
+        fn root(&self) -> Arc<dyn Dir> {
+            self.0.clone()
+        }
+    }
+
+    impl Inode for TestDir {
+        fn metadata(&self) -> Metadata {
+            Metadata {
+                kind: FileType::Directory,
+                size: self.0.lock().len() as u64,
+            }
+        }
+
+        fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+            Some(self)
+        }
+    }
+
+    impl Dir for TestDir {
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+            self.0.lock().get(name).cloned().ok_or(Error::NotFound)
+        }
+
+        fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+            Ok(self
+                .0
+                .lock()
+                .iter()
+                .map(|(name, inode)| DirEntry {
+                    name: name.clone(),
+                    kind: inode.metadata().kind,
+                })
+                .collect())
+        }
+
+        fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+            let mut entries = self.0.lock();
+            if entries.contains_key(name) {
+                return Err(Error::AlreadyExists);
+            }
+            let inode: Arc<dyn Inode> = match kind {
+                FileType::File => Arc::new(TestFile(Mutex::new(Vec::new()))),
+                FileType::Directory => Arc::new(TestDir(Mutex::new(BTreeMap::new()))),
+            };
+            entries.insert(name.to_string(), inode.clone());
+            Ok(inode)
+        }
+
+        fn remove(&self, name: &str) -> Result<(), Error> {
+            self.0
+                .lock()
+                .remove(name)
+                .map(|_| ())
+                .ok_or(Error::NotFound)
+        }
+    }
+
+    impl Inode for TestFile {
+        fn metadata(&self) -> Metadata {
+            Metadata {
+                kind: FileType::File,
+                size: self.0.lock().len() as u64,
+            }
+        }
+
+        fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+            Some(self)
+        }
+    }
+
+    impl File for TestFile {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+            let data = self.0.lock();
+            let start = (offset as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            Ok(len)
+        }
+
+        fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+            let mut contents = self.0.lock();
+            let end = offset as usize + data.len();
+            if contents.len() < end {
+                contents.resize(end, 0);
+            }
+            contents[offset as usize..end].copy_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn truncate(&self, len: u64) -> Result<(), Error> {
+            self.0.lock().resize(len as usize, 0);
+            Ok(())
+        }
+    }
+
+    /// A file whose asynchronous reads wait once before completing, like a
+    /// disk waiting for its interrupt.
+    struct SlowFile;
+
+    impl Inode for SlowFile {
+        fn metadata(&self) -> Metadata {
+            Metadata {
+                kind: FileType::File,
+                size: 3,
+            }
+        }
+    }
+
+    impl File for SlowFile {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+            let data = &b"abc"[(offset as usize).min(3)..];
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+
+        fn read_async<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> IoFuture<'a, usize> {
+            Box::pin(async move {
+                crate::task::future::yield_now().await;
+                self.read_at(offset, buf)
+            })
+        }
+    }
+
+    fn test_fs() -> Arc<dyn FileSystem> {
+        Arc::new(TestFs(Arc::new(TestDir(Mutex::new(BTreeMap::new())))))
+    }
+
+    #[test_case]
+    fn test_normalize() {
+        assert_eq!(normalize("/").unwrap(), "/");
+        assert_eq!(normalize("//a/./b//").unwrap(), "/a/b");
+        assert_eq!(normalize("/a/../../b").unwrap(), "/b");
+        assert_eq!(normalize("a/b"), Err(Error::InvalidPath));
+        assert_eq!(
+            normalize(&alloc::format!("/{}", "x".repeat(MAX_NAME_LEN + 1))),
+            Err(Error::InvalidPath)
+        );
+    }
+
+    #[test_case]
+    fn test_mount_and_unmount() {
+        mount("/vfs-mount", test_fs()).unwrap();
+        assert_eq!(mount("/vfs-mount/", test_fs()), Err(Error::Busy));
+        assert!(mounts().contains(&("/vfs-mount".to_string(), "testfs")));
+        assert_eq!(metadata("/vfs-mount").unwrap().kind, FileType::Directory);
+
+        assert!(unmount("/vfs-mount").is_ok());
+        assert!(unmount("/vfs-mount").is_err());
+    }
+
+    #[test_case]
+    fn test_files_and_directories() {
+        mount("/vfs-files", test_fs()).unwrap();
+        create_dir("/vfs-files/dir").unwrap();
+        assert_eq!(create_dir("/vfs-files/dir"), Err(Error::AlreadyExists));
+        write("/vfs-files/dir/file", b"hello").unwrap();
+
+        assert_eq!(read("/vfs-files/dir/../dir/file").unwrap(), b"hello");
+        assert_eq!(
+            read_dir("/vfs-files/dir").unwrap(),
+            vec![DirEntry {
+                name: "file".to_string(),
+                kind: FileType::File,
+            }]
+        );
+        assert_eq!(
+            lookup("/vfs-files/dir/file/x").err(),
+            Some(Error::NotADirectory)
+        );
+        assert_eq!(read("/vfs-files/dir").err(), Some(Error::IsADirectory));
+
+        remove("/vfs-files/dir/file").unwrap();
+        assert_eq!(lookup("/vfs-files/dir/file").err(), Some(Error::NotFound));
+        unmount("/vfs-files").unwrap();
+    }
+
+    #[test_case]
+    fn test_open_file_position() {
+        mount("/vfs-open", test_fs()).unwrap();
+        let file = open(
+            "/vfs-open/file",
+            OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+        )
+        .unwrap();
+        assert_eq!(file.write(b"abcdef"), Ok(6));
+        assert_eq!(file.seek(SeekFrom::Start(2)), Ok(2));
+
+        let mut buf = [0; 3];
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"cde");
+        assert_eq!(file.seek(SeekFrom::End(-1)), Ok(5));
+        assert_eq!(file.read(&mut buf), Ok(1));
+        assert_eq!(file.read(&mut buf), Ok(0));
+        assert_eq!(
+            file.seek(SeekFrom::Current(-10)),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(file.seek(SeekFrom::Start(u64::MAX)), Ok(u64::MAX));
+        assert_eq!(file.write(b"x"), Err(Error::InvalidArgument));
+        assert_eq!(file.read(&mut buf), Err(Error::InvalidArgument));
+
+        let append = open("/vfs-open/file", OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+        append.write(b"g").unwrap();
+        assert_eq!(append.read(&mut buf), Err(Error::WriteOnly));
+        assert_eq!(read("/vfs-open/file").unwrap(), b"abcdefg");
+
+        assert!(open("/vfs-open", OpenFlags::WRITE).is_err());
+        assert_eq!(
+            open("/vfs-open", OpenFlags::READ)
+                .unwrap()
+                .read_dir()
+                .unwrap()
+                .len(),
+            1
+        );
+        unmount("/vfs-open").unwrap();
+    }
+
+    #[test_case]
+    fn test_async_read() {
+        use core::task::{Context, Poll, Waker};
+
+        let file = OpenFile {
+            node: Node::File(Arc::new(SlowFile)),
+            flags: OpenFlags::READ,
+            position: AsyncMutex::new(0),
+        };
+        let mut buf = [0; 4];
+        {
+            let mut context = Context::from_waker(Waker::noop());
+            let mut read = Box::pin(file.read_async(&mut buf));
+            assert!(read.as_mut().poll(&mut context).is_pending());
+            assert_eq!(read.as_mut().poll(&mut context), Poll::Ready(Ok(3)));
+        }
+        assert_eq!(&buf[..3], b"abc");
+
+        // The synchronous API parks the thread until the read completes.
+        file.seek(SeekFrom::Start(1)).unwrap();
+        assert_eq!(file.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"bc");
+    }
+}
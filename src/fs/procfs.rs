@@ -0,0 +1,260 @@
+//! A read-only filesystem of kernel information, mounted at `/proc` during
+//! boot.
+//!
+//! The contents of every file are generated from the kernel's statistics
+//! whenever the file is read, as text meant to be easy to parse:
+//!
+//! - `meminfo` holds `Name: value kB` lines describing the heap.
+//! - `interrupts` holds a `vector: count name` line for every interrupt
+//!   vector serviced at least once, as printed by [InterruptStats].
+//! - `tasks` holds a line for every live async task: its ID, state,
+//!   priority, poll and wake counts, time spent polling in microseconds and
+//!   name, separated by spaces.
+//! - `uptime` holds the seconds since boot, with millisecond precision.
+//! - `dmi` holds `Name: value` lines describing the machine as given by its
+//!   [SMBIOS tables](crate::smbios), with a `MemoryDevice` line for every
+//!   memory slot: its size in kB, its speed in MT/s and its label. Unknown
+//!   values are `-`.
+//!
+//! [InterruptStats]: crate::interrupts::InterruptStats
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use super::{Dir, DirEntry, Error, File, FileSystem, FileType, Inode, Metadata};
+use crate::{allocator, interrupts, smbios, task::executor, time};
+
+/// The kernel information filesystem.
+pub struct ProcFs {
+    root: Arc<ProcDir>,
+}
+
+impl ProcFs {
+    pub fn new() -> Self {
+        ProcFs {
+            root: Arc::new(ProcDir),
+        }
+    }
+}
+
+impl Default for ProcFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+struct ProcDir;
+
+impl Inode for ProcDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: ProcFile::ALL.len() as u64,
+        }
+    }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        Some(self)
+    }
+}
+
+impl Dir for ProcDir {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        ProcFile::ALL
+            .iter()
+            .find(|file| file.name() == name)
+            .map(|&file| Arc::new(file) as Arc<dyn Inode>)
+            .ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        Ok(ProcFile::ALL
+            .iter()
+            .map(|file| DirEntry {
+                name: file.name().to_string(),
+                kind: FileType::File,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProcFile {
+    MemInfo,
+    Interrupts,
+    Tasks,
+    Uptime,
+    Dmi,
+}
+
+impl ProcFile {
+    const ALL: [ProcFile; 5] = [
+        ProcFile::MemInfo,
+        ProcFile::Interrupts,
+        ProcFile::Tasks,
+        ProcFile::Uptime,
+        ProcFile::Dmi,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ProcFile::MemInfo => "meminfo",
+            ProcFile::Interrupts => "interrupts",
+            ProcFile::Tasks => "tasks",
+            ProcFile::Uptime => "uptime",
+            ProcFile::Dmi => "dmi",
+        }
+    }
+
+    /// Returns the current contents of the file.
+    fn generate(self) -> String {
+        let mut text = String::new();
+        // Writing to a string cannot fail.
+        let _ = match self {
+            ProcFile::MemInfo => {
+                let heap = allocator::usage();
+                write!(
+                    text,
+                    "HeapTotal: {} kB\nHeapUsed: {} kB\nHeapFree: {} kB\n",
+                    allocator::HEAP_SIZE / 1024,
+                    heap.used / 1024,
+                    heap.free / 1024,
+                )
+            }
+            ProcFile::Interrupts => write!(text, "{}", interrupts::stats()),
+            ProcFile::Tasks => executor::task_list().iter().try_for_each(|task| {
+                writeln!(
+                    text,
+                    "{} {:?} {:?} {} {} {} {}",
+                    task.id,
+                    task.state,
+                    task.priority,
+                    task.polls,
+                    task.wakes,
+                    task.poll_time.as_micros(),
+                    task.name.as_deref().unwrap_or("-"),
+                )
+            }),
+            ProcFile::Uptime => {
+                let uptime = time::uptime();
+                writeln!(text, "{}.{:03}", uptime.as_secs(), uptime.subsec_millis())
+            }
+            ProcFile::Dmi => write_dmi(&mut text),
+        };
+        text
+    }
+}
+
+/// Writes the contents of `dmi` to `text`.
+fn write_dmi(text: &mut String) -> core::fmt::Result {
+    if let Some(bios) = smbios::bios() {
+        writeln!(text, "BiosVendor: {}", bios.vendor.unwrap_or("-"))?;
+        writeln!(text, "BiosVersion: {}", bios.version.unwrap_or("-"))?;
+        writeln!(text, "BiosDate: {}", bios.release_date.unwrap_or("-"))?;
+    }
+    if let Some(system) = smbios::system() {
+        writeln!(
+            text,
+            "SystemManufacturer: {}",
+            system.manufacturer.unwrap_or("-")
+        )?;
+        writeln!(text, "SystemProduct: {}", system.product.unwrap_or("-"))?;
+        writeln!(text, "SystemVersion: {}", system.version.unwrap_or("-"))?;
+    }
+    for device in smbios::memory_devices() {
+        writeln!(
+            text,
+            "MemoryDevice: {} kB {} MT/s {}",
+            device
+                .size_kib
+                .map_or("-".to_string(), |size| size.to_string()),
+            device
+                .speed
+                .map_or("-".to_string(), |speed| speed.to_string()),
+            device.locator.unwrap_or("-"),
+        )?;
+    }
+    Ok(())
+}
+
+impl Inode for ProcFile {
+    /// Reports the size of the contents as they would be generated now, so
+    /// that readers sizing their buffer from it read everything.
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.generate().len() as u64,
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for ProcFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let text = self.generate();
+        let start = (offset as usize).min(text.len());
+        let len = buf.len().min(text.len() - start);
+        buf[..len].copy_from_slice(&text.as_bytes()[start..start + len]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_entries() {
+        let proc = ProcFs::new().root();
+        let names: Vec<String> = proc
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["meminfo", "interrupts", "tasks", "uptime", "dmi"]);
+        assert_eq!(
+            proc.create("x", FileType::File).err(),
+            Some(Error::ReadOnly)
+        );
+
+        let meminfo = proc.lookup("meminfo").unwrap().as_file().unwrap();
+        let mut buf = [0; 256];
+        let len = meminfo.read_at(0, &mut buf).unwrap();
+        let text = core::str::from_utf8(&buf[..len]).unwrap();
+        assert!(text.starts_with("HeapTotal: 1024 kB\n"));
+        assert!(text.contains("HeapUsed: "));
+    }
+
+    #[test_case]
+    fn test_uptime() {
+        let text = ProcFile::Uptime.generate();
+        let (secs, millis) = text.trim_end().split_once('.').unwrap();
+        assert!(secs.parse::<u64>().is_ok());
+        assert_eq!(millis.len(), 3);
+    }
+
+    #[test_case]
+    fn test_dmi() {
+        let text = ProcFile::Dmi.generate();
+        assert!(text.starts_with("BiosVendor: "));
+        assert!(text.contains("\nMemoryDevice: "));
+    }
+}
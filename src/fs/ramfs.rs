@@ -0,0 +1,283 @@
+//! A filesystem kept entirely on the heap.
+//!
+//! Files are growable byte vectors and directories are maps of names to
+//! inodes; everything is lost on reboot. A ramfs is mounted at `/tmp` during
+//! boot. Being the simplest filesystem supporting every operation of the
+//! [VFS](super), it is also the one to test the VFS against.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::any::Any;
+
+use spin::Mutex;
+
+use super::{Dir, DirEntry, Error, File, FileSystem, FileType, Inode, Metadata};
+
+/// Maximum size of a single file, so that one file cannot use up the heap.
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A heap-backed filesystem.
+pub struct RamFs {
+    root: Arc<Directory>,
+}
+
+impl RamFs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        RamFs {
+            root: Arc::new(Directory::default()),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+#[derive(Default)]
+struct Directory {
+    entries: Mutex<BTreeMap<String, Arc<dyn Inode>>>,
+}
+
+impl Inode for Directory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: self.entries.lock().len() as u64,
+        }
+    }
+
+    fn as_dir(self: Arc<Self>) -> Option<Arc<dyn Dir>> {
+        Some(self)
+    }
+}
+
+/// Checks that an existing entry of kind `existing` may be replaced by one
+/// of kind `kind`, like Unix `rename` does.
+fn check_replace(existing: &Arc<dyn Inode>, kind: FileType) -> Result<(), Error> {
+    let metadata = existing.metadata();
+    match (metadata.kind, kind) {
+        (FileType::Directory, FileType::File) => Err(Error::IsADirectory),
+        (FileType::File, FileType::Directory) => Err(Error::NotADirectory),
+        (FileType::Directory, _) if metadata.size > 0 => Err(Error::NotEmpty),
+        _ => Ok(()),
+    }
+}
+
+/// Returns whether `inode` is `dir`.
+fn is_same(inode: &Arc<dyn Inode>, dir: &Directory) -> bool {
+    core::ptr::addr_eq(Arc::as_ptr(inode), dir)
+}
+
+impl Dir for Directory {
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.entries
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, Error> {
+        Ok(self
+            .entries
+            .lock()
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                kind: inode.metadata().kind,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        let inode: Arc<dyn Inode> = match kind {
+            FileType::File => Arc::new(RamFile::default()),
+            FileType::Directory => Arc::new(Directory::default()),
+        };
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        let mut entries = self.entries.lock();
+        let inode = entries.get(name).ok_or(Error::NotFound)?;
+        let metadata = inode.metadata();
+        if metadata.kind == FileType::Directory && metadata.size > 0 {
+            return Err(Error::NotEmpty);
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, target: &dyn Dir, new_name: &str) -> Result<(), Error> {
+        let target = (target as &dyn Any)
+            .downcast_ref::<Directory>()
+            .ok_or(Error::NotSupported)?;
+
+        // Lock both directories in address order so that concurrent renames
+        // in opposite directions cannot deadlock.
+        let (mut source, mut destination) = if core::ptr::eq(self, target) {
+            (self.entries.lock(), None)
+        } else if (self as *const Directory) < (target as *const Directory) {
+            let source = self.entries.lock();
+            (source, Some(target.entries.lock()))
+        } else {
+            let destination = target.entries.lock();
+            (self.entries.lock(), Some(destination))
+        };
+
+        let inode = source.get(name).cloned().ok_or(Error::NotFound)?;
+        // Neither directory involved is checked through its metadata, as
+        // their entries are locked. A directory cannot be moved into itself,
+        // and the source directory is not empty, so it cannot be replaced.
+        if is_same(&inode, target) {
+            return Err(Error::InvalidPath);
+        }
+        let existing = match &destination {
+            Some(destination) => destination.get(new_name),
+            None => source.get(new_name),
+        };
+        if let Some(existing) = existing {
+            if Arc::ptr_eq(existing, &inode) {
+                return Ok(());
+            }
+            if is_same(existing, self) {
+                return Err(Error::NotEmpty);
+            }
+            check_replace(existing, inode.metadata().kind)?;
+        }
+
+        source.remove(name);
+        match &mut destination {
+            Some(destination) => destination.insert(new_name.to_string(), inode),
+            None => source.insert(new_name.to_string(), inode),
+        };
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct RamFile {
+    data: Mutex<Vec<u8>>,
+}
+
+impl Inode for RamFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.data.lock().len() as u64,
+        }
+    }
+
+    fn as_file(self: Arc<Self>) -> Option<Arc<dyn File>> {
+        Some(self)
+    }
+}
+
+impl File for RamFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = self.data.lock();
+        let start = offset.min(data.len() as u64) as usize;
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .ok_or(Error::NoSpace)?;
+        let mut contents = self.data.lock();
+        if (contents.len() as u64) < end {
+            contents.resize(end as usize, 0);
+        }
+        contents[offset as usize..end as usize].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), Error> {
+        if len > MAX_FILE_SIZE {
+            return Err(Error::NoSpace);
+        }
+        let mut data = self.data.lock();
+        data.resize(len as usize, 0);
+        data.shrink_to_fit();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_rename() {
+        let root = RamFs::new().root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        let dir = dir.as_dir().unwrap();
+        root.create("a", FileType::File).unwrap();
+        root.create("b", FileType::File).unwrap();
+        dir.create("c", FileType::File).unwrap();
+
+        root.rename("a", &*root, "b").unwrap();
+        assert_eq!(root.lookup("a").err(), Some(Error::NotFound));
+        root.rename("b", &*dir, "d").unwrap();
+        assert!(dir.lookup("d").is_ok());
+        assert_eq!(root.metadata().size, 1);
+
+        root.create("empty", FileType::Directory).unwrap();
+        assert_eq!(root.rename("empty", &*root, "dir"), Err(Error::NotEmpty));
+        assert_eq!(dir.rename("d", &*root, "empty"), Err(Error::IsADirectory));
+        assert_eq!(dir.rename("d", &*root, "dir"), Err(Error::NotEmpty));
+        assert_eq!(root.rename("dir", &*dir, "x"), Err(Error::InvalidPath));
+        dir.rename("c", &*dir, "c").unwrap();
+        assert!(dir.lookup("c").is_ok());
+    }
+
+    #[test_case]
+    fn test_remove() {
+        let root = RamFs::new().root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        dir.as_dir()
+            .unwrap()
+            .create("file", FileType::File)
+            .unwrap();
+        assert_eq!(root.remove("dir"), Err(Error::NotEmpty));
+        assert_eq!(root.remove("missing"), Err(Error::NotFound));
+    }
+
+    #[test_case]
+    fn test_truncate_and_grow() {
+        let file = RamFile::default();
+        assert_eq!(file.write_at(4, b"xy"), Ok(2));
+        let mut buf = [0xff; 8];
+        assert_eq!(file.read_at(0, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"\0\0\0\0xy");
+
+        file.truncate(5).unwrap();
+        assert_eq!(file.metadata().size, 5);
+        assert_eq!(file.write_at(MAX_FILE_SIZE, b"z"), Err(Error::NoSpace));
+    }
+}
@@ -3,59 +3,209 @@
 //! the Task State Segment (TSS) which contains the Interrupt Stack Table (IST)
 //! which allows stack swapping when calling interrupt handlers.
 //!
+//! Besides the kernel segments, the GDT contains ring 3 code and data
+//! segments for user mode. They are laid out as kernel code, kernel data,
+//! user data, user code so that the selectors satisfy the ordering the
+//! `syscall`/`sysret` instructions derive from the `STAR` MSR.
+//!
+//! Every CPU has its own GDT and TSS, and with them its own interrupt stacks,
+//! stored in [PerCpu] slots.
+//!
 //! See: https://os.phil-opp.com/double-fault-exceptions/
 
-use lazy_static::lazy_static;
-use x86_64::registers::segmentation::{Segment, CS};
+use core::{cell::UnsafeCell, ptr::addr_of_mut};
+
+use x86_64::registers::segmentation::{Segment, CS, DS, ES, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::percpu::{self, PerCpu, MAX_CPUS};
+
 /// Interrupt Stack Table (IST) index for the double fault handler stack.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-struct Segments {
-    code_segment: SegmentSelector,
-    tss_segment: SegmentSelector,
+/// Interrupt Stack Table (IST) index for the non-maskable interrupt handler
+/// stack.
+pub const NMI_IST_INDEX: u16 = 1;
+
+/// Interrupt Stack Table (IST) index for the machine check handler stack.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Index of the privilege stack in the per-CPU stack array. The IST stacks
+/// occupy the indices before it.
+const PRIVILEGE_STACK_INDEX: usize = 3;
+
+/// Number of statically allocated stacks for each CPU.
+const STACKS_PER_CPU: usize = 4;
+
+/// Size of each statically allocated stack in bytes.
+const STACK_SIZE: usize = 4096 * 5;
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+// TODO: replace with proper stack allocation
+static mut STACKS: [[Stack; STACKS_PER_CPU]; MAX_CPUS] =
+    [const { [const { Stack([0; STACK_SIZE]) }; STACKS_PER_CPU] }; MAX_CPUS];
+
+/// Returns the address of the top of one of a CPU's static stacks.
+fn stack_top(cpu: usize, index: usize) -> VirtAddr {
+    let stack = unsafe { core::ptr::addr_of!(STACKS[cpu][index]) };
+    VirtAddr::from_ptr(stack) + STACK_SIZE
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
+/// Segment selectors for the entries in the GDT.
+///
+/// Every CPU's GDT has the same layout, so the selectors are identical across
+/// CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
 
-        // Setup a dedicated stack for the `double fault` exception handler.
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // TODO: replace with proper stack allocation
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// A task state segment which its CPU may update after loading it.
+struct Tss(UnsafeCell<TaskStateSegment>);
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+// SAFETY: each TSS is only modified by the CPU it belongs to.
+unsafe impl Sync for Tss {}
 
-        tss
-    };
+static TSS: PerCpu<Tss> = PerCpu::new(|cpu| Tss(UnsafeCell::new(new_tss(cpu))));
+
+static GDT: PerCpu<(GlobalDescriptorTable, Selectors)> = PerCpu::new(new_gdt);
+
+/// Creates the task state segment for a CPU.
+fn new_tss(cpu: usize) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+
+    // Setup a dedicated stack for the `double fault` exception handler.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        stack_top(cpu, DOUBLE_FAULT_IST_INDEX as usize);
 
-    static ref GDT: (GlobalDescriptorTable, Segments) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_segment = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_segment = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Segments { code_segment, tss_segment })
+    // NMIs and machine checks can arrive at any point, including while
+    // the current stack is unusable, so they get their own stacks too.
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_top(cpu, NMI_IST_INDEX as usize);
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] =
+        stack_top(cpu, MACHINE_CHECK_IST_INDEX as usize);
+
+    // Setup the stack the CPU switches to when entering ring 0 from
+    // ring 3.
+    tss.privilege_stack_table[0] = stack_top(cpu, PRIVILEGE_STACK_INDEX);
+
+    tss
+}
+
+/// Creates the global descriptor table for a CPU.
+fn new_gdt(cpu: usize) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(unsafe {
+        &*TSS.get_for(cpu).0.get()
+    }));
+    let selectors = Selectors {
+        kernel_code,
+        kernel_data,
+        user_data,
+        user_code,
+        tss,
     };
+    (gdt, selectors)
+}
+
+/// Returns the selectors of the segments in the GDT.
+///
+/// The user selectors have a requested privilege level of 3 and can be loaded
+/// directly when transitioning to user mode.
+pub fn selectors() -> Selectors {
+    GDT.get().1
+}
+
+/// Returns a pointer to the calling CPU's ring 0 stack pointer in its TSS,
+/// for use by assembly code. The pointer is not 8 byte aligned.
+pub(crate) fn kernel_stack_slot() -> *mut VirtAddr {
+    unsafe { addr_of_mut!((*TSS.get().0.get()).privilege_stack_table[0]) }
 }
 
-/// Initializes this module by creating and loading the global descriptor
-/// table and task state segment.
+/// Returns the stack the calling CPU switches to when an interrupt or
+/// exception arrives while it runs in ring 3.
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { kernel_stack_slot().read_unaligned() }
+}
+
+/// Sets the stack the calling CPU switches to when an interrupt or exception
+/// arrives while it runs in ring 3.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { kernel_stack_slot().write_unaligned(top) };
+}
+
+/// Initializes this module for the bootstrap processor. See [init_cpu].
 pub fn init() {
+    init_cpu(percpu::BSP_ID);
+}
+
+/// Initializes the calling CPU's per-CPU data area and creates and loads its
+/// global descriptor table and task state segment.
+///
+/// This must be the first initialization step performed on each CPU.
+pub fn init_cpu(cpu: usize) {
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    unsafe {
+        percpu::init(cpu);
+    }
+
+    let (gdt, selectors) = GDT.get();
+    gdt.load();
     unsafe {
         // Set the code segment register.
-        CS::set_reg(GDT.1.code_segment);
+        CS::set_reg(selectors.kernel_code);
+
+        // Set the data segment registers.
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
 
         // Load the task state segment.
-        load_tss(GDT.1.tss_segment);
+        load_tss(selectors.tss);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86_64::PrivilegeLevel;
+
+    #[test_case]
+    fn test_selectors_follow_star_layout() {
+        let selectors = selectors();
+        assert_eq!(selectors.kernel_data.0, selectors.kernel_code.0 + 8);
+        assert_eq!(selectors.user_code.index(), selectors.user_data.index() + 1);
+        assert_eq!(selectors.user_code.rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(selectors.user_data.rpl(), PrivilegeLevel::Ring3);
+    }
+
+    #[test_case]
+    fn test_set_kernel_stack() {
+        let original = kernel_stack();
+        set_kernel_stack(VirtAddr::new(0x1000));
+        assert_eq!(kernel_stack(), VirtAddr::new(0x1000));
+        set_kernel_stack(original);
+        assert_eq!(
+            kernel_stack(),
+            stack_top(percpu::cpu_id(), PRIVILEGE_STACK_INDEX)
+        );
+    }
+
+    #[test_case]
+    fn test_stacks_are_per_cpu() {
+        assert_ne!(stack_top(0, 0), stack_top(1, 0));
+        assert_ne!(stack_top(0, 0), stack_top(0, 1));
     }
 }
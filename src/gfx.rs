@@ -0,0 +1,339 @@
+//! 2D drawing on framebuffers.
+//!
+//! Drawing happens on a [Canvas], an image in memory, so that nothing is
+//! seen half drawn. A [Screen] pairs a canvas, its back buffer, with a
+//! [Framebuffer] and copies the rows drawn on since the last
+//! [present](Screen::present) to the framebuffer in one go.
+//!
+//! Coordinates are signed and anything outside a canvas is clipped, so that
+//! a mouse cursor or panel can hang over its edges.
+//!
+//! ```no_run
+//! use toyos::{framebuffer::Rgb, gfx::{self, Screen}};
+//!
+//! let framebuffer = toyos::framebuffer::bochs::init(640, 480).unwrap();
+//! let mut screen = Screen::new(framebuffer);
+//! let canvas = screen.canvas();
+//! canvas.panel(20, 20, 200, 100, "hello", Rgb::new(0x55, 0x55, 0x55));
+//! canvas.blit(100, 60, &gfx::arrow(), Some(gfx::TRANSPARENT));
+//! screen.present();
+//! ```
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    framebuffer::{font, Framebuffer, Rgb},
+    vga::Color,
+};
+
+/// The color of pixels left out by [Canvas::blit] when used as its key.
+pub const TRANSPARENT: Rgb = Rgb::new(0xff, 0x00, 0xff);
+
+/// Rows of the mouse [arrow]: `#` is its outline, `.` its inside and spaces
+/// are transparent.
+const ARROW: [&str; 16] = [
+    "#          ",
+    "##         ",
+    "#.#        ",
+    "#..#       ",
+    "#...#      ",
+    "#....#     ",
+    "#.....#    ",
+    "#......#   ",
+    "#.......#  ",
+    "#........# ",
+    "#.....#####",
+    "#..#..#    ",
+    "#.# #..#   ",
+    "##  #..#   ",
+    "#    #..#  ",
+    "     ####  ",
+];
+
+/// Height of the title bar of a [panel](Canvas::panel).
+const TITLE_HEIGHT: i32 = font::HEIGHT as i32 + 4;
+
+/// An image in memory to draw on.
+#[derive(Clone)]
+pub struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+    /// The rows drawn on since they were last taken, as a range.
+    dirty: Option<(usize, usize)>,
+}
+
+impl Canvas {
+    /// Creates a canvas filled with one color.
+    pub fn new(width: usize, height: usize, color: Rgb) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![color; width * height],
+            dirty: None,
+        }
+    }
+
+    /// Returns the width and height of the canvas.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Returns the color of a pixel, or `None` outside the canvas.
+    pub fn pixel(&self, x: i32, y: i32) -> Option<Rgb> {
+        let (x, y) = self.index(x, y)?;
+        Some(self.pixels[y * self.width + x])
+    }
+
+    /// Returns the pixels of a row.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such row.
+    pub fn row(&self, y: usize) -> &[Rgb] {
+        &self.pixels[y * self.width..(y + 1) * self.width]
+    }
+
+    /// Converts coordinates to indices, or `None` outside the canvas.
+    fn index(&self, x: i32, y: i32) -> Option<(usize, usize)> {
+        let (x, y) = (usize::try_from(x).ok()?, usize::try_from(y).ok()?);
+        (x < self.width && y < self.height).then_some((x, y))
+    }
+
+    /// Marks the rows from `top` up to `bottom` as drawn on.
+    fn touch(&mut self, top: usize, bottom: usize) {
+        self.dirty = match self.dirty {
+            Some((first, end)) => Some((first.min(top), end.max(bottom))),
+            None => Some((top, bottom)),
+        };
+    }
+
+    /// Returns the rows drawn on since the last call, and forgets them.
+    pub fn take_dirty(&mut self) -> Option<(usize, usize)> {
+        self.dirty.take()
+    }
+
+    /// Sets the color of a pixel.
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: Rgb) {
+        if let Some((x, y)) = self.index(x, y) {
+            self.pixels[y * self.width + x] = color;
+            self.touch(y, y + 1);
+        }
+    }
+
+    /// Fills the whole canvas with one color.
+    pub fn clear(&mut self, color: Rgb) {
+        self.pixels.fill(color);
+        self.touch(0, self.height);
+    }
+
+    /// Draws a line from one point to another, both included.
+    pub fn line(&mut self, (x0, y0): (i32, i32), (x1, y1): (i32, i32), color: Rgb) {
+        // Bresenham's algorithm, stepping along both axes as the error of
+        // the line so far demands.
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y) = (x0, y0);
+        let mut error = dx + dy;
+        loop {
+            self.set_pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of a rectangle one pixel wide.
+    pub fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: Rgb) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        self.line((x, y), (right, y), color);
+        self.line((x, bottom), (right, bottom), color);
+        self.line((x, y), (x, bottom), color);
+        self.line((right, y), (right, bottom), color);
+    }
+
+    /// Fills a rectangle with a color.
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: Rgb) {
+        let left = x.clamp(0, self.width as i32) as usize;
+        let right = x.saturating_add(width).clamp(0, self.width as i32) as usize;
+        let top = y.clamp(0, self.height as i32) as usize;
+        let bottom = y.saturating_add(height).clamp(0, self.height as i32) as usize;
+        if left >= right || top >= bottom {
+            return;
+        }
+        for y in top..bottom {
+            self.pixels[y * self.width + left..y * self.width + right].fill(color);
+        }
+        self.touch(top, bottom);
+    }
+
+    /// Copies another canvas onto this one with its top left corner at the
+    /// given point. Pixels of `source` in the color `transparent` are left
+    /// out.
+    pub fn blit(&mut self, x: i32, y: i32, source: &Canvas, transparent: Option<Rgb>) {
+        for sy in 0..source.height {
+            for sx in 0..source.width {
+                let color = source.pixels[sy * source.width + sx];
+                if Some(color) != transparent {
+                    self.set_pixel(x + sx as i32, y + sy as i32, color);
+                }
+            }
+        }
+    }
+
+    /// Draws text in the bitmap font with its top left corner at the given
+    /// point, leaving the pixels between the strokes as they are. The text
+    /// is a single line.
+    pub fn text(&mut self, x: i32, y: i32, text: &str, color: Rgb) {
+        for (i, c) in text.chars().enumerate() {
+            let left = x + (i * font::WIDTH) as i32;
+            for (row, bits) in font::glyph(c).iter().enumerate() {
+                for column in 0..font::WIDTH {
+                    if bits & (0x80 >> column) != 0 {
+                        self.set_pixel(left + column as i32, y + row as i32, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draws a window-like panel: a box in `color` with a white border and a
+    /// title bar holding `title`.
+    pub fn panel(&mut self, x: i32, y: i32, width: i32, height: i32, title: &str, color: Rgb) {
+        let white = Color::White.into();
+        self.fill_rect(x, y, width, height, color);
+        self.fill_rect(x, y, width, TITLE_HEIGHT, Color::Blue.into());
+        self.rect(x, y, width, height, white);
+        self.text(x + 4, y + 2, title, white);
+    }
+}
+
+/// Returns a mouse cursor, an arrow pointing at its top left corner, with
+/// [TRANSPARENT] around it.
+pub fn arrow() -> Canvas {
+    let mut arrow = Canvas::new(ARROW[0].len(), ARROW.len(), TRANSPARENT);
+    for (y, row) in ARROW.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+            let color = match c {
+                '#' => Color::Black.into(),
+                '.' => Color::White.into(),
+                _ => continue,
+            };
+            arrow.set_pixel(x as i32, y as i32, color);
+        }
+    }
+    arrow
+}
+
+/// A framebuffer with a back buffer to draw on.
+pub struct Screen {
+    framebuffer: Framebuffer,
+    back: Canvas,
+}
+
+impl Screen {
+    /// Creates a screen with a black back buffer the size of `framebuffer`,
+    /// which is overwritten on the first [present](Screen::present).
+    pub fn new(framebuffer: Framebuffer) -> Screen {
+        let info = framebuffer.info();
+        let mut back = Canvas::new(info.width, info.height, Color::Black.into());
+        back.touch(0, info.height);
+        Screen { framebuffer, back }
+    }
+
+    /// Returns the back buffer.
+    pub fn canvas(&mut self) -> &mut Canvas {
+        &mut self.back
+    }
+
+    /// Copies the rows of the back buffer drawn on since the last call to
+    /// the framebuffer.
+    pub fn present(&mut self) {
+        let Some((top, bottom)) = self.back.take_dirty() else {
+            return;
+        };
+        for y in top..bottom {
+            self.framebuffer.write_pixels(0, y, self.back.row(y));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::framebuffer::{Info, PixelFormat};
+    use alloc::boxed::Box;
+    use x86_64::VirtAddr;
+
+    const BLACK: Rgb = Rgb::new(0, 0, 0);
+    const RED: Rgb = Rgb::new(0xff, 0, 0);
+
+    #[test_case]
+    fn test_line() {
+        let mut canvas = Canvas::new(8, 8, BLACK);
+        canvas.line((6, 1), (0, 4), RED);
+        assert_eq!(canvas.pixel(6, 1), Some(RED));
+        assert_eq!(canvas.pixel(0, 4), Some(RED));
+        let count = (0..8).filter(|&x| canvas.pixel(x, 2) == Some(RED)).count();
+        assert_eq!(count, 2);
+
+        // Clipped, rather than wrapped around, at the edges.
+        canvas.line((-4, 7), (12, 7), RED);
+        assert_eq!(canvas.row(7), &[RED; 8]);
+        assert_eq!(canvas.pixel(8, 7), None);
+    }
+
+    #[test_case]
+    fn test_rect() {
+        let mut canvas = Canvas::new(8, 8, BLACK);
+        canvas.rect(1, 1, 4, 3, RED);
+        assert_eq!(canvas.pixel(4, 3), Some(RED));
+        assert_eq!(canvas.pixel(2, 2), Some(BLACK));
+        canvas.fill_rect(-2, 6, 100, 100, RED);
+        assert_eq!(canvas.row(6), &[RED; 8]);
+        assert_eq!(canvas.take_dirty(), Some((1, 8)));
+        assert_eq!(canvas.take_dirty(), None);
+    }
+
+    #[test_case]
+    fn test_blit() {
+        let mut canvas = Canvas::new(32, 32, RED);
+        canvas.blit(-1, 4, &arrow(), Some(TRANSPARENT));
+        assert_eq!(canvas.pixel(0, 5), Some(Color::Black.into()));
+        assert_eq!(canvas.pixel(0, 6), Some(Color::White.into()));
+        assert_eq!(canvas.pixel(3, 4), Some(RED));
+        assert!((0..32).all(|x| (0..32).all(|y| canvas.pixel(x, y) != Some(TRANSPARENT))));
+    }
+
+    #[test_case]
+    fn test_present() {
+        let info = Info {
+            width: 4,
+            height: 4,
+            stride: 6,
+            bytes_per_pixel: 4,
+            format: PixelFormat::Rgb,
+        };
+        let bytes = Box::leak(vec![0u8; 6 * 4 * 4].into_boxed_slice()).as_mut_ptr();
+        let mut screen = Screen::new(unsafe { Framebuffer::new(VirtAddr::from_ptr(bytes), info) });
+        let pixel = |x: usize, y: usize| unsafe { *bytes.add((y * 6 + x) * 4).cast::<[u8; 3]>() };
+        screen.canvas().set_pixel(3, 2, RED);
+        assert_eq!(pixel(3, 2), [0, 0, 0]);
+        screen.present();
+        assert_eq!(pixel(3, 2), [0xff, 0, 0]);
+        assert_eq!(pixel(2, 3), [0, 0, 0]);
+        assert_eq!(screen.canvas().take_dirty(), None);
+    }
+}
@@ -0,0 +1,462 @@
+//! Driver for the local APIC and inter-processor interrupts (IPIs).
+//!
+//! Every CPU has a local APIC which receives interrupts destined for it and
+//! can send interrupts to other CPUs. The local APIC is accessed either
+//! through memory mapped registers (xAPIC) or, when supported, through MSRs
+//! (x2APIC). The legacy PICs remain responsible for device interrupts and
+//! reach the bootstrap processor through the local APIC's virtual wire mode.
+//!
+//! Two IPIs are provided out of the box:
+//!
+//! - [RESCHEDULE_VECTOR] wakes a CPU so that it re-examines its run queue.
+//! - [TLB_SHOOTDOWN_VECTOR] makes a CPU flush stale TLB entries after a page
+//!   table change; see [tlb_shootdown].
+//!
+//! Every local APIC also has a timer, counting down from a value the kernel
+//! sets at a rate which must be measured: [calibrate_timer] measures it
+//! against the PIT. Once [start_timer] has started the bootstrap processor's
+//! timer, every CPU initialized afterwards starts its own in the same
+//! [TimerMode], interrupting on [TIMER_VECTOR].
+//!
+//! See: https://wiki.osdev.org/APIC
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::vectors::claim_vector;
+use crate::percpu::{self, MAX_CPUS};
+use crate::time::pit;
+
+/// Vector of the local APIC timer's interrupts. Its IDT entry is a dedicated
+/// gate rather than a dynamic vector, as the handler needs the interrupted
+/// stack frame.
+pub const TIMER_VECTOR: u8 = 0xfc;
+
+/// Vector used to wake a CPU so it can pick up new work.
+pub const RESCHEDULE_VECTOR: u8 = 0xfd;
+
+/// Vector used to request a TLB flush from other CPUs.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xfe;
+
+/// Vector the local APIC delivers spurious interrupts on.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// First MSR of the x2APIC register range. Register offsets are divided by
+/// 16 and added to this base.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets in xAPIC mode.
+const REG_ID: u32 = 0x20;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SVR: u32 = 0xf0;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// Divide configuration making the timer count at its bus clock divided by
+/// 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long each round of [calibrate_timer] waits for.
+const TIMER_CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+/// Number of calibration rounds, the fastest of which is used.
+const TIMER_CALIBRATION_ROUNDS: usize = 3;
+
+/// How the local APIC timer raises its interrupts.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// The timer reloads itself every time it reaches zero.
+    Periodic,
+    /// The timer stops at zero and is rearmed by its interrupt handler,
+    /// which leaves room to program each interrupt differently.
+    OneShot,
+}
+
+/// How the local APIC's registers are accessed.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    XApic(VirtAddr),
+    X2Apic,
+}
+
+/// Set once the bootstrap processor has initialized its local APIC.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the local APICs run in x2APIC mode.
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// Virtual address of the xAPIC register window.
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// APIC ID of each CPU, recorded when the CPU initializes its local APIC.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Bitmask of CPUs whose local APIC has been initialized.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Rate at which the local APIC timers count, in Hz, or zero if not yet
+/// calibrated.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The [TimerMode] of the local APIC timers.
+static TIMER_MODE: AtomicU8 = AtomicU8::new(TimerMode::Periodic as u8);
+
+/// The count the local APIC timers start from, or zero while they are not
+/// used.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Number of local APIC timer interrupts received by each CPU.
+static TIMER_TICKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Number of IPIs received by each CPU.
+static IPIS_RECEIVED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+fn mode() -> Mode {
+    if X2APIC.load(Ordering::Relaxed) {
+        Mode::X2Apic
+    } else {
+        Mode::XApic(VirtAddr::new(XAPIC_BASE.load(Ordering::Relaxed)))
+    }
+}
+
+fn read(reg: u32) -> u32 {
+    match mode() {
+        Mode::XApic(base) => unsafe { (base + reg as u64).as_ptr::<u32>().read_volatile() },
+        Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32 },
+    }
+}
+
+fn write(reg: u32, value: u32) {
+    match mode() {
+        Mode::XApic(base) => unsafe {
+            (base + reg as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        },
+        Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(value as u64) },
+    }
+}
+
+/// Writes the interrupt command register, which sends an IPI.
+fn write_icr(destination: u32, command: u32) {
+    match mode() {
+        Mode::XApic(_) => {
+            write(REG_ICR_HIGH, destination << 24);
+            write(REG_ICR_LOW, command);
+            while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+
+        // In x2APIC mode the ICR is a single 64-bit MSR and writing it is
+        // serializing, so there is no delivery status to poll.
+        Mode::X2Apic => unsafe {
+            Msr::new(X2APIC_MSR_BASE + (REG_ICR_LOW >> 4))
+                .write(((destination as u64) << 32) | command as u64);
+        },
+    }
+}
+
+/// Returns `true` if the local APIC has been initialized.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Initializes the bootstrap processor's local APIC and registers the IPI
+/// handlers.
+///
+/// Must be called after [crate::mem::init] since the xAPIC registers are
+/// accessed through the physical memory mapping. Does nothing if the
+/// processor has no local APIC.
+pub fn init() {
+    if !crate::cpu::has_apic() {
+        return;
+    }
+
+    let mut base = Msr::new(IA32_APIC_BASE);
+    let value = unsafe { base.read() };
+    if crate::cpu::has_x2apic() {
+        unsafe { base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC) };
+        X2APIC.store(true, Ordering::Relaxed);
+    } else {
+        let phys = PhysAddr::new(value & APIC_BASE_ADDRESS_MASK);
+        XAPIC_BASE.store(crate::mem::phys_to_virt(phys).as_u64(), Ordering::Relaxed);
+    }
+
+    claim_vector(SPURIOUS_VECTOR, spurious_handler);
+    claim_vector(RESCHEDULE_VECTOR, reschedule_handler);
+    claim_vector(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_handler);
+
+    ENABLED.store(true, Ordering::Release);
+    init_cpu();
+}
+
+/// Enables the calling CPU's local APIC and records its APIC ID.
+///
+/// The bootstrap processor is initialized by [init]; application processors
+/// must call this themselves once their per-CPU data is set up.
+pub fn init_cpu() {
+    if !is_enabled() {
+        return;
+    }
+
+    if X2APIC.load(Ordering::Relaxed) {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        unsafe {
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+    }
+
+    // Accept all interrupt priorities and software enable the APIC.
+    write(REG_TPR, 0);
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let cpu = percpu::cpu_id();
+    APIC_IDS[cpu].store(id(), Ordering::Relaxed);
+    ONLINE_CPUS.fetch_or(1 << cpu, Ordering::AcqRel);
+
+    // Every CPU gets a tick once the bootstrap processor's timer runs.
+    if TIMER_INITIAL_COUNT.load(Ordering::Acquire) != 0 {
+        program_timer();
+    }
+}
+
+/// Returns the local APIC ID of the calling CPU.
+pub fn id() -> u32 {
+    match mode() {
+        Mode::XApic(_) => read(REG_ID) >> 24,
+        Mode::X2Apic => read(REG_ID),
+    }
+}
+
+/// Returns the local APIC ID of the given CPU.
+pub fn apic_id(cpu: usize) -> u32 {
+    APIC_IDS[cpu].load(Ordering::Relaxed)
+}
+
+/// Returns a bitmask of the CPUs with an initialized local APIC.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Returns the number of IPIs received by the given CPU.
+pub fn ipis_received(cpu: usize) -> u64 {
+    IPIS_RECEIVED[cpu].load(Ordering::Relaxed)
+}
+
+/// Signals the end of an interrupt delivered through the local APIC.
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Sends a fixed interrupt with the given vector to a CPU.
+pub fn send_ipi(cpu: usize, vector: u8) {
+    write_icr(apic_id(cpu), ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends a fixed interrupt with the given vector to every CPU except the
+/// calling one.
+pub fn send_ipi_all_excluding_self(vector: u8) {
+    write_icr(0, ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends an INIT IPI to the processor with the given APIC ID, putting it in
+/// the wait-for-SIPI state.
+pub(crate) fn send_init(apic_id: u32) {
+    write_icr(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a STARTUP IPI to the processor with the given APIC ID, starting it
+/// in real mode at physical address `page << 12`.
+pub(crate) fn send_startup(apic_id: u32, page: u8) {
+    write_icr(apic_id, ICR_DELIVERY_STARTUP | page as u32);
+}
+
+/// Wakes a CPU so that it re-examines its run queue.
+pub fn send_reschedule(cpu: usize) {
+    send_ipi(cpu, RESCHEDULE_VECTOR);
+}
+
+/// Measures the rate at which the local APIC timers count against the PIT,
+/// returning it in Hz, or zero if the local APIC is not enabled.
+///
+/// This busy-waits for a few tens of milliseconds with interrupts disabled and
+/// should only be called once during boot. Every CPU's timer is assumed to
+/// count at the same rate.
+pub fn calibrate_timer() -> u64 {
+    use x86_64::instructions::interrupts;
+
+    if !is_enabled() {
+        return 0;
+    }
+
+    let counts = interrupts::without_interrupts(|| {
+        write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        let counts = (0..TIMER_CALIBRATION_ROUNDS)
+            .map(|_| {
+                write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+                pit::busy_wait(TIMER_CALIBRATION_PERIOD);
+                u32::MAX - read(REG_TIMER_CURRENT_COUNT)
+            })
+            .min()
+            .unwrap_or(0);
+        write(REG_TIMER_INITIAL_COUNT, 0);
+        counts
+    });
+
+    let frequency = counts as u128 * 1_000_000_000 / TIMER_CALIBRATION_PERIOD.as_nanos();
+    TIMER_FREQUENCY.store(frequency as u64, Ordering::Relaxed);
+    frequency as u64
+}
+
+/// Returns the rate at which the local APIC timers count in Hz, or zero if
+/// [calibrate_timer] has not been called.
+pub fn timer_frequency() -> u64 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Returns the [TimerMode] of the local APIC timers.
+pub fn timer_mode() -> TimerMode {
+    match TIMER_MODE.load(Ordering::Relaxed) {
+        mode if mode == TimerMode::OneShot as u8 => TimerMode::OneShot,
+        _ => TimerMode::Periodic,
+    }
+}
+
+/// Starts the calling CPU's local APIC timer, interrupting at (approximately)
+/// the given frequency in Hz on [TIMER_VECTOR], and makes every CPU
+/// initialized afterwards start its own.
+///
+/// Returns the actual frequency, or `None` if the timer has not been
+/// calibrated or cannot interrupt that often.
+pub fn start_timer(mode: TimerMode, frequency: u32) -> Option<u32> {
+    let count = timer_frequency() / frequency.max(1) as u64;
+    let count = u32::try_from(count).ok().filter(|&count| count != 0)?;
+    TIMER_MODE.store(mode as u8, Ordering::Relaxed);
+    TIMER_INITIAL_COUNT.store(count, Ordering::Release);
+    program_timer();
+    Some((timer_frequency() / count as u64) as u32)
+}
+
+/// Programs the calling CPU's timer to count down from
+/// [TIMER_INITIAL_COUNT].
+fn program_timer() {
+    let mode = match timer_mode() {
+        TimerMode::Periodic => LVT_TIMER_PERIODIC,
+        TimerMode::OneShot => 0,
+    };
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, mode | TIMER_VECTOR as u32);
+    write(
+        REG_TIMER_INITIAL_COUNT,
+        TIMER_INITIAL_COUNT.load(Ordering::Relaxed),
+    );
+}
+
+/// Returns the number of local APIC timer interrupts received by the given
+/// CPU.
+pub fn timer_ticks(cpu: usize) -> u64 {
+    TIMER_TICKS[cpu].load(Ordering::Relaxed)
+}
+
+/// Counts a local APIC timer interrupt on the calling CPU and rearms the
+/// timer in one-shot mode. Called by the timer interrupt handler, which
+/// signals the end of the interrupt itself.
+pub(crate) fn timer_interrupt() {
+    TIMER_TICKS[percpu::cpu_id()].fetch_add(1, Ordering::Relaxed);
+    if timer_mode() == TimerMode::OneShot {
+        write(
+            REG_TIMER_INITIAL_COUNT,
+            TIMER_INITIAL_COUNT.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Serializes TLB shootdowns.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+
+/// The address being flushed by the current shootdown, with zero meaning the
+/// entire TLB.
+static SHOOTDOWN_ADDRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of CPUs which have yet to acknowledge the current shootdown.
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Flushes the TLB entry for `address` (or the entire TLB if `None`) on every
+/// online CPU, waiting until all of them have done so.
+///
+/// Must be called with interrupts enabled, as another CPU may be waiting for
+/// this one to acknowledge a concurrent shootdown.
+pub fn tlb_shootdown(address: Option<VirtAddr>) {
+    let _guard = SHOOTDOWN.lock();
+
+    let others = (online_cpus().count_ones() as usize).saturating_sub(1);
+    if is_enabled() && others > 0 {
+        SHOOTDOWN_ADDRESS.store(address.map_or(0, VirtAddr::as_u64), Ordering::Relaxed);
+        SHOOTDOWN_PENDING.store(others, Ordering::Release);
+        send_ipi_all_excluding_self(TLB_SHOOTDOWN_VECTOR);
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    flush(address);
+}
+
+fn flush(address: Option<VirtAddr>) {
+    match address {
+        Some(address) => tlb::flush(address),
+        None => tlb::flush_all(),
+    }
+}
+
+//
+// MARK: Interrupt Handlers
+//
+
+/// Spurious interrupts must not be acknowledged.
+fn spurious_handler(_vector: u8) {}
+
+fn reschedule_handler(_vector: u8) {
+    IPIS_RECEIVED[percpu::cpu_id()].fetch_add(1, Ordering::Relaxed);
+    eoi();
+}
+
+fn tlb_shootdown_handler(_vector: u8) {
+    IPIS_RECEIVED[percpu::cpu_id()].fetch_add(1, Ordering::Relaxed);
+    let address = match SHOOTDOWN_ADDRESS.load(Ordering::Relaxed) {
+        0 => None,
+        address => Some(VirtAddr::new(address)),
+    };
+    flush(address);
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+    eoi();
+}
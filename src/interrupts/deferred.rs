@@ -0,0 +1,87 @@
+//! The `deferred` module implements a bottom-half mechanism for interrupt
+//! handlers.
+//!
+//! Interrupt handlers run with interrupts disabled, so any time spent in them
+//! adds to the latency of every other interrupt. Instead of doing real work,
+//! a handler should do the bare minimum required to acknowledge the device
+//! and then [raise](Work::raise) a piece of deferred work. The executor drains
+//! pending work via [run_pending] outside of interrupt context where it is
+//! free to take locks, print, and wake tasks.
+//!
+//! Raising work is a single atomic operation and is safe to do from any
+//! context. Raising the same work multiple times before it runs coalesces into
+//! a single invocation, so handlers must be written to process everything
+//! that is outstanding rather than a single event.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+
+/// Maximum number of deferred work items that can be registered.
+const MAX_WORK: usize = 32;
+
+/// A deferred work handler.
+type Handler = fn();
+
+/// Bitmask of work items which have been raised but not yet run.
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+/// Registered work handlers indexed by their bit in [PENDING].
+///
+/// This lock is never taken from interrupt context.
+static HANDLERS: Mutex<[Option<Handler>; MAX_WORK]> = Mutex::new([None; MAX_WORK]);
+
+/// A handle to a registered piece of deferred work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Work(u8);
+
+impl Work {
+    /// Marks this work as pending so that it is run the next time
+    /// [run_pending] is called.
+    ///
+    /// This function is safe to call from interrupt context.
+    #[inline]
+    pub fn raise(self) {
+        PENDING.fetch_or(1 << self.0, Ordering::Release);
+    }
+}
+
+/// Registers a handler to be run outside of interrupt context whenever the
+/// returned [Work] is raised.
+///
+/// # Panics
+///
+/// Panics if more than 32 work items are registered.
+pub fn register(handler: Handler) -> Work {
+    let mut handlers = HANDLERS.lock();
+    let slot = handlers
+        .iter()
+        .position(Option::is_none)
+        .expect("deferred work table full");
+    handlers[slot] = Some(handler);
+    Work(slot as u8)
+}
+
+/// Returns `true` if any deferred work is waiting to be run.
+#[inline]
+pub fn is_pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0
+}
+
+/// Runs all pending deferred work.
+///
+/// Must not be called from interrupt context.
+pub fn run_pending() {
+    let mut pending = PENDING.swap(0, Ordering::Acquire);
+    while pending != 0 {
+        let slot = pending.trailing_zeros() as usize;
+        pending &= pending - 1;
+
+        // Copy the handler out so the lock isn't held while it runs, allowing
+        // handlers to register further work.
+        let handler = HANDLERS.lock()[slot];
+        if let Some(handler) = handler {
+            handler();
+        }
+    }
+}
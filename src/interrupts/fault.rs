@@ -0,0 +1,262 @@
+//! Register capturing entry points for CPU exceptions.
+//!
+//! By the time an `extern "x86-interrupt"` handler runs, the compiler has
+//! already clobbered the general purpose registers, making them useless for
+//! diagnosing a fault. The [exception_entry] macro defines an assembly stub
+//! which saves every general purpose register to the stack before calling a
+//! Rust handler with an [ExceptionFrame] describing the complete processor
+//! state at the time of the exception.
+//!
+//! [RegisterDump] renders that state, along with the control and segment
+//! registers, in a fixed format panel for fatal exceptions.
+
+use core::{arch::asm, fmt, mem::offset_of};
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::segmentation::{Segment, DS, ES, FS, GS};
+
+/// General purpose registers in the order they are pushed by
+/// [exception_entry].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SavedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// The stack layout seen by a handler installed with [exception_entry].
+///
+/// Handlers may modify the frame; the modified state is restored when the
+/// handler returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionFrame {
+    pub registers: SavedRegisters,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    /// Captures the registers of the caller, for reports of failures other
+    /// than exceptions such as panics. The error code reads as 0, and one of
+    /// the general purpose registers as the address of the frame.
+    #[inline(always)]
+    pub fn capture() -> ExceptionFrame {
+        // Every field is an integer, so zero is a valid frame.
+        let mut frame: ExceptionFrame = unsafe { core::mem::zeroed() };
+        unsafe {
+            asm!(
+                "mov [{frame} + {r15}], r15",
+                "mov [{frame} + {r14}], r14",
+                "mov [{frame} + {r13}], r13",
+                "mov [{frame} + {r12}], r12",
+                "mov [{frame} + {r11}], r11",
+                "mov [{frame} + {r10}], r10",
+                "mov [{frame} + {r9}], r9",
+                "mov [{frame} + {r8}], r8",
+                "mov [{frame} + {rbp}], rbp",
+                "mov [{frame} + {rdi}], rdi",
+                "mov [{frame} + {rsi}], rsi",
+                "mov [{frame} + {rdx}], rdx",
+                "mov [{frame} + {rcx}], rcx",
+                "mov [{frame} + {rbx}], rbx",
+                "mov [{frame} + {rax}], rax",
+                "lea {scratch}, [rip]",
+                "mov [{frame} + {rip}], {scratch}",
+                "mov {scratch}, cs",
+                "mov [{frame} + {cs}], {scratch}",
+                "pushfq",
+                "pop {scratch}",
+                "mov [{frame} + {rflags}], {scratch}",
+                "mov [{frame} + {rsp}], rsp",
+                "mov {scratch}, ss",
+                "mov [{frame} + {ss}], {scratch}",
+                frame = in(reg) &mut frame,
+                scratch = out(reg) _,
+                r15 = const offset_of!(ExceptionFrame, registers.r15),
+                r14 = const offset_of!(ExceptionFrame, registers.r14),
+                r13 = const offset_of!(ExceptionFrame, registers.r13),
+                r12 = const offset_of!(ExceptionFrame, registers.r12),
+                r11 = const offset_of!(ExceptionFrame, registers.r11),
+                r10 = const offset_of!(ExceptionFrame, registers.r10),
+                r9 = const offset_of!(ExceptionFrame, registers.r9),
+                r8 = const offset_of!(ExceptionFrame, registers.r8),
+                rbp = const offset_of!(ExceptionFrame, registers.rbp),
+                rdi = const offset_of!(ExceptionFrame, registers.rdi),
+                rsi = const offset_of!(ExceptionFrame, registers.rsi),
+                rdx = const offset_of!(ExceptionFrame, registers.rdx),
+                rcx = const offset_of!(ExceptionFrame, registers.rcx),
+                rbx = const offset_of!(ExceptionFrame, registers.rbx),
+                rax = const offset_of!(ExceptionFrame, registers.rax),
+                rip = const offset_of!(ExceptionFrame, rip),
+                cs = const offset_of!(ExceptionFrame, cs),
+                rflags = const offset_of!(ExceptionFrame, rflags),
+                rsp = const offset_of!(ExceptionFrame, rsp),
+                ss = const offset_of!(ExceptionFrame, ss),
+            );
+        }
+        frame
+    }
+}
+
+/// Defines an assembly entry stub named `$stub` for an exception which pushes
+/// an error code. The stub saves all general purpose registers and calls
+/// `$handler`, an `extern "C" fn(&mut ExceptionFrame)`, then restores the
+/// (possibly modified) registers and returns from the interrupt. Exceptions
+/// raised in ring 3 run the handler with the kernel's GS base swapped in.
+///
+/// The stub must be installed with `Entry::set_handler_addr`.
+macro_rules! exception_entry {
+    ($stub:ident, $handler:path) => {
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            "test qword ptr [rsp + 16], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "mov rdi, rsp",
+            "cld",
+            // The CPU aligns the stack to 16 bytes before pushing the six
+            // qword interrupt frame; the 15 registers above leave it 8 bytes
+            // short of the alignment required by the System V ABI.
+            "sub rsp, 8",
+            "call {handler}",
+            "add rsp, 8",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            // Discard the error code.
+            "add rsp, 8",
+            "test qword ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "iretq",
+            handler = sym $handler,
+        );
+
+        extern "C" {
+            fn $stub();
+        }
+    };
+}
+
+pub(crate) use exception_entry;
+
+/// A snapshot of the processor state at the time of an exception, formatted
+/// as a fixed layout panel.
+pub struct RegisterDump<'a> {
+    title: &'a str,
+    frame: &'a ExceptionFrame,
+}
+
+impl<'a> RegisterDump<'a> {
+    /// Creates a dump of the given frame. The control and segment registers
+    /// are read when the dump is formatted.
+    pub fn new(title: &'a str, frame: &'a ExceptionFrame) -> Self {
+        RegisterDump { title, frame }
+    }
+}
+
+impl fmt::Display for RegisterDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.frame.registers;
+        let frame = self.frame;
+
+        let fill = 58usize.saturating_sub(self.title.len());
+        writeln!(f, "==== {} {:=<fill$}", self.title, "")?;
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x}",
+            r.rax, r.rbx, r.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX={:016x} RSI={:016x} RDI={:016x}",
+            r.rdx, r.rsi, r.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP={:016x} RSP={:016x} R8 ={:016x}",
+            r.rbp, frame.rsp, r.r8
+        )?;
+        writeln!(f, "R9 ={:016x} R10={:016x} R11={:016x}", r.r9, r.r10, r.r11)?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x}",
+            r.r12, r.r13, r.r14
+        )?;
+        writeln!(
+            f,
+            "R15={:016x} RIP={:016x} RFL={:016x}",
+            r.r15, frame.rip, frame.rflags
+        )?;
+        writeln!(
+            f,
+            "CS={:04x} SS={:04x} DS={:04x} ES={:04x} FS={:04x} GS={:04x}  ERR={:016x}",
+            frame.cs,
+            frame.ss,
+            DS::get_reg().0,
+            ES::get_reg().0,
+            FS::get_reg().0,
+            GS::get_reg().0,
+            frame.error_code,
+        )?;
+
+        let (cr3_frame, cr3_flags) = Cr3::read_raw();
+        let cr3 = cr3_frame.start_address().as_u64() | cr3_flags as u64;
+        writeln!(
+            f,
+            "CR0={:016x} CR2={:016x}",
+            Cr0::read_raw(),
+            Cr2::read_raw()
+        )?;
+        writeln!(f, "CR3={:016x} CR4={:016x}", cr3, Cr4::read_raw())?;
+        write!(f, "{:=<64}", "")
+    }
+}
@@ -0,0 +1,95 @@
+//! Diagnostics for non-maskable interrupts and machine check exceptions.
+//!
+//! Both indicate a hardware problem. The functions here decode whatever state
+//! the hardware leaves behind so that the handlers can print something more
+//! useful than the bare exception. They print with [early_println], which
+//! takes no lock, as they may interrupt code holding any.
+//!
+//! See: Intel SDM Vol. 3B, Chapter 15 "Machine-Check Architecture".
+
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+use crate::early_println;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MC0_STATUS: u32 = 0x401;
+
+/// Bit in IA32_MCi_STATUS set when the bank contains a valid error.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+/// Bit in IA32_MCi_STATUS set when IA32_MCi_ADDR contains a valid address.
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+/// Bit in IA32_MCi_STATUS set when IA32_MCi_MISC contains valid information.
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+
+/// System control port B which reports the source of legacy NMIs.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+const PORT_B_IO_CHECK: u8 = 1 << 6;
+const PORT_B_PARITY_CHECK: u8 = 1 << 7;
+
+/// Enables machine check exceptions if supported by the processor.
+///
+/// Without this, a machine check causes the processor to shut down instead of
+/// invoking the handler.
+pub fn enable() {
+    if crate::cpu::has_machine_check() {
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+        }
+    }
+}
+
+/// Prints the reason for a non-maskable interrupt reported by the legacy
+/// system control port.
+pub fn log_nmi_reason() {
+    let mut port: Port<u8> = Port::new(SYSTEM_CONTROL_PORT_B);
+    let reason = unsafe { port.read() };
+
+    early_println!("System Control Port B: {:#04x}", reason);
+    if reason & PORT_B_PARITY_CHECK != 0 {
+        early_println!("  memory parity error");
+    }
+    if reason & PORT_B_IO_CHECK != 0 {
+        early_println!("  I/O channel check");
+    }
+}
+
+/// Prints the global machine check status and every bank reporting an error.
+pub fn log_banks() {
+    if !crate::cpu::has_machine_check() {
+        early_println!("Machine check architecture not supported");
+        return;
+    }
+
+    let (capabilities, status) = unsafe {
+        (
+            Msr::new(IA32_MCG_CAP).read(),
+            Msr::new(IA32_MCG_STATUS).read(),
+        )
+    };
+    early_println!("IA32_MCG_CAP: {:#018x}", capabilities);
+    early_println!("IA32_MCG_STATUS: {:#018x}", status);
+
+    let bank_count = (capabilities & 0xff) as u32;
+    for bank in 0..bank_count {
+        let base = IA32_MC0_STATUS + 4 * bank;
+        let status = unsafe { Msr::new(base).read() };
+        if status & MCI_STATUS_VAL == 0 {
+            continue;
+        }
+
+        early_println!("MC{} STATUS: {:#018x}", bank, status);
+        if status & MCI_STATUS_ADDRV != 0 {
+            early_println!("MC{} ADDR:   {:#018x}", bank, unsafe {
+                Msr::new(base + 1).read()
+            });
+        }
+        if status & MCI_STATUS_MISCV != 0 {
+            early_println!("MC{} MISC:   {:#018x}", bank, unsafe {
+                Msr::new(base + 2).read()
+            });
+        }
+    }
+}
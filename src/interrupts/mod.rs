@@ -0,0 +1,591 @@
+//! The `interrupts` module defines interrupt handlers for CPU faults and
+//! hardware interrupts.
+//!
+//! The [init_idt] function can be used to initialize the interrupt descriptor
+//! table during the kernel's initial boot sequence.
+//!
+//! Handlers should do as little work as possible, handing anything expensive
+//! off to the [deferred] module to be run outside of interrupt context.
+//!
+//! Every handler records the vector it services in a per-vector counter which
+//! can be inspected through [stats].
+//!
+//! See https://wiki.osdev.org/Exceptions for more info on CPU exceptions.
+//! See https://os.phil-opp.com/hardware-interrupts/ for hardware interrupts.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    cprintln, early_println,
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX},
+    percpu::{KernelGs, PerCpu},
+    sync::IrqSpinlock,
+    vga::Color,
+};
+
+pub mod apic;
+pub mod deferred;
+pub mod fault;
+pub mod machine_check;
+pub mod vectors;
+
+pub use vectors::{allocate_vector, claim_vector, free_vector};
+
+use fault::{exception_entry, ExceptionFrame, RegisterDump};
+use lazy_static::lazy_static;
+use log::{error, info};
+use pic8259::ChainedPics;
+use x86_64::structures::idt::{
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+// Offset into the interrupt table for hardware interrupt handlers for the two
+// programmable interrupt controllers (PICs). Positions 0x0 through 0x1f are
+// reserved for CPU fault handlers.
+const PIC_1_OFFSET: u8 = 0x20;
+const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// Number of entries in the interrupt descriptor table.
+const IDT_ENTRIES: usize = 256;
+
+// Exception vectors as defined by the x86_64 architecture.
+const NMI_VECTOR: u8 = 0x02;
+const BREAKPOINT_VECTOR: u8 = 0x03;
+const DOUBLE_FAULT_VECTOR: u8 = 0x08;
+const GENERAL_PROTECTION_FAULT_VECTOR: u8 = 0x0d;
+const PAGE_FAULT_VECTOR: u8 = 0x0e;
+const MACHINE_CHECK_VECTOR: u8 = 0x12;
+
+/// Number of times each interrupt vector has been serviced.
+static COUNTERS: [AtomicU64; IDT_ENTRIES] = [const { AtomicU64::new(0) }; IDT_ENTRIES];
+
+/// Each CPU loads its own copy of the IDT.
+static IDT: PerCpu<InterruptDescriptorTable> = PerCpu::new(new_idt);
+
+/// Creates the interrupt descriptor table for a CPU.
+fn new_idt(_cpu: usize) -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+
+    // CPU faults
+
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+
+    unsafe {
+        // Fatal faults go through register saving stubs so that the
+        // complete processor state can be dumped.
+        idt.general_protection_fault
+            .set_handler_addr(entry_addr(general_protection_fault_entry));
+        idt.page_fault
+            .set_handler_addr(entry_addr(page_fault_entry));
+
+        // Register the double fault handler and configure it to use a
+        // dedicated stack.
+        //
+        // We need a separate stack for this handler because we can't
+        // ensure that the current stack is in a valid state. For example,
+        // if a page fault exception occurs due to a stack overflow, an
+        // attempt is made to push a new stack frame onto the stack in
+        // order to call the page fault exception handler. Since the stack
+        // point is already invalid due to the stack overflow, another page
+        // fault exception is triggered which triggers a double fault
+        // exception. However, like before, a stack frame cannot be pushed
+        // to call the double fault exception handler which triggers a
+        // triple fault causing a hard reset. Having a dedicated stack for
+        // the double fault handler ensures that it can always be called,
+        // even if the original stack is borked.
+        idt.double_fault
+            .set_handler_addr(entry_addr(double_fault_entry))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+
+        // NMIs and machine checks can interrupt any code, including code
+        // running on a broken stack, so they also get dedicated stacks.
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+    }
+
+    // Hardware interrupts
+
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+
+    idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+
+    idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+
+    idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_interrupt_handler);
+
+    // IRQs without a dedicated handler run those added with add_irq_handler.
+    let shared: [(u8, HandlerFunc); 11] = [
+        (3, irq_handler::<3>),
+        (4, irq_handler::<4>),
+        (5, irq_handler::<5>),
+        (6, irq_handler::<6>),
+        (7, irq_handler::<7>),
+        (8, irq_handler::<8>),
+        (9, irq_handler::<9>),
+        (10, irq_handler::<10>),
+        (11, irq_handler::<11>),
+        (12, irq_handler::<12>),
+        (13, irq_handler::<13>),
+    ];
+    for (irq, handler) in shared {
+        idt[(PIC_1_OFFSET + irq) as usize].set_handler_fn(handler);
+    }
+
+    // Dynamically allocated vectors
+    vectors::install(&mut idt);
+
+    // The local APIC timer takes over from the PIT's timer interrupt.
+    idt[apic::TIMER_VECTOR as usize].set_handler_fn(apic_timer_interrupt_handler);
+
+    // The user mode exit gate may be raised from ring 3.
+    unsafe {
+        idt[crate::usermode::EXIT_VECTOR as usize]
+            .set_handler_addr(entry_addr(crate::usermode::toyos_user_exit))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+
+    idt
+}
+
+lazy_static! {
+    static ref PICS: IrqSpinlock<ChainedPics> =
+        IrqSpinlock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+}
+
+/// Interrupt indices for the Intel 8259 interrupt controller.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
+}
+
+impl InterruptIndex {
+    #[inline]
+    fn as_usize(self) -> usize {
+        self as u8 as usize
+    }
+}
+
+/// Loads the calling CPU's interrupt descriptor table and enables machine
+/// check exceptions.
+pub fn init_idt() {
+    IDT.get().load();
+    machine_check::enable();
+}
+
+/// Initializes the two programmable interrupt controllers (PICs) and enable
+/// interrupts.
+pub fn init_hw_interrupts() {
+    unsafe {
+        PICS.lock().initialize();
+    }
+
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Unmasks the given IRQ of the PICs, along with the cascade IRQ if it is one
+/// of the second PIC's.
+pub fn enable_irq(irq: u8) {
+    /// The IRQ of the first PIC which the second is chained to.
+    const CASCADE_IRQ: u8 = 2;
+
+    let mut pics = PICS.lock();
+    unsafe {
+        let [mut mask1, mut mask2] = pics.read_masks();
+        if irq < 8 {
+            mask1 &= !(1 << irq);
+        } else {
+            mask1 &= !(1 << CASCADE_IRQ);
+            mask2 &= !(1 << (irq - 8));
+        }
+        pics.write_masks(mask1, mask2);
+    }
+}
+
+/// Masks the given IRQ of the PICs.
+pub fn disable_irq(irq: u8) {
+    let mut pics = PICS.lock();
+    unsafe {
+        let [mut mask1, mut mask2] = pics.read_masks();
+        if irq < 8 {
+            mask1 |= 1 << irq;
+        } else {
+            mask2 |= 1 << (irq - 8);
+        }
+        pics.write_masks(mask1, mask2);
+    }
+}
+
+/// Maximum number of handlers sharing an IRQ.
+const MAX_IRQ_HANDLERS: usize = 4;
+
+/// Handlers added with [add_irq_handler], by IRQ. The table is statically
+/// allocated so that handlers can be added before the heap is initialized.
+static IRQ_HANDLERS: IrqSpinlock<[[Option<IrqHandler>; MAX_IRQ_HANDLERS]; 16]> =
+    IrqSpinlock::new([[None; MAX_IRQ_HANDLERS]; 16]);
+
+/// A handler added with [add_irq_handler].
+pub type IrqHandler = fn();
+
+/// Runs `handler` whenever the given IRQ of the PICs is raised, and unmasks
+/// it. Several handlers may be added for an IRQ shared by PCI devices, each
+/// of which must check whether its device interrupted.
+///
+/// Handlers run in interrupt context, before the end of interrupt is
+/// signalled. Only IRQs 3 to 13 can have handlers added, as the others have
+/// dedicated handlers; returns `false` for those, and if the IRQ already has
+/// [MAX_IRQ_HANDLERS] handlers.
+pub fn add_irq_handler(irq: u8, handler: IrqHandler) -> bool {
+    if !(3..=13).contains(&irq) {
+        return false;
+    }
+    let mut handlers = IRQ_HANDLERS.lock();
+    let Some(slot) = handlers[irq as usize]
+        .iter_mut()
+        .find(|slot| slot.is_none())
+    else {
+        return false;
+    };
+    *slot = Some(handler);
+    drop(handlers);
+    enable_irq(irq);
+    true
+}
+
+/// Records that the interrupt with the given vector has been serviced.
+#[inline]
+pub(crate) fn record(vector: u8) {
+    COUNTERS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns a human readable name for an interrupt vector.
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        NMI_VECTOR => "non-maskable interrupt",
+        BREAKPOINT_VECTOR => "breakpoint",
+        DOUBLE_FAULT_VECTOR => "double fault",
+        GENERAL_PROTECTION_FAULT_VECTOR => "general protection fault",
+        PAGE_FAULT_VECTOR => "page fault",
+        MACHINE_CHECK_VECTOR => "machine check",
+        v if v == InterruptIndex::Timer as u8 => "timer",
+        apic::TIMER_VECTOR => "APIC timer",
+        v if v == InterruptIndex::Keyboard as u8 => "keyboard",
+        v if v == InterruptIndex::PrimaryAta as u8 => "primary ATA",
+        v if v == InterruptIndex::SecondaryAta as u8 => "secondary ATA",
+        v if (PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&v) => "IRQ",
+        v if v >= vectors::FIRST_DYNAMIC_VECTOR => "dynamic",
+        _ => "unknown",
+    }
+}
+
+/// A snapshot of the per-vector interrupt counters.
+///
+/// The [fmt::Display] implementation renders the snapshot in a format similar
+/// to Linux's `/proc/interrupts`, listing only vectors which have fired at
+/// least once.
+#[derive(Clone)]
+pub struct InterruptStats {
+    counts: [u64; IDT_ENTRIES],
+}
+
+impl InterruptStats {
+    /// Returns the number of times the given vector had been serviced when
+    /// this snapshot was taken.
+    pub fn count(&self, vector: u8) -> u64 {
+        self.counts[vector as usize]
+    }
+
+    /// Returns the number of timer interrupts which have been serviced, from
+    /// both the PIT and the local APIC timers.
+    pub fn timer(&self) -> u64 {
+        self.count(InterruptIndex::Timer as u8) + self.count(apic::TIMER_VECTOR)
+    }
+
+    /// Returns the number of keyboard interrupts which have been serviced.
+    pub fn keyboard(&self) -> u64 {
+        self.count(InterruptIndex::Keyboard as u8)
+    }
+
+    /// Returns the total number of interrupts across all vectors.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns an iterator over `(vector, count)` pairs for every vector which
+    /// has been serviced at least once.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count != 0)
+            .map(|(vector, &count)| (vector as u8, count))
+    }
+}
+
+impl fmt::Display for InterruptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (vector, count) in self.iter() {
+            writeln!(f, "{:>4x}: {:>12}  {}", vector, count, vector_name(vector))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns a snapshot of the number of times each interrupt vector has been
+/// serviced since boot.
+pub fn stats() -> InterruptStats {
+    let mut counts = [0; IDT_ENTRIES];
+    for (count, counter) in counts.iter_mut().zip(COUNTERS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    InterruptStats { counts }
+}
+
+//
+// MARK: Interrupt Handlers
+//
+
+/// Handler for the breakpoint CPU exception.
+///
+/// A breakpoint exception is triggered when the CPU executes a `int3`
+/// instruction.
+///
+/// See: https://wiki.osdev.org/Exceptions#Breakpoint
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(BREAKPOINT_VECTOR);
+    info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+/// Returns the address of an assembly entry stub for installation in the IDT.
+fn entry_addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Prints a register dump for a fatal exception to both the VGA display and
+/// the serial port.
+fn dump_registers(title: &str, frame: &ExceptionFrame) {
+    let dump = RegisterDump::new(title, frame);
+    cprintln!(Color::LightRed, "{}", dump);
+    crate::serial_println!("{}", dump);
+}
+
+exception_entry!(
+    general_protection_fault_entry,
+    general_protection_fault_handler
+);
+
+/// Handler for general protection fault CPU exceptions.
+///
+/// See: https://wiki.osdev.org/Exceptions#General_Protection_Fault
+extern "C" fn general_protection_fault_handler(frame: &mut ExceptionFrame) {
+    record(GENERAL_PROTECTION_FAULT_VECTOR);
+    dump_registers("EXCEPTION: GENERAL PROTECTION FAULT", frame);
+    crate::hlt();
+}
+
+exception_entry!(page_fault_entry, page_fault_handler);
+
+/// Handler for page fault CPU exceptions.
+///
+/// Faults raised by user processes are handed to the [process](crate::process)
+/// module, which maps demand paged memory or terminates the process.
+extern "C" fn page_fault_handler(frame: &mut ExceptionFrame) {
+    use x86_64::registers::control::Cr2;
+
+    record(PAGE_FAULT_VECTOR);
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    if frame.cs & 3 == 3 {
+        let present = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+        // Like a system call, a fault from user mode runs on the kernel stack
+        // of the process's thread without holding any locks, so it may block.
+        x86_64::instructions::interrupts::enable();
+        let resolved = crate::process::handle_page_fault(Cr2::read(), present);
+        x86_64::instructions::interrupts::disable();
+        if resolved {
+            return;
+        }
+    }
+    dump_registers("EXCEPTION: PAGE FAULT", frame);
+    error!("Accessed Address: {:?}", Cr2::read());
+    error!("Error Code: {:?}", error_code);
+    crate::hlt();
+}
+
+exception_entry!(double_fault_entry, double_fault_handler);
+
+/// Handler for double fault CPU exceptions.
+///
+/// Recovery from this handler is not permitted. As such, this function does
+/// not return.
+///
+/// See: https://wiki.osdev.org/Exception#Double_Fault
+extern "C" fn double_fault_handler(frame: &mut ExceptionFrame) -> ! {
+    record(DOUBLE_FAULT_VECTOR);
+    dump_registers("EXCEPTION: DOUBLE FAULT", frame);
+    panic!("EXCEPTION: DOUBLE FAULT");
+}
+
+/// Handler for non-maskable interrupts.
+///
+/// NMIs are typically raised by the chipset to report hardware errors. The
+/// interrupted code may be in any state, even holding the locks of the
+/// logger or the consoles, so this handler only reports the reason to COM1
+/// with [early_println] and returns.
+///
+/// See: https://wiki.osdev.org/Non_Maskable_Interrupt
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter_paranoid();
+    record(NMI_VECTOR);
+    early_println!("EXCEPTION: NON-MASKABLE INTERRUPT");
+    machine_check::log_nmi_reason();
+    early_println!("Stack Frame: {:#?}", stack_frame);
+}
+
+/// Handler for machine check exceptions.
+///
+/// A machine check indicates an unrecoverable hardware error. Like the
+/// [NMI handler](nmi_handler), this handler reports to COM1 with
+/// [early_println], as the interrupted code may hold any lock. It dumps the
+/// machine check banks and halts the CPU.
+///
+/// See: https://wiki.osdev.org/Exceptions#Machine_Check
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _gs = KernelGs::enter_paranoid();
+    record(MACHINE_CHECK_VECTOR);
+    early_println!("EXCEPTION: MACHINE CHECK");
+    machine_check::log_banks();
+    early_println!("Stack Frame: {:#?}", stack_frame);
+    crate::hlt();
+}
+
+/// Handler for timer interrupts of the PIT.
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(InterruptIndex::Timer as u8);
+    timer_tick(&stack_frame, || unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+    });
+}
+
+/// Handler for interrupts of the local APIC timers.
+///
+/// Only the bootstrap processor's timer drives the kernel's clock and
+/// scheduling; those of the other CPUs merely wake them from `hlt`.
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(apic::TIMER_VECTOR);
+    apic::timer_interrupt();
+    if crate::percpu::cpu_id() == 0 {
+        timer_tick(&stack_frame, apic::eoi);
+    } else {
+        apic::eoi();
+    }
+}
+
+/// Advances the kernel's clock and scheduling by a tick, calling `eoi` to
+/// signal the end of the interrupt before switching threads.
+fn timer_tick(stack_frame: &InterruptStackFrame, eoi: impl FnOnce()) {
+    crate::time::tick();
+    crate::task::preempt::tick();
+    crate::task::timer::tick();
+    crate::task::watchdog::tick();
+    eoi();
+
+    // May switch to another thread, so must come after the end of interrupt
+    // notification.
+    crate::task::thread::tick();
+
+    // Lets signals stop processes which never make system calls.
+    if stack_frame.code_segment & 3 == 3 {
+        crate::process::deliver_signals_from_interrupt();
+    }
+}
+
+/// Handler for keyboard interrupts.
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(InterruptIndex::Keyboard as u8);
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::task::input::keyboard::add_scancode(scancode);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+    }
+}
+
+/// Handler for interrupts of the primary IDE channel.
+extern "x86-interrupt" fn primary_ata_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(InterruptIndex::PrimaryAta as u8);
+    crate::drivers::ata::handle_interrupt(0);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta as u8);
+    }
+}
+
+/// Handler for interrupts of the secondary IDE channel.
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(InterruptIndex::SecondaryAta as u8);
+    crate::drivers::ata::handle_interrupt(1);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta as u8);
+    }
+}
+
+/// Handler for IRQs run by the handlers added with [add_irq_handler].
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame.code_segment);
+    record(PIC_1_OFFSET + IRQ);
+    for handler in IRQ_HANDLERS.lock()[IRQ as usize].iter().flatten() {
+        handler();
+    }
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + IRQ);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_breakpoint_exception() {
+        x86_64::instructions::interrupts::int3();
+    }
+
+    #[test_case]
+    fn test_breakpoint_is_counted() {
+        let before = stats().count(BREAKPOINT_VECTOR);
+        x86_64::instructions::interrupts::int3();
+        assert_eq!(stats().count(BREAKPOINT_VECTOR), before + 1);
+    }
+
+    #[test_case]
+    fn test_timer_is_firing() {
+        let before = stats().timer();
+        while stats().timer() == before {
+            x86_64::instructions::hlt();
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Dynamic allocation of interrupt vectors.
+//!
+//! Vectors `0x00` through `0x2f` are reserved for CPU exceptions and the
+//! legacy PICs. The remaining vectors, [FIRST_DYNAMIC_VECTOR] through `0xff`,
+//! can be claimed at runtime by subsystems such as the APIC timer, IPIs and
+//! MSI capable devices via [allocate_vector] or [claim_vector].
+//!
+//! Every dynamic vector's IDT entry points at a tiny assembly stub which
+//! pushes the vector number and jumps to a common entry point. The common
+//! entry point saves the caller-saved registers and calls the handler
+//! registered for the vector, if any. Since the IDT itself never changes,
+//! handlers can be registered and released at any time on any CPU.
+//!
+//! Handlers run in interrupt context and are responsible for acknowledging
+//! the interrupt with whichever interrupt controller raised it.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+/// First vector available for dynamic allocation.
+pub const FIRST_DYNAMIC_VECTOR: u8 = 0x30;
+
+/// Vectors in the dynamic range which the kernel installs its own gates for
+/// and never hands out.
+const RESERVED_VECTORS: [u8; 2] = [crate::usermode::EXIT_VECTOR, super::apic::TIMER_VECTOR];
+
+/// Number of dynamically allocatable vectors.
+const DYNAMIC_VECTOR_COUNT: usize = 0x100 - FIRST_DYNAMIC_VECTOR as usize;
+
+/// Size of each entry stub in bytes. Must match the `.align` in the stubs.
+const STUB_SIZE: u64 = 16;
+
+/// A handler for a dynamically allocated vector. It is passed the vector it
+/// was invoked for.
+pub type Handler = fn(vector: u8);
+
+/// Registered handlers indexed by `vector - FIRST_DYNAMIC_VECTOR`. A null
+/// pointer marks a free vector.
+static HANDLERS: [AtomicPtr<()>; DYNAMIC_VECTOR_COUNT] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; DYNAMIC_VECTOR_COUNT];
+
+global_asm!(
+    ".pushsection .text",
+    ".global dynamic_vector_stubs",
+    ".align 16",
+    "dynamic_vector_stubs:",
+    ".set vector, {first}",
+    ".rept {count}",
+    ".align 16",
+    "push vector",
+    "jmp dynamic_vector_common",
+    ".set vector, vector + 1",
+    ".endr",
+    "",
+    "dynamic_vector_common:",
+    "test qword ptr [rsp + 16], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "mov rdi, [rsp + 9 * 8]",
+    "cld",
+    // The CPU aligns the stack before pushing its five qword frame. Together
+    // with the vector and the nine saved registers the stack ends up 8 bytes
+    // short of the alignment required by the System V ABI.
+    "sub rsp, 8",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    // Discard the vector number.
+    "add rsp, 8",
+    "test qword ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "iretq",
+    ".popsection",
+    first = const FIRST_DYNAMIC_VECTOR,
+    count = const DYNAMIC_VECTOR_COUNT,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn dynamic_vector_stubs();
+}
+
+/// Points every dynamic vector's IDT entry at its entry stub.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    let base = dynamic_vector_stubs as unsafe extern "C" fn() as usize as u64;
+    for (i, vector) in (FIRST_DYNAMIC_VECTOR as usize..0x100).enumerate() {
+        let stub = VirtAddr::new(base + i as u64 * STUB_SIZE);
+        unsafe {
+            idt[vector].set_handler_addr(stub);
+        }
+    }
+}
+
+/// Called by the common entry stub for every dynamic vector.
+extern "C" fn dispatch(vector: u64) {
+    let vector = vector as u8;
+    super::record(vector);
+
+    let handler = HANDLERS[(vector - FIRST_DYNAMIC_VECTOR) as usize].load(Ordering::Acquire);
+    if !handler.is_null() {
+        let handler: Handler = unsafe { core::mem::transmute::<*mut (), Handler>(handler) };
+        handler(vector);
+    }
+}
+
+/// Claims the lowest free dynamic vector, installing `handler` for it.
+///
+/// Returns `None` if every dynamic vector is in use.
+pub fn allocate_vector(handler: Handler) -> Option<u8> {
+    (FIRST_DYNAMIC_VECTOR..=0xff).find(|&vector| claim_vector(vector, handler))
+}
+
+/// Claims a specific vector, installing `handler` for it.
+///
+/// Returns `false` if the vector is already in use or is not a dynamic
+/// vector.
+pub fn claim_vector(vector: u8, handler: Handler) -> bool {
+    if vector < FIRST_DYNAMIC_VECTOR || RESERVED_VECTORS.contains(&vector) {
+        return false;
+    }
+
+    HANDLERS[(vector - FIRST_DYNAMIC_VECTOR) as usize]
+        .compare_exchange(
+            core::ptr::null_mut(),
+            handler as *mut (),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+}
+
+/// Releases a vector previously claimed with [allocate_vector] or
+/// [claim_vector]. Interrupts arriving on the vector afterwards are counted
+/// but otherwise ignored.
+pub fn free_vector(vector: u8) {
+    if vector >= FIRST_DYNAMIC_VECTOR {
+        HANDLERS[(vector - FIRST_DYNAMIC_VECTOR) as usize]
+            .store(core::ptr::null_mut(), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    const TEST_VECTOR: u8 = 0xf0;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn test_handler(vector: u8) {
+        assert_eq!(vector, TEST_VECTOR);
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_claimed_vector_is_dispatched() {
+        assert!(claim_vector(TEST_VECTOR, test_handler));
+        assert!(!claim_vector(TEST_VECTOR, test_handler));
+
+        let before = CALLS.load(Ordering::Relaxed);
+        unsafe {
+            core::arch::asm!("int {}", const TEST_VECTOR);
+        }
+        assert_eq!(CALLS.load(Ordering::Relaxed), before + 1);
+
+        free_vector(TEST_VECTOR);
+        unsafe {
+            core::arch::asm!("int {}", const TEST_VECTOR);
+        }
+        assert_eq!(CALLS.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test_case]
+    fn test_allocate_vector() {
+        let vector = allocate_vector(test_handler).expect("no free vectors");
+        assert!(vector >= FIRST_DYNAMIC_VECTOR);
+        assert!(!claim_vector(vector, test_handler));
+        free_vector(vector);
+        assert!(claim_vector(vector, test_handler));
+        free_vector(vector);
+    }
+}
@@ -0,0 +1,417 @@
+//! Message passing between processes and kernel tasks.
+//!
+//! A [Port] is a queue of messages identified by a [PortId]. Anyone who knows
+//! a port's ID may send to it, but only the process which created it, its
+//! owner, may receive from it; ports created by the kernel can only be
+//! received from by the kernel. Ports are closed when their owner exits or
+//! destroys them.
+//!
+//! Sending never blocks: a message is copied into the port's queue, or
+//! rejected if the queue is full. Receiving waits for a message through
+//! [Port::receive], which async tasks await directly and the receive system
+//! call blocks on.
+//!
+//! Byte streams between processes are provided by [pipes](pipe) instead, and
+//! [shared memory](shm) regions avoid copying data altogether.
+
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{process::Pid, task::sync::Notify};
+
+pub mod pipe;
+pub mod shm;
+
+/// Maximum number of messages queued on a port.
+pub const PORT_CAPACITY: usize = 64;
+
+/// Maximum size of a message in bytes.
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Uniquely identifies a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortId(u64);
+
+impl PortId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        PortId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Returns the port ID with the given value, which need not belong to
+    /// any port.
+    pub fn from_u64(id: u64) -> Self {
+        PortId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A message queued on a port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The sending process, or `None` if sent by the kernel.
+    pub sender: Option<Pid>,
+    pub data: Vec<u8>,
+}
+
+/// Reasons [Port::send] fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The message is larger than [MAX_MESSAGE_SIZE].
+    TooLarge,
+    /// The port already holds [PORT_CAPACITY] messages.
+    Full,
+    /// The port has been closed.
+    Closed,
+}
+
+/// Returned when receiving from a closed port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+struct State {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+/// A message queue. See the [module documentation](self).
+pub struct Port {
+    id: PortId,
+    owner: Option<Pid>,
+    state: Mutex<State>,
+    /// Notified when a message is queued or the port is closed.
+    notify: Notify,
+}
+
+impl Port {
+    pub fn id(&self) -> PortId {
+        self.id
+    }
+
+    /// Returns the process allowed to receive from the port, or `None` if the
+    /// kernel owns it.
+    pub fn owner(&self) -> Option<Pid> {
+        self.owner
+    }
+
+    /// Queues a message.
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        if message.data.len() > MAX_MESSAGE_SIZE {
+            return Err(SendError::TooLarge);
+        }
+
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        if state.messages.len() >= PORT_CAPACITY {
+            return Err(SendError::Full);
+        }
+        state.messages.push_back(message);
+        drop(state);
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Removes the oldest message if there is one.
+    pub fn try_receive(&self) -> Result<Option<Message>, Closed> {
+        let mut state = self.state.lock();
+        match state.messages.pop_front() {
+            Some(message) => Ok(Some(message)),
+            None if state.closed => Err(Closed),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the size of the oldest message without removing it.
+    pub fn peek_len(&self) -> Option<usize> {
+        self.state
+            .lock()
+            .messages
+            .front()
+            .map(|message| message.data.len())
+    }
+
+    /// Waits until a message is queued, without removing it. Fails once the
+    /// port is closed and empty.
+    pub async fn readable(&self) -> Result<(), Closed> {
+        loop {
+            {
+                let state = self.state.lock();
+                if !state.messages.is_empty() {
+                    return Ok(());
+                }
+                if state.closed {
+                    return Err(Closed);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Waits for a message and removes it. Messages queued before the port
+    /// was closed are still received.
+    pub async fn receive(&self) -> Result<Message, Closed> {
+        loop {
+            self.readable().await?;
+            if let Some(message) = self.try_receive()? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Closes the port and removes it from the port table. Pending messages
+    /// can still be received, but no new ones can be sent.
+    pub fn close(&self) {
+        PORTS.lock().remove(&self.id);
+        self.state.lock().closed = true;
+
+        // The permit covers a receiver which checked the port just before it
+        // was closed but has not started waiting yet.
+        self.notify.notify_waiters();
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Port")
+            .field("id", &self.id)
+            .field("owner", &self.owner)
+            .finish()
+    }
+}
+
+static PORTS: Mutex<BTreeMap<PortId, Arc<Port>>> = Mutex::new(BTreeMap::new());
+
+/// Creates a port receivable by `owner`, or by the kernel if `None`.
+pub fn create(owner: Option<Pid>) -> Arc<Port> {
+    let port = Arc::new(Port {
+        id: PortId::new(),
+        owner,
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            closed: false,
+        }),
+        notify: Notify::new(),
+    });
+    PORTS.lock().insert(port.id, port.clone());
+    port
+}
+
+/// Returns the open port with the given ID.
+pub fn get(id: PortId) -> Option<Arc<Port>> {
+    PORTS.lock().get(&id).cloned()
+}
+
+/// Closes every port owned by `owner`. Called when a process exits.
+pub fn close_owned_by(owner: Pid) {
+    let owned: Vec<Arc<Port>> = PORTS
+        .lock()
+        .values()
+        .filter(|port| port.owner == Some(owner))
+        .cloned()
+        .collect();
+    for port in owned {
+        port.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        process::{self, elf},
+        syscall::{
+            Errno, SYS_EXIT, SYS_FORK, SYS_PORT_CREATE, SYS_PORT_DESTROY, SYS_PORT_RECEIVE,
+            SYS_PORT_SEND,
+        },
+        task::{executor::Executor, Task},
+        usermode::user_program,
+    };
+    use alloc::vec;
+    use core::arch::global_asm;
+
+    global_asm!(
+        ".pushsection .text",
+        // Creates a port and forks a child which sends "hi" to it. Receives
+        // into a buffer which is too small, then into one which fits, checks
+        // the length and sender and destroys the port. Exits with the message
+        // if receiving from the destroyed port fails, and 0xff if any check
+        // fails.
+        "toyos_test_port:",
+        "mov eax, {create}",
+        "syscall",
+        "mov rbx, rax",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov eax, {send}",
+        "mov rdi, rbx",
+        "lea rsi, [rip + 4f]",
+        "mov edx, 2",
+        "syscall",
+        "mov rdi, rax",
+        "mov eax, {exit}",
+        "syscall",
+        "2:",
+        "mov r12, rax",
+        "sub rsp, 16",
+        "mov eax, {receive}",
+        "mov rdi, rbx",
+        "mov rsi, rsp",
+        "mov edx, 1",
+        "xor r10d, r10d",
+        "syscall",
+        "cmp rax, {emsgsize}",
+        "jne 3f",
+        "mov eax, {receive}",
+        "mov edx, 8",
+        "lea r10, [rsp + 8]",
+        "syscall",
+        "cmp rax, 2",
+        "jne 3f",
+        "cmp [rsp + 8], r12",
+        "jne 3f",
+        "movzx r13d, word ptr [rsp]",
+        "mov eax, {destroy}",
+        "mov rdi, rbx",
+        "syscall",
+        "mov eax, {receive}",
+        "mov rdi, rbx",
+        "mov rsi, rsp",
+        "mov edx, 8",
+        "xor r10d, r10d",
+        "syscall",
+        "cmp rax, {ebadf}",
+        "jne 3f",
+        "mov eax, {exit}",
+        "mov edi, r13d",
+        "syscall",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "4:",
+        ".ascii \"hi\"",
+        "toyos_test_port_end:",
+        ".popsection",
+        exit = const SYS_EXIT,
+        fork = const SYS_FORK,
+        create = const SYS_PORT_CREATE,
+        send = const SYS_PORT_SEND,
+        receive = const SYS_PORT_RECEIVE,
+        destroy = const SYS_PORT_DESTROY,
+        emsgsize = const -(Errno::MsgSize as i64),
+        ebadf = const -(Errno::BadF as i64),
+    );
+
+    fn message(data: &[u8]) -> Message {
+        Message {
+            sender: None,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test_case]
+    fn test_send_and_receive() {
+        let port = create(None);
+        port.send(message(b"one")).unwrap();
+        port.send(message(b"two")).unwrap();
+
+        assert_eq!(port.peek_len(), Some(3));
+        assert_eq!(port.try_receive(), Ok(Some(message(b"one"))));
+        assert_eq!(port.try_receive(), Ok(Some(message(b"two"))));
+        assert_eq!(port.try_receive(), Ok(None));
+        port.close();
+    }
+
+    #[test_case]
+    fn test_send_errors() {
+        let port = create(None);
+        let large = Message {
+            sender: None,
+            data: vec![0; MAX_MESSAGE_SIZE + 1],
+        };
+        assert_eq!(port.send(large), Err(SendError::TooLarge));
+
+        for _ in 0..PORT_CAPACITY {
+            port.send(message(b"")).unwrap();
+        }
+        assert_eq!(port.send(message(b"")), Err(SendError::Full));
+
+        port.close();
+        assert!(get(port.id()).is_none());
+        assert_eq!(port.send(message(b"")), Err(SendError::Closed));
+        assert_eq!(port.try_receive(), Ok(Some(message(b""))));
+    }
+
+    #[test_case]
+    fn test_receive_wakes_task() {
+        static RECEIVED: Mutex<Option<Result<Message, Closed>>> = Mutex::new(None);
+
+        let port = create(None);
+        let receiver = port.clone();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async move {
+            *RECEIVED.lock() = Some(receiver.receive().await);
+        }));
+
+        executor.run_until_idle();
+        assert!(RECEIVED.lock().is_none());
+
+        port.send(message(b"hello")).unwrap();
+        executor.run_until_idle();
+        assert_eq!(RECEIVED.lock().take(), Some(Ok(message(b"hello"))));
+        port.close();
+    }
+
+    #[test_case]
+    fn test_close_wakes_receiver() {
+        static RECEIVED: Mutex<Option<Result<Message, Closed>>> = Mutex::new(None);
+
+        let port = create(None);
+        let receiver = port.clone();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async move {
+            *RECEIVED.lock() = Some(receiver.receive().await);
+        }));
+
+        executor.run_until_idle();
+        port.close();
+        executor.run_until_idle();
+        assert_eq!(RECEIVED.lock().take(), Some(Err(Closed)));
+    }
+
+    #[test_case]
+    fn test_port_system_calls() {
+        let code = user_program!(toyos_test_port);
+        let process = process::spawn(&elf::test::image(code)).expect("failed to spawn process");
+
+        assert_eq!(process.wait(), u64::from(u16::from_le_bytes(*b"hi")));
+        assert!(PORTS
+            .lock()
+            .values()
+            .all(|port| port.owner() != Some(process.pid())));
+    }
+}
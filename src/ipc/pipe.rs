@@ -0,0 +1,330 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a byte stream through a ring buffer of [PIPE_CAPACITY] bytes,
+//! with a [Reader] at one end and a [Writer] at the other. Reads wait until
+//! data is available and writes wait until there is room. Once every writer
+//! is dropped, reads of an empty pipe return end of file; once every reader
+//! is dropped, writes fail with [BrokenPipe].
+//!
+//! Ends are shared through [Arc]s, like Unix file descriptions: an end counts
+//! as open until the last reference to it is dropped.
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use spin::Mutex;
+
+use crate::task::sync::Notify;
+
+/// Number of bytes a pipe buffers.
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Returned when writing to a pipe without readers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokenPipe;
+
+struct State {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct Pipe {
+    state: Mutex<State>,
+    /// Notified when data is written or the last writer is dropped.
+    readable: Notify,
+    /// Notified when data is read or the last reader is dropped.
+    writable: Notify,
+}
+
+/// The reading end of a pipe.
+pub struct Reader(Arc<Pipe>);
+
+/// The writing end of a pipe.
+pub struct Writer(Arc<Pipe>);
+
+/// Creates a pipe and returns its two ends.
+pub fn pipe() -> (Reader, Writer) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(State {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            readers: 1,
+            writers: 1,
+        }),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (Reader(pipe.clone()), Writer(pipe))
+}
+
+impl Reader {
+    /// Copies buffered data into `buf` without waiting. Returns `None` if the
+    /// pipe is empty but still has writers, and `Some(0)` at end of file.
+    pub fn try_read(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut state = self.0.state.lock();
+        if state.buffer.is_empty() && !buf.is_empty() {
+            return if state.writers == 0 { Some(0) } else { None };
+        }
+
+        let len = buf.len().min(state.buffer.len());
+        for (byte, value) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *byte = value;
+        }
+        let remaining = !state.buffer.is_empty();
+        drop(state);
+
+        self.0.writable.notify_one();
+        if remaining {
+            // Pass the wake-up on to any other reader.
+            self.0.readable.notify_one();
+        }
+        Some(len)
+    }
+
+    /// Waits until data is available and copies as much of it as fits into
+    /// `buf`. Returns the number of bytes read, or 0 at end of file.
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        loop {
+            if let Some(len) = self.try_read(buf) {
+                return len;
+            }
+            self.0.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            drop(state);
+            self.0.writable.notify_waiters();
+            self.0.writable.notify_one();
+        }
+    }
+}
+
+impl Writer {
+    /// Copies as much of `data` as fits into the pipe without waiting.
+    /// Returns `None` if the pipe is full.
+    pub fn try_write(&self, data: &[u8]) -> Option<Result<usize, BrokenPipe>> {
+        let mut state = self.0.state.lock();
+        if state.readers == 0 {
+            return Some(Err(BrokenPipe));
+        }
+        let len = data.len().min(PIPE_CAPACITY - state.buffer.len());
+        if len == 0 && !data.is_empty() {
+            return None;
+        }
+        state.buffer.extend(&data[..len]);
+        let room = state.buffer.len() < PIPE_CAPACITY;
+        drop(state);
+
+        self.0.readable.notify_one();
+        if room {
+            // Pass the wake-up on to any other writer.
+            self.0.writable.notify_one();
+        }
+        Some(Ok(len))
+    }
+
+    /// Writes all of `data`, waiting for room as needed. Returns the number of
+    /// bytes written, which is less than the length of `data` only if the
+    /// last reader was dropped part way through.
+    pub async fn write(&self, data: &[u8]) -> Result<usize, BrokenPipe> {
+        let mut written = 0;
+        loop {
+            match self.try_write(&data[written..]) {
+                Some(Ok(len)) => {
+                    written += len;
+                    if written == data.len() {
+                        return Ok(written);
+                    }
+                }
+                Some(Err(BrokenPipe)) if written == 0 => return Err(BrokenPipe),
+                Some(Err(BrokenPipe)) => return Ok(written),
+                None => self.0.writable.notified().await,
+            }
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.writers -= 1;
+        if state.writers == 0 {
+            drop(state);
+            self.0.readable.notify_waiters();
+            self.0.readable.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        process::{self, elf},
+        syscall::{SYS_CLOSE, SYS_DUP2, SYS_EXIT, SYS_FORK, SYS_PIPE, SYS_READ, SYS_WRITE},
+        task::{executor::Executor, Task},
+        usermode::user_program,
+    };
+    use alloc::vec;
+    use core::arch::global_asm;
+
+    global_asm!(
+        ".pushsection .text",
+        // Creates a pipe and forks a child which makes the writing end its
+        // standard output and writes "pipe" to it. Reads until end of file
+        // and exits with the number of bytes read shifted left by 32 bits and
+        // the first four of them, or with 0xff on error.
+        "toyos_test_pipe:",
+        "sub rsp, 16",
+        "mov eax, {pipe}",
+        "mov rdi, rsp",
+        "syscall",
+        "test rax, rax",
+        "jnz 3f",
+        "mov rbx, [rsp]",
+        "mov r12, [rsp + 8]",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov eax, {close}",
+        "mov rdi, rbx",
+        "syscall",
+        "mov eax, {dup2}",
+        "mov rdi, r12",
+        "mov esi, 1",
+        "syscall",
+        "mov eax, {close}",
+        "mov rdi, r12",
+        "syscall",
+        "mov eax, {write}",
+        "mov edi, 1",
+        "lea rsi, [rip + 4f]",
+        "mov edx, 4",
+        "syscall",
+        "mov rdi, rax",
+        "mov eax, {exit}",
+        "syscall",
+        "2:",
+        "mov eax, {close}",
+        "mov rdi, r12",
+        "syscall",
+        "xor r13d, r13d",
+        "mov qword ptr [rsp], 0",
+        "5:",
+        "mov eax, {read}",
+        "mov rdi, rbx",
+        "lea rsi, [rsp + r13]",
+        "mov edx, 8",
+        "sub rdx, r13",
+        "syscall",
+        "test rax, rax",
+        "js 3f",
+        "jz 6f",
+        "add r13, rax",
+        "cmp r13, 8",
+        "jb 5b",
+        "jmp 3f",
+        "6:",
+        "mov edi, [rsp]",
+        "shl r13, 32",
+        "or rdi, r13",
+        "mov eax, {exit}",
+        "syscall",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "4:",
+        ".ascii \"pipe\"",
+        "toyos_test_pipe_end:",
+        ".popsection",
+        exit = const SYS_EXIT,
+        write = const SYS_WRITE,
+        fork = const SYS_FORK,
+        pipe = const SYS_PIPE,
+        read = const SYS_READ,
+        close = const SYS_CLOSE,
+        dup2 = const SYS_DUP2,
+    );
+
+    #[test_case]
+    fn test_read_and_write() {
+        let (reader, writer) = pipe();
+        let mut buf = [0; 8];
+        assert_eq!(reader.try_read(&mut buf), None);
+
+        assert_eq!(writer.try_write(b"hello"), Some(Ok(5)));
+        assert_eq!(reader.try_read(&mut buf[..3]), Some(3));
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(reader.try_read(&mut buf), Some(2));
+        assert_eq!(&buf[..2], b"lo");
+    }
+
+    #[test_case]
+    fn test_closed_ends() {
+        let (reader, writer) = pipe();
+        writer.try_write(b"x").unwrap().unwrap();
+        drop(writer);
+
+        let mut buf = [0; 4];
+        assert_eq!(reader.try_read(&mut buf), Some(1));
+        assert_eq!(reader.try_read(&mut buf), Some(0));
+
+        let (reader, writer) = pipe();
+        drop(reader);
+        assert_eq!(writer.try_write(b"x"), Some(Err(BrokenPipe)));
+    }
+
+    #[test_case]
+    fn test_write_waits_for_room() {
+        static WRITTEN: Mutex<Option<Result<usize, BrokenPipe>>> = Mutex::new(None);
+
+        let (reader, writer) = pipe();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async move {
+            let data = vec![1; PIPE_CAPACITY + 10];
+            *WRITTEN.lock() = Some(writer.write(&data).await);
+        }));
+
+        executor.run_until_idle();
+        assert!(WRITTEN.lock().is_none());
+
+        let mut buf = [0; 16];
+        assert_eq!(reader.try_read(&mut buf), Some(16));
+        executor.run_until_idle();
+        assert_eq!(WRITTEN.lock().take(), Some(Ok(PIPE_CAPACITY + 10)));
+    }
+
+    #[test_case]
+    fn test_read_wakes_at_end_of_file() {
+        static READ: Mutex<Option<usize>> = Mutex::new(None);
+
+        let (reader, writer) = pipe();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async move {
+            let mut buf = [0; 4];
+            *READ.lock() = Some(reader.read(&mut buf).await);
+        }));
+
+        executor.run_until_idle();
+        assert!(READ.lock().is_none());
+        drop(writer);
+        executor.run_until_idle();
+        assert_eq!(READ.lock().take(), Some(0));
+    }
+
+    #[test_case]
+    fn test_pipe_between_processes() {
+        let code = user_program!(toyos_test_pipe);
+        let process = process::spawn(&elf::test::image(code)).expect("failed to spawn process");
+
+        let data = u64::from(u32::from_le_bytes(*b"pipe"));
+        assert_eq!(process.wait(), 4 << 32 | data);
+    }
+}
@@ -0,0 +1,293 @@
+//! Shared memory regions.
+//!
+//! A [SharedMemory] region is a set of frames which can be mapped into any
+//! number of address spaces at once, so processes can exchange data without
+//! copying it through the kernel. Regions are identified by a [ShmId] while
+//! registered; the registry holds one reference to a region and every
+//! address space mapping it holds another. The frames are freed once the
+//! last reference is dropped, so unregistering a region does not affect
+//! existing mappings.
+//!
+//! Like [ports](super::Port), regions created by a process are unregistered
+//! when it exits.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
+
+use crate::{
+    mem::GlobalFrameAllocator,
+    process::{address_space, Pid},
+};
+
+/// Maximum size of a region in bytes.
+pub const MAX_SHM_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Uniquely identifies a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShmId(u64);
+
+impl ShmId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ShmId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Returns the region ID with the given value, which need not belong to
+    /// any region.
+    pub fn from_u64(id: u64) -> Self {
+        ShmId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ShmId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Reasons [create] fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The size is 0 or larger than [MAX_SHM_SIZE].
+    InvalidSize,
+    /// There are not enough free frames.
+    OutOfMemory,
+}
+
+/// Frames shared between address spaces.
+pub struct SharedMemory {
+    id: ShmId,
+    owner: Option<Pid>,
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    pub fn id(&self) -> ShmId {
+        self.id
+    }
+
+    /// Returns the process which created the region, or `None` if the kernel
+    /// did.
+    pub fn owner(&self) -> Option<Pid> {
+        self.owner
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * 4096
+    }
+
+    /// Returns the region's frames in the order they are mapped.
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+impl fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("id", &self.id)
+            .field("owner", &self.owner)
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+static REGIONS: Mutex<BTreeMap<ShmId, Arc<SharedMemory>>> = Mutex::new(BTreeMap::new());
+
+/// Creates and registers a zeroed region of at least `size` bytes, rounded up
+/// to whole pages.
+pub fn create(owner: Option<Pid>, size: u64) -> Result<Arc<SharedMemory>, Error> {
+    if size == 0 || size > MAX_SHM_SIZE {
+        return Err(Error::InvalidSize);
+    }
+
+    let count = size.div_ceil(4096) as usize;
+    let mut frames = Vec::with_capacity(count);
+    for _ in 0..count {
+        match address_space::allocate_zeroed_frame() {
+            Ok(frame) => frames.push(frame),
+            Err(_) => {
+                for frame in frames {
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+                return Err(Error::OutOfMemory);
+            }
+        }
+    }
+
+    let region = Arc::new(SharedMemory {
+        id: ShmId::new(),
+        owner,
+        frames,
+    });
+    REGIONS.lock().insert(region.id, region.clone());
+    Ok(region)
+}
+
+/// Returns the registered region with the given ID.
+pub fn get(id: ShmId) -> Option<Arc<SharedMemory>> {
+    REGIONS.lock().get(&id).cloned()
+}
+
+/// Unregisters the region with the given ID. Its frames are freed once no
+/// address space maps it anymore.
+pub fn remove(id: ShmId) -> Option<Arc<SharedMemory>> {
+    REGIONS.lock().remove(&id)
+}
+
+/// Unregisters every region created by `owner`. Called when a process exits.
+pub fn remove_owned_by(owner: Pid) {
+    REGIONS
+        .lock()
+        .retain(|_, region| region.owner != Some(owner));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        process::{self, elf, AddressSpace},
+        syscall::{Errno, SYS_EXIT, SYS_FORK, SYS_SHM_CREATE, SYS_SHM_MAP, SYS_WAITPID},
+        usermode::{user_program, USER_END, USER_START},
+    };
+    use core::arch::global_asm;
+    use x86_64::{
+        structures::paging::{mapper::Translate, PageTableFlags},
+        VirtAddr,
+    };
+
+    /// Address at which the test program maps its region.
+    const SHM_ADDR: u64 = USER_START + 0x100_0000;
+
+    global_asm!(
+        ".pushsection .text",
+        // Creates a region, maps it and checks that mapping it again at the
+        // same address fails. Forks a child which writes 42 to the region,
+        // waits for it and exits with the value the child wrote, or with
+        // 0xff on error.
+        "toyos_test_shm:",
+        "mov eax, {create}",
+        "mov edi, 4096",
+        "syscall",
+        "mov rbx, rax",
+        "mov eax, {map}",
+        "mov rdi, rbx",
+        "mov rsi, {addr}",
+        "syscall",
+        "cmp rax, rsi",
+        "jne 3f",
+        "mov r12, rax",
+        "mov eax, {map}",
+        "syscall",
+        "cmp rax, {eexist}",
+        "jne 3f",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov byte ptr [r12], 42",
+        "mov eax, {exit}",
+        "xor edi, edi",
+        "syscall",
+        "2:",
+        "mov rdi, rax",
+        "xor esi, esi",
+        "xor edx, edx",
+        "mov eax, {waitpid}",
+        "syscall",
+        "movzx edi, byte ptr [r12]",
+        "mov eax, {exit}",
+        "syscall",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "toyos_test_shm_end:",
+        ".popsection",
+        exit = const SYS_EXIT,
+        fork = const SYS_FORK,
+        waitpid = const SYS_WAITPID,
+        create = const SYS_SHM_CREATE,
+        map = const SYS_SHM_MAP,
+        addr = const SHM_ADDR,
+        eexist = const -(Errno::Exist as i64),
+    );
+
+    #[test_case]
+    fn test_create() {
+        assert_eq!(create(None, 0).unwrap_err(), Error::InvalidSize);
+        assert_eq!(
+            create(None, MAX_SHM_SIZE + 1).unwrap_err(),
+            Error::InvalidSize
+        );
+
+        let region = create(None, 4097).expect("out of frames");
+        assert_eq!(region.size(), 2 * 4096);
+        assert!(get(region.id()).is_some());
+        assert!(remove(region.id()).is_some());
+        assert!(get(region.id()).is_none());
+    }
+
+    #[test_case]
+    fn test_mappings_share_frames() {
+        let region = create(None, 4096).expect("out of frames");
+        remove(region.id());
+
+        let first_addr = VirtAddr::new(USER_START);
+        let second_addr = VirtAddr::new(USER_END - 4096);
+        let mut first = AddressSpace::new().expect("out of frames");
+        let mut second = AddressSpace::new().expect("out of frames");
+        first
+            .map_shared(first_addr, &region, PageTableFlags::WRITABLE)
+            .expect("failed to map region");
+        second
+            .map_shared(second_addr, &region, PageTableFlags::WRITABLE)
+            .expect("failed to map region");
+        assert_eq!(Arc::strong_count(&region), 3);
+
+        assert!(first.write(first_addr + 8u64, &[42]));
+        let phys = crate::mem::with_page_table_of(second.page_table(), |page_table| {
+            page_table.translate_addr(second_addr + 8u64)
+        })
+        .expect("region not mapped");
+        assert_eq!(
+            unsafe { *crate::mem::phys_to_virt(phys).as_ptr::<u8>() },
+            42
+        );
+
+        drop(first);
+        drop(second);
+        assert_eq!(Arc::strong_count(&region), 1);
+    }
+
+    #[test_case]
+    fn test_shared_between_processes() {
+        let code = user_program!(toyos_test_shm);
+        let process = process::spawn(&elf::test::image(code)).expect("failed to spawn process");
+
+        assert_eq!(process.wait(), 42);
+        assert!(REGIONS
+            .lock()
+            .values()
+            .all(|region| region.owner() != Some(process.pid())));
+    }
+}
@@ -10,19 +10,66 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod block;
+pub mod cmdline;
+pub mod console;
+pub mod cpu;
+pub mod debugcon;
+pub mod drivers;
+pub mod earlylog;
+pub mod fpu;
+pub mod framebuffer;
+pub mod fs;
 pub mod gdt;
+pub mod gfx;
 pub mod interrupts;
+pub mod ipc;
+pub mod logger;
 pub mod mem;
+pub mod net;
+pub mod panic;
+pub mod pci;
+pub mod percpu;
+pub mod power;
+pub mod process;
+pub mod rand;
 pub mod serial;
+pub mod smbios;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod tap;
 pub mod task;
+pub mod time;
+pub mod usermode;
 pub mod vga;
+pub mod vt;
 
 /// Initializes the kernel.
 pub fn init() {
+    early_trace!("init: logger");
+    logger::init();
+    early_trace!("init: gdt");
     gdt::init();
+    early_trace!("init: fpu");
+    fpu::init();
+    early_trace!("init: idt");
     interrupts::init_idt();
+    early_trace!("init: syscall");
+    syscall::init();
+    early_trace!("init: input");
+    task::input::keyboard::init();
+    early_trace!("init: timers");
+    task::timer::init();
+    time::init();
+    early_trace!("init: interrupts");
     interrupts::init_hw_interrupts();
+    task::input::mouse::init();
+    serial::COM1.init_input();
+    vga::enable_cursor(vga::CursorShape::Underline);
+    early_trace!("init: done");
 }
 
 /// Halts the CPU causing it to enter a sleep state until the next interrupt
@@ -75,17 +122,18 @@ impl<F> Testable for F
 where
     F: Fn(),
 {
-    /// Invokes the test case printing test information to the serial port.
+    /// Invokes the test case reporting its result to the serial port in
+    /// [TAP](tap).
     fn run(&self) -> () {
-        serial_print!("{}... ", core::any::type_name::<F>());
+        tap::start(core::any::type_name::<F>());
         self();
-        serial_println!("[ok]");
+        tap::pass();
     }
 }
 
 /// Invokes a given set of test cases.
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
+    tap::plan(tests.len());
     for test in tests {
         test.run();
     }
@@ -105,8 +153,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 /// }
 /// ```
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]");
-    serial_println!("Error: {}", info);
+    tap::fail(info);
     exit_qemu(QemuExitCode::Error);
 }
 
@@ -115,8 +162,16 @@ bootloader::entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test` when testing this crate.
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static bootloader::BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
     init();
+
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { mem::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { mem::BootInfoFrameAllocator::new(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    mem::init_frame_allocator(frame_allocator);
+    fs::init();
+
     test_main();
     hlt();
 }
@@ -0,0 +1,306 @@
+//! The kernel log, behind the macros of the `log` crate.
+//!
+//! Records go to every registered [console](crate::console) as
+//! `[seconds] [LEVEL module] message`, in a color for their level. The
+//! seconds are the [uptime](time::precise_uptime) to the microsecond, and
+//! modules are named by their path in the kernel, like `interrupts::apic`.
+//!
+//! Which records are kept is set with the `log` argument of the
+//! [command line](crate::cmdline): a list of directives separated by commas,
+//! each a level for every module or a level for a module and those within
+//! it. The most specific directive wins, and records of levels more verbose
+//! than [DEFAULT_LEVEL] are dropped unless a directive keeps them.
+//!
+//! ```text
+//! -smbios type=11,value="toyos:log=warn,,mem=debug"
+//! ```
+//!
+//! QEMU needs the comma in the value doubled.
+//!
+//! The last [DMESG_SIZE] bytes of records kept, whichever consoles they went
+//! to, are also kept in memory once the heap is initialized, and returned by
+//! [dmesg].
+
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{allocator, cmdline, console, sync::IrqSpinlock, time, vga::Color};
+
+/// The level of records kept unless the command line says otherwise.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Number of bytes of log output kept for [dmesg].
+pub const DMESG_SIZE: usize = 16 * 1024;
+
+/// The prefix of the targets of the kernel's records, left out of their
+/// module names.
+const CRATE_PREFIX: &str = "toyos::";
+
+/// A level for the records of a module and those within it.
+struct Directive {
+    module: String,
+    level: LevelFilter,
+}
+
+/// The levels of records kept.
+struct Filter {
+    default: LevelFilter,
+    directives: Vec<Directive>,
+}
+
+impl Filter {
+    /// Returns the most verbose level kept for the given module.
+    fn level(&self, module: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|directive| is_within(module, &directive.module))
+            .max_by_key(|directive| directive.module.len())
+            .map_or(self.default, |directive| directive.level)
+    }
+
+    /// Returns the most verbose level kept for any module.
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|directive| directive.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+static FILTER: IrqSpinlock<Filter> = IrqSpinlock::new(Filter {
+    default: DEFAULT_LEVEL,
+    directives: Vec::new(),
+});
+
+/// The most recent bytes of log output, dropping the oldest when full.
+struct Ring {
+    bytes: VecDeque<u8>,
+    /// The most bytes kept, or 0 until the ring is allocated.
+    size: usize,
+    /// Whether bytes were dropped, so that the first line may be cut.
+    wrapped: bool,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            bytes: VecDeque::new(),
+            size: 0,
+            wrapped: false,
+        }
+    }
+
+    /// Allocates room for `size` bytes, so that writing never allocates.
+    fn allocate(&mut self, size: usize) {
+        self.bytes.reserve_exact(size);
+        self.size = size;
+    }
+
+    /// Returns the bytes kept from the first whole line on.
+    fn text(&self) -> String {
+        let bytes: Vec<u8> = self.bytes.iter().copied().collect();
+        let start = match self.wrapped {
+            true => bytes
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(bytes.len(), |i| i + 1),
+            false => 0,
+        };
+        String::from_utf8_lossy(&bytes[start..]).to_string()
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.bytes.len() == self.size {
+                if self.bytes.pop_front().is_none() {
+                    return Ok(());
+                }
+                self.wrapped = true;
+            }
+            self.bytes.push_back(byte);
+        }
+        Ok(())
+    }
+}
+
+static DMESG: IrqSpinlock<Ring> = IrqSpinlock::new(Ring::new());
+
+/// Returns `true` if `module` is `parent` or within it.
+fn is_within(module: &str, parent: &str) -> bool {
+    match module.strip_prefix(parent) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+/// Returns the module name of a record's target.
+fn module(target: &str) -> &str {
+    target.strip_prefix(CRATE_PREFIX).unwrap_or(target)
+}
+
+/// An invalid directive of a log filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError<'a>(&'a str);
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid log directive `{}`", self.0)
+    }
+}
+
+/// Parses a list of directives, like `warn,mem=debug`.
+fn parse(spec: &str) -> Result<Filter, ParseError<'_>> {
+    let mut filter = Filter {
+        default: DEFAULT_LEVEL,
+        directives: Vec::new(),
+    };
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let invalid = || ParseError(directive);
+        match directive.split_once('=') {
+            Some((module, level)) => filter.directives.push(Directive {
+                module: String::from(module.trim()),
+                level: LevelFilter::from_str(level.trim()).map_err(|_| invalid())?,
+            }),
+            None => filter.default = LevelFilter::from_str(directive).map_err(|_| invalid())?,
+        }
+    }
+    Ok(filter)
+}
+
+/// Routes records to the consoles.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.lock().level(module(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let color = match record.level() {
+            Level::Error => Some(Color::LightRed),
+            Level::Warn => Some(Color::Pink),
+            Level::Info => None,
+            Level::Debug => Some(Color::LightGray),
+            Level::Trace => Some(Color::DarkGray),
+        };
+        let uptime = time::precise_uptime();
+        let (secs, micros) = (uptime.as_secs(), uptime.subsec_micros());
+        let (level, module, message) = (record.level(), module(record.target()), record.args());
+        let args = format_args!(
+            "[{:>5}.{:06}] [{:>5} {}] {}\n",
+            secs, micros, level, module, message
+        );
+        match color {
+            Some(color) => console::_cprint(color, args),
+            None => console::_print(args),
+        }
+
+        let mut dmesg = DMESG.lock();
+        // The ring cannot be allocated before the heap exists.
+        if dmesg.size == 0 && allocator::is_initialized() {
+            dmesg.allocate(DMESG_SIZE);
+        }
+        _ = dmesg.write_fmt(args);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Installs the logger, keeping records of [DEFAULT_LEVEL] and less verbose.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(DEFAULT_LEVEL);
+    }
+}
+
+/// Replaces the levels of records kept with those of a list of directives,
+/// like `warn,mem=debug`. The levels are left as they are if the list is
+/// invalid.
+pub fn set_filter(spec: &str) -> Result<(), ParseError<'_>> {
+    let filter = parse(spec)?;
+    log::set_max_level(filter.max_level());
+    *FILTER.lock() = filter;
+    Ok(())
+}
+
+/// Returns the last [DMESG_SIZE] bytes of log output, without a line cut off
+/// at the start. Output from before the heap was initialized is not kept.
+pub fn dmesg() -> String {
+    DMESG.lock().text()
+}
+
+/// Sets the levels of records kept from the `log` argument of the command
+/// line, if there is one.
+///
+/// # Panics
+///
+/// Panics if called before [crate::mem::init].
+pub fn init_from_command_line() {
+    if let Some(spec) = cmdline::get("log") {
+        if let Err(error) = set_filter(spec) {
+            log::warn!("{}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse() {
+        let filter = parse("warn, mem=debug,mem::frame=off,interrupts=TRACE").unwrap();
+        assert_eq!(filter.default, LevelFilter::Warn);
+        assert_eq!(filter.level("smp"), LevelFilter::Warn);
+        assert_eq!(filter.level("mem"), LevelFilter::Debug);
+        assert_eq!(filter.level("mem::frame"), LevelFilter::Off);
+        assert_eq!(filter.level("memory"), LevelFilter::Warn);
+        assert_eq!(filter.level("interrupts::apic"), LevelFilter::Trace);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        assert_eq!(parse("").unwrap().default, DEFAULT_LEVEL);
+        assert_eq!(parse("mem=loud").err(), Some(ParseError("mem=loud")));
+        assert!(parse("verbose").is_err());
+    }
+
+    #[test_case]
+    fn test_ring() {
+        let mut ring = Ring::new();
+        write!(ring, "dropped").unwrap();
+        assert_eq!(ring.text(), "");
+
+        ring.allocate(8);
+        write!(ring, "abc\ndef\n").unwrap();
+        assert_eq!(ring.text(), "abc\ndef\n");
+        write!(ring, "gh").unwrap();
+        assert_eq!(ring.text(), "def\ngh");
+    }
+
+    #[test_case]
+    fn test_dmesg() {
+        log::warn!("kept in dmesg");
+        assert!(dmesg().lines().any(|line| line.ends_with("kept in dmesg")));
+    }
+
+    #[test_case]
+    fn test_module() {
+        assert_eq!(module("toyos::interrupts::apic"), "interrupts::apic");
+        assert_eq!(module("user"), "user");
+    }
+}
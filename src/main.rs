@@ -10,14 +10,18 @@
 
 extern crate alloc;
 
+use alloc::{format, string::String};
 use core::panic::PanicInfo;
 
 use bootloader::BootInfo;
 use pkg_version::{pkg_version_major, pkg_version_minor, pkg_version_patch};
 use toyos::{
     mem::BootInfoFrameAllocator,
-    println,
-    task::{executor::Executor, keyboard::print_keypresses, Task},
+    println, serial_print, serial_println,
+    task::{
+        executor::Executor, input::keyboard::print_keypresses, status_bar, watchdog, Priority, Task,
+    },
+    time::Duration,
 };
 use x86_64::VirtAddr;
 
@@ -27,6 +31,15 @@ const VERSION_MAJOR: u32 = pkg_version_major!();
 const VERSION_MINOR: u32 = pkg_version_minor!();
 const VERSION_PATCH: u32 = pkg_version_patch!();
 
+/// The first program to run, if the initrd has one.
+const INIT: &str = "/bin/init";
+
+/// How long the executor may go without polling the watchdog's task.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the status bar is updated.
+const STATUS_BAR_PERIOD: Duration = Duration::from_secs(1);
+
 async fn async_number() -> u32 {
     42
 }
@@ -36,6 +49,77 @@ async fn example_task() {
     println!("async number: {}", number);
 }
 
+/// Runs the commands typed on the serial console.
+async fn serial_console() {
+    loop {
+        serial_print!("> ");
+        match toyos::serial::COM1.read_line().await.trim() {
+            "" => {}
+            "shutdown" => toyos::power::shutdown(),
+            "reboot" => toyos::power::reboot(),
+            "dmesg" => serial_print!("{}", toyos::logger::dmesg()),
+            command => match command.split_once(' ') {
+                Some(("rx", path)) => receive_file(path.trim()).await,
+                _ => serial_println!("unknown command: {}", command),
+            },
+        }
+    }
+}
+
+/// Receives a file sent with XMODEM on the serial console and writes it to
+/// `path`.
+async fn receive_file(path: &str) {
+    use toyos::serial::{xmodem, COM1};
+
+    serial_println!("waiting for {} with XMODEM", path);
+    match xmodem::receive_file(&COM1, path).await {
+        Ok(size) => serial_println!("received {} bytes", size),
+        Err(error) => serial_println!("rx: {}", error),
+    }
+}
+
+/// Mounts the filesystem of every ATA disk holding one at `/mnt/<disk>`.
+fn mount_disks() {
+    for (name, device) in toyos::block::devices() {
+        if !name.starts_with("ata") {
+            continue;
+        }
+        let path = format!("/mnt/{}", name);
+        if let Ok(fs) = toyos::fs::mount_device(&path, device) {
+            println!("mounted {} ({}) at {}", name, fs, path);
+        }
+    }
+}
+
+/// Prints the make and model of the machine and its firmware, as given by
+/// the SMBIOS tables.
+fn print_machine() {
+    use toyos::smbios;
+
+    if let Some(system) = smbios::system() {
+        println!(
+            "machine: {} {}",
+            system.manufacturer.unwrap_or("unknown"),
+            system.product.unwrap_or("system"),
+        );
+    }
+    if let Some(bios) = smbios::bios() {
+        println!(
+            "BIOS: {} {} ({})",
+            bios.vendor.unwrap_or("unknown"),
+            bios.version.unwrap_or("-"),
+            bios.release_date.unwrap_or("-"),
+        );
+    }
+    let memory: u64 = smbios::memory_devices()
+        .iter()
+        .filter_map(|device| device.size_kib)
+        .sum();
+    if memory != 0 {
+        println!("{} MiB of memory installed", memory / 1024);
+    }
+}
+
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!(
         "Toy-OS version {}.{}.{}",
@@ -50,23 +134,66 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     toyos::allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    toyos::logger::init_from_command_line();
+
+    toyos::interrupts::apic::init();
+    if toyos::time::use_apic_timer(toyos::interrupts::apic::TimerMode::Periodic) {
+        println!(
+            "tick source: local APIC timer at {} Hz",
+            toyos::time::tick_frequency()
+        );
+    }
+    let aps = toyos::smp::init(&mut mapper, &mut frame_allocator);
+    println!("{} CPU(s) online", aps + 1);
+    print_machine();
+    toyos::mem::init_frame_allocator(frame_allocator);
+    toyos::fs::init();
+    println!("{}", toyos::drivers::rtc::init());
+    toyos::pci::init();
+    println!("{} PCI function(s)", toyos::pci::devices().len());
+    toyos::drivers::ata::init();
+    mount_disks();
+    toyos::drivers::e1000::init();
+    toyos::drivers::rtl8139::init();
+    toyos::drivers::virtio::net::init();
+    if toyos::drivers::virtio::rng::init() != 0 {
+        println!("entropy from virtio-rng");
+    }
+    for (name, device) in toyos::net::devices() {
+        println!("{}: {}", name, device.mac_address());
+    }
 
     #[cfg(test)]
     test_main();
 
     println!("It did not crash!");
 
+    // The kernel log stays on the first terminal, shown with Alt+F1.
+    toyos::vt::switch(toyos::vt::SHELL);
+    if let Ok(motd) = toyos::fs::read("/etc/motd") {
+        let motd = String::from_utf8_lossy(&motd);
+        toyos::vt::print(toyos::vt::SHELL, format_args!("{}", motd));
+    }
+    match toyos::process::spawn_program(INIT, &[INIT], &[]) {
+        Ok(init) => println!("started {} as process {}", INIT, init.pid()),
+        Err(toyos::process::Error::NotFound) => {}
+        Err(error) => println!("failed to start {}: {}", INIT, error),
+    }
+
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(print_keypresses()));
+    executor.spawn(Task::new(example_task()).named("example"));
+    executor.spawn(Task::with_priority(print_keypresses(), Priority::High).named("keyboard"));
+    executor.spawn(Task::new(serial_console()).named("serial console"));
+    executor.spawn(watchdog::start(WATCHDOG_TIMEOUT, watchdog::Action::Report));
+    executor.spawn(status_bar::start(STATUS_BAR_PERIOD));
     executor.run();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    toyos::hlt();
+    toyos::panic::report(info);
+    toyos::power::panic_action().run();
 }
 
 /// Panic handler for `cargo test`.
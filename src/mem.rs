@@ -1,9 +1,38 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+use crate::{early_println, early_trace};
+
+/// Virtual address at which the bootloader mapped all of physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Physical address of the level 4 page table set up by the bootloader,
+/// which kernel threads run on.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Serializes changes to the active page tables made through
+/// [with_page_table].
+static PAGE_TABLE_LOCK: Mutex<()> = Mutex::new(());
+
+/// The allocator backing [GlobalFrameAllocator], set by
+/// [init_frame_allocator].
+static BOOT_FRAMES: Mutex<Option<BootInfoFrameAllocator<'static>>> = Mutex::new(None);
+
+/// Head of the list of frames returned to [GlobalFrameAllocator]. Each free
+/// frame stores the physical address of the next one in its first 8 bytes,
+/// with [FREE_LIST_END] ending the list.
+static FREE_FRAMES: Mutex<u64> = Mutex::new(FREE_LIST_END);
+
+const FREE_LIST_END: u64 = u64::MAX;
+
 /// Initializes a new offset page table.
 ///
 /// # Safety
@@ -13,10 +42,85 @@ use x86_64::{
 /// function must only be called once to avoid aliasing `&mut` references which
 /// is undefined behavior.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+
+    early_trace!(
+        "mem: physical memory at {:#x}, level 4 table at {:#x}",
+        physical_memory_offset.as_u64(),
+        Cr3::read().0.start_address().as_u64()
+    );
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
     let l4_table = active_level_4_page_table(physical_memory_offset);
     OffsetPageTable::new(l4_table, physical_memory_offset)
 }
 
+/// Returns the virtual address through which the given physical address can
+/// be accessed.
+///
+/// The bootloader maps physical memory up to the end of the highest region in
+/// the memory map, which includes the memory mapped registers of the local
+/// APIC and I/O APIC on PC compatible machines.
+///
+/// # Panics
+///
+/// Panics if called before [init].
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        early_println!("mem: {:?} used before mem::init", addr);
+        panic!("physical memory offset not initialized");
+    }
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// Returns the frame of the kernel's level 4 page table, which every address
+/// space shares the kernel's mappings with.
+///
+/// # Panics
+///
+/// Panics if called before [init].
+pub fn kernel_page_table() -> PhysFrame {
+    let addr = KERNEL_PAGE_TABLE.load(Ordering::Relaxed);
+    assert!(addr != 0, "kernel page table not initialized");
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+/// Calls `f` with the active page tables.
+///
+/// The tables are locked for the duration of the call. The [OffsetPageTable]
+/// returned by [init] must no longer be used once this function has been
+/// called.
+///
+/// # Panics
+///
+/// Panics if called before [init].
+pub fn with_page_table<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    use x86_64::registers::control::Cr3;
+
+    with_page_table_of(Cr3::read().0, f)
+}
+
+/// Calls `f` with the page tables whose level 4 table is in `level_4`, which
+/// need not be active.
+///
+/// The tables are locked for the duration of the call, as with
+/// [with_page_table].
+///
+/// # Panics
+///
+/// Panics if called before [init].
+pub fn with_page_table_of<R>(
+    level_4: PhysFrame,
+    f: impl FnOnce(&mut OffsetPageTable<'static>) -> R,
+) -> R {
+    let _guard = PAGE_TABLE_LOCK.lock();
+    let offset = phys_to_virt(PhysAddr::new(0));
+    let table = unsafe { &mut *phys_to_virt(level_4.start_address()).as_mut_ptr::<PageTable>() };
+    let mut page_table = unsafe { OffsetPageTable::new(table, offset) };
+    f(&mut page_table)
+}
+
 /// Returns a mutable reference to the active level 4 page table.
 ///
 /// # Safety
@@ -60,10 +164,12 @@ impl<'a> BootInfoFrameAllocator<'a> {
     /// memory map is valid. The main requirement is that all frames marked as
     /// `USEABLE` are really unused.
     pub unsafe fn new(memory_map: &'a MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+        };
+        early_trace!("mem: {} usable frames", allocator.useable_frames().count());
+        allocator
     }
 
     /// Returns an iterator over all useable frames specified in the memory map.
@@ -80,7 +186,147 @@ impl<'a> BootInfoFrameAllocator<'a> {
 unsafe impl<'a> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<'a> {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         let frame = self.useable_frames().nth(self.next);
+        if frame.is_none() && self.next == self.useable_frames().count() {
+            early_println!("mem: out of boot frames");
+        }
         self.next += 1;
         frame
     }
 }
+
+/// Hands the boot frame allocator over to [GlobalFrameAllocator], after which
+/// frames can be allocated and freed from anywhere in the kernel.
+///
+/// The boot allocator must not be used elsewhere afterwards.
+pub fn init_frame_allocator(allocator: BootInfoFrameAllocator<'static>) {
+    early_trace!("mem: {} boot frames used", allocator.next);
+    *BOOT_FRAMES.lock() = Some(allocator);
+}
+
+/// The kernel's shared physical frame allocator.
+///
+/// Freed frames are reused before any frames are taken from the boot memory
+/// map. Allocation fails until [init_frame_allocator] has been called.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let mut head = FREE_FRAMES.lock();
+        if *head != FREE_LIST_END {
+            let frame = PhysFrame::containing_address(PhysAddr::new(*head));
+            *head = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+            return Some(frame);
+        }
+        drop(head);
+
+        BOOT_FRAMES.lock().as_mut()?.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let mut head = FREE_FRAMES.lock();
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u64>()
+            .write(*head);
+        *head = frame.start_address().as_u64();
+    }
+}
+
+/// Physically contiguous, zeroed memory for devices to access through DMA,
+/// freed when dropped.
+pub struct DmaRegion {
+    start: PhysFrame,
+    frames: usize,
+}
+
+impl DmaRegion {
+    /// Allocates at least `size` bytes, rounded up to whole frames. Returns
+    /// `None` if no run of contiguous frames is left.
+    ///
+    /// Frames are taken from the boot memory map, where consecutive frames
+    /// are contiguous within a region. Frames skipped over at the end of a
+    /// region are freed.
+    pub fn new(size: usize) -> Option<DmaRegion> {
+        let frames = size.div_ceil(4096).max(1);
+        let mut boot = BOOT_FRAMES.lock();
+        let boot = boot.as_mut()?;
+        let mut start = boot.allocate_frame()?;
+        let mut count = 1;
+        while count < frames {
+            let frame = boot.allocate_frame();
+            if frame == Some(start + count as u64) {
+                count += 1;
+                continue;
+            }
+            for skipped in PhysFrame::range(start, start + count as u64) {
+                unsafe { GlobalFrameAllocator.deallocate_frame(skipped) };
+            }
+            start = frame?;
+            count = 1;
+        }
+
+        let region = DmaRegion { start, frames };
+        unsafe { core::ptr::write_bytes(region.as_mut_ptr::<u8>(), 0, region.size()) };
+        Some(region)
+    }
+
+    /// Returns the physical address of the start of the region, to hand to
+    /// the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// Returns a pointer to the start of the region.
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        phys_to_virt(self.phys_addr()).as_mut_ptr()
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.frames * 4096
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        for frame in PhysFrame::range(self.start, self.start + self.frames as u64) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_freed_frames_are_reused() {
+        let mut allocator = GlobalFrameAllocator;
+        let first = allocator.allocate_frame().expect("out of frames");
+        let second = allocator.allocate_frame().expect("out of frames");
+        assert_ne!(first, second);
+
+        unsafe {
+            allocator.deallocate_frame(first);
+            allocator.deallocate_frame(second);
+        }
+        assert_eq!(allocator.allocate_frame(), Some(second));
+        assert_eq!(allocator.allocate_frame(), Some(first));
+        unsafe {
+            allocator.deallocate_frame(first);
+            allocator.deallocate_frame(second);
+        }
+    }
+
+    #[test_case]
+    fn test_dma_region() {
+        let region = DmaRegion::new(3 * 4096 - 1).expect("out of frames");
+        assert_eq!(region.size(), 3 * 4096);
+        assert!(region.phys_addr().is_aligned(4096u64));
+        let bytes =
+            unsafe { core::slice::from_raw_parts(region.as_mut_ptr::<u8>(), region.size()) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,179 @@
+//! Network devices.
+//!
+//! A [NetworkDevice] sends and receives Ethernet frames, such as a NIC.
+//! A network stack moves frames through the trait without knowing which
+//! driver is behind it.
+//!
+//! Drivers [register] their devices under a name like `eth0`, which is how
+//! the rest of the kernel finds them. Their interrupt handlers call
+//! [frame_received] when frames arrive, waking the threads blocked in
+//! [receive].
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt;
+
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::{interrupts, sync::IrqSpinlock, task::thread::WaitQueue};
+
+/// Largest Ethernet frame without a VLAN tag, excluding the checksum: a
+/// 14 byte header and 1500 bytes of payload.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// Smallest Ethernet frame, excluding the checksum. Devices pad shorter
+/// frames.
+pub const MIN_FRAME_SIZE: usize = 60;
+
+/// Reasons network device operations fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The frame is longer than [MAX_FRAME_SIZE], or the buffer is too small
+    /// for the received frame.
+    TooLarge,
+    /// The device has no room for another frame to send.
+    Busy,
+    /// The device reported an error.
+    Io,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::TooLarge => "frame too large",
+            Error::Busy => "device busy",
+            Error::Io => "input/output error",
+        })
+    }
+}
+
+/// The hardware address of a network device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// The address frames are sent to for every device to receive them.
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// A device sending and receiving Ethernet frames.
+pub trait NetworkDevice: Send + Sync {
+    /// Returns the address of the device, which frames it sends come from.
+    fn mac_address(&self) -> MacAddress;
+
+    /// Sends `frame`, which starts with the destination address and excludes
+    /// the checksum the device appends. Returns without waiting for the
+    /// frame to be sent.
+    fn send_frame(&self, frame: &[u8]) -> Result<(), Error>;
+
+    /// Takes the oldest frame received and not yet taken, copying it into
+    /// `buf`. Returns its length, or `None` if there is no such frame.
+    fn recv_frame(&self, buf: &mut [u8]) -> Result<Option<usize>, Error>;
+}
+
+/// Threads waiting in [receive] for a frame to arrive.
+static RECEIVED: WaitQueue = WaitQueue::new();
+
+/// Wakes the threads waiting for frames. Called by drivers when a device
+/// interrupts after receiving frames, which may be in interrupt context.
+pub fn frame_received() {
+    RECEIVED.wake_all();
+}
+
+/// Adds a controller and its IRQ to the `devices` of the driver named
+/// `driver`, and adds the driver's interrupt `handler` for the IRQ unless
+/// another of its controllers shares it. Without its interrupt, frames can
+/// still be polled for with [NetworkDevice::recv_frame].
+pub fn add_controller<T>(
+    driver: &str,
+    devices: &IrqSpinlock<Vec<(T, u8)>>,
+    controller: T,
+    irq: u8,
+    handler: interrupts::IrqHandler,
+) {
+    let shared = {
+        let mut devices = devices.lock();
+        let shared = devices.iter().any(|&(_, line)| line == irq);
+        devices.push((controller, irq));
+        shared
+    };
+    if shared {
+        info!("{}: sharing IRQ {}", driver, irq);
+    } else if interrupts::add_irq_handler(irq, handler) {
+        info!("{}: using IRQ {}", driver, irq);
+    } else {
+        warn!("{}: cannot handle IRQ {}, polling for frames", driver, irq);
+    }
+}
+
+/// Blocks until `device` received a frame, then takes it like
+/// [NetworkDevice::recv_frame].
+pub fn receive(device: &dyn NetworkDevice, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut result = Ok(None);
+    RECEIVED.wait_until(|| {
+        result = device.recv_frame(buf);
+        !matches!(result, Ok(None))
+    });
+    result.map(Option::unwrap)
+}
+
+/// Registered network devices, by name.
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn NetworkDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Makes `device` available under `name`, replacing any device previously
+/// registered under it.
+pub fn register(name: &str, device: Arc<dyn NetworkDevice>) {
+    DEVICES.lock().insert(name.to_string(), device);
+}
+
+/// Returns the device registered under `name`.
+pub fn get(name: &str) -> Option<Arc<dyn NetworkDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// Returns the registered devices, ordered by name.
+pub fn devices() -> Vec<(String, Arc<dyn NetworkDevice>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
+
+/// Registers `device` under the first free name `eth<n>`, returning it.
+pub fn register_ethernet(device: Arc<dyn NetworkDevice>) -> String {
+    let mut devices = DEVICES.lock();
+    let name = (0..)
+        .map(|index| alloc::format!("eth{}", index))
+        .find(|name| !devices.contains_key(name))
+        .unwrap();
+    devices.insert(name.clone(), device);
+    name
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_mac_address() {
+        let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert_eq!(alloc::format!("{}", mac), "52:54:00:12:34:56");
+        assert_eq!(
+            alloc::format!("{}", MacAddress::BROADCAST),
+            "ff:ff:ff:ff:ff:ff"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! The report of a kernel panic.
+//!
+//! [report] fills the screen with the panic message, its location, the
+//! registers and a backtrace in a fixed layout, and mirrors the same report
+//! to serial, where it survives the machine stopping.
+//!
+//! The backtrace follows the chain of saved frame pointers, which the kernel
+//! is built to keep, and lists return addresses only; they can be looked up
+//! with `addr2line` against the kernel binary.
+
+use core::{fmt, panic::PanicInfo};
+
+use crate::{
+    interrupts::fault::{ExceptionFrame, RegisterDump},
+    serial_println,
+    task::executor,
+    vga, vt,
+};
+
+/// Maximum number of return addresses in a backtrace.
+pub const MAX_FRAMES: usize = 16;
+
+/// How far above the first frame the chain of frame pointers may lead before
+/// it is taken to have left the stack.
+const MAX_STACK_SPAN: u64 = 1024 * 1024;
+
+/// Return addresses of the calls leading to the caller, innermost first.
+pub struct Backtrace {
+    addresses: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Walks the frames of the caller's stack.
+    #[inline(always)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp) };
+        Backtrace::from_frame_pointer(rbp)
+    }
+
+    /// Walks the frames starting at the given frame pointer. The walk stops at
+    /// a frame pointer which is null, misaligned or does not lead up the
+    /// stack.
+    fn from_frame_pointer(mut rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace {
+            addresses: [0; MAX_FRAMES],
+            len: 0,
+        };
+        let bottom = rbp;
+        while backtrace.len < MAX_FRAMES
+            && rbp != 0
+            && rbp.is_multiple_of(8)
+            && rbp - bottom < MAX_STACK_SPAN
+        {
+            // A frame holds the caller's frame pointer followed by the
+            // return address.
+            let frame = rbp as *const u64;
+            let (next, address) = unsafe { (*frame, *frame.add(1)) };
+            if address == 0 {
+                break;
+            }
+            backtrace.addresses[backtrace.len] = address;
+            backtrace.len += 1;
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        backtrace
+    }
+
+    /// Returns the return addresses, innermost first.
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    /// Lists the addresses three to a line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, address) in self.addresses().iter().enumerate() {
+            let separator = if i % 3 == 2 { "\n" } else { "  " };
+            write!(f, "#{:<2} {:016x}{}", i, address, separator)?;
+        }
+        if !self.len.is_multiple_of(3) {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything known about a panic, in the layout of the panic screen.
+struct Report<'a> {
+    info: &'a PanicInfo<'a>,
+    frame: ExceptionFrame,
+    backtrace: Backtrace,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "KERNEL PANIC: {}", self.info.message())?;
+        match self.info.location() {
+            Some(location) => writeln!(f, "at {}", location)?,
+            None => writeln!(f, "at an unknown location")?,
+        }
+        match executor::current_task() {
+            Some(task) => writeln!(f, "while polling task {}", task)?,
+            None => writeln!(f)?,
+        }
+        writeln!(f, "{}", RegisterDump::new("REGISTERS", &self.frame))?;
+        writeln!(f, "backtrace:")?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+/// Reports a panic to serial, then on the screen of the first virtual
+/// terminal. The screen is left as it is if it cannot be taken over.
+pub fn report(info: &PanicInfo) {
+    x86_64::instructions::interrupts::disable();
+    let report = Report {
+        info,
+        frame: ExceptionFrame::capture(),
+        backtrace: Backtrace::capture(),
+    };
+    serial_println!("{}", report);
+    vt::switch(vt::LOG);
+    vga::show_panic_screen(&report);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_backtrace() {
+        let backtrace = Backtrace::capture();
+        assert!(!backtrace.addresses().is_empty());
+        assert!(backtrace.addresses().len() <= MAX_FRAMES);
+    }
+
+    #[test_case]
+    fn test_backtrace_stops_at_null() {
+        assert!(Backtrace::from_frame_pointer(0).addresses().is_empty());
+    }
+}
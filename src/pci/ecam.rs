@@ -0,0 +1,84 @@
+//! Memory-mapped configuration space (ECAM).
+//!
+//! PCI Express maps 4 KiB of configuration space per function into physical
+//! memory at the regions listed by the [ACPI MCFG](crate::acpi::mcfg). Unlike
+//! the legacy ports, which only reach the first 256 bytes, this also reaches
+//! the extended capabilities starting at offset 0x100.
+//!
+//! Only segment group 0 is used, as [Address] has no segment.
+
+use alloc::vec::Vec;
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use super::Address;
+use crate::{
+    acpi::{self, mcfg::ConfigRegion},
+    mem::phys_to_virt,
+};
+
+/// Size of the configuration space of a function.
+pub const CONFIG_SPACE_SIZE: u16 = 4096;
+
+static REGIONS: Once<Vec<ConfigRegion>> = Once::new();
+
+/// Looks up the regions in the MCFG. Until this is called, and on machines
+/// without an MCFG, configuration space is only accessed through ports.
+pub fn init() {
+    REGIONS.call_once(|| {
+        if !acpi::is_present() {
+            return Vec::new();
+        }
+        acpi::mcfg::regions()
+            .into_iter()
+            .filter(|region| region.segment == 0)
+            .collect()
+    });
+}
+
+/// Returns whether configuration space is memory-mapped.
+pub fn is_enabled() -> bool {
+    REGIONS.get().is_some_and(|regions| !regions.is_empty())
+}
+
+/// Returns the physical address of the register at `offset` of the function
+/// at `address`, if a region covers its bus.
+pub(super) fn register(address: Address, offset: u16) -> Option<PhysAddr> {
+    let region = REGIONS
+        .get()?
+        .iter()
+        .find(|region| (region.start_bus..=region.end_bus).contains(&address.bus))?;
+    Some(PhysAddr::new(region.base + offset_of(address, offset)))
+}
+
+/// Returns the offset of a register from the base of a region.
+fn offset_of(address: Address, offset: u16) -> u64 {
+    (address.bus as u64) << 20
+        | (address.device as u64) << 15
+        | (address.function as u64) << 12
+        | (offset & !3) as u64
+}
+
+pub(super) fn read_u32(register: PhysAddr) -> u32 {
+    unsafe { phys_to_virt(register).as_ptr::<u32>().read_volatile() }
+}
+
+pub(super) fn write_u32(register: PhysAddr, value: u32) {
+    unsafe {
+        phys_to_virt(register)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_offset_of() {
+        assert_eq!(offset_of(Address::new(0, 0, 0), 0x100), 0x100);
+        assert_eq!(offset_of(Address::new(1, 2, 3), 0x12), 0x11_3010);
+    }
+}
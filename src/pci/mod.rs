@@ -0,0 +1,533 @@
+//! The PCI bus.
+//!
+//! Every PCI function has configuration space describing it: who made it,
+//! what kind of device it is and which memory and I/O ranges it decodes
+//! through its base address registers (BARs). Configuration space is reached
+//! through memory when the firmware maps it, see [ecam], and otherwise
+//! through the address and data ports at `0xcf8` and `0xcfc`, which only
+//! reach its first 256 bytes.
+//!
+//! Functions list optional features such as MSI as capabilities, which
+//! [Device::capabilities] and [Device::extended_capabilities] walk.
+//!
+//! [init] scans every bus once during boot and keeps the functions it finds
+//! in a registry, which drivers query with [find] or [find_class] rather than
+//! scanning again.
+//!
+//! See: https://wiki.osdev.org/PCI
+
+use alloc::vec::Vec;
+use core::{fmt, iter};
+
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::sync::IrqSpinlock;
+
+pub mod ecam;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Vendor ID read from functions which do not exist.
+const NO_VENDOR: u16 = 0xffff;
+
+const OFFSET_VENDOR_ID: u16 = 0x00;
+const OFFSET_COMMAND: u16 = 0x04;
+const OFFSET_STATUS: u16 = 0x06;
+const OFFSET_CLASS: u16 = 0x08;
+const OFFSET_HEADER_TYPE: u16 = 0x0e;
+const OFFSET_BAR0: u16 = 0x10;
+const OFFSET_SECONDARY_BUS: u16 = 0x19;
+const OFFSET_CAPABILITIES: u16 = 0x34;
+const OFFSET_INTERRUPT_LINE: u16 = 0x3c;
+/// Offset of the first extended capability, past the legacy space.
+const OFFSET_EXTENDED_CAPABILITIES: u16 = 0x100;
+
+/// Size of the configuration space reachable through the ports.
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 256;
+
+/// Bit of the header type set by devices with more than one function.
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// Bits of the command register enabling I/O and memory decoding.
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+/// Bit of the command register allowing the device to master the bus, as
+/// DMA needs.
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Bit of the status register set by functions with a capability list.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Capability ID of message signaled interrupts.
+pub const CAPABILITY_MSI: u8 = 0x05;
+/// Capability ID of the PCI Express capability.
+pub const CAPABILITY_PCIE: u8 = 0x10;
+/// Capability ID of extended message signaled interrupts.
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// Upper bound of the length of a capability list, which stops walking a
+/// list with a loop in it.
+const MAX_CAPABILITIES: usize = 48;
+
+/// Serializes the two-step accesses through the address and data ports.
+static PORTS: IrqSpinlock<(Port<u32>, Port<u32>)> =
+    IrqSpinlock::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// The location of a function on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address {
+    pub bus: u8,
+    /// Between 0 and 31.
+    pub device: u8,
+    /// Between 0 and 7.
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Address {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Reads the aligned 32-bit register at `offset` of the function's
+    /// configuration space. Registers which cannot be reached read as all
+    /// ones, like those of functions which do not exist.
+    pub fn read_u32(self, offset: u16) -> u32 {
+        if let Some(register) = ecam::register(self, offset) {
+            return ecam::read_u32(register);
+        }
+        if offset >= LEGACY_CONFIG_SPACE_SIZE {
+            return u32::MAX;
+        }
+        let mut ports = PORTS.lock();
+        unsafe {
+            ports.0.write(self.config_address(offset));
+            ports.1.read()
+        }
+    }
+
+    /// Writes the aligned 32-bit register at `offset` of the function's
+    /// configuration space. Writes to registers which cannot be reached are
+    /// dropped.
+    pub fn write_u32(self, offset: u16, value: u32) {
+        if let Some(register) = ecam::register(self, offset) {
+            return ecam::write_u32(register, value);
+        }
+        if offset >= LEGACY_CONFIG_SPACE_SIZE {
+            return;
+        }
+        let mut ports = PORTS.lock();
+        unsafe {
+            ports.0.write(self.config_address(offset));
+            ports.1.write(value);
+        }
+    }
+
+    pub fn read_u16(self, offset: u16) -> u16 {
+        (self.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u16) -> u8 {
+        (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Writes a 16-bit register, preserving the other half of the 32-bit
+    /// register containing it.
+    pub fn write_u16(self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset & !3) & !(0xffff << shift);
+        self.write_u32(offset & !3, old | (value as u32) << shift);
+    }
+
+    fn config_address(self, offset: u16) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & !3) as u32
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// The kind of a device, made of its class, subclass and programming
+/// interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassCode {
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl ClassCode {
+    /// Returns a description of the class and subclass, or of the class
+    /// alone for less common subclasses.
+    pub fn name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "network controller",
+            (0x03, 0x00) => "VGA controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x05, _) => "memory controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI-to-PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x08, _) => "system peripheral",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus controller",
+            (0x0c, _) => "serial bus controller",
+            _ => "unknown device",
+        }
+    }
+}
+
+/// A range decoded by a function, as described by one of its base address
+/// registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Physical memory, usually device registers.
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    /// I/O ports.
+    Io { port: u16, size: u16 },
+}
+
+impl Bar {
+    /// Decodes a BAR from its value and the value read back after writing
+    /// all ones to it, which has zeroes in the bits selecting an address
+    /// within the range. For 64-bit memory BARs, both values include the
+    /// following register in their upper half.
+    fn decode(value: u64, mask: u64) -> Option<Bar> {
+        if value & 1 == 1 {
+            let mask = (mask as u16) & !0x3;
+            return (mask != 0).then(|| Bar::Io {
+                port: value as u16 & !0x3,
+                size: (!mask).wrapping_add(1),
+            });
+        }
+        let is_64bit = (value >> 1) & 0x3 == 0x2;
+        let (value, mask) = if is_64bit {
+            (value, mask & !0xf)
+        } else {
+            // The register only covers the lower 32 bits of the address.
+            let mask = match mask as u32 & !0xf {
+                0 => 0,
+                mask => mask as u64 | 0xffff_ffff_0000_0000,
+            };
+            (value as u32 as u64, mask)
+        };
+        (mask != 0).then(|| Bar::Memory {
+            address: value & !0xf,
+            size: (!mask).wrapping_add(1),
+            prefetchable: value & 0x8 != 0,
+        })
+    }
+}
+
+/// A function found on the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: ClassCode,
+    pub revision: u8,
+    pub header_type: u8,
+    /// The ranges of the base address registers which are implemented. A
+    /// 64-bit memory BAR takes up two registers and is stored at the index
+    /// of the first, leaving the second `None`.
+    pub bars: [Option<Bar>; 6],
+    /// The legacy PIC interrupt routed to the function by the firmware.
+    pub interrupt_line: u8,
+    /// The interrupt pin used by the function, 1 to 4 for INTA# to INTD#, or
+    /// 0 if it uses none.
+    pub interrupt_pin: u8,
+}
+
+impl Device {
+    /// Reads the description of the function at `address`, or returns `None`
+    /// if there is none.
+    pub fn probe(address: Address) -> Option<Device> {
+        let ids = address.read_u32(OFFSET_VENDOR_ID);
+        if ids as u16 == NO_VENDOR {
+            return None;
+        }
+        let class = address.read_u32(OFFSET_CLASS);
+        let header_type = address.read_u8(OFFSET_HEADER_TYPE) & !HEADER_MULTI_FUNCTION;
+        let interrupt = address.read_u16(OFFSET_INTERRUPT_LINE);
+        let bar_count = match header_type {
+            HEADER_TYPE_DEVICE => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        Some(Device {
+            address,
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            class: ClassCode {
+                class: (class >> 24) as u8,
+                subclass: (class >> 16) as u8,
+                prog_if: (class >> 8) as u8,
+            },
+            revision: class as u8,
+            header_type,
+            bars: read_bars(address, bar_count),
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+        })
+    }
+
+    /// Returns the ID and offset of every capability in the list in the
+    /// legacy configuration space.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let address = self.address;
+        let first = if address.read_u16(OFFSET_STATUS) & STATUS_CAPABILITIES != 0 {
+            (address.read_u8(OFFSET_CAPABILITIES) & !3) as u16
+        } else {
+            0
+        };
+        iter::successors((first != 0).then_some(first), move |&offset| {
+            let next = (address.read_u8(offset + 1) & !3) as u16;
+            (next != 0).then_some(next)
+        })
+        .take(MAX_CAPABILITIES)
+        .map(move |offset| (address.read_u8(offset), offset))
+    }
+
+    /// Returns the ID and offset of every capability in the list in the
+    /// extended configuration space, which is empty unless it is
+    /// [memory-mapped](ecam).
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let address = self.address;
+        let read_header = move |offset: u16| {
+            let header = address.read_u32(offset);
+            // All ones where nothing can be read, and zero where the list is
+            // empty.
+            (header != 0 && header != u32::MAX).then_some(header)
+        };
+        iter::successors(
+            read_header(OFFSET_EXTENDED_CAPABILITIES)
+                .map(|header| (header, OFFSET_EXTENDED_CAPABILITIES)),
+            move |&(header, _)| {
+                let next = (header >> 20) as u16 & !3;
+                if !(OFFSET_EXTENDED_CAPABILITIES..ecam::CONFIG_SPACE_SIZE).contains(&next) {
+                    return None;
+                }
+                read_header(next).map(|header| (header, next))
+            },
+        )
+        .take(MAX_CAPABILITIES)
+        .map(|(header, offset)| (header as u16, offset))
+    }
+
+    /// Returns the offset of the first capability with the given ID.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|&(capability, _)| capability == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Allows the function to access memory on its own, as needed for DMA.
+    pub fn enable_bus_mastering(&self) {
+        let command = self.address.read_u16(OFFSET_COMMAND);
+        self.address
+            .write_u16(OFFSET_COMMAND, command | COMMAND_BUS_MASTER);
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.class.name()
+        )
+    }
+}
+
+/// Reads the first `count` BARs of the function at `address`.
+///
+/// Sizing a BAR temporarily changes the range it decodes, so decoding is
+/// disabled in the command register meanwhile.
+fn read_bars(address: Address, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = address.read_u16(OFFSET_COMMAND);
+    address.write_u16(
+        OFFSET_COMMAND,
+        command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+
+    let mut index = 0;
+    while index < count {
+        let offset = OFFSET_BAR0 + index as u16 * 4;
+        let (value, mask) = size_register(address, offset);
+        let is_64bit = value & 0x7 == 0x4;
+        if is_64bit && index + 1 < count {
+            let (high, high_mask) = size_register(address, offset + 4);
+            let value = (high as u64) << 32 | value as u64;
+            let mask = (high_mask as u64) << 32 | mask as u64;
+            bars[index] = Bar::decode(value, mask);
+            index += 2;
+        } else {
+            bars[index] = Bar::decode(value as u64, mask as u64);
+            index += 1;
+        }
+    }
+
+    address.write_u16(OFFSET_COMMAND, command);
+    bars
+}
+
+/// Returns the value of the register at `offset` and the value read back
+/// after writing all ones to it, then restores it.
+fn size_register(address: Address, offset: u16) -> (u32, u32) {
+    let value = address.read_u32(offset);
+    address.write_u32(offset, u32::MAX);
+    let mask = address.read_u32(offset);
+    address.write_u32(offset, value);
+    (value, mask)
+}
+
+/// Returns every function on the bus, ordered by address.
+///
+/// Buses are found by following PCI-to-PCI bridges from bus 0, and every
+/// function of a device is checked only if its first one is multi-function.
+pub fn scan() -> Vec<Device> {
+    let mut devices = Vec::new();
+    scan_bus(0, &mut devices);
+    devices.sort_by_key(|device| device.address);
+    devices
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<Device>) {
+    for device in 0..32 {
+        let Some(first) = Device::probe(Address::new(bus, device, 0)) else {
+            continue;
+        };
+        let functions = if first.address.read_u8(OFFSET_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+            8
+        } else {
+            1
+        };
+        let found = iter::once(first).chain(
+            (1..functions)
+                .filter_map(|function| Device::probe(Address::new(bus, device, function))),
+        );
+        for found in found {
+            if found.header_type == HEADER_TYPE_BRIDGE {
+                let secondary = found.address.read_u8(OFFSET_SECONDARY_BUS);
+                // A secondary bus at or below this one would be scanned
+                // forever.
+                if secondary > bus {
+                    scan_bus(secondary, devices);
+                }
+            }
+            devices.push(found);
+        }
+    }
+}
+
+/// Every function found by [init].
+static DEVICES: Once<Vec<Device>> = Once::new();
+
+/// Scans the bus and fills the registry, using memory-mapped configuration
+/// space if available. Later calls do nothing.
+///
+/// Must be called after [crate::mem::init].
+pub fn init() {
+    ecam::init();
+    DEVICES.call_once(scan);
+}
+
+/// Returns the functions found by [init], or none before it has been called.
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the first function with the given vendor and device IDs.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<&'static Device> {
+    devices()
+        .iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// Returns the functions of the given class and subclass.
+pub fn find_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static Device> {
+    devices()
+        .iter()
+        .filter(move |device| device.class.class == class && device.class.subclass == subclass)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_decode_bars() {
+        assert_eq!(
+            Bar::decode(0xfebf_0000, 0xffff_f000),
+            Some(Bar::Memory {
+                address: 0xfebf_0000,
+                size: 0x1000,
+                prefetchable: false,
+            })
+        );
+        assert_eq!(
+            Bar::decode(0xc041, 0xffff_ffc1),
+            Some(Bar::Io {
+                port: 0xc040,
+                size: 0x40,
+            })
+        );
+        assert_eq!(
+            Bar::decode(0x1_0000_000c, 0xffff_ffff_ffe0_000c),
+            Some(Bar::Memory {
+                address: 0x1_0000_0000,
+                size: 0x20_0000,
+                prefetchable: true,
+            })
+        );
+        assert_eq!(Bar::decode(0, 0), None);
+    }
+
+    #[test_case]
+    fn test_scan_finds_host_bridge() {
+        init();
+        let host = devices()
+            .iter()
+            .find(|device| device.address == Address::new(0, 0, 0))
+            .expect("no device at 00:00.0");
+        assert_eq!(host.class.name(), "host bridge");
+        assert_eq!(find(host.vendor_id, host.device_id), Some(host));
+        assert!(find_class(0x06, 0x00).any(|device| device == host));
+        assert_eq!(alloc::format!("{}", host.address), "00:00.0");
+        if !ecam::is_enabled() {
+            assert_eq!(host.address.read_u32(0x100), u32::MAX);
+            assert_eq!(host.extended_capabilities().count(), 0);
+        }
+    }
+}
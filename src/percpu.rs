@@ -0,0 +1,179 @@
+//! The `percpu` module provides storage for data which exists once per CPU.
+//!
+//! Each CPU's GS base register points at a small [CpuLocal] block identifying
+//! the CPU. [PerCpu] uses the ID stored there to select the calling CPU's
+//! instance of a value, lazily constructing it on first access.
+//!
+//! User code can change the GS base by loading a segment selector, so the
+//! kernel's value is only active in ring 0. Before entering user mode the
+//! kernel executes `swapgs`, which parks its GS base in the `KernelGsBase`
+//! MSR, and every entry from ring 3 swaps it back before touching per-CPU
+//! data: assembly stubs do so themselves, and interrupt handlers written in
+//! Rust hold a [KernelGs] guard.
+//!
+//! See: https://wiki.osdev.org/SWAPGS
+
+use core::{arch::asm, mem::offset_of, sync::atomic::AtomicU64};
+
+use spin::Once;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+/// Maximum number of CPUs supported by the kernel.
+pub const MAX_CPUS: usize = 8;
+
+/// ID of the bootstrap processor.
+pub const BSP_ID: usize = 0;
+
+/// Data addressed through the GS base register.
+///
+/// The layout is relied upon by [cpu_id], which reads the ID from offset 0,
+/// and by the user mode entry and exit paths, which use the stack fields.
+#[repr(C)]
+struct CpuLocal {
+    id: usize,
+    /// Stack used by the kernel while servicing the user mode session running
+    /// on this CPU.
+    kernel_stack: AtomicU64,
+    /// Scratch slot for the user stack pointer on system call entry.
+    user_stack: AtomicU64,
+}
+
+/// Offset of [CpuLocal::kernel_stack] from the GS base.
+pub(crate) const KERNEL_STACK_OFFSET: usize = offset_of!(CpuLocal, kernel_stack);
+
+/// Offset of [CpuLocal::user_stack] from the GS base.
+pub(crate) const USER_STACK_OFFSET: usize = offset_of!(CpuLocal, user_stack);
+
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = {
+    let mut locals = [const {
+        CpuLocal {
+            id: 0,
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+        }
+    }; MAX_CPUS];
+    let mut id = 0;
+    while id < MAX_CPUS {
+        locals[id].id = id;
+        id += 1;
+    }
+    locals
+};
+
+/// Points the calling CPU's GS base at the local data for CPU `id`.
+///
+/// # Safety
+///
+/// Must be called exactly once on each CPU, with a unique ID, before any
+/// per-CPU data is accessed on that CPU.
+///
+/// # Panics
+///
+/// Panics if `id` is not less than [MAX_CPUS].
+pub unsafe fn init(id: usize) {
+    assert!(id < MAX_CPUS, "CPU ID {} exceeds MAX_CPUS", id);
+    GsBase::write(VirtAddr::from_ptr(&CPU_LOCALS[id]));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Returns `true` if `addr` is the GS base of one of the CPUs.
+fn is_cpu_local(addr: VirtAddr) -> bool {
+    let start = VirtAddr::from_ptr(&CPU_LOCALS);
+    (start..start + core::mem::size_of_val(&CPU_LOCALS)).contains(&addr)
+}
+
+/// Makes the kernel's GS base active for the lifetime of an interrupt
+/// handler, restoring the interrupted code's on drop.
+///
+/// Create the guard before anything else in the handler, since most kernel
+/// code relies on per-CPU data.
+pub(crate) struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// Swaps in the kernel's GS base if the interrupted code, with the given
+    /// code segment selector, ran in ring 3.
+    #[inline]
+    pub(crate) fn enter(code_segment: u64) -> Self {
+        Self::swap_if(code_segment & 3 == 3)
+    }
+
+    /// Swaps in the kernel's GS base unless it is already active. Handlers
+    /// of NMIs and machine checks use this, as those may arrive in ring 0
+    /// right after an entry stub was entered from ring 3 but before it
+    /// executed `swapgs`.
+    pub(crate) fn enter_paranoid() -> Self {
+        Self::swap_if(!is_cpu_local(GsBase::read()))
+    }
+
+    #[inline]
+    fn swap_if(swap: bool) -> Self {
+        if swap {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGs { swapped: swap }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline]
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+/// Returns the calling CPU's [CpuLocal::kernel_stack].
+pub(crate) fn kernel_stack() -> &'static AtomicU64 {
+    &CPU_LOCALS[cpu_id()].kernel_stack
+}
+
+/// Returns the ID of the calling CPU.
+#[inline]
+pub fn cpu_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) id, options(nostack, readonly, preserves_flags));
+    }
+    id
+}
+
+/// A value with a separate instance for every CPU.
+///
+/// Instances are created on first access from the owning CPU (or via
+/// [PerCpu::get_for]) using the function supplied to [PerCpu::new].
+pub struct PerCpu<T> {
+    slots: [Once<T>; MAX_CPUS],
+    init: fn(usize) -> T,
+}
+
+impl<T> PerCpu<T> {
+    /// Creates per-CPU storage which constructs each CPU's instance by
+    /// calling `init` with the CPU's ID.
+    pub const fn new(init: fn(usize) -> T) -> Self {
+        PerCpu {
+            slots: [const { Once::new() }; MAX_CPUS],
+            init,
+        }
+    }
+
+    /// Returns the calling CPU's instance.
+    #[inline]
+    pub fn get(&self) -> &T {
+        self.get_for(cpu_id())
+    }
+
+    /// Returns the instance belonging to the given CPU.
+    pub fn get_for(&self, cpu: usize) -> &T {
+        self.slots[cpu].call_once(|| (self.init)(cpu))
+    }
+
+    /// Returns an iterator over the instances of every CPU which have been
+    /// initialized.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(Once::get)
+    }
+}
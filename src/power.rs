@@ -0,0 +1,195 @@
+//! Powering the machine off and restarting it.
+//!
+//! [shutdown] enters the ACPI soft-off state S5 by writing its sleep type to
+//! the PM1 control registers given by the FADT. Without ACPI, or if that
+//! fails, it tries the ports through which QEMU, Bochs and VirtualBox power
+//! off.
+//!
+//! [reboot] asks the 8042 keyboard controller to pulse the CPU's reset line,
+//! then writes the ACPI reset register, and finally triple faults, which
+//! resets the CPU on every PC.
+//!
+//! The kernel's panic handler finishes with the [PanicAction] set by
+//! [set_panic_action], which halts by default.
+//!
+//! See: https://wiki.osdev.org/Shutdown and https://wiki.osdev.org/Reboot
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::{
+        self,
+        fadt::{self, ResetRegister},
+    },
+    mem::phys_to_virt,
+};
+
+/// Bit of the PM1 control registers set once ACPI is enabled.
+const PM1_SCI_ENABLED: u16 = 1 << 0;
+/// Bits of the PM1 control registers selecting the sleep type.
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE: u16 = 0b111 << PM1_SLEEP_TYPE_SHIFT;
+/// Bit of the PM1 control registers entering the selected sleep state.
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// Reads the 8042 controller's status, and takes its commands when written.
+const CONTROLLER_COMMAND_PORT: u16 = 0x64;
+/// Bit of the 8042 controller's status set while it has not taken the last
+/// byte written.
+const CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+/// Makes the 8042 controller pulse the CPU's reset line.
+const CONTROLLER_PULSE_RESET: u8 = 0xfe;
+
+/// Number of times the PM1a control register is polled for ACPI to be
+/// enabled, and the 8042 controller for taking a command.
+const POLL_LIMIT: usize = 100_000;
+
+/// Ports and values which power off emulators without ACPI: newer QEMU,
+/// Bochs and older QEMU, then VirtualBox.
+const EMULATOR_PORTS: [(u16, u16); 3] = [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+
+/// What the kernel does after reporting a panic.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Halts the CPU, leaving the message on screen.
+    Halt,
+    /// Powers the machine off.
+    Shutdown,
+    /// Restarts the machine.
+    Reboot,
+}
+
+impl PanicAction {
+    /// Carries out the action.
+    pub fn run(self) -> ! {
+        match self {
+            PanicAction::Halt => crate::hlt(),
+            PanicAction::Shutdown => shutdown(),
+            PanicAction::Reboot => reboot(),
+        }
+    }
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+/// Sets what the kernel does after reporting a panic.
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// Returns what the kernel does after reporting a panic.
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        action if action == PanicAction::Shutdown as u8 => PanicAction::Shutdown,
+        action if action == PanicAction::Reboot as u8 => PanicAction::Reboot,
+        _ => PanicAction::Halt,
+    }
+}
+
+/// Powers the machine off. Halts if every way of doing so fails.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if acpi::is_present() {
+        acpi_shutdown();
+    }
+    for (port, value) in EMULATOR_PORTS {
+        unsafe { Port::new(port).write(value) };
+    }
+    crate::hlt()
+}
+
+/// Enters the soft-off state through ACPI, returning if it cannot.
+fn acpi_shutdown() {
+    let Some(fadt) = fadt::find() else {
+        return;
+    };
+    let Some(sleep_type) = fadt::s5_sleep_type(&fadt) else {
+        return;
+    };
+
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control);
+    if unsafe { pm1a.read() } & PM1_SCI_ENABLED == 0 && fadt.smi_command != 0 {
+        // The firmware still owns the registers; ask it to hand them over.
+        unsafe { Port::new(fadt.smi_command).write(fadt.acpi_enable) };
+        (0..POLL_LIMIT).find(|_| unsafe { pm1a.read() } & PM1_SCI_ENABLED != 0);
+    }
+
+    let enter = |port: u16, sleep_type: u8| {
+        let mut control = Port::<u16>::new(port);
+        unsafe {
+            let value = control.read() & !PM1_SLEEP_TYPE;
+            control.write(value | ((sleep_type as u16) << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE);
+        }
+    };
+    enter(fadt.pm1a_control, sleep_type.a);
+    if fadt.pm1b_control != 0 {
+        enter(fadt.pm1b_control, sleep_type.b);
+    }
+}
+
+/// Restarts the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    let mut status = Port::<u8>::new(CONTROLLER_COMMAND_PORT);
+    if (0..POLL_LIMIT).any(|_| unsafe { status.read() } & CONTROLLER_INPUT_FULL == 0) {
+        unsafe { status.write(CONTROLLER_PULSE_RESET) };
+        // Give the reset a moment before trying the next way.
+        for _ in 0..POLL_LIMIT {
+            unsafe { status.read() };
+        }
+    }
+
+    if acpi::is_present() {
+        if let Some(fadt) = fadt::find() {
+            match fadt.reset_register {
+                Some(ResetRegister::Io(port)) => unsafe { Port::new(port).write(fadt.reset_value) },
+                Some(ResetRegister::Memory(address)) => unsafe {
+                    phys_to_virt(PhysAddr::new(address))
+                        .as_mut_ptr::<u8>()
+                        .write_volatile(fadt.reset_value)
+                },
+                None => {}
+            }
+        }
+    }
+
+    triple_fault()
+}
+
+/// Resets the CPU by raising an exception with an empty IDT: the exception
+/// and the double fault it causes cannot be delivered, which is a triple
+/// fault.
+fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&idt);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_panic_action() {
+        assert_eq!(panic_action(), PanicAction::Halt);
+        set_panic_action(PanicAction::Shutdown);
+        assert_eq!(panic_action(), PanicAction::Shutdown);
+        set_panic_action(PanicAction::Reboot);
+        assert_eq!(panic_action(), PanicAction::Reboot);
+        set_panic_action(PanicAction::Halt);
+    }
+}
@@ -0,0 +1,626 @@
+//! Per-process address spaces.
+//!
+//! Every address space has its own level 4 page table. The entries covering
+//! the user range are private to it, while all others are copied from the
+//! kernel's page table when the address space is created, so the kernel is
+//! mapped identically in all of them. Kernel mappings added later within
+//! existing level 4 entries are therefore shared, but new level 4 entries are
+//! not.
+//!
+//! Most user pages belong to a single address space and are freed with it.
+//! Pages of [shared memory](crate::ipc::shm) regions are marked with
+//! [SHARED] instead, and their frames are freed by the region once no
+//! address space holds it anymore.
+//!
+//! The heap, which grows from the end of the program up to the program
+//! break, and the areas handed out by [AddressSpace::reserve] are demand
+//! paged: their pages are only mapped, zeroed, when first accessed, through
+//! [AddressSpace::populate].
+
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Range;
+
+use x86_64::{
+    align_up,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, Translate},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    ipc::shm::SharedMemory,
+    mem::{self, GlobalFrameAllocator},
+    usermode::{self, USER_END, USER_STACK_SIZE, USER_STACK_TOP, USER_START},
+};
+
+/// Level 4 page table entries covering the user range.
+const USER_ENTRIES: Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Page table flag marking pages whose frames belong to a shared memory
+/// region rather than to the address space.
+const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Address below which [AddressSpace::reserve] places areas, leaving 256 MiB
+/// free under the stack.
+const RESERVE_TOP: u64 = USER_STACK_TOP - USER_STACK_SIZE - 0x1000_0000;
+
+/// A page aligned range of addresses mapped on first access.
+#[derive(Debug, Clone)]
+struct Reservation {
+    pages: Range<u64>,
+    flags: PageTableFlags,
+}
+
+/// A set of user mappings and the page tables holding them.
+///
+/// Dropping an address space frees its user pages and page tables.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    /// Shared memory regions mapped into the address space.
+    shared: Vec<Arc<SharedMemory>>,
+    /// Areas handed out by [AddressSpace::reserve].
+    reserved: Vec<Reservation>,
+    /// Page ranges mapped by [AddressSpace::map] and
+    /// [AddressSpace::map_shared], which may overlap.
+    mapped: Vec<Range<u64>>,
+    /// From the start of the heap to the program break.
+    heap: Range<u64>,
+    /// Lowest address handed out by [AddressSpace::reserve] so far.
+    reserve_bottom: u64,
+}
+
+impl AddressSpace {
+    /// Creates an address space without any user mappings.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let level_4 = allocate_zeroed_frame()?;
+        let table = unsafe { table_mut(level_4) };
+        mem::with_page_table_of(mem::kernel_page_table(), |kernel| {
+            for (index, entry) in kernel.level_4_table().iter().enumerate() {
+                if !USER_ENTRIES.contains(&index) {
+                    table[index] = entry.clone();
+                }
+            }
+        });
+        Ok(AddressSpace {
+            level_4,
+            shared: Vec::new(),
+            reserved: Vec::new(),
+            mapped: Vec::new(),
+            heap: 0..0,
+            reserve_bottom: RESERVE_TOP,
+        })
+    }
+
+    /// Returns the frame of the level 4 page table, to be loaded into `CR3`.
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    /// Maps zeroed, user accessible pages covering `len` bytes from `start`.
+    /// Pages which are already mapped are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not within the user range.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let pages = usermode::user_pages(start, len);
+        self.mapped.push(page_range(start, len));
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        mem::with_page_table_of(self.level_4, |page_table| {
+            for page in pages {
+                if page_table.translate_page(page).is_ok() {
+                    continue;
+                }
+                let frame = allocate_zeroed_frame()?;
+                unsafe { page_table.map_to(page, frame, flags, &mut GlobalFrameAllocator)? }
+                    .flush();
+            }
+            Ok(())
+        })
+    }
+
+    /// Maps the frames of `region` from `start`, which must be page aligned.
+    /// Fails without mapping anything if part of the range is already
+    /// mapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not within the user range.
+    pub fn map_shared(
+        &mut self,
+        start: VirtAddr,
+        region: &Arc<SharedMemory>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | SHARED;
+        mem::with_page_table_of(self.level_4, |page_table| {
+            for page in usermode::user_pages(start, region.size()) {
+                if let Ok(frame) = page_table.translate_page(page) {
+                    return Err(MapToError::PageAlreadyMapped(frame));
+                }
+            }
+
+            // Hold the region before mapping any of it, in case mapping fails
+            // part way through.
+            self.shared.push(region.clone());
+            self.mapped.push(page_range(start, region.size()));
+            let pages = usermode::user_pages(start, region.size());
+            for (page, &frame) in pages.zip(region.frames()) {
+                unsafe { page_table.map_to(page, frame, flags, &mut GlobalFrameAllocator)? }
+                    .flush();
+            }
+            Ok(())
+        })
+    }
+
+    /// Places an empty heap at `start`, rounded up to a page boundary. Called
+    /// once the program is loaded.
+    pub fn set_heap_start(&mut self, start: VirtAddr) {
+        let start = start.align_up(4096u64).as_u64();
+        self.heap = start..start;
+    }
+
+    /// Returns the program break, the end of the heap.
+    pub fn program_break(&self) -> VirtAddr {
+        VirtAddr::new(self.heap.end)
+    }
+
+    /// Moves the program break to `end`, growing or shrinking the heap.
+    /// Returns `false` if `end` is below the start of the heap or the heap
+    /// would run into other mappings.
+    pub fn set_break(&mut self, end: VirtAddr) -> bool {
+        let end = end.as_u64();
+        if end < self.heap.start {
+            return false;
+        }
+
+        let old_pages = self.heap_pages();
+        let new_end = align_up(end, 4096);
+        if new_end > old_pages.end && !self.is_free(old_pages.end..new_end) {
+            return false;
+        }
+        if new_end < old_pages.end {
+            self.unmap_pages(new_end..old_pages.end);
+        }
+        self.heap.end = end;
+        true
+    }
+
+    /// Reserves `len` bytes, rounded up to whole pages, to be mapped with
+    /// `flags` on first access. Returns the start of the reserved area, or
+    /// `None` if there is no room left.
+    pub fn reserve(&mut self, len: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+        if len == 0 || len > self.reserve_bottom - USER_START {
+            return None;
+        }
+        let start = self.reserve_bottom - align_up(len, 4096);
+        if start < USER_START || !self.is_free(start..self.reserve_bottom) {
+            return None;
+        }
+        self.reserved.push(Reservation {
+            pages: start..self.reserve_bottom,
+            flags,
+        });
+        self.reserve_bottom = start;
+        Some(VirtAddr::new(start))
+    }
+
+    /// Maps a zeroed page at `addr` if it lies in the heap or a reserved area
+    /// and is not mapped yet. Returns whether a page was mapped.
+    pub fn populate(&mut self, addr: VirtAddr) -> Result<bool, MapToError<Size4KiB>> {
+        let flags = if self.heap_pages().contains(&addr.as_u64()) {
+            PageTableFlags::WRITABLE
+        } else {
+            match self
+                .reserved
+                .iter()
+                .find(|reservation| reservation.pages.contains(&addr.as_u64()))
+            {
+                Some(reservation) => reservation.flags,
+                None => return Ok(false),
+            }
+        };
+
+        let page = Page::containing_address(addr);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        mem::with_page_table_of(self.level_4, |page_table| {
+            if page_table.translate_page(page).is_ok() {
+                return Ok(false);
+            }
+            let frame = allocate_zeroed_frame()?;
+            unsafe { page_table.map_to(page, frame, flags, &mut GlobalFrameAllocator)? }.flush();
+            Ok(true)
+        })
+    }
+
+    /// Unmaps the pages covering `len` bytes from the page aligned `start`
+    /// and removes them from any reserved area.
+    pub fn unmap(&mut self, start: VirtAddr, len: u64) {
+        let start = start.as_u64();
+        let end = align_up(start.saturating_add(len).min(USER_END), 4096);
+        let hole = start..end;
+        self.reserved = self
+            .reserved
+            .drain(..)
+            .flat_map(|reservation| {
+                subtract(&reservation.pages, &hole).map(move |pages| Reservation {
+                    pages,
+                    flags: reservation.flags,
+                })
+            })
+            .collect();
+        self.mapped = self
+            .mapped
+            .drain(..)
+            .flat_map(|pages| subtract(&pages, &hole))
+            .collect();
+        self.unmap_pages(hole);
+    }
+
+    fn heap_pages(&self) -> Range<u64> {
+        self.heap.start..align_up(self.heap.end, 4096)
+    }
+
+    /// Returns `true` if the page aligned `range` lies in the user range and
+    /// overlaps neither the heap, a reserved area nor mapped pages. Only the
+    /// recorded ranges are compared, so this takes no longer for a large
+    /// `range` than a small one.
+    fn is_free(&self, range: Range<u64>) -> bool {
+        let overlaps = |pages: &Range<u64>| pages.start < range.end && range.start < pages.end;
+        range.start >= USER_START
+            && range.end <= USER_END
+            && !overlaps(&self.heap_pages())
+            && !self
+                .reserved
+                .iter()
+                .any(|reservation| overlaps(&reservation.pages))
+            && !self.mapped.iter().any(overlaps)
+    }
+
+    /// Unmaps the pages in the page aligned `range`, freeing their frames
+    /// unless they are shared. Only mapped pages are visited, however large
+    /// the range.
+    fn unmap_pages(&mut self, range: Range<u64>) {
+        let mut pages = Vec::new();
+        self.walk(
+            &mut |page, _, flags| {
+                if range.contains(&page.start_address().as_u64()) {
+                    pages.push((page, flags));
+                }
+            },
+            &mut |_| {},
+        );
+        mem::with_page_table_of(self.level_4, |page_table| {
+            for (page, flags) in pages {
+                if let Ok((frame, flush)) = page_table.unmap(page) {
+                    flush.flush();
+                    if !flags.contains(SHARED) {
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                    }
+                }
+            }
+        });
+    }
+
+    /// Copies `data` to `addr`, regardless of the page permissions. Returns
+    /// `false` if part of the range is not mapped.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> bool {
+        mem::with_page_table_of(self.level_4, |page_table| {
+            let mut addr = addr;
+            let mut data = data;
+            while !data.is_empty() {
+                let phys = match page_table.translate_addr(addr) {
+                    Some(phys) => phys,
+                    None => return false,
+                };
+                let len = data
+                    .len()
+                    .min(4096 - u64::from(addr.page_offset()) as usize);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        data.as_ptr(),
+                        mem::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                        len,
+                    );
+                }
+                addr += len as u64;
+                data = &data[len..];
+            }
+            true
+        })
+    }
+
+    /// Creates a copy of this address space, with every user page copied to
+    /// a new frame. Shared memory stays shared with the copy.
+    pub fn try_clone(&self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut clone = AddressSpace::new()?;
+        clone.shared = self.shared.clone();
+        clone.reserved = self.reserved.clone();
+        clone.mapped = self.mapped.clone();
+        clone.heap = self.heap.clone();
+        clone.reserve_bottom = self.reserve_bottom;
+        let mut result = Ok(());
+        self.walk(
+            &mut |page, frame, flags| {
+                if result.is_ok() {
+                    result = if flags.contains(SHARED) {
+                        clone.map_frame(page, frame, flags)
+                    } else {
+                        clone.map_copy(page, frame, flags)
+                    };
+                }
+            },
+            &mut |_| {},
+        );
+        result.map(|()| clone)
+    }
+
+    /// Maps `page` to a new frame holding a copy of `source`.
+    fn map_copy(
+        &self,
+        page: Page,
+        source: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                mem::phys_to_virt(source.start_address()).as_ptr::<u8>(),
+                mem::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            );
+        }
+        self.map_frame(page, frame, flags)
+    }
+
+    fn map_frame(
+        &self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        mem::with_page_table_of(self.level_4, |page_table| {
+            unsafe { page_table.map_to(page, frame, flags, &mut GlobalFrameAllocator)? }.flush();
+            Ok(())
+        })
+    }
+
+    /// Calls `page` with every mapped user page, and `table` with the frame
+    /// of every user page table after its entries have been visited.
+    fn walk(
+        &self,
+        page: &mut impl FnMut(Page, PhysFrame, PageTableFlags),
+        table: &mut impl FnMut(PhysFrame),
+    ) {
+        let level_4 = unsafe { table_mut(self.level_4) };
+        for index in USER_ENTRIES {
+            if let Ok(frame) = level_4[index].frame() {
+                walk_table(frame, 3, (index as u64) << 39, page, table);
+                table(frame);
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.level_4,
+            "dropping the active address space"
+        );
+
+        self.walk(
+            &mut |_, frame, flags| {
+                if !flags.contains(SHARED) {
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) }
+                }
+            },
+            &mut |frame| unsafe { GlobalFrameAllocator.deallocate_frame(frame) },
+        );
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4) };
+    }
+}
+
+/// Visits the entries of the page table of the given `level` in `frame`,
+/// which maps the addresses from `base`. See [AddressSpace::walk].
+fn walk_table(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    page: &mut impl FnMut(Page, PhysFrame, PageTableFlags),
+    table: &mut impl FnMut(PhysFrame),
+) {
+    let entries = unsafe { table_mut(frame) };
+    for (index, entry) in entries.iter().enumerate() {
+        // User mappings never use huge pages, for which `frame` fails.
+        let frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+
+        let addr = base | (index as u64) << (12 + 9 * (level as u64 - 1));
+        if level == 1 {
+            page(
+                Page::containing_address(VirtAddr::new(addr)),
+                frame,
+                entry.flags(),
+            );
+        } else {
+            walk_table(frame, level - 1, addr, page, table);
+            table(frame);
+        }
+    }
+}
+
+/// Returns the addresses of the pages covering `len` bytes from `start`.
+fn page_range(start: VirtAddr, len: u64) -> Range<u64> {
+    start.align_down(4096u64).as_u64()..(start + len).align_up(4096u64).as_u64()
+}
+
+/// Returns what is left of `pages` without the addresses in `hole`: the part
+/// below it and the part above it, if not empty.
+fn subtract(pages: &Range<u64>, hole: &Range<u64>) -> impl Iterator<Item = Range<u64>> {
+    let below = pages.start..pages.end.min(hole.start);
+    let above = pages.start.max(hole.end)..pages.end;
+    [below, above].into_iter().filter(|range| !range.is_empty())
+}
+
+/// Returns the page table held in `frame`.
+///
+/// # Safety
+///
+/// The frame must hold a page table which is not otherwise being modified.
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *mem::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+pub(crate) fn allocate_zeroed_frame() -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let frame = GlobalFrameAllocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe {
+        core::ptr::write_bytes(
+            mem::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            4096,
+        );
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86_64::PhysAddr;
+
+    fn translate(address_space: &AddressSpace, addr: VirtAddr) -> Option<PhysAddr> {
+        mem::with_page_table_of(address_space.level_4, |page_table| {
+            page_table.translate_addr(addr)
+        })
+    }
+
+    fn read(address_space: &AddressSpace, addr: VirtAddr) -> u8 {
+        let phys = translate(address_space, addr).expect("address not mapped");
+        unsafe { *mem::phys_to_virt(phys).as_ptr::<u8>() }
+    }
+
+    #[test_case]
+    fn test_kernel_is_mapped() {
+        let address_space = AddressSpace::new().expect("out of frames");
+        let kernel_addr = VirtAddr::from_ptr(&USER_ENTRIES);
+        assert_eq!(
+            translate(&address_space, kernel_addr),
+            mem::with_page_table(|page_table| page_table.translate_addr(kernel_addr))
+        );
+        assert_eq!(translate(&address_space, VirtAddr::new(USER_START)), None);
+    }
+
+    #[test_case]
+    fn test_write_across_pages() {
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        let start = VirtAddr::new(USER_START);
+        address_space
+            .map(start, 2 * 4096, PageTableFlags::empty())
+            .expect("out of frames");
+
+        assert!(address_space.write(start + 4095u64, &[1, 2]));
+        assert_eq!(read(&address_space, start + 4095u64), 1);
+        assert_eq!(read(&address_space, start + 4096u64), 2);
+        assert!(!address_space.write(start + (2 * 4096 - 1) as u64, &[3, 4]));
+    }
+
+    #[test_case]
+    fn test_reserved_pages_are_mapped_on_access() {
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        let start = address_space
+            .reserve(2 * 4096, PageTableFlags::WRITABLE)
+            .expect("no room left");
+        assert_eq!(translate(&address_space, start), None);
+
+        assert!(address_space
+            .populate(start + 4096u64)
+            .expect("out of frames"));
+        assert!(!address_space
+            .populate(start + 4096u64)
+            .expect("out of frames"));
+        assert!(translate(&address_space, start + 4096u64).is_some());
+        assert!(!address_space.populate(start - 1u64).expect("out of frames"));
+
+        address_space.unmap(start, 2 * 4096);
+        assert_eq!(translate(&address_space, start + 4096u64), None);
+        assert!(!address_space.populate(start).expect("out of frames"));
+    }
+
+    #[test_case]
+    fn test_program_break() {
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        let start = VirtAddr::new(USER_START + 10);
+        address_space.set_heap_start(start);
+        let heap = VirtAddr::new(USER_START + 4096);
+        assert_eq!(address_space.program_break(), heap);
+
+        assert!(!address_space.set_break(heap - 1u64));
+        assert!(address_space.set_break(heap + 5000u64));
+        assert!(address_space
+            .populate(heap + 8191u64)
+            .expect("out of frames"));
+        assert!(!address_space
+            .populate(heap + 8192u64)
+            .expect("out of frames"));
+
+        assert!(address_space.set_break(heap));
+        assert_eq!(translate(&address_space, heap + 4096u64), None);
+    }
+
+    #[test_case]
+    fn test_huge_requests() {
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        assert_eq!(
+            address_space.reserve(u64::MAX, PageTableFlags::empty()),
+            None
+        );
+        assert_eq!(
+            address_space.reserve(u64::MAX - 4095, PageTableFlags::empty()),
+            None
+        );
+
+        let start = VirtAddr::new(USER_START);
+        address_space.set_heap_start(start);
+        assert!(address_space.set_break(VirtAddr::new(USER_END)));
+        assert!(address_space.set_break(start));
+
+        address_space
+            .map(VirtAddr::new(USER_END - 4096), 1, PageTableFlags::WRITABLE)
+            .expect("out of frames");
+        assert!(!address_space.set_break(VirtAddr::new(USER_END)));
+    }
+
+    #[test_case]
+    fn test_clone_copies_pages() {
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        let addr = VirtAddr::new(USER_END - 4096);
+        address_space
+            .map(addr, 1, PageTableFlags::WRITABLE)
+            .expect("out of frames");
+        assert!(address_space.write(addr, &[7]));
+
+        let mut clone = address_space.try_clone().expect("out of frames");
+        assert!(clone.write(addr, &[8]));
+        assert_eq!(read(&address_space, addr), 7);
+        assert_eq!(read(&clone, addr), 8);
+        assert_ne!(translate(&address_space, addr), translate(&clone, addr));
+    }
+}
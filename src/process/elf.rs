@@ -0,0 +1,231 @@
+//! Parsing of ELF executables.
+//!
+//! Only statically linked 64-bit little endian x86-64 executables are
+//! supported. [parse] extracts the entry point and the loadable segments,
+//! which [super::load] then maps into an address space.
+//!
+//! See: https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use crate::usermode::{USER_END, USER_START};
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_WRITABLE: u32 = 2;
+
+/// Reasons an image cannot be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The image ends before a structure it refers to.
+    Truncated,
+    /// The image does not start with the ELF magic number.
+    NotElf,
+    /// The image is not a 64-bit x86-64 executable.
+    Unsupported,
+    /// A segment or the entry point lies outside of the user range.
+    BadAddress,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::Truncated => "image is truncated",
+            Error::NotElf => "not an ELF image",
+            Error::Unsupported => "not a 64-bit x86-64 executable",
+            Error::BadAddress => "address outside of user space",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A segment to be loaded into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub addr: VirtAddr,
+    /// Size of the segment in memory. Memory beyond `data` is zeroed.
+    pub mem_size: u64,
+    pub data: &'a [u8],
+    pub writable: bool,
+}
+
+/// The parts of an executable needed to run it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executable<'a> {
+    pub entry: VirtAddr,
+    pub segments: Vec<Segment<'a>>,
+    /// Where the program headers are loaded, if a segment includes them.
+    pub program_headers: Option<VirtAddr>,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+}
+
+/// Parses an executable image.
+pub fn parse(image: &[u8]) -> Result<Executable<'_>, Error> {
+    let header = image.get(..HEADER_SIZE).ok_or(Error::Truncated)?;
+    if header[..4] != MAGIC {
+        return Err(Error::NotElf);
+    }
+    if header[4] != CLASS_64
+        || header[5] != DATA_LITTLE_ENDIAN
+        || read_u16(header, 16) != TYPE_EXECUTABLE
+        || read_u16(header, 18) != MACHINE_X86_64
+    {
+        return Err(Error::Unsupported);
+    }
+
+    let entry = read_u64(header, 24);
+    let program_headers = read_u64(header, 32) as usize;
+    let program_header_size = read_u16(header, 54) as usize;
+    let program_header_count = read_u16(header, 56) as usize;
+    if program_header_size < PROGRAM_HEADER_SIZE {
+        return Err(Error::Unsupported);
+    }
+    if !is_user_range(entry, 1) {
+        return Err(Error::BadAddress);
+    }
+
+    let headers_end = program_headers.saturating_add(program_header_count * program_header_size);
+    let mut loaded_headers = None;
+    let mut segments = Vec::new();
+    for index in 0..program_header_count {
+        let header = program_headers
+            .checked_add(index * program_header_size)
+            .and_then(|offset| image.get(offset..offset.checked_add(PROGRAM_HEADER_SIZE)?))
+            .ok_or(Error::Truncated)?;
+        if read_u32(header, 0) != SEGMENT_LOAD {
+            continue;
+        }
+
+        let flags = read_u32(header, 4);
+        let file_offset = read_u64(header, 8) as usize;
+        let addr = read_u64(header, 16);
+        let file_size = read_u64(header, 32) as usize;
+        let mem_size = read_u64(header, 40);
+        if file_size as u64 > mem_size || !is_user_range(addr, mem_size) {
+            return Err(Error::BadAddress);
+        }
+        let data = file_offset
+            .checked_add(file_size)
+            .and_then(|end| image.get(file_offset..end))
+            .ok_or(Error::Truncated)?;
+        if file_offset <= program_headers && headers_end <= file_offset + file_size {
+            loaded_headers = Some(VirtAddr::new(addr + (program_headers - file_offset) as u64));
+        }
+
+        segments.push(Segment {
+            addr: VirtAddr::new(addr),
+            mem_size,
+            data,
+            writable: flags & SEGMENT_WRITABLE != 0,
+        });
+    }
+
+    Ok(Executable {
+        entry: VirtAddr::new(entry),
+        segments,
+        program_headers: loaded_headers,
+        program_header_size: program_header_size as u16,
+        program_header_count: program_header_count as u16,
+    })
+}
+
+fn is_user_range(addr: u64, len: u64) -> bool {
+    addr >= USER_START && addr.checked_add(len).is_some_and(|end| end <= USER_END)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Address at which [image] loads its code.
+    pub(crate) const CODE_ADDR: u64 = USER_START + 0x40_0000;
+
+    /// Builds an executable which loads `code` at [CODE_ADDR] and starts
+    /// executing it there.
+    pub(crate) fn image(code: &[u8]) -> Vec<u8> {
+        let code_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&MAGIC);
+        image.extend_from_slice(&[CLASS_64, DATA_LITTLE_ENDIAN, 1]);
+        image.resize(16, 0);
+        image.extend_from_slice(&TYPE_EXECUTABLE.to_le_bytes());
+        image.extend_from_slice(&MACHINE_X86_64.to_le_bytes());
+        image.extend_from_slice(&1u32.to_le_bytes()); // version
+        image.extend_from_slice(&CODE_ADDR.to_le_bytes()); // entry
+        image.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes()); // program headers
+        image.extend_from_slice(&0u64.to_le_bytes()); // section headers
+        image.extend_from_slice(&0u32.to_le_bytes()); // flags
+        image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&1u16.to_le_bytes()); // program header count
+        image.extend_from_slice(&[0; 6]); // section header size, count and names
+
+        image.extend_from_slice(&SEGMENT_LOAD.to_le_bytes());
+        image.extend_from_slice(&(SEGMENT_WRITABLE | 5).to_le_bytes()); // rwx
+        image.extend_from_slice(&code_offset.to_le_bytes());
+        image.extend_from_slice(&CODE_ADDR.to_le_bytes()); // virtual address
+        image.extend_from_slice(&CODE_ADDR.to_le_bytes()); // physical address
+        image.extend_from_slice(&(code.len() as u64).to_le_bytes()); // file size
+        image.extend_from_slice(&(code.len() as u64 + 16).to_le_bytes()); // memory size
+        image.extend_from_slice(&4096u64.to_le_bytes()); // alignment
+
+        image.extend_from_slice(code);
+        image
+    }
+
+    #[test_case]
+    fn test_parse() {
+        let image = image(&[0x90, 0xcc]);
+        let executable = parse(&image).expect("failed to parse image");
+
+        assert_eq!(executable.entry, VirtAddr::new(CODE_ADDR));
+        assert_eq!(executable.segments.len(), 1);
+        let segment = &executable.segments[0];
+        assert_eq!(segment.addr, VirtAddr::new(CODE_ADDR));
+        assert_eq!(segment.data, &[0x90, 0xcc]);
+        assert_eq!(segment.mem_size, 18);
+        assert!(segment.writable);
+        assert_eq!(executable.program_headers, None);
+        assert_eq!(executable.program_header_count, 1);
+    }
+
+    #[test_case]
+    fn test_parse_errors() {
+        let mut image = image(&[0x90]);
+        assert_eq!(parse(&image[..40]), Err(Error::Truncated));
+        assert_eq!(parse(&image[..HEADER_SIZE + 8]), Err(Error::Truncated));
+        let mut far = image.clone();
+        far[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(parse(&far), Err(Error::Truncated));
+
+        image[18] = 3; // i386
+        assert_eq!(parse(&image), Err(Error::Unsupported));
+        image[0] = 0;
+        assert_eq!(parse(&image), Err(Error::NotElf));
+    }
+}
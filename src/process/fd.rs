@@ -0,0 +1,146 @@
+//! Per-process file descriptor tables.
+//!
+//! A file descriptor is an index into the calling process's table, whose
+//! entries refer to [Descriptor]s. Entries are shared by reference, so
+//! descriptors duplicated within a table or inherited through fork refer to
+//! the same pipe end or open file, including its position, which stays open
+//! until every copy is closed.
+//!
+//! Code running without a process, which is the kernel itself and user code
+//! started with [usermode::run](crate::usermode::run), uses a single table
+//! shared among all of it, returned by [kernel_files].
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use spin::{Lazy, Mutex, MutexGuard};
+
+use crate::{fs, ipc::pipe};
+
+/// Maximum number of open file descriptors per process.
+pub const MAX_FILES: usize = 64;
+
+/// Returned for file descriptors which are not open or out of range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadDescriptor;
+
+/// What a file descriptor refers to.
+#[derive(Clone)]
+pub enum Descriptor {
+    /// Writes are printed to the screen. Reading is not supported yet and
+    /// always hits end of file.
+    Console,
+    PipeReader(Arc<pipe::Reader>),
+    PipeWriter(Arc<pipe::Writer>),
+    /// A file or directory opened through the [VFS](fs).
+    File(Arc<fs::OpenFile>),
+}
+
+/// A process's open file descriptors.
+#[derive(Clone)]
+pub struct FileTable {
+    entries: Vec<Option<Descriptor>>,
+}
+
+impl FileTable {
+    /// Creates a table with standard input, standard output and standard
+    /// error open on the console.
+    pub fn new() -> Self {
+        FileTable {
+            entries: vec![Some(Descriptor::Console); 3],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Option<Descriptor> {
+        self.entries.get(fd as usize)?.clone()
+    }
+
+    /// Stores `descriptor` under the lowest unused file descriptor and returns
+    /// it, or `None` if [MAX_FILES] descriptors are already open.
+    pub fn insert(&mut self, descriptor: Descriptor) -> Option<u64> {
+        let fd = match self.entries.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.entries.len() < MAX_FILES => {
+                self.entries.push(None);
+                self.entries.len() - 1
+            }
+            None => return None,
+        };
+        self.entries[fd] = Some(descriptor);
+        Some(fd as u64)
+    }
+
+    /// Makes `new` refer to the same descriptor as `old`, closing whatever
+    /// `new` referred to before, which is returned.
+    pub fn duplicate(&mut self, old: u64, new: u64) -> Result<Option<Descriptor>, BadDescriptor> {
+        let descriptor = self.get(old).ok_or(BadDescriptor)?;
+        let new = new as usize;
+        if new >= MAX_FILES {
+            return Err(BadDescriptor);
+        }
+        if new >= self.entries.len() {
+            self.entries.resize(new + 1, None);
+        }
+        Ok(self.entries[new].replace(descriptor))
+    }
+
+    /// Closes `fd`, returning what it referred to.
+    pub fn remove(&mut self, fd: u64) -> Option<Descriptor> {
+        self.entries.get_mut(fd as usize)?.take()
+    }
+
+    /// Closes every file descriptor.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The table of code running without a process.
+static KERNEL_FILES: Lazy<Mutex<FileTable>> = Lazy::new(|| Mutex::new(FileTable::new()));
+
+/// Returns the file descriptor table of code running without a process.
+pub fn kernel_files() -> MutexGuard<'static, FileTable> {
+    KERNEL_FILES.lock()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_insert_uses_lowest_free_descriptor() {
+        let mut files = FileTable::new();
+        assert!(files.remove(0).is_some());
+        assert_eq!(files.insert(Descriptor::Console), Some(0));
+        assert_eq!(files.insert(Descriptor::Console), Some(3));
+        assert!(files.remove(1).is_some());
+        assert!(files.remove(1).is_none());
+        assert_eq!(files.insert(Descriptor::Console), Some(1));
+    }
+
+    #[test_case]
+    fn test_duplicate_shares_pipe_end() {
+        let (reader, writer) = pipe::pipe();
+        let mut files = FileTable::new();
+        let fd = files
+            .insert(Descriptor::PipeWriter(Arc::new(writer)))
+            .unwrap();
+        assert!(files.duplicate(fd, 10).unwrap().is_none());
+        assert_eq!(files.duplicate(20, 11).err(), Some(BadDescriptor));
+        assert_eq!(
+            files.duplicate(fd, MAX_FILES as u64).err(),
+            Some(BadDescriptor)
+        );
+
+        files.remove(fd);
+        let mut buf = [0; 1];
+        assert_eq!(reader.try_read(&mut buf), None);
+        files.remove(10);
+        assert_eq!(reader.try_read(&mut buf), Some(0));
+    }
+}
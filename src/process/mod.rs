@@ -0,0 +1,1119 @@
+//! User processes.
+//!
+//! A process is a user program running in its own [AddressSpace]. Each
+//! process is driven by a kernel thread which switches to the process's page
+//! table and enters user mode, returning to the kernel for system calls and
+//! interrupts. Once the program exits, the thread frees the address space and
+//! records the exit status.
+//!
+//! Processes are stopped by sending them [signals](signal).
+//!
+//! An exited process stays in the process table as a zombie until its parent
+//! collects the exit status with [wait_child]. Processes without a parent,
+//! either because the kernel started them or because their parent exited
+//! first, are removed as soon as they exit.
+//!
+//! New processes are created the Unix way: [fork] duplicates the calling
+//! process, copying every page of its address space and sharing its open
+//! [files](fd), and [exec] replaces the program a process runs with an ELF
+//! executable. Executables are looked up by name among those added with
+//! [register_program], and otherwise in the [filesystem](crate::fs): names
+//! starting with `/` are paths, while other names refer to files in `/bin`.
+//! Programs start with their arguments and environment on the [stack], as
+//! the System V ABI specifies.
+
+use alloc::{
+    borrow::Cow,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    fs, ipc,
+    syscall::SyscallFrame,
+    task::thread::{self, ThreadId, WaitQueue},
+    usermode::{self, Registers, USER_STACK_SIZE, USER_STACK_TOP, USER_START},
+};
+
+pub mod address_space;
+pub mod elf;
+pub mod fd;
+pub mod signal;
+pub mod stack;
+
+pub use address_space::AddressSpace;
+pub use fd::FileTable;
+pub use signal::Signal;
+
+/// Uniquely identifies a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Returns the process ID with the given value, which need not belong to
+    /// any process.
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Reasons a process cannot be created or an executable cannot be run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No program is registered or stored under the given name.
+    NotFound,
+    /// The executable could not be read from the filesystem.
+    File(fs::Error),
+    /// The executable is malformed or unsupported.
+    Elf(elf::Error),
+    /// There are no free frames left for the address space.
+    OutOfMemory,
+    /// The maximum number of threads is already running.
+    TooManyThreads,
+    /// The arguments and environment do not fit on the initial stack.
+    ArgumentsTooLong,
+}
+
+impl From<elf::Error> for Error {
+    fn from(error: elf::Error) -> Self {
+        Error::Elf(error)
+    }
+}
+
+impl From<fs::Error> for Error {
+    fn from(error: fs::Error) -> Self {
+        match error {
+            fs::Error::NotFound | fs::Error::NotADirectory => Error::NotFound,
+            error => Error::File(error),
+        }
+    }
+}
+
+impl From<MapToError<Size4KiB>> for Error {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        Error::OutOfMemory
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => f.write_str("no such program"),
+            Error::File(error) => write!(f, "cannot read executable: {}", error),
+            Error::Elf(error) => write!(f, "invalid executable: {}", error),
+            Error::OutOfMemory => f.write_str("out of memory"),
+            Error::TooManyThreads => f.write_str("too many threads"),
+            Error::ArgumentsTooLong => f.write_str("argument list too long"),
+        }
+    }
+}
+
+/// A user process.
+pub struct Process {
+    pid: Pid,
+    /// Cleared when the parent exits.
+    parent: Mutex<Option<Pid>>,
+    /// The thread running the process, set once it has started.
+    thread: Once<ThreadId>,
+    /// Taken when the process exits.
+    address_space: Mutex<Option<AddressSpace>>,
+    /// Children which have not been reaped yet.
+    children: Mutex<Vec<Arc<Process>>>,
+    /// Set when the process exits, while holding the [PROCESSES] lock.
+    exit_status: Once<u64>,
+    /// Woken when the process exits.
+    exited: WaitQueue,
+    /// Woken when a child exits.
+    child_exited: WaitQueue,
+    signals: signal::Pending,
+    /// Cleared when the process exits.
+    files: Mutex<FileTable>,
+}
+
+impl Process {
+    fn new(parent: Option<Pid>, address_space: AddressSpace, files: FileTable) -> Arc<Process> {
+        Arc::new(Process {
+            pid: Pid::new(),
+            parent: Mutex::new(parent),
+            thread: Once::new(),
+            address_space: Mutex::new(Some(address_space)),
+            children: Mutex::new(Vec::new()),
+            exit_status: Once::new(),
+            exited: WaitQueue::new(),
+            child_exited: WaitQueue::new(),
+            signals: signal::Pending::new(),
+            files: Mutex::new(files),
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Returns the process which forked this one, if it is still running.
+    pub fn parent(&self) -> Option<Pid> {
+        *self.parent.lock()
+    }
+
+    /// Returns the process's address space, or `None` once it has exited.
+    pub fn address_space(&self) -> MutexGuard<'_, Option<AddressSpace>> {
+        self.address_space.lock()
+    }
+
+    /// Returns the process's file descriptor table.
+    pub fn files(&self) -> MutexGuard<'_, FileTable> {
+        self.files.lock()
+    }
+
+    /// Returns the processes forked by this one which have not been reaped.
+    pub fn children(&self) -> Vec<Arc<Process>> {
+        self.children.lock().clone()
+    }
+
+    /// Returns the status the process exited with, or `None` if it is still
+    /// running.
+    pub fn exit_status(&self) -> Option<u64> {
+        self.exit_status.get().copied()
+    }
+
+    /// Blocks the current thread until the process has exited, and returns
+    /// its exit status. Unlike [wait_child], this does not reap the process.
+    pub fn wait(&self) -> u64 {
+        self.exited.wait_until(|| self.exit_status().is_some());
+        self.exit_status().unwrap()
+    }
+}
+
+impl fmt::Debug for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Process")
+            .field("pid", &self.pid)
+            .field("parent", &self.parent())
+            .field("exit_status", &self.exit_status())
+            .finish()
+    }
+}
+
+/// Running processes and zombies.
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+
+/// The process which receives signals sent from the keyboard.
+static FOREGROUND: Mutex<Option<Pid>> = Mutex::new(None);
+
+/// Executables available to [exec], by name.
+static PROGRAMS: Mutex<BTreeMap<String, &'static [u8]>> = Mutex::new(BTreeMap::new());
+
+/// Makes an executable image available to [exec] under `name`, replacing any
+/// program previously registered under it.
+pub fn register_program(name: &str, image: &'static [u8]) {
+    PROGRAMS.lock().insert(name.to_string(), image);
+}
+
+/// Returns the executable image called `name`, as described in the
+/// [module documentation](self).
+fn find_program(name: &str) -> Result<Cow<'static, [u8]>, Error> {
+    if let Some(&image) = PROGRAMS.lock().get(name) {
+        return Ok(Cow::Borrowed(image));
+    }
+    let image = match name.starts_with('/') {
+        true => fs::read(name)?,
+        false if name.is_empty() || name.contains('/') => return Err(Error::NotFound),
+        false => fs::read(&alloc::format!("/bin/{}", name))?,
+    };
+    Ok(Cow::Owned(image))
+}
+
+/// Calls `f` with the file descriptor table of the current process, or with
+/// the [kernel's](fd::kernel_files) if the current thread runs no process.
+pub fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> R {
+    match current() {
+        Some(process) => f(&mut process.files()),
+        None => f(&mut fd::kernel_files()),
+    }
+}
+
+/// Returns the process with the given ID, which may be a zombie.
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Returns the running processes and zombies, ordered by ID.
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// Returns the process the current thread runs, if any.
+pub fn current() -> Option<Arc<Process>> {
+    let thread = thread::current();
+    PROCESSES
+        .lock()
+        .values()
+        .find(|process| process.thread.get() == Some(&thread))
+        .cloned()
+}
+
+/// Makes `pid` the process which receives [Signal::Interrupt] when Ctrl+C is
+/// pressed. The foreground process is cleared when it exits.
+pub fn set_foreground(pid: Option<Pid>) {
+    *FOREGROUND.lock() = pid;
+}
+
+/// Returns the process which receives signals sent from the keyboard.
+pub fn foreground() -> Option<Pid> {
+    *FOREGROUND.lock()
+}
+
+/// Sends `signal` to the process with the given ID. Signals sent to zombies
+/// have no effect.
+pub fn kill(pid: Pid, signal: Signal) -> Result<(), NoSuchProcess> {
+    let process = get(pid).ok_or(NoSuchProcess)?;
+    process.signals.raise(signal);
+    // Let the process see the signal if it is blocked in a system call.
+    if let Some(&thread) = process.thread.get() {
+        thread::unpark(thread);
+    }
+    Ok(())
+}
+
+/// Returned by [kill] if there is no process with the given ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoSuchProcess;
+
+/// Returns `true` if the current thread runs a process with a pending
+/// signal which terminates it. Blocking system calls check this to return
+/// early.
+pub fn is_interrupted() -> bool {
+    current().is_some_and(|process| process.signals.is_fatal())
+}
+
+/// Maps the page holding `addr` if the current process reserved it but has
+/// not accessed it yet. Returns `false` if the current thread does not run a
+/// process or the address is not reserved.
+pub(crate) fn populate(addr: VirtAddr) -> bool {
+    let process = match current() {
+        Some(process) => process,
+        None => return false,
+    };
+    let mut address_space = process.address_space.lock();
+    matches!(
+        address_space
+            .as_mut()
+            .map(|address_space| address_space.populate(addr)),
+        Some(Ok(true))
+    )
+}
+
+/// Handles a page fault on `addr` raised in user mode. A fault on a reserved
+/// page which is not `present` yet is resolved by mapping it; any other fault
+/// terminates the current process with [Signal::SegmentationFault].
+///
+/// Returns `false` if the fault was not resolved because the current thread
+/// does not run a process.
+pub(crate) fn handle_page_fault(addr: VirtAddr, present: bool) -> bool {
+    if !present && populate(addr) {
+        return true;
+    }
+    if current().is_some() {
+        // SAFETY: the fault arrived in user mode, so it is handled on the
+        // stack of the session's thread.
+        unsafe { usermode::return_to_kernel(Signal::SegmentationFault.exit_status()) };
+    }
+    false
+}
+
+/// Acts on the pending signals of the current process, if any, before it
+/// returns from a system call. Does not return if a signal terminates the
+/// process.
+pub(crate) fn deliver_signals() {
+    let signal = current().and_then(|process| process.signals.take_fatal());
+    if let Some(signal) = signal {
+        // SAFETY: called from the system call path of a process thread, and
+        // nothing on this stack needs dropping.
+        unsafe { usermode::return_to_kernel(signal.exit_status()) };
+    }
+}
+
+/// Like [deliver_signals], but called from a timer interrupt which arrived in
+/// user mode.
+///
+/// The process table is only tried, as the interrupted thread may have been
+/// switched to while another thread held it. Signals are then delivered on a
+/// later interrupt.
+pub(crate) fn deliver_signals_from_interrupt() {
+    let thread = thread::current();
+    let signal = match PROCESSES.try_lock() {
+        // Processes are only borrowed: dropping the last reference here
+        // could free memory, which interrupt handlers must not do.
+        Some(processes) => processes
+            .values()
+            .find(|process| process.thread.get() == Some(&thread))
+            .and_then(|process| process.signals.take_fatal()),
+        None => return,
+    };
+    if let Some(signal) = signal {
+        // SAFETY: the interrupt arrived in user mode, so it is handled on the
+        // stack of the session's thread.
+        unsafe { usermode::return_to_kernel(signal.exit_status()) };
+    }
+}
+
+/// Starts a new process running the given executable image, without
+/// arguments or environment variables.
+pub fn spawn(image: &[u8]) -> Result<Arc<Process>, Error> {
+    spawn_with_args(image, &[], &[])
+}
+
+/// Starts a new process running the given executable image with the given
+/// arguments and environment variables, the latter in `NAME=value` form.
+pub fn spawn_with_args(image: &[u8], args: &[&str], env: &[&str]) -> Result<Arc<Process>, Error> {
+    let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+    let env: Vec<&[u8]> = env.iter().map(|var| var.as_bytes()).collect();
+    let (address_space, registers) = load(image, &args, &env)?;
+    let process = Process::new(None, address_space, FileTable::new());
+    start(process.clone(), registers)?;
+    Ok(process)
+}
+
+/// Starts a new process running the executable called `name`, found as
+/// described in the [module documentation](self), with the given arguments
+/// and environment variables.
+pub fn spawn_program(name: &str, args: &[&str], env: &[&str]) -> Result<Arc<Process>, Error> {
+    spawn_with_args(&find_program(name)?, args, env)
+}
+
+/// Creates an address space holding the executable `image` and a stack set
+/// up with `args` and `env`, and returns it along with the registers to
+/// start the program with.
+fn load(image: &[u8], args: &[&[u8]], env: &[&[u8]]) -> Result<(AddressSpace, Registers), Error> {
+    let executable = elf::parse(image)?;
+    let mut address_space = AddressSpace::new()?;
+    for segment in &executable.segments {
+        let flags = if segment.writable {
+            PageTableFlags::WRITABLE
+        } else {
+            PageTableFlags::empty()
+        };
+        address_space.map(segment.addr, segment.mem_size, flags)?;
+        address_space.write(segment.addr, segment.data);
+    }
+    let program_end = executable
+        .segments
+        .iter()
+        .map(|segment| segment.addr + segment.mem_size)
+        .max()
+        .unwrap_or(VirtAddr::new(USER_START));
+    address_space.set_heap_start(program_end);
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    address_space.map(stack_bottom, USER_STACK_SIZE, PageTableFlags::WRITABLE)?;
+
+    let stack_pointer = stack::build(&mut address_space, &executable, args, env)?;
+    let registers = Registers::new(executable.entry, stack_pointer);
+    Ok((address_space, registers))
+}
+
+/// Adds `process` to the process table and spawns the thread running it.
+fn start(process: Arc<Process>, registers: Registers) -> Result<(), Error> {
+    PROCESSES.lock().insert(process.pid, process.clone());
+
+    let runner = process.clone();
+    match thread::try_spawn(move || run(runner, registers)) {
+        Some(_) => Ok(()),
+        None => {
+            PROCESSES.lock().remove(&process.pid);
+            Err(Error::TooManyThreads)
+        }
+    }
+}
+
+/// Body of the thread running a process.
+fn run(process: Arc<Process>, registers: Registers) {
+    process.thread.call_once(thread::current);
+
+    let page_table = process
+        .address_space
+        .lock()
+        .as_ref()
+        .map(AddressSpace::page_table);
+    // SAFETY: the address space is only replaced by `exec`, which switches
+    // to the new one itself, and is dropped below.
+    unsafe { thread::set_page_table(page_table) };
+    let status = unsafe { usermode::resume(&registers) };
+    unsafe { thread::set_page_table(None) };
+
+    drop(process.address_space.lock().take());
+    process.files().clear();
+    ipc::close_owned_by(process.pid);
+    ipc::shm::remove_owned_by(process.pid);
+    exit(&process, status);
+}
+
+/// Records the exit of `process`, turning it into a zombie if its parent is
+/// still running, and orphans its children.
+fn exit(process: &Process, status: u64) {
+    let children = core::mem::take(&mut *process.children.lock());
+
+    // Exiting, orphaning and reaping are serialized by the process table
+    // lock, so a zombie is never left without a parent to reap it.
+    let mut processes = PROCESSES.lock();
+    process.exit_status.call_once(|| status);
+    let mut foreground = FOREGROUND.lock();
+    if *foreground == Some(process.pid) {
+        *foreground = None;
+    }
+    drop(foreground);
+    for child in children {
+        *child.parent.lock() = None;
+        if child.exit_status().is_some() {
+            processes.remove(&child.pid);
+        }
+    }
+    if let Some(parent) = process.parent().and_then(|parent| processes.get(&parent)) {
+        parent.child_exited.wake_all();
+    }
+    if process.parent().is_none() {
+        processes.remove(&process.pid);
+    }
+    process.exited.wake_all();
+}
+
+/// Selects the children [wait_child] waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitFor {
+    Any,
+    Pid(Pid),
+}
+
+/// Reaps an exited child of the current process matching `target`, returning
+/// its ID and exit status. If no matching child has exited yet, blocks until
+/// one does, or returns `Ok(None)` if `block` is `false`.
+///
+/// Returns `None` if the current thread does not run a process.
+pub fn wait_child(target: WaitFor, block: bool) -> Option<Result<Option<(Pid, u64)>, WaitError>> {
+    let process = current()?;
+    let matches = |child: &Arc<Process>| match target {
+        WaitFor::Any => true,
+        WaitFor::Pid(pid) => child.pid == pid,
+    };
+
+    loop {
+        {
+            let mut processes = PROCESSES.lock();
+            let mut children = process.children.lock();
+            if !children.iter().any(matches) {
+                return Some(Err(WaitError::NoChildren));
+            }
+
+            let exited = children
+                .iter()
+                .position(|child| matches(child) && child.exit_status().is_some());
+            if let Some(index) = exited {
+                let child = children.remove(index);
+                processes.remove(&child.pid);
+                return Some(Ok(Some((child.pid, child.exit_status().unwrap()))));
+            }
+        }
+
+        if !block {
+            return Some(Ok(None));
+        }
+        if process.signals.is_fatal() {
+            return Some(Err(WaitError::Interrupted));
+        }
+        process.child_exited.wait_until(|| {
+            let children = process.children.lock();
+            let exited = children
+                .iter()
+                .any(|child| matches(child) && child.exit_status().is_some());
+            exited || process.signals.is_fatal()
+        });
+    }
+}
+
+/// Reasons [wait_child] fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The process has no matching children.
+    NoChildren,
+    /// A signal which terminates the process arrived while waiting.
+    Interrupted,
+}
+
+/// Creates a child of the current process with a copy of its address space
+/// and file descriptor table. The child resumes from the system call
+/// described by `frame`, with a result of 0.
+///
+/// Returns `None` if the current thread does not run a process.
+pub(crate) fn fork(frame: &SyscallFrame) -> Option<Result<Pid, Error>> {
+    let parent = current()?;
+    let address_space = match parent.address_space.lock().as_ref()?.try_clone() {
+        Ok(address_space) => address_space,
+        Err(error) => return Some(Err(error.into())),
+    };
+
+    let mut registers = frame.registers();
+    registers.rax = 0;
+    let files = parent.files().clone();
+    let child = Process::new(Some(parent.pid), address_space, files);
+    if let Err(error) = start(child.clone(), registers) {
+        return Some(Err(error));
+    }
+    parent.children.lock().push(child.clone());
+    Some(Ok(child.pid))
+}
+
+/// Replaces the program the current process runs with the one called `name`,
+/// found as described in the [module documentation](self), started with
+/// `args` and `env`. On success, the system call described by `frame`
+/// returns to the start of the new program.
+///
+/// Returns `None` if the current thread does not run a process.
+pub(crate) fn exec(
+    frame: &mut SyscallFrame,
+    name: &str,
+    args: &[&[u8]],
+    env: &[&[u8]],
+) -> Option<Result<(), Error>> {
+    let process = current()?;
+    let image = match find_program(name) {
+        Ok(image) => image,
+        Err(error) => return Some(Err(error)),
+    };
+    let (address_space, registers) = match load(&image, args, env) {
+        Ok(loaded) => loaded,
+        Err(error) => return Some(Err(error)),
+    };
+
+    let mut current = process.address_space.lock();
+    // SAFETY: the new address space is kept alive by the process until it
+    // exits or execs again, both of which switch away from it first.
+    unsafe { thread::set_page_table(Some(address_space.page_table())) };
+    let old = current.replace(address_space);
+    drop(current);
+    drop(old);
+
+    frame.set_registers(&registers);
+    Some(Ok(()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syscall::{
+        Errno, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE, SYS_BRK, SYS_EXEC, SYS_EXIT,
+        SYS_FORK, SYS_KILL, SYS_MMAP, SYS_MUNMAP, SYS_PIPE, SYS_READ, SYS_SLEEP, SYS_WAITPID,
+        SYS_WRITE, WNOHANG,
+    };
+    use crate::usermode::user_program;
+    use alloc::boxed::Box;
+    use core::arch::global_asm;
+
+    global_asm!(
+        ".pushsection .text",
+        // Exits with 5.
+        "toyos_test_exit:",
+        "mov eax, {exit}",
+        "mov edi, 5",
+        "syscall",
+        "toyos_test_exit_end:",
+        // Forks. The child overwrites a value on its stack and exits with 7.
+        // The parent waits for it, then exits with the child's exit status
+        // shifted left by 8 bits and its own copy of the value.
+        "toyos_test_fork:",
+        "push 1",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov qword ptr [rsp], 2",
+        "mov eax, {exit}",
+        "mov edi, 7",
+        "syscall",
+        "2:",
+        "mov rbx, rax",
+        "sub rsp, 8",
+        "mov eax, {waitpid}",
+        "mov rdi, rbx",
+        "mov rsi, rsp",
+        "xor edx, edx",
+        "syscall",
+        "cmp rax, rbx",
+        "jne 3f",
+        "mov rdi, [rsp]",
+        "shl rdi, 8",
+        "or rdi, [rsp + 8]",
+        "mov eax, {exit}",
+        "syscall",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "toyos_test_fork_end:",
+        // Forks two children which exit with 3 and 4 and waits for any child
+        // three times. Exits with the sum of the statuses if the third wait
+        // fails because there are no children left.
+        "toyos_test_wait_any:",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov eax, {exit}",
+        "mov edi, 3",
+        "syscall",
+        "2:",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov eax, {exit}",
+        "mov edi, 4",
+        "syscall",
+        "2:",
+        "sub rsp, 8",
+        "mov eax, {waitpid}",
+        "mov rdi, -1",
+        "mov rsi, rsp",
+        "xor edx, edx",
+        "syscall",
+        "mov rbx, [rsp]",
+        "mov eax, {waitpid}",
+        "syscall",
+        "add rbx, [rsp]",
+        "mov eax, {waitpid}",
+        "syscall",
+        "cmp rax, {echild}",
+        "jne 3f",
+        "mov eax, {exit}",
+        "mov rdi, rbx",
+        "syscall",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "toyos_test_wait_any_end:",
+        // Forks a child which sleeps for 50ms before exiting with 1. Exits
+        // without blocking, with the result of a non-blocking wait shifted
+        // left by 32 bits and the child's ID.
+        "toyos_test_orphan:",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "mov eax, {sleep}",
+        "mov edi, 50",
+        "syscall",
+        "mov eax, {exit}",
+        "mov edi, 1",
+        "syscall",
+        "2:",
+        "mov rbx, rax",
+        "mov eax, {waitpid}",
+        "mov rdi, rbx",
+        "xor esi, esi",
+        "mov edx, {wnohang}",
+        "syscall",
+        "mov rdi, rax",
+        "shl rdi, 32",
+        "or rdi, rbx",
+        "mov eax, {exit}",
+        "syscall",
+        "toyos_test_orphan_end:",
+        // Execs a missing program, then the one registered as "exit", and
+        // exits with 1 if either exec returns unexpectedly.
+        "toyos_test_exec:",
+        "mov eax, {exec}",
+        "lea rdi, [rip + 3f]",
+        "mov esi, 7",
+        "syscall",
+        "cmp rax, {enoent}",
+        "jne 2f",
+        "mov eax, {exec}",
+        "lea rdi, [rip + 3f]",
+        "mov esi, 4",
+        "syscall",
+        "2:",
+        "mov eax, {exit}",
+        "mov edi, 1",
+        "syscall",
+        "3:",
+        ".ascii \"exit???\"",
+        "toyos_test_exec_end:",
+        // Checks that the stack is aligned to 16 bytes and that the auxiliary
+        // vector holds the entry point. Exits with the argument count shifted
+        // left by 16 bits, the first byte of the second argument shifted left
+        // by 8 bits and the first byte of the first environment variable, or
+        // with 0xff on error.
+        "toyos_test_args:",
+        "test rsp, 15",
+        "jnz 3f",
+        "mov rbx, [rsp]",
+        "mov rax, [rsp + 16]",
+        "movzx r12d, byte ptr [rax]",
+        "lea rcx, [rsp + rbx * 8 + 16]",
+        "mov rax, [rcx]",
+        "movzx r13d, byte ptr [rax]",
+        "2:",
+        "add rcx, 8",
+        "cmp qword ptr [rcx - 8], 0",
+        "jne 2b",
+        "lea rdx, [rip + toyos_test_args]",
+        "4:",
+        "mov rax, [rcx]",
+        "test rax, rax",
+        "jz 3f",
+        "add rcx, 16",
+        "cmp rax, {at_entry}",
+        "jne 4b",
+        "cmp [rcx - 8], rdx",
+        "jne 3f",
+        "mov rdi, rbx",
+        "shl rdi, 16",
+        "shl r12, 8",
+        "or rdi, r12",
+        "or rdi, r13",
+        "mov eax, {exit}",
+        "syscall",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "toyos_test_args_end:",
+        // Execs the program registered as "args" with the arguments "prog"
+        // and "x" and the environment variable "y=1", and exits with 1 if the
+        // exec returns.
+        "toyos_test_exec_args:",
+        "lea rax, [rip + 2f]",
+        "lea rcx, [rip + 3f]",
+        "lea rdx, [rip + 4f]",
+        "push 0",
+        "push rdx",
+        "mov r10, rsp",
+        "push 0",
+        "push rcx",
+        "push rax",
+        "mov rdx, rsp",
+        "mov eax, {exec}",
+        "lea rdi, [rip + 5f]",
+        "mov esi, 4",
+        "syscall",
+        "mov eax, {exit}",
+        "mov edi, 1",
+        "syscall",
+        "2:",
+        ".asciz \"prog\"",
+        "3:",
+        ".asciz \"x\"",
+        "4:",
+        ".asciz \"y=1\"",
+        "5:",
+        ".ascii \"args\"",
+        "toyos_test_exec_args_end:",
+        // Spins without making system calls.
+        "toyos_test_spin:",
+        "jmp toyos_test_spin",
+        "toyos_test_spin_end:",
+        // Sleeps for 50ms and exits with the result.
+        "toyos_test_sleep:",
+        "mov eax, {sleep}",
+        "mov edi, 50",
+        "syscall",
+        "mov rdi, rax",
+        "mov eax, {exit}",
+        "syscall",
+        "toyos_test_sleep_end:",
+        // Forks a child which spins, kills it and exits with its status.
+        "toyos_test_kill:",
+        "mov eax, {fork}",
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "3:",
+        "jmp 3b",
+        "2:",
+        "mov rbx, rax",
+        "mov eax, {kill}",
+        "mov rdi, rbx",
+        "mov esi, 9",
+        "syscall",
+        "sub rsp, 8",
+        "mov eax, {waitpid}",
+        "mov rdi, rbx",
+        "mov rsi, rsp",
+        "xor edx, edx",
+        "syscall",
+        "mov rdi, [rsp]",
+        "mov eax, {exit}",
+        "syscall",
+        "toyos_test_kill_end:",
+        // Grows the heap by two pages, writes to the second and shrinks it
+        // again. Maps two anonymous pages, writes to the first and copies it
+        // to the second through a pipe, so that the kernel touches the page
+        // first. Unmaps both pages and writes to them, which terminates the
+        // process; exits with 0xff if anything fails before that.
+        "toyos_test_memory:",
+        "mov eax, {brk}",
+        "xor edi, edi",
+        "syscall",
+        "test rax, rax",
+        "jz 3f",
+        "mov rbx, rax",
+        "lea rdi, [rbx + 8192]",
+        "mov eax, {brk}",
+        "syscall",
+        "lea rcx, [rbx + 8192]",
+        "cmp rax, rcx",
+        "jne 3f",
+        "mov byte ptr [rbx + 4096], 7",
+        "mov rdi, rbx",
+        "mov eax, {brk}",
+        "syscall",
+        "cmp rax, rbx",
+        "jne 3f",
+        "mov eax, {mmap}",
+        "xor edi, edi",
+        "mov esi, 8192",
+        "mov edx, {prot}",
+        "mov r10d, {map}",
+        "mov r8, -1",
+        "xor r9d, r9d",
+        "syscall",
+        "test rax, rax",
+        "js 3f",
+        "mov r12, rax",
+        "mov byte ptr [r12], 5",
+        "sub rsp, 16",
+        "mov eax, {pipe}",
+        "mov rdi, rsp",
+        "syscall",
+        "test rax, rax",
+        "jnz 3f",
+        "mov eax, {write}",
+        "mov rdi, [rsp + 8]",
+        "mov rsi, r12",
+        "mov edx, 1",
+        "syscall",
+        "cmp rax, 1",
+        "jne 3f",
+        "mov eax, {read}",
+        "mov rdi, [rsp]",
+        "lea rsi, [r12 + 4096]",
+        "mov edx, 1",
+        "syscall",
+        "cmp rax, 1",
+        "jne 3f",
+        "cmp byte ptr [r12 + 4096], 5",
+        "jne 3f",
+        "mov eax, {munmap}",
+        "mov rdi, r12",
+        "mov esi, 8192",
+        "syscall",
+        "test rax, rax",
+        "jnz 3f",
+        "mov byte ptr [r12], 1",
+        "3:",
+        "mov eax, {exit}",
+        "mov edi, 0xff",
+        "syscall",
+        "toyos_test_memory_end:",
+        ".popsection",
+        exit = const SYS_EXIT,
+        fork = const SYS_FORK,
+        sleep = const SYS_SLEEP,
+        exec = const SYS_EXEC,
+        waitpid = const SYS_WAITPID,
+        kill = const SYS_KILL,
+        brk = const SYS_BRK,
+        mmap = const SYS_MMAP,
+        munmap = const SYS_MUNMAP,
+        pipe = const SYS_PIPE,
+        read = const SYS_READ,
+        write = const SYS_WRITE,
+        prot = const PROT_READ | PROT_WRITE,
+        map = const MAP_PRIVATE | MAP_ANONYMOUS,
+        wnohang = const WNOHANG,
+        at_entry = const stack::AT_ENTRY,
+        enoent = const -(Errno::NoEnt as i64),
+        echild = const -(Errno::Child as i64),
+    );
+
+    #[test_case]
+    fn test_spawn() {
+        let image = elf::test::image(user_program!(toyos_test_exit));
+        let process = spawn(&image).expect("failed to spawn process");
+
+        assert_eq!(process.parent(), None);
+        assert_eq!(process.wait(), 5);
+        assert!(get(process.pid()).is_none());
+    }
+
+    #[test_case]
+    fn test_spawn_invalid_image() {
+        assert_eq!(spawn(&[0; 64]).unwrap_err(), Error::Elf(elf::Error::NotElf));
+    }
+
+    #[test_case]
+    fn test_fork_and_waitpid() {
+        let image = elf::test::image(user_program!(toyos_test_fork));
+        let parent = spawn(&image).expect("failed to spawn process");
+
+        assert_eq!(parent.wait(), 7 << 8 | 1);
+        assert!(parent.children().is_empty());
+    }
+
+    #[test_case]
+    fn test_wait_for_any_child() {
+        let image = elf::test::image(user_program!(toyos_test_wait_any));
+        let parent = spawn(&image).expect("failed to spawn process");
+
+        assert_eq!(parent.wait(), 7);
+        assert!(list()
+            .iter()
+            .all(|process| process.parent() != Some(parent.pid())));
+    }
+
+    #[test_case]
+    fn test_orphans_are_reaped() {
+        let image = elf::test::image(user_program!(toyos_test_orphan));
+        let parent = spawn(&image).expect("failed to spawn process");
+        let status = parent.wait();
+        assert_eq!(status >> 32, 0);
+
+        let pid = Pid::from_u64(status & 0xffff_ffff);
+        if let Some(child) = get(pid) {
+            assert_eq!(child.parent(), None);
+            assert_eq!(child.wait(), 1);
+        }
+        assert!(get(pid).is_none());
+    }
+
+    #[test_case]
+    fn test_exec() {
+        let exit = elf::test::image(user_program!(toyos_test_exit));
+        register_program("exit", Box::leak(exit.into_boxed_slice()));
+
+        let image = elf::test::image(user_program!(toyos_test_exec));
+        let process = spawn(&image).expect("failed to spawn process");
+        assert_eq!(process.wait(), 5);
+    }
+
+    #[test_case]
+    fn test_spawn_program_from_filesystem() {
+        let exit = elf::test::image(user_program!(toyos_test_exit));
+        let mut builder = fs::initrd::Builder::new();
+        builder
+            .file("exit", Box::leak(exit.into_boxed_slice()))
+            .unwrap();
+        fs::mount("/process-test", Arc::new(builder.build())).unwrap();
+
+        let process = spawn_program("/process-test/exit", &[], &[]).unwrap();
+        assert_eq!(process.wait(), 5);
+        assert_eq!(
+            spawn_program("/process-test/missing", &[], &[]).unwrap_err(),
+            Error::NotFound
+        );
+        assert_eq!(
+            spawn_program("/process-test", &[], &[]).unwrap_err(),
+            Error::File(fs::Error::IsADirectory)
+        );
+        fs::unmount("/process-test").unwrap();
+    }
+
+    #[test_case]
+    fn test_spawn_with_args() {
+        let image = elf::test::image(user_program!(toyos_test_args));
+        let process =
+            spawn_with_args(&image, &["prog", "x"], &["y=1"]).expect("failed to spawn process");
+        assert_eq!(
+            process.wait(),
+            2 << 16 | u64::from(b'x') << 8 | u64::from(b'y')
+        );
+
+        let arg = "a".repeat(stack::MAX_ARGUMENTS_SIZE as usize);
+        assert_eq!(
+            spawn_with_args(&image, &[&arg], &[]).unwrap_err(),
+            Error::ArgumentsTooLong
+        );
+    }
+
+    #[test_case]
+    fn test_exec_with_args() {
+        let args = elf::test::image(user_program!(toyos_test_args));
+        register_program("args", Box::leak(args.into_boxed_slice()));
+
+        let image = elf::test::image(user_program!(toyos_test_exec_args));
+        let process = spawn(&image).expect("failed to spawn process");
+        assert_eq!(
+            process.wait(),
+            2 << 16 | u64::from(b'x') << 8 | u64::from(b'y')
+        );
+    }
+
+    #[test_case]
+    fn test_signal_stops_spinning_process() {
+        let image = elf::test::image(user_program!(toyos_test_spin));
+        let process = spawn(&image).expect("failed to spawn process");
+        set_foreground(Some(process.pid()));
+
+        kill(process.pid(), Signal::Interrupt).expect("process missing");
+        assert_eq!(process.wait(), Signal::Interrupt.exit_status());
+        assert_eq!(foreground(), None);
+        assert_eq!(kill(process.pid(), Signal::Interrupt), Err(NoSuchProcess));
+    }
+
+    #[test_case]
+    fn test_signal_interrupts_sleep() {
+        let image = elf::test::image(user_program!(toyos_test_sleep));
+        let process = spawn(&image).expect("failed to spawn process");
+        let _ = kill(process.pid(), Signal::Terminate);
+        assert_eq!(process.wait(), Signal::Terminate.exit_status());
+    }
+
+    #[test_case]
+    fn test_ignored_signal() {
+        let image = elf::test::image(user_program!(toyos_test_sleep));
+        let process = spawn(&image).expect("failed to spawn process");
+        let _ = kill(process.pid(), Signal::Child);
+        assert_eq!(process.wait(), 0);
+    }
+
+    #[test_case]
+    fn test_kill_system_call() {
+        let image = elf::test::image(user_program!(toyos_test_kill));
+        let process = spawn(&image).expect("failed to spawn process");
+        assert_eq!(process.wait(), Signal::Kill.exit_status());
+    }
+
+    #[test_case]
+    fn test_heap_and_anonymous_memory() {
+        let image = elf::test::image(user_program!(toyos_test_memory));
+        let process = spawn(&image).expect("failed to spawn process");
+        assert_eq!(process.wait(), Signal::SegmentationFault.exit_status());
+    }
+}
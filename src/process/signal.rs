@@ -0,0 +1,157 @@
+//! Signals sent to processes.
+//!
+//! Sending a signal only marks it pending in the target's bitmap. Pending
+//! signals are acted upon when the process is about to return to user mode,
+//! at the end of a system call or of a timer interrupt which arrived in user
+//! mode. Processes cannot install handlers yet, so every signal takes its
+//! [default action](Signal::default_action).
+//!
+//! A process terminated by a signal exits with a status of 128 plus the
+//! signal number, as reported by shells.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A signal. The numbers match those used by Linux on x86-64.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Signal {
+    Hangup = 1,
+    Interrupt = 2,
+    Quit = 3,
+    Kill = 9,
+    User1 = 10,
+    SegmentationFault = 11,
+    User2 = 12,
+    Terminate = 15,
+    Child = 17,
+}
+
+/// What happens to a process when it receives a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Terminate,
+    Ignore,
+}
+
+impl Signal {
+    /// All signals, ordered by number.
+    pub const ALL: [Signal; 9] = [
+        Signal::Hangup,
+        Signal::Interrupt,
+        Signal::Quit,
+        Signal::Kill,
+        Signal::User1,
+        Signal::SegmentationFault,
+        Signal::User2,
+        Signal::Terminate,
+        Signal::Child,
+    ];
+
+    /// Returns the signal with the given number, if there is one.
+    pub fn from_number(number: u64) -> Option<Signal> {
+        Signal::ALL
+            .iter()
+            .copied()
+            .find(|&signal| signal as u64 == number)
+    }
+
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns what a process does on receiving the signal.
+    pub fn default_action(self) -> Action {
+        match self {
+            Signal::Child => Action::Ignore,
+            _ => Action::Terminate,
+        }
+    }
+
+    /// Returns the exit status of a process terminated by the signal.
+    pub fn exit_status(self) -> u64 {
+        128 + self as u64
+    }
+
+    fn bit(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Signal::Hangup => "SIGHUP",
+            Signal::Interrupt => "SIGINT",
+            Signal::Quit => "SIGQUIT",
+            Signal::Kill => "SIGKILL",
+            Signal::User1 => "SIGUSR1",
+            Signal::SegmentationFault => "SIGSEGV",
+            Signal::User2 => "SIGUSR2",
+            Signal::Terminate => "SIGTERM",
+            Signal::Child => "SIGCHLD",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The signals pending for a process.
+#[derive(Debug, Default)]
+pub struct Pending(AtomicU64);
+
+impl Pending {
+    pub const fn new() -> Self {
+        Pending(AtomicU64::new(0))
+    }
+
+    /// Marks `signal` as pending.
+    pub fn raise(&self, signal: Signal) {
+        self.0.fetch_or(signal.bit(), Ordering::AcqRel);
+    }
+
+    /// Returns `true` if a signal which terminates the process is pending.
+    pub fn is_fatal(&self) -> bool {
+        let pending = self.0.load(Ordering::Acquire);
+        Signal::ALL.iter().any(|&signal| {
+            pending & signal.bit() != 0 && signal.default_action() == Action::Terminate
+        })
+    }
+
+    /// Clears all pending signals and returns the lowest numbered one which
+    /// terminates the process, if any.
+    pub fn take_fatal(&self) -> Option<Signal> {
+        let pending = self.0.swap(0, Ordering::AcqRel);
+        Signal::ALL.iter().copied().find(|&signal| {
+            pending & signal.bit() != 0 && signal.default_action() == Action::Terminate
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_from_number() {
+        assert_eq!(Signal::from_number(2), Some(Signal::Interrupt));
+        assert_eq!(Signal::from_number(9), Some(Signal::Kill));
+        assert_eq!(Signal::from_number(0), None);
+        assert_eq!(Signal::from_number(64), None);
+    }
+
+    #[test_case]
+    fn test_ignored_signals_are_discarded() {
+        let pending = Pending::new();
+        pending.raise(Signal::Child);
+        assert!(!pending.is_fatal());
+        assert_eq!(pending.take_fatal(), None);
+
+        pending.raise(Signal::Terminate);
+        pending.raise(Signal::Interrupt);
+        assert!(pending.is_fatal());
+        assert_eq!(pending.take_fatal(), Some(Signal::Interrupt));
+        assert_eq!(pending.take_fatal(), None);
+    }
+}
@@ -0,0 +1,143 @@
+//! The initial stack of a program.
+//!
+//! Programs start with the stack pointer at their argument count, followed
+//! by null terminated arrays of pointers to the arguments and the environment
+//! variables, and then by the auxiliary vector: key value pairs describing
+//! the executable, ended by [AT_NULL]. The strings themselves are stored at
+//! the top of the stack. This is the layout the x86-64 System V ABI
+//! specifies, so C runtimes' `_start` works unmodified.
+//!
+//! See: https://refspecs.linuxfoundation.org/elf/x86_64-abi-0.99.pdf, 3.4.1
+
+use alloc::vec::Vec;
+
+use x86_64::{align_down, VirtAddr};
+
+use super::{elf::Executable, AddressSpace, Error};
+use crate::usermode::{USER_STACK_SIZE, USER_STACK_TOP};
+
+/// Ends the auxiliary vector.
+pub const AT_NULL: u64 = 0;
+/// Address of the program headers in memory.
+pub const AT_PHDR: u64 = 3;
+/// Size of a program header.
+pub const AT_PHENT: u64 = 4;
+/// Number of program headers.
+pub const AT_PHNUM: u64 = 5;
+/// Size of a page.
+pub const AT_PAGESZ: u64 = 6;
+/// Entry point of the program.
+pub const AT_ENTRY: u64 = 9;
+
+/// Maximum size of the arguments, environment and auxiliary vector, so that
+/// at least half of the stack is left to the program.
+pub const MAX_ARGUMENTS_SIZE: u64 = USER_STACK_SIZE / 2;
+
+/// Writes the initial stack for `executable` to the top of the stack in
+/// `address_space` and returns the stack pointer to start with.
+pub(super) fn build(
+    address_space: &mut AddressSpace,
+    executable: &Executable,
+    args: &[&[u8]],
+    env: &[&[u8]],
+) -> Result<VirtAddr, Error> {
+    let mut auxv = Vec::new();
+    if let Some(program_headers) = executable.program_headers {
+        auxv.push((AT_PHDR, program_headers.as_u64()));
+    }
+    auxv.push((AT_PHENT, executable.program_header_size.into()));
+    auxv.push((AT_PHNUM, executable.program_header_count.into()));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, executable.entry.as_u64()));
+
+    let (stack_pointer, contents) = layout(USER_STACK_TOP, args, env, &auxv)?;
+    address_space.write(VirtAddr::new(stack_pointer), &contents);
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// Lays out an initial stack ending at `top`. Returns the stack pointer and
+/// the contents of the stack from there up to `top`.
+fn layout(
+    top: u64,
+    args: &[&[u8]],
+    env: &[&[u8]],
+    auxv: &[(u64, u64)],
+) -> Result<(u64, Vec<u8>), Error> {
+    let strings_size: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * (auxv.len() + 1);
+    let size = strings_size as u64 + words as u64 * 8 + 15;
+    if size > MAX_ARGUMENTS_SIZE {
+        return Err(Error::ArgumentsTooLong);
+    }
+
+    let strings_start = top - strings_size as u64;
+    let stack_pointer = align_down(strings_start - words as u64 * 8, 16);
+
+    let mut pointers = Vec::with_capacity(words);
+    let mut strings = Vec::with_capacity(strings_size);
+    pointers.push(args.len() as u64);
+    for list in [args, env] {
+        for string in list {
+            pointers.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(string);
+            strings.push(0);
+        }
+        pointers.push(0);
+    }
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        pointers.push(key);
+        pointers.push(value);
+    }
+
+    let mut contents: Vec<u8> = pointers
+        .iter()
+        .flat_map(|word| word.to_ne_bytes())
+        .collect();
+    contents.resize((strings_start - stack_pointer) as usize, 0);
+    contents.extend_from_slice(&strings);
+    Ok((stack_pointer, contents))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn word(contents: &[u8], index: usize) -> u64 {
+        u64::from_ne_bytes(contents[index * 8..index * 8 + 8].try_into().unwrap())
+    }
+
+    #[test_case]
+    fn test_layout() {
+        let top = 0x1000;
+        let (stack_pointer, contents) =
+            layout(top, &[b"prog", b"-v"], &[b"A=1"], &[(AT_PAGESZ, 4096)]).unwrap();
+        assert_eq!(stack_pointer % 16, 0);
+        assert_eq!(stack_pointer + contents.len() as u64, top);
+
+        let string = |pointer: u64| {
+            let start = (pointer - stack_pointer) as usize;
+            let len = contents[start..]
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap();
+            &contents[start..start + len]
+        };
+        assert_eq!(word(&contents, 0), 2);
+        assert_eq!(string(word(&contents, 1)), b"prog");
+        assert_eq!(string(word(&contents, 2)), b"-v");
+        assert_eq!(word(&contents, 3), 0);
+        assert_eq!(string(word(&contents, 4)), b"A=1");
+        assert_eq!(word(&contents, 5), 0);
+        assert_eq!([word(&contents, 6), word(&contents, 7)], [AT_PAGESZ, 4096]);
+        assert_eq!([word(&contents, 8), word(&contents, 9)], [AT_NULL, 0]);
+    }
+
+    #[test_case]
+    fn test_layout_too_large() {
+        let arg = [b'a'; MAX_ARGUMENTS_SIZE as usize];
+        assert_eq!(
+            layout(0x10_0000, &[&arg], &[], &[]).unwrap_err(),
+            Error::ArgumentsTooLong
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Random numbers.
+//!
+//! The kernel's generator is a [ChaChaRng] seeded from the best source of
+//! entropy the CPU has: `rdseed`, then `rdrand`, and otherwise the jitter of
+//! the time stamp counter. [fill] and [next_u64] draw from it, and drivers
+//! for other sources of entropy mix what they gather in with [add_entropy].
+//!
+//! After every use the generator replaces its key with its own output, so
+//! that its state does not reveal what it generated before.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc8439 and
+//! https://wiki.osdev.org/Random_Number_Generator
+
+use core::arch::asm;
+
+use spin::Once;
+
+use crate::{cpu, sync::IrqSpinlock, time::tsc};
+
+/// The first words of the ChaCha state: "expand 32-byte k".
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// Size of a block of ChaCha output.
+const BLOCK_SIZE: usize = 64;
+
+/// Number of times `rdseed` and `rdrand` are retried when they run out of
+/// entropy, as they may while other CPUs use them.
+const RETRIES: usize = 10;
+
+/// Number of time stamp counter samples folded into 64 bits of jitter.
+const JITTER_SAMPLES: usize = 64;
+
+/// Where the seed of the kernel's generator comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    RdSeed,
+    RdRand,
+    /// The timing of a short loop, measured with the time stamp counter,
+    /// which varies with caches, interrupts and the state of the pipeline.
+    Jitter,
+}
+
+static SOURCE: Once<Source> = Once::new();
+
+/// The kernel's generator, seeded when first used.
+static RNG: IrqSpinlock<Option<ChaChaRng>> = IrqSpinlock::new(None);
+
+/// Returns the source of entropy the kernel's generator is seeded from.
+pub fn source() -> Source {
+    *SOURCE.call_once(|| {
+        if cpu::has_rdseed() {
+            Source::RdSeed
+        } else if cpu::has_rdrand() {
+            Source::RdRand
+        } else {
+            Source::Jitter
+        }
+    })
+}
+
+fn rdseed() -> Option<u64> {
+    (0..RETRIES).find_map(|_| {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        (ok != 0).then_some(value)
+    })
+}
+
+fn rdrand() -> Option<u64> {
+    (0..RETRIES).find_map(|_| {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        (ok != 0).then_some(value)
+    })
+}
+
+/// Returns 64 bits gathered from the jitter of the time stamp counter. Each
+/// sample contributes little entropy, so many are folded together.
+fn jitter() -> u64 {
+    (0..JITTER_SAMPLES).fold(0, |value: u64, _| {
+        let start = tsc::rdtsc();
+        for round in 0..16 {
+            core::hint::black_box(round);
+        }
+        value.rotate_left(7) ^ tsc::rdtsc().wrapping_sub(start)
+    })
+}
+
+/// Fills `buf` straight from the CPU's source of entropy, which is slow.
+fn fill_entropy(buf: &mut [u8]) {
+    let source = source();
+    for chunk in buf.chunks_mut(8) {
+        let value = match source {
+            Source::RdSeed => rdseed().or_else(rdrand),
+            Source::RdRand => rdrand(),
+            Source::Jitter => None,
+        }
+        .unwrap_or_else(jitter);
+        chunk.copy_from_slice(&value.to_ne_bytes()[..chunk.len()]);
+    }
+}
+
+/// Computes a block of ChaCha20 output.
+fn block(key: &[u8; 32], counter: u64, nonce: u64) -> [u8; BLOCK_SIZE] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CONSTANTS);
+    for (word, bytes) in state[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    state[12] = counter as u32;
+    state[13] = (counter >> 32) as u32;
+    state[14] = nonce as u32;
+    state[15] = (nonce >> 32) as u32;
+
+    let mut working = state;
+    let mut quarter_round = |a: usize, b: usize, c: usize, d: usize| {
+        working[a] = working[a].wrapping_add(working[b]);
+        working[d] = (working[d] ^ working[a]).rotate_left(16);
+        working[c] = working[c].wrapping_add(working[d]);
+        working[b] = (working[b] ^ working[c]).rotate_left(12);
+        working[a] = working[a].wrapping_add(working[b]);
+        working[d] = (working[d] ^ working[a]).rotate_left(8);
+        working[c] = working[c].wrapping_add(working[d]);
+        working[b] = (working[b] ^ working[c]).rotate_left(7);
+    };
+    // 20 rounds, alternating between columns and diagonals.
+    for _ in 0..10 {
+        quarter_round(0, 4, 8, 12);
+        quarter_round(1, 5, 9, 13);
+        quarter_round(2, 6, 10, 14);
+        quarter_round(3, 7, 11, 15);
+        quarter_round(0, 5, 10, 15);
+        quarter_round(1, 6, 11, 12);
+        quarter_round(2, 7, 8, 13);
+        quarter_round(3, 4, 9, 14);
+    }
+
+    let mut output = [0; BLOCK_SIZE];
+    for ((bytes, word), initial) in output.chunks_exact_mut(4).zip(working).zip(state) {
+        bytes.copy_from_slice(&word.wrapping_add(initial).to_le_bytes());
+    }
+    output
+}
+
+/// A cryptographically secure pseudo-random number generator producing the
+/// ChaCha20 keystream of its seed.
+pub struct ChaChaRng {
+    key: [u8; 32],
+    counter: u64,
+    block: [u8; BLOCK_SIZE],
+    /// Number of bytes of `block` already used.
+    used: usize,
+}
+
+impl ChaChaRng {
+    /// Creates a generator whose output is determined by `seed`.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        ChaChaRng {
+            key: seed,
+            counter: 0,
+            block: [0; BLOCK_SIZE],
+            used: BLOCK_SIZE,
+        }
+    }
+
+    /// Creates a generator seeded from the CPU's source of entropy.
+    pub fn from_entropy() -> Self {
+        let mut seed = [0; 32];
+        fill_entropy(&mut seed);
+        ChaChaRng::from_seed(seed)
+    }
+
+    /// Fills `buf` with the next bytes of output.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.used == BLOCK_SIZE {
+                self.block = block(&self.key, self.counter, 0);
+                self.counter += 1;
+                self.used = 0;
+            }
+            let len = (buf.len() - filled).min(BLOCK_SIZE - self.used);
+            buf[filled..filled + len].copy_from_slice(&self.block[self.used..self.used + len]);
+            self.used += len;
+            filled += len;
+        }
+    }
+
+    /// Returns the next 8 bytes of output as a number.
+    pub fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Mixes `data` into the key, and replaces the key with output.
+    fn reseed(&mut self, data: &[u8]) {
+        // The key is replaced after each chunk, so that chunks do not cancel
+        // each other out.
+        for chunk in data.chunks(32) {
+            self.key
+                .iter_mut()
+                .zip(chunk)
+                .for_each(|(key, byte)| *key ^= byte);
+            self.rekey();
+        }
+        self.rekey();
+    }
+
+    /// Replaces the key with output of a fresh block.
+    fn rekey(&mut self) {
+        self.used = BLOCK_SIZE;
+        let mut key = [0; 32];
+        self.fill(&mut key);
+        self.key = key;
+        // Nothing generated with the old key may be handed out.
+        self.used = BLOCK_SIZE;
+    }
+}
+
+/// Runs `f` with the kernel's generator, then replaces its key.
+fn with_rng<R>(f: impl FnOnce(&mut ChaChaRng) -> R) -> R {
+    let mut rng = RNG.lock();
+    let rng = rng.get_or_insert_with(ChaChaRng::from_entropy);
+    let result = f(rng);
+    rng.rekey();
+    result
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    with_rng(|rng| rng.fill(buf))
+}
+
+/// Returns a random number.
+pub fn next_u64() -> u64 {
+    with_rng(ChaChaRng::next_u64)
+}
+
+/// Mixes `data`, gathered from a source of entropy, into the kernel's
+/// generator.
+pub fn add_entropy(data: &[u8]) {
+    with_rng(|rng| rng.reseed(data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_block() {
+        // The test vector of section 2.3.2 of RFC 8439, whose 96-bit nonce
+        // overlaps the high half of the counter.
+        let key = core::array::from_fn(|index| index as u8);
+        let output = block(&key, 0x0900_0000_0000_0001, 0x4a00_0000);
+        assert_eq!(
+            output[..8],
+            [0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15]
+        );
+        assert_eq!(
+            output[56..],
+            [0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e]
+        );
+    }
+
+    #[test_case]
+    fn test_chacha_rng() {
+        let mut rng = ChaChaRng::from_seed([7; 32]);
+        let mut bytes = [0; 100];
+        rng.fill(&mut bytes[..3]);
+        rng.fill(&mut bytes[3..]);
+        assert_eq!(bytes[..BLOCK_SIZE], block(&[7; 32], 0, 0));
+        assert_eq!(bytes[BLOCK_SIZE..], block(&[7; 32], 1, 0)[..36]);
+
+        let mut other = ChaChaRng::from_seed([7; 32]);
+        other.reseed(b"entropy");
+        assert_ne!(other.next_u64(), ChaChaRng::from_seed([7; 32]).next_u64());
+    }
+
+    #[test_case]
+    fn test_fill() {
+        let mut first = [0; 32];
+        let mut second = [0; 32];
+        fill(&mut first);
+        add_entropy(b"entropy");
+        fill(&mut second);
+        assert_ne!(first, second);
+        assert_ne!(jitter(), jitter());
+    }
+}
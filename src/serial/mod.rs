@@ -0,0 +1,377 @@
+//! Serial ports.
+//!
+//! [COM1] to [COM4] are the standard serial ports of PC compatibles. Each is
+//! a [SerialPort] with its own lock, initialized when first used, so that
+//! different ports can serve different purposes at the same time. Kernel
+//! output written by [serial_print] and [serial_println] goes to COM1, or
+//! to the [Output] selected with [set_output].
+//!
+//! Once [SerialPort::init_input] has been called for a port, the interrupt
+//! handler of its IRQ queues the bytes it receives and deferred work wakes the
+//! task waiting for them in [SerialPort::read_byte] or
+//! [SerialPort::read_line]. Files can be received with [xmodem].
+
+pub mod uart;
+pub mod xmodem;
+
+use alloc::string::String;
+use core::{
+    future,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU8, AtomicUsize, Ordering},
+    task::Poll,
+};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use log::warn;
+use spin::{Mutex, Once};
+
+use self::uart::{Config, Uart};
+use crate::{
+    console::Console,
+    debugcon,
+    interrupts::{
+        self,
+        deferred::{self, Work},
+    },
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+    task::input::ByteQueue,
+};
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Maximum number of bytes buffered for each port between the interrupt
+/// handler and the readers.
+const RECEIVE_QUEUE_CAPACITY: usize = 256;
+
+pub static COM1: SerialPort = SerialPort::new("COM1", 0x3f8, 4);
+pub static COM2: SerialPort = SerialPort::new("COM2", 0x2f8, 3);
+pub static COM3: SerialPort = SerialPort::new("COM3", 0x3e8, 4);
+pub static COM4: SerialPort = SerialPort::new("COM4", 0x2e8, 3);
+
+static PORTS: [&SerialPort; 4] = [&COM1, &COM2, &COM3, &COM4];
+
+/// Where [serial_print] and [serial_println] write to.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+    /// The [debug console](debugcon) of Bochs and QEMU.
+    DebugCon,
+}
+
+static OUTPUT: AtomicU8 = AtomicU8::new(Output::Com1 as u8);
+
+/// Sets where [serial_print] and [serial_println] write to.
+pub fn set_output(output: Output) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+/// Returns where [serial_print] and [serial_println] write to.
+pub fn output() -> Output {
+    match OUTPUT.load(Ordering::Relaxed) {
+        output if output == Output::DebugCon as u8 => Output::DebugCon,
+        output => [Output::Com1, Output::Com2, Output::Com3, Output::Com4][output as usize],
+    }
+}
+
+/// Bit mask of the IRQs which have a handler for received bytes.
+static HANDLED_IRQS: AtomicU16 = AtomicU16::new(0);
+
+/// Deferred work raised by the interrupt handlers when bytes have been
+/// received.
+static RECEIVE_WORK: OnceCell<Work> = OnceCell::uninit();
+
+/// One of the standard serial ports.
+pub struct SerialPort {
+    name: &'static str,
+    base: u16,
+    irq: u8,
+    uart: Once<IrqSpinlock<Uart>>,
+    /// Set once [init_input](Self::init_input) has been called.
+    input: AtomicBool,
+    receive_queue: IrqSpinlock<ByteQueue<RECEIVE_QUEUE_CAPACITY>>,
+    receive_waker: AtomicWaker,
+    /// Number of bytes dropped by the interrupt handler since the deferred
+    /// work last ran.
+    dropped_bytes: AtomicUsize,
+    /// The line [read_line](Self::read_line) is reading, which survives a
+    /// dropped future.
+    line: Mutex<LineEditor>,
+}
+
+impl SerialPort {
+    const fn new(name: &'static str, base: u16, irq: u8) -> Self {
+        SerialPort {
+            name,
+            base,
+            irq,
+            uart: Once::new(),
+            input: AtomicBool::new(false),
+            receive_queue: IrqSpinlock::new(ByteQueue::new()),
+            receive_waker: AtomicWaker::new(),
+            dropped_bytes: AtomicUsize::new(0),
+            line: Mutex::new(LineEditor::new()),
+        }
+    }
+
+    /// Returns the port's name, like `COM1`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the port's UART, initializing it if this is its first use.
+    fn uart(&self) -> &IrqSpinlock<Uart> {
+        self.uart.call_once(|| {
+            let mut uart = unsafe { Uart::new(self.base) };
+            uart.init();
+            IrqSpinlock::new(uart)
+        })
+    }
+
+    /// Locks the port's UART, initializing it if this is its first use.
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, Uart> {
+        self.uart().lock()
+    }
+
+    /// Returns the port's UART without locking it, initializing it if this is
+    /// its first use.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that using the UART while the lock may be
+    /// held does no harm, as when sending bytes which may interleave with
+    /// those of the holder.
+    pub unsafe fn unlocked(&self) -> Uart {
+        self.uart();
+        Uart::new(self.base)
+    }
+
+    /// Changes how the port sends and receives characters, initializing it
+    /// first if this is its first use.
+    pub fn configure(&self, config: Config) -> Result<(), uart::Error> {
+        self.lock().configure(config)
+    }
+
+    /// Routes the bytes the port receives through its IRQ to
+    /// [read_byte](Self::read_byte) and [read_line](Self::read_line).
+    pub fn init_input(&self) {
+        RECEIVE_WORK.init_once(|| deferred::register(bytes_received));
+        self.input.store(true, Ordering::Release);
+        self.lock().enable_receive_interrupt();
+
+        // Ports sharing an IRQ share its handler.
+        if HANDLED_IRQS.fetch_or(1 << self.irq, Ordering::AcqRel) & (1 << self.irq) != 0 {
+            return;
+        }
+        let handler = match self.irq {
+            3 => handle_interrupt::<3>,
+            _ => handle_interrupt::<4>,
+        };
+        if !interrupts::add_irq_handler(self.irq, handler) {
+            warn!("no handler for {} IRQ {}", self.name, self.irq);
+        }
+    }
+
+    /// Queues the bytes received. Called in interrupt context.
+    fn take_received(&self) {
+        let mut uart = self.lock();
+        let mut queue = self.receive_queue.lock();
+        // The FIFO may hold several bytes by the time the interrupt arrives.
+        while let Some(byte) = uart.try_receive() {
+            if !queue.push(byte) {
+                self.dropped_bytes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Waits for the next byte received.
+    pub async fn read_byte(&self) -> u8 {
+        future::poll_fn(|cx| {
+            if let Some(byte) = self.receive_queue.lock().pop() {
+                return Poll::Ready(byte);
+            }
+
+            self.receive_waker.register(cx.waker());
+            match self.receive_queue.lock().pop() {
+                Some(byte) => Poll::Ready(byte),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Waits for a line to be typed on the port, echoing it as it is typed.
+    /// The line ends at a carriage return or line feed, which is not
+    /// included.
+    pub async fn read_line(&self) -> String {
+        loop {
+            let byte = self.read_byte().await;
+            let mut uart = self.lock();
+            if let Some(line) = self.line.lock().feed(byte, |byte| uart.send(byte)) {
+                return line;
+            }
+        }
+    }
+}
+
+impl Console for SerialPort {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn print(&self, args: core::fmt::Arguments) {
+        use core::fmt::Write;
+
+        _ = self.lock().write_fmt(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let result = match output() {
+        Output::DebugCon => debugcon::lock().write_fmt(args),
+        output => PORTS[output as usize].lock().write_fmt(args),
+    };
+    result.expect("Printing to serial failed");
+}
+
+/// Prints to the host through the serial interface.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*));
+    };
+}
+
+/// Prints to the host through the serial interface, appending a newline.
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Handler for the IRQ of the ports with input, which are polled as ports
+/// may share it.
+///
+/// This function runs in interrupt context and must not block or print; the
+/// readers are woken and errors are reported by deferred work instead.
+fn handle_interrupt<const IRQ: u8>() {
+    for port in PORTS {
+        if port.irq == IRQ && port.input.load(Ordering::Acquire) {
+            port.take_received();
+        }
+    }
+
+    if let Ok(work) = RECEIVE_WORK.try_get() {
+        work.raise();
+    }
+}
+
+/// Deferred half of the interrupt handlers.
+fn bytes_received() {
+    for port in PORTS {
+        let dropped = port.dropped_bytes.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            warn!("{} queue full; dropped {} byte(s)", port.name, dropped);
+        }
+        port.receive_waker.wake();
+    }
+}
+
+/// Assembles received bytes into lines, as a terminal in canonical mode
+/// would.
+struct LineEditor {
+    line: String,
+    /// Set after a line ended with a carriage return, so that the line feed
+    /// terminals send along with it does not end another line.
+    after_carriage_return: bool,
+}
+
+impl LineEditor {
+    const fn new() -> Self {
+        LineEditor {
+            line: String::new(),
+            after_carriage_return: false,
+        }
+    }
+
+    /// Feeds a received byte to the editor, passing the bytes to echo to
+    /// `echo`. Returns the line the byte ends, if any.
+    ///
+    /// Backspace and delete erase the last character. Other control
+    /// characters and bytes outside of ASCII are ignored.
+    fn feed(&mut self, byte: u8, mut echo: impl FnMut(u8)) -> Option<String> {
+        let after_carriage_return = core::mem::take(&mut self.after_carriage_return);
+        match byte {
+            b'\n' if after_carriage_return => {}
+            b'\r' | b'\n' => {
+                self.after_carriage_return = byte == b'\r';
+                echo(b'\r');
+                echo(b'\n');
+                return Some(core::mem::take(&mut self.line));
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    // Moves back over the character and blanks it.
+                    b"\x08 \x08".iter().for_each(|&byte| echo(byte));
+                }
+            }
+            b' '..=b'~' => {
+                self.line.push(byte as char);
+                echo(byte);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn feed(editor: &mut LineEditor, bytes: &[u8], echoed: &mut Vec<u8>) -> Vec<String> {
+        bytes
+            .iter()
+            .filter_map(|&byte| editor.feed(byte, |byte| echoed.push(byte)))
+            .collect()
+    }
+
+    #[test_case]
+    fn test_line_editor() {
+        let mut editor = LineEditor::new();
+        let mut echoed = Vec::new();
+
+        let lines = feed(&mut editor, b"lx\x7fs\x01\r\nhalt\n\n", &mut echoed);
+        assert_eq!(lines, ["ls", "halt", ""]);
+        assert_eq!(echoed, b"lx\x08 \x08s\r\nhalt\r\n\r\n");
+    }
+
+    #[test_case]
+    fn test_output() {
+        assert_eq!(output(), Output::Com1);
+        set_output(Output::Com2);
+        assert_eq!(output(), Output::Com2);
+        set_output(Output::DebugCon);
+        assert_eq!(output(), Output::DebugCon);
+        set_output(Output::Com1);
+    }
+
+    #[test_case]
+    fn test_ports() {
+        let names = PORTS.map(SerialPort::name);
+        assert_eq!(names, ["COM1", "COM2", "COM3", "COM4"]);
+        // COM1 is initialized by the test runner's output.
+        assert!(COM1.uart.is_completed());
+    }
+}
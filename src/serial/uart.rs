@@ -0,0 +1,298 @@
+//! Driver for the 16550 UART behind the serial ports of PC compatibles.
+//!
+//! A UART starts out with the [default](Config::default) configuration,
+//! which [Uart::configure] changes: the baud rate, the format of characters
+//! and the use of the FIFOs. Both ends of the line must agree on them.
+//!
+//! See: https://wiki.osdev.org/Serial_Ports
+
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+// Offsets of the registers from the base port.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+// While the line control register's DLAB bit is set, the first two
+// registers hold the divisor of the baud rate instead.
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+/// Bit of the interrupt enable register raising an interrupt when a byte is
+/// received.
+const INTERRUPT_RECEIVED: u8 = 1 << 0;
+
+// Bits of the FIFO control register.
+const FIFO_ENABLE: u8 = 1 << 0;
+const FIFO_CLEAR_RECEIVE: u8 = 1 << 1;
+const FIFO_CLEAR_TRANSMIT: u8 = 1 << 2;
+const FIFO_TRIGGER_SHIFT: u8 = 6;
+
+// Bits of the line control register.
+const LINE_CONTROL_STOP_BITS: u8 = 1 << 2;
+const LINE_CONTROL_PARITY_SHIFT: u8 = 3;
+/// Gives access to the divisor.
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+
+/// Sets data terminal ready, request to send and auxiliary output 2, which
+/// connects the UART's interrupt to the PIC.
+const MODEM_CONTROL_READY: u8 = 0x0b;
+
+/// Bit of the line status set when there is a byte to read.
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+/// Bit of the line status set when a byte can be written.
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// Frequency of the UART's clock divided by 16, the fastest baud rate.
+pub const MAX_BAUD_RATE: u32 = 115200;
+
+/// Reasons a [Config] cannot be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The baud rate does not divide [MAX_BAUD_RATE], or is below 2.
+    InvalidBaudRate,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::InvalidBaudRate => "invalid baud rate",
+        })
+    }
+}
+
+/// Number of bits in each character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five = 5,
+    Six = 6,
+    Seven = 7,
+    Eight = 8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always set.
+    Mark,
+    /// The parity bit is always clear.
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// Two stop bits, or one and a half with [DataBits::Five].
+    Two,
+}
+
+/// Number of bytes in the receive FIFO at which the UART interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoTrigger {
+    One,
+    Four,
+    Eight,
+    Fourteen,
+}
+
+/// How a UART sends and receives characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// When to interrupt with the FIFOs enabled, or `None` to disable them
+    /// and interrupt for every byte.
+    pub fifo: Option<FifoTrigger>,
+}
+
+/// 38400 baud with 8 data bits, no parity and one stop bit, and FIFOs
+/// interrupting at 14 bytes.
+const DEFAULT_CONFIG: Config = Config {
+    baud_rate: 38400,
+    data_bits: DataBits::Eight,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+    fifo: Some(FifoTrigger::Fourteen),
+};
+
+impl Default for Config {
+    /// 38400 baud with 8 data bits, no parity and one stop bit, and FIFOs
+    /// interrupting at 14 bytes.
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+impl Config {
+    /// Returns the divisor of [MAX_BAUD_RATE] giving the baud rate.
+    fn divisor(&self) -> Result<u16, Error> {
+        if !MAX_BAUD_RATE.is_multiple_of(self.baud_rate) {
+            return Err(Error::InvalidBaudRate);
+        }
+        u16::try_from(MAX_BAUD_RATE / self.baud_rate).map_err(|_| Error::InvalidBaudRate)
+    }
+
+    /// Returns the value of the line control register selecting the format
+    /// of characters.
+    fn line_control(&self) -> u8 {
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => LINE_CONTROL_STOP_BITS,
+        };
+        (self.data_bits as u8 - 5) | stop_bits | (parity << LINE_CONTROL_PARITY_SHIFT)
+    }
+
+    /// Returns the value of the FIFO control register, which also clears the
+    /// FIFOs.
+    fn fifo_control(&self) -> u8 {
+        match self.fifo {
+            None => 0,
+            Some(trigger) => {
+                let trigger = match trigger {
+                    FifoTrigger::One => 0,
+                    FifoTrigger::Four => 1,
+                    FifoTrigger::Eight => 2,
+                    FifoTrigger::Fourteen => 3,
+                };
+                FIFO_ENABLE
+                    | FIFO_CLEAR_RECEIVE
+                    | FIFO_CLEAR_TRANSMIT
+                    | (trigger << FIFO_TRIGGER_SHIFT)
+            }
+        }
+    }
+}
+
+/// The registers of a UART.
+pub struct Uart {
+    base: u16,
+    config: Config,
+}
+
+impl Uart {
+    /// Creates an interface to the UART whose registers start at the given
+    /// port.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the port belongs to a UART.
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart {
+            base,
+            config: DEFAULT_CONFIG,
+        }
+    }
+
+    fn register(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Sets the UART up with the [default](Config::default) configuration.
+    /// Its interrupts are disabled.
+    pub fn init(&mut self) {
+        self.configure(DEFAULT_CONFIG).unwrap();
+        unsafe {
+            self.register(INTERRUPT_ENABLE).write(0);
+            self.register(MODEM_CONTROL).write(MODEM_CONTROL_READY);
+        }
+    }
+
+    /// Changes how the UART sends and receives characters. Bytes in its
+    /// FIFOs are discarded.
+    pub fn configure(&mut self, config: Config) -> Result<(), Error> {
+        let [low, high] = config.divisor()?.to_le_bytes();
+        unsafe {
+            self.register(LINE_CONTROL).write(LINE_CONTROL_DLAB);
+            self.register(DIVISOR_LOW).write(low);
+            self.register(DIVISOR_HIGH).write(high);
+            self.register(LINE_CONTROL).write(config.line_control());
+            self.register(FIFO_CONTROL).write(config.fifo_control());
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// Returns how the UART sends and receives characters.
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Makes the UART interrupt when it receives bytes.
+    pub fn enable_receive_interrupt(&mut self) {
+        unsafe { self.register(INTERRUPT_ENABLE).write(INTERRUPT_RECEIVED) };
+    }
+
+    /// Waits until the UART can take a byte and sends it as it is.
+    pub fn send(&mut self, byte: u8) {
+        let mut line_status = self.register(LINE_STATUS);
+        while unsafe { line_status.read() } & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.register(DATA).write(byte) };
+    }
+
+    /// Takes the oldest byte received, if any.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        let ready = unsafe { self.register(LINE_STATUS).read() } & LINE_STATUS_DATA_READY != 0;
+        ready.then(|| unsafe { self.register(DATA).read() })
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_config() {
+        let config = Config::default();
+        assert_eq!(config.divisor(), Ok(3));
+        assert_eq!(config.line_control(), 0x03);
+        assert_eq!(config.fifo_control(), 0xc7);
+
+        let config = Config {
+            baud_rate: 115200,
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            fifo: None,
+        };
+        assert_eq!(config.divisor(), Ok(1));
+        assert_eq!(config.line_control(), 0x1e);
+        assert_eq!(config.fifo_control(), 0);
+
+        let config = Config {
+            baud_rate: 50000,
+            ..Config::default()
+        };
+        assert_eq!(config.divisor(), Err(Error::InvalidBaudRate));
+        let config = Config {
+            baud_rate: 1,
+            ..Config::default()
+        };
+        assert_eq!(config.divisor(), Err(Error::InvalidBaudRate));
+    }
+}
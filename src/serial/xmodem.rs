@@ -0,0 +1,291 @@
+//! Receiving files over a serial port with XMODEM.
+//!
+//! The sender splits a file into numbered blocks of 128 bytes, or 1 KiB with
+//! XMODEM-1K, each checked with a CRC-16, and the receiver acknowledges every
+//! block or asks for it again. [receive] takes a file this way and
+//! [receive_file] stores it in the filesystem, such as the ramfs at `/tmp`,
+//! so that programs can be tried without rebuilding the disk image:
+//!
+//! ```text
+//! > rx /tmp/hello
+//! $ sx -k hello < /dev/ttyS0 > /dev/ttyS0
+//! ```
+//!
+//! Nothing else should write to the port during a transfer, so kernel output
+//! is best moved to another [Output](super::Output) first.
+//!
+//! The last block is padded with `0x1a`, which is stripped from the end of
+//! the file, as the protocol has no other way to tell its length.
+//!
+//! See: http://pauillac.inria.fr/~doligez/zmodem/ymodem.txt
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::SerialPort;
+use crate::{fs, task::future::timeout, time::Duration};
+
+/// Starts a block of 128 bytes.
+const SOH: u8 = 0x01;
+/// Starts a block of 1024 bytes.
+const STX: u8 = 0x02;
+/// Ends the transfer.
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+/// Cancels the transfer when sent twice in a row.
+const CAN: u8 = 0x18;
+/// Asks the sender to start, checking blocks with a CRC-16.
+const START: u8 = b'C';
+/// Fills the last block past the end of the file.
+const PADDING: u8 = 0x1a;
+
+/// How long to wait for the sender to start before asking again.
+const START_TIMEOUT: Duration = Duration::from_secs(3);
+/// Number of times to ask the sender to start before giving up.
+const START_ATTEMPTS: usize = 20;
+/// How long to wait for each byte once the transfer started.
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the line must be quiet before a block is asked for again.
+const PURGE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Number of bad blocks or timeouts in a row before giving up.
+const MAX_ERRORS: usize = 10;
+
+/// The largest file received.
+pub const MAX_SIZE: usize = 256 * 1024;
+
+/// Reasons a transfer fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The sender did not start, or stopped sending.
+    Timeout,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// Too many blocks in a row were corrupt.
+    TooManyErrors,
+    /// A block arrived out of sequence.
+    OutOfSequence,
+    /// The file is larger than [MAX_SIZE].
+    TooLarge,
+    /// The file could not be written to the filesystem.
+    File(fs::Error),
+}
+
+impl From<fs::Error> for Error {
+    fn from(error: fs::Error) -> Self {
+        Error::File(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => f.write_str("timed out"),
+            Error::Cancelled => f.write_str("cancelled by the sender"),
+            Error::TooManyErrors => f.write_str("too many errors"),
+            Error::OutOfSequence => f.write_str("block out of sequence"),
+            Error::TooLarge => f.write_str("file too large"),
+            Error::File(error) => write!(f, "cannot write file: {}", error),
+        }
+    }
+}
+
+/// What a received block turned out to be.
+#[derive(Debug, PartialEq, Eq)]
+enum Block<'a> {
+    /// The block expected next, with its data.
+    Next(&'a [u8]),
+    /// The block before, sent again as its acknowledgement was lost.
+    Repeat,
+    /// A block with a bad number or checksum.
+    Corrupt,
+    /// A block which is neither expected nor a repeat.
+    OutOfSequence,
+}
+
+/// Returns the CRC-16 of `data` used by XMODEM, with polynomial 0x1021 and
+/// an initial value of 0.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1021,
+        })
+    })
+}
+
+/// Checks a block following its header: its number, the complement of the
+/// number, the data and the CRC.
+fn check(block: &[u8], expected: u8) -> Block<'_> {
+    let (number, complement) = (block[0], block[1]);
+    let (data, crc) = block[2..].split_at(block.len() - 4);
+    if number != !complement || crc16(data) != u16::from_be_bytes([crc[0], crc[1]]) {
+        Block::Corrupt
+    } else if number == expected {
+        Block::Next(data)
+    } else if number == expected.wrapping_sub(1) {
+        Block::Repeat
+    } else {
+        Block::OutOfSequence
+    }
+}
+
+/// Returns `data` without the padding of the last block.
+fn strip_padding(data: &[u8]) -> &[u8] {
+    let len = data
+        .iter()
+        .rposition(|&byte| byte != PADDING)
+        .map_or(0, |i| i + 1);
+    &data[..len]
+}
+
+/// Waits for the next byte for at most `duration`.
+async fn read(port: &SerialPort, duration: Duration) -> Option<u8> {
+    timeout(duration, port.read_byte()).await.ok()
+}
+
+/// Discards bytes until the line is quiet.
+async fn purge(port: &SerialPort) {
+    while read(port, PURGE_TIMEOUT).await.is_some() {}
+}
+
+/// Tells the sender to stop.
+fn cancel(port: &SerialPort) {
+    let mut uart = port.lock();
+    uart.send(CAN);
+    uart.send(CAN);
+}
+
+/// Receives a file sent with XMODEM on `port`, which must have had
+/// [init_input](SerialPort::init_input) called.
+pub async fn receive(port: &SerialPort) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    let mut expected = 1u8;
+    let mut started = false;
+    let mut errors = 0;
+    port.lock().send(START);
+
+    loop {
+        let wait = if started { BYTE_TIMEOUT } else { START_TIMEOUT };
+        let header = match read(port, wait).await {
+            Some(header) => header,
+            None => {
+                errors += 1;
+                let attempts = if started { MAX_ERRORS } else { START_ATTEMPTS };
+                if errors >= attempts {
+                    cancel(port);
+                    return Err(Error::Timeout);
+                }
+                port.lock().send(if started { NAK } else { START });
+                continue;
+            }
+        };
+        let len = match header {
+            SOH => 128,
+            STX => 1024,
+            EOT => {
+                port.lock().send(ACK);
+                let len = strip_padding(&data).len();
+                data.truncate(len);
+                return Ok(data);
+            }
+            CAN if read(port, BYTE_TIMEOUT).await == Some(CAN) => return Err(Error::Cancelled),
+            _ => continue,
+        };
+
+        let mut block = alloc::vec![0; len + 4];
+        let mut complete = true;
+        for byte in block.iter_mut() {
+            match read(port, BYTE_TIMEOUT).await {
+                Some(received) => *byte = received,
+                None => {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+        let block = match complete {
+            true => check(&block, expected),
+            false => Block::Corrupt,
+        };
+
+        match block {
+            Block::Next(block) => {
+                if data.len() + block.len() > MAX_SIZE {
+                    cancel(port);
+                    return Err(Error::TooLarge);
+                }
+                data.extend_from_slice(block);
+                expected = expected.wrapping_add(1);
+                started = true;
+                errors = 0;
+                port.lock().send(ACK);
+            }
+            Block::Repeat => port.lock().send(ACK),
+            Block::Corrupt => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(port);
+                    return Err(Error::TooManyErrors);
+                }
+                purge(port).await;
+                port.lock().send(NAK);
+            }
+            Block::OutOfSequence => {
+                cancel(port);
+                return Err(Error::OutOfSequence);
+            }
+        }
+    }
+}
+
+/// Receives a file sent with XMODEM on `port` and writes it to `path`,
+/// replacing what is there. Returns the size of the file.
+pub async fn receive_file(port: &SerialPort, path: &str) -> Result<usize, Error> {
+    let data = receive(port).await?;
+    fs::write(path, &data)?;
+    Ok(data.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn block(number: u8, data: &[u8]) -> Vec<u8> {
+        let mut block = vec![number, !number];
+        block.extend_from_slice(data);
+        block.extend_from_slice(&crc16(data).to_be_bytes());
+        block
+    }
+
+    #[test_case]
+    fn test_crc16() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test_case]
+    fn test_check() {
+        let data = [0x55; 128];
+        assert_eq!(check(&block(1, &data), 1), Block::Next(&data));
+        assert_eq!(check(&block(0, &data), 1), Block::Repeat);
+        assert_eq!(check(&block(3, &data), 1), Block::OutOfSequence);
+        assert_eq!(check(&block(0, &data), 0), Block::Next(&data));
+        assert_eq!(check(&block(255, &data), 0), Block::Repeat);
+
+        let mut corrupt = block(1, &data);
+        corrupt[10] ^= 1;
+        assert_eq!(check(&corrupt, 1), Block::Corrupt);
+        let mut corrupt = block(1, &data);
+        corrupt[1] = 0;
+        assert_eq!(check(&corrupt, 1), Block::Corrupt);
+    }
+
+    #[test_case]
+    fn test_strip_padding() {
+        assert_eq!(strip_padding(b"file\x1a\x1a\x1a"), b"file");
+        assert_eq!(strip_padding(b"file"), b"file");
+        assert_eq!(strip_padding(b"\x1a\x1a"), b"");
+    }
+}
@@ -0,0 +1,414 @@
+//! Discovery of the SMBIOS tables, also known as DMI, which describe the
+//! machine: the firmware, the system's make and model, its memory devices
+//! and OEM strings, which the kernel takes its
+//! [command line](crate::cmdline) from.
+//!
+//! The firmware leaves an entry point in the BIOS ROM area which gives the
+//! address of a table of structures. Each structure starts with its type,
+//! length and handle, followed by its formatted fields and then by a set of
+//! strings, which fields refer to by their 1-based index. Two zero bytes end
+//! the strings.
+//!
+//! Like the ACPI tables, the structures are read in place through the
+//! physical memory mapping set up by [crate::mem::init].
+//!
+//! See: https://wiki.osdev.org/System_Management_BIOS and
+//! https://www.dmtf.org/standards/smbios
+
+use alloc::vec::Vec;
+use core::{slice, str};
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::mem::phys_to_virt;
+
+// Types of structures.
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_OEM_STRINGS: u8 = 11;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+// Offsets of fields in BIOS information structures.
+const BIOS_VENDOR: usize = 0x04;
+const BIOS_VERSION: usize = 0x05;
+const BIOS_RELEASE_DATE: usize = 0x08;
+
+// Offsets of fields in system information structures.
+const SYSTEM_MANUFACTURER: usize = 0x04;
+const SYSTEM_PRODUCT: usize = 0x05;
+const SYSTEM_VERSION: usize = 0x06;
+
+// Offsets of fields in OEM strings structures.
+const OEM_STRING_COUNT: usize = 0x04;
+
+// Offsets of fields in memory device structures.
+const MEMORY_SIZE: usize = 0x0c;
+const MEMORY_LOCATOR: usize = 0x10;
+const MEMORY_SPEED: usize = 0x15;
+const MEMORY_MANUFACTURER: usize = 0x17;
+const MEMORY_EXTENDED_SIZE: usize = 0x1c;
+
+/// Bit of the size of a memory device set if it is in KiB rather than MiB.
+const MEMORY_SIZE_KIB: u16 = 1 << 15;
+/// Size of a memory device whose size is in the extended size field.
+const MEMORY_SIZE_EXTENDED: u16 = 0x7fff;
+/// Size of a memory device whose size is unknown.
+const MEMORY_SIZE_UNKNOWN: u16 = 0xffff;
+
+/// The parts of the entry point locating the structure table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryPoint {
+    major: u8,
+    minor: u8,
+    /// Physical address of the structure table.
+    table: u64,
+    /// Length of the table, or its maximum length for the 64-bit entry
+    /// point, which ends it with an end-of-table structure instead.
+    len: usize,
+}
+
+static ENTRY_POINT: Once<Option<EntryPoint>> = Once::new();
+
+/// Returns `true` if the bytes sum to zero, as required of the entry points.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Parses the 32-bit entry point, whose anchor is `_SM_`, or the 64-bit one,
+/// whose anchor is `_SM3_`, at the start of `bytes`.
+fn parse_entry_point(bytes: &[u8]) -> Option<EntryPoint> {
+    let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    if bytes.starts_with(b"_SM3_") {
+        let len = *bytes.get(6)? as usize;
+        if len < 0x18 || !checksum(bytes.get(..len)?) {
+            return None;
+        }
+        let table = u64::from_le_bytes(bytes[0x10..0x18].try_into().unwrap());
+        Some(EntryPoint {
+            major: bytes[7],
+            minor: bytes[8],
+            table,
+            len: u32_at(0x0c) as usize,
+        })
+    } else if bytes.starts_with(b"_SM_") {
+        let len = *bytes.get(5)? as usize;
+        // The entry point ends with the intermediate one, anchored by `_DMI_`.
+        if len < 0x1f || !checksum(bytes.get(..len)?) || &bytes[0x10..0x15] != b"_DMI_" {
+            return None;
+        }
+        Some(EntryPoint {
+            major: bytes[6],
+            minor: bytes[7],
+            table: u32_at(0x18) as u64,
+            len: u16_at(0x16) as usize,
+        })
+    } else {
+        None
+    }
+}
+
+/// Searches the BIOS ROM area for an entry point, which is always 16 byte
+/// aligned. The 64-bit entry point is preferred, as the 32-bit one cannot
+/// locate tables above 4 GiB.
+fn find_entry_point() -> Option<EntryPoint> {
+    const START: u64 = 0xf0000;
+    const LEN: usize = 0x10000;
+
+    let rom = unsafe { slice::from_raw_parts(phys_to_virt(PhysAddr::new(START)).as_ptr(), LEN) };
+    let mut found = None;
+    for candidate in (0..LEN).step_by(16).map(|offset| &rom[offset..]) {
+        match parse_entry_point(candidate) {
+            Some(entry) if candidate.starts_with(b"_SM3_") => return Some(entry),
+            Some(entry) => found = found.or(Some(entry)),
+            None => {}
+        }
+    }
+    found
+}
+
+/// A structure of the SMBIOS table.
+#[derive(Debug, Clone, Copy)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    /// The formatted fields, including the header.
+    fields: &'a [u8],
+    /// The strings, each ending with a zero byte.
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    /// Returns the byte at `offset` of the formatted fields, or `None` if
+    /// the structure is too short to have it, as in older versions.
+    pub fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.fields.get(offset).copied()
+    }
+
+    pub fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.fields.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.fields.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Returns the string the byte at `offset` refers to, or `None` if it
+    /// refers to none or the string is not valid UTF-8.
+    pub fn string_at(&self, offset: usize) -> Option<&'a str> {
+        self.string(self.u8_at(offset)?)
+    }
+
+    /// Returns the string with the given 1-based index, or `None` if there
+    /// is no such string or it is not valid UTF-8.
+    pub fn string(&self, index: u8) -> Option<&'a str> {
+        let index = index.checked_sub(1)?;
+        let string = self.strings.split(|&byte| byte == 0).nth(index as usize)?;
+        str::from_utf8(string)
+            .ok()
+            .filter(|string| !string.is_empty())
+    }
+}
+
+/// An iterator over the structures of a table.
+struct Structures<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Structure<'a>> {
+        let len = *self.table.get(1)? as usize;
+        let fields = self.table.get(..len).filter(|_| len >= 4)?;
+        // The strings end with two zero bytes, even if there are none.
+        let strings_len = self.table[len..]
+            .windows(2)
+            .position(|pair| pair == [0, 0])?;
+        let strings = &self.table[len..len + strings_len + 1];
+        self.table = &self.table[len + strings_len + 2..];
+        if fields[0] == TYPE_END {
+            self.table = &[];
+            return None;
+        }
+        Some(Structure {
+            kind: fields[0],
+            handle: u16::from_le_bytes([fields[2], fields[3]]),
+            fields,
+            strings,
+        })
+    }
+}
+
+/// Returns an iterator over every structure of the SMBIOS table, which is
+/// empty if the firmware provides none.
+///
+/// # Panics
+///
+/// Panics if called before [crate::mem::init].
+pub fn structures() -> impl Iterator<Item = Structure<'static>> {
+    let table: &[u8] = match ENTRY_POINT.call_once(find_entry_point) {
+        Some(entry) => unsafe {
+            slice::from_raw_parts(phys_to_virt(PhysAddr::new(entry.table)).as_ptr(), entry.len)
+        },
+        None => &[],
+    };
+    Structures { table }
+}
+
+/// Returns the version of the SMBIOS specification the table follows, as its
+/// major and minor numbers.
+pub fn version() -> Option<(u8, u8)> {
+    ENTRY_POINT
+        .call_once(find_entry_point)
+        .map(|entry| (entry.major, entry.minor))
+}
+
+/// Returns `true` if the firmware provides SMBIOS tables.
+pub fn is_present() -> bool {
+    version().is_some()
+}
+
+/// Information on the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BiosInfo {
+    pub vendor: Option<&'static str>,
+    pub version: Option<&'static str>,
+    /// The date of release, as `mm/dd/yyyy`.
+    pub release_date: Option<&'static str>,
+}
+
+impl From<Structure<'static>> for BiosInfo {
+    fn from(structure: Structure<'static>) -> Self {
+        BiosInfo {
+            vendor: structure.string_at(BIOS_VENDOR),
+            version: structure.string_at(BIOS_VERSION),
+            release_date: structure.string_at(BIOS_RELEASE_DATE),
+        }
+    }
+}
+
+/// The make and model of the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemInfo {
+    pub manufacturer: Option<&'static str>,
+    pub product: Option<&'static str>,
+    pub version: Option<&'static str>,
+}
+
+impl From<Structure<'static>> for SystemInfo {
+    fn from(structure: Structure<'static>) -> Self {
+        SystemInfo {
+            manufacturer: structure.string_at(SYSTEM_MANUFACTURER),
+            product: structure.string_at(SYSTEM_PRODUCT),
+            version: structure.string_at(SYSTEM_VERSION),
+        }
+    }
+}
+
+/// A memory device, usually a module in a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDevice {
+    /// The label of the slot, like `DIMM 0`.
+    pub locator: Option<&'static str>,
+    /// Size in KiB, 0 if the slot is empty, or `None` if unknown.
+    pub size_kib: Option<u64>,
+    /// Speed in megatransfers per second, if known.
+    pub speed: Option<u16>,
+    pub manufacturer: Option<&'static str>,
+}
+
+impl From<Structure<'static>> for MemoryDevice {
+    fn from(structure: Structure<'static>) -> Self {
+        let size_kib = match structure.u16_at(MEMORY_SIZE) {
+            None | Some(MEMORY_SIZE_UNKNOWN) => None,
+            Some(MEMORY_SIZE_EXTENDED) => structure
+                .u32_at(MEMORY_EXTENDED_SIZE)
+                .map(|mib| (mib & 0x7fff_ffff) as u64 * 1024),
+            Some(size) if size & MEMORY_SIZE_KIB != 0 => Some((size & !MEMORY_SIZE_KIB) as u64),
+            Some(size) => Some(size as u64 * 1024),
+        };
+        MemoryDevice {
+            locator: structure.string_at(MEMORY_LOCATOR),
+            size_kib,
+            speed: structure.u16_at(MEMORY_SPEED).filter(|&speed| speed != 0),
+            manufacturer: structure.string_at(MEMORY_MANUFACTURER),
+        }
+    }
+}
+
+/// Returns information on the firmware, if the table has it.
+pub fn bios() -> Option<BiosInfo> {
+    structures()
+        .find(|structure| structure.kind == TYPE_BIOS)
+        .map(BiosInfo::from)
+}
+
+/// Returns the make and model of the system, if the table has it.
+pub fn system() -> Option<SystemInfo> {
+    structures()
+        .find(|structure| structure.kind == TYPE_SYSTEM)
+        .map(SystemInfo::from)
+}
+
+/// Returns the OEM strings, free-form strings which QEMU sets with
+/// `-smbios type=11,value=<string>`.
+pub fn oem_strings() -> Vec<&'static str> {
+    structures()
+        .filter(|structure| structure.kind == TYPE_OEM_STRINGS)
+        .flat_map(|structure| {
+            let count = structure.u8_at(OEM_STRING_COUNT).unwrap_or(0);
+            (1..=count).filter_map(move |index| structure.string(index))
+        })
+        .collect()
+}
+
+/// Returns every memory device in the table, including empty slots.
+pub fn memory_devices() -> Vec<MemoryDevice> {
+    structures()
+        .filter(|structure| structure.kind == TYPE_MEMORY_DEVICE)
+        .map(MemoryDevice::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_entry_point() {
+        let mut entry = [0u8; 0x1f];
+        entry[..4].copy_from_slice(b"_SM_");
+        entry[5] = 0x1f;
+        entry[6] = 2;
+        entry[7] = 8;
+        entry[0x10..0x15].copy_from_slice(b"_DMI_");
+        entry[0x16..0x18].copy_from_slice(&0x1234u16.to_le_bytes());
+        entry[0x18..0x1c].copy_from_slice(&0x000f_0000u32.to_le_bytes());
+        assert_eq!(parse_entry_point(&entry), None);
+        entry[4] = 0u8.wrapping_sub(entry.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+        assert_eq!(
+            parse_entry_point(&entry),
+            Some(EntryPoint {
+                major: 2,
+                minor: 8,
+                table: 0xf0000,
+                len: 0x1234,
+            })
+        );
+    }
+
+    #[test_case]
+    fn test_structures() {
+        #[rustfmt::skip]
+        static TABLE: [u8; 58] = [
+            // BIOS information, with its strings.
+            TYPE_BIOS, 0x09, 0x00, 0x00, 1, 2, 0, 0, 3,
+            b'S', b'e', b'a', 0, b'1', b'.', b'0', 0, b'0', b'4', 0, 0,
+            // A memory device of 2 GiB without strings.
+            TYPE_MEMORY_DEVICE, 0x17, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0,
+            0x00, 0x08, 0, 0, 0, 0, 0, 0, 0, 0x40, 0x06,
+            0, 0,
+            // The end of the table, and a structure past it.
+            TYPE_END, 0x04, 0x02, 0x00, 0, 0,
+            TYPE_SYSTEM, 0x04, 0x03, 0x00, 0, 0,
+        ];
+        let structures: Vec<_> = Structures { table: &TABLE }.collect();
+        assert_eq!(structures.len(), 2);
+        assert_eq!(structures[1].handle, 0x0101);
+        assert_eq!(structures[0].string_at(6), None);
+        assert_eq!(structures[0].string(3), Some("04"));
+        assert_eq!(structures[0].string(4), None);
+
+        assert_eq!(
+            BiosInfo::from(structures[0]),
+            BiosInfo {
+                vendor: Some("Sea"),
+                version: Some("1.0"),
+                release_date: Some("04"),
+            }
+        );
+        assert_eq!(
+            MemoryDevice::from(structures[1]),
+            MemoryDevice {
+                locator: None,
+                size_kib: Some(2 * 1024 * 1024),
+                speed: Some(1600),
+                manufacturer: None,
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_tables() {
+        assert!(is_present());
+        assert!(bios().is_some_and(|bios| bios.vendor.is_some()));
+        assert!(memory_devices()
+            .iter()
+            .any(|device| device.size_kib.is_some_and(|size| size > 0)));
+    }
+}
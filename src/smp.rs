@@ -0,0 +1,261 @@
+//! Bring-up of application processors (APs).
+//!
+//! Only the bootstrap processor (BSP) runs after the firmware hands over
+//! control. The other processors listed in the ACPI MADT wait in a halted
+//! state until the BSP sends them an INIT IPI followed by two STARTUP IPIs
+//! (SIPIs). A SIPI starts the target processor in real mode at a page aligned
+//! address below 1 MiB, so a small trampoline is copied there which switches
+//! straight to long mode using the BSP's page tables and calls [ap_entry] on a
+//! freshly allocated stack.
+//!
+//! Each AP then sets up its own GDT, TSS, IDT and local APIC before entering
+//! the multi-core task [runtime](crate::task::runtime), where it halts with
+//! interrupts enabled whenever it has no work.
+//!
+//! See: https://wiki.osdev.org/SMP
+
+use alloc::{vec, vec::Vec};
+use core::{
+    arch::global_asm,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::madt,
+    gdt,
+    interrupts::apic,
+    percpu::MAX_CPUS,
+    println,
+    time::{pit, Duration, Instant},
+};
+
+/// Physical address the trampoline is copied to. The memory lies in the
+/// region used by the bootloader's real mode stages, which is no longer
+/// needed once the kernel runs.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Size of each AP's initial kernel stack.
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// How long to wait for an AP to reach [ap_entry] after the second SIPI.
+const AP_START_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Offsets of the data fields at the start of the trampoline, written by the
+// BSP before starting each AP.
+const TRAMPOLINE_CR3: u64 = 8;
+const TRAMPOLINE_STACK: u64 = 16;
+const TRAMPOLINE_ENTRY: u64 = 24;
+const TRAMPOLINE_CPU: u64 = 32;
+const TRAMPOLINE_GDT: u64 = 40;
+const TRAMPOLINE_GDT_PTR: u64 = 64;
+
+global_asm!(
+    ".pushsection .text",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".align 16",
+    ".code16",
+    "ap_trampoline_start:",
+    "jmp ap_trampoline_code",
+    ".align 8",
+    ".quad 0", // cr3
+    ".quad 0", // stack
+    ".quad 0", // entry
+    ".quad 0", // cpu
+    ".quad 0", // gdt: null
+    ".quad 0x00af9a000000ffff", // gdt: 64-bit code
+    ".quad 0x00cf92000000ffff", // gdt: data
+    ".word 23", // gdt pointer
+    ".long {base} + {gdt}",
+    "ap_trampoline_code:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    "lgdt [{base} + {gdt_ptr}]",
+    // Enable PAE and load the BSP's page tables.
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [{base} + {cr3}]",
+    "mov cr3, eax",
+    // Enable long mode and no-execute pages in the EFER.
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // Enable paging, write protection and protected mode at once, which
+    // activates long mode.
+    "mov eax, cr0",
+    "or eax, (1 << 31) | (1 << 16) | 1",
+    "mov cr0, eax",
+    // Far jump into the 64-bit code segment.
+    ".byte 0x66, 0xea",
+    ".long {base} + (ap_trampoline_long_mode - ap_trampoline_start)",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor ax, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, [{base} + {stack}]",
+    "mov rdi, [{base} + {cpu}]",
+    "mov rax, [{base} + {entry}]",
+    "call rax",
+    "ud2",
+    "ap_trampoline_end:",
+    ".popsection",
+    base = const TRAMPOLINE_ADDR,
+    cr3 = const TRAMPOLINE_CR3,
+    stack = const TRAMPOLINE_STACK,
+    entry = const TRAMPOLINE_ENTRY,
+    cpu = const TRAMPOLINE_CPU,
+    gdt = const TRAMPOLINE_GDT,
+    gdt_ptr = const TRAMPOLINE_GDT_PTR,
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Set by an AP once it no longer needs the trampoline.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Writes a value into the copy of the trampoline at the given offset.
+fn write_trampoline_field(offset: u64, value: u64) {
+    let addr = crate::mem::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR + offset));
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(value) };
+}
+
+/// Copies the trampoline into low memory, identity mapping it so that the
+/// AP can keep executing it after enabling paging.
+fn install_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> bool {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    match mapper.translate_page(page) {
+        Ok(mapped) if mapped == frame => {}
+        Ok(_) => {
+            println!("smp: trampoline page is already mapped elsewhere");
+            return false;
+        }
+        Err(_) => {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    println!("smp: failed to map trampoline: {:?}", err);
+                    return false;
+                }
+            }
+        }
+    }
+
+    let start = addr_of!(ap_trampoline_start);
+    let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+    let dest = crate::mem::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR));
+    unsafe { core::ptr::copy_nonoverlapping(start, dest.as_mut_ptr::<u8>(), len) };
+
+    let (pml4, _) = Cr3::read();
+    write_trampoline_field(TRAMPOLINE_CR3, pml4.start_address().as_u64());
+    write_trampoline_field(
+        TRAMPOLINE_ENTRY,
+        ap_entry as extern "C" fn(usize) -> ! as usize as u64,
+    );
+    true
+}
+
+/// Starts the AP with the given APIC ID as CPU `cpu`, returning `true` once
+/// it is running.
+fn start_ap(cpu: usize, apic_id: u32) -> bool {
+    // The stack is never freed; APs run until the machine shuts down.
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+    write_trampoline_field(TRAMPOLINE_STACK, top);
+    write_trampoline_field(TRAMPOLINE_CPU, cpu as u64);
+    AP_STARTED.store(false, Ordering::Release);
+
+    let page = (TRAMPOLINE_ADDR >> 12) as u8;
+    apic::send_init(apic_id);
+    pit::busy_wait(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        pit::busy_wait(Duration::from_micros(200));
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < AP_START_TIMEOUT {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Starts every enabled AP listed in the MADT, returning the number started.
+///
+/// Must be called on the BSP after [apic::init]. APs are numbered from 1 in
+/// MADT order; any beyond [MAX_CPUS] are left halted.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> usize {
+    if !apic::is_enabled() {
+        return 0;
+    }
+
+    let bsp = apic::id();
+    let aps: Vec<_> = madt::processors()
+        .into_iter()
+        .filter(|processor| processor.enabled && processor.apic_id != bsp)
+        .collect();
+    if aps.is_empty() || !install_trampoline(mapper, frame_allocator) {
+        return 0;
+    }
+
+    let mut started = 0;
+    for processor in aps.iter().take(MAX_CPUS - 1) {
+        let cpu = started + 1;
+        if start_ap(cpu, processor.apic_id) {
+            started += 1;
+        } else {
+            println!("smp: APIC {} did not start", processor.apic_id);
+        }
+    }
+    started
+}
+
+/// Returns the number of CPUs which are online, including the BSP.
+pub fn cpu_count() -> usize {
+    apic::online_cpus().count_ones() as usize
+}
+
+/// Rust entry point of an AP, called by the trampoline.
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    gdt::init_cpu(cpu);
+    crate::fpu::init();
+    crate::interrupts::init_idt();
+    crate::syscall::init();
+    apic::init_cpu();
+    AP_STARTED.store(true, Ordering::Release);
+
+    crate::task::runtime::run();
+}
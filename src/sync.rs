@@ -0,0 +1,179 @@
+//! The `sync` module provides synchronization primitives for kernel code.
+
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// A spinlock which disables interrupts while it is held.
+///
+/// Data shared with interrupt handlers must be protected by this lock rather
+/// than a plain spinlock. Otherwise, an interrupt arriving while the lock is
+/// held would spin forever waiting for the interrupted code to release it.
+///
+/// Interrupts are disabled before the lock is acquired and, when the guard is
+/// dropped, restored to whatever state they were in beforehand. This makes
+/// nested locking safe: only the outermost guard re-enables interrupts.
+pub struct IrqSpinlock<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+/// A guard providing access to the data protected by an [IrqSpinlock].
+///
+/// Interrupts remain disabled for as long as the guard is alive.
+pub struct IrqSpinlockGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    /// Creates a new lock protecting the given data.
+    pub const fn new(data: T) -> Self {
+        IrqSpinlock {
+            inner: Mutex::new(data),
+        }
+    }
+
+    /// Consumes the lock, returning the protected data.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    /// Disables interrupts and acquires the lock, spinning until it is
+    /// available.
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinlockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Attempts to acquire the lock without spinning.
+    ///
+    /// The interrupt state is left untouched if the lock is unavailable.
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            }),
+
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Returns a mutable reference to the protected data without locking,
+    /// which is safe since the borrow checker guarantees exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Forcibly releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// This is only safe to call when the lock is held by code which will
+    /// never release it, such as code interrupted by a panic. Any outstanding
+    /// guard must never be used again.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("IrqSpinlock")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("IrqSpinlock { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before re-enabling interrupts so that an interrupt
+        // handler can never observe it held.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_lock_disables_interrupts() {
+        let lock = IrqSpinlock::new(0);
+        assert!(interrupts::are_enabled());
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test_case]
+    fn test_nested_locks_restore_state() {
+        let outer = IrqSpinlock::new(());
+        let inner = IrqSpinlock::new(());
+        {
+            let _outer = outer.lock();
+            {
+                let _inner = inner.lock();
+            }
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+    }
+
+    #[test_case]
+    fn test_try_lock_when_held() {
+        let lock = IrqSpinlock::new(());
+        let _guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+}
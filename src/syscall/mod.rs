@@ -0,0 +1,1215 @@
+//! System calls made by user mode through the `syscall` instruction.
+//!
+//! [init] points the `LSTAR` MSR at an entry stub which saves the user stack
+//! pointer in the per-CPU data, switches to the kernel stack of the running
+//! user mode session and saves the caller's registers in a [SyscallFrame].
+//! The call number in `rax` then selects a handler from the dispatch table.
+//!
+//! Arguments are passed in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, as on
+//! Linux. The result is returned in `rax`: a non-negative value on success,
+//! or a negated [Errno] on failure. `rcx` and `r11` are clobbered by the
+//! instruction itself; every other register is preserved.
+//!
+//! Calls which manage processes are implemented by the [process] module,
+//! those which pass messages by the [ipc] module, and those on files by the
+//! [fs] module. File descriptors index the calling process's table, or the
+//! [kernel's](process::fd::kernel_files) for user code run without a process.
+//!
+//! See: https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    arch::global_asm,
+    future::Future,
+    pin::pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{mapper::MapToError, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::{
+    fs::{self, OpenFlags, SeekFrom},
+    gdt,
+    ipc::{
+        self,
+        pipe::{self, BrokenPipe},
+        shm::{self, ShmId},
+        Message, Port, PortId, SendError,
+    },
+    percpu::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET},
+    power,
+    process::{
+        self,
+        fd::{BadDescriptor, Descriptor},
+        Pid, Signal, WaitError, WaitFor,
+    },
+    task, time,
+    usermode::{self, Registers, USER_END, USER_START},
+    vt,
+};
+
+/// Exits the calling process, or ends the user mode session, with the status
+/// in the first argument.
+pub const SYS_EXIT: u64 = 0;
+
+/// Writes the buffer at the second argument, with the length in the third, to
+/// the file descriptor in the first. Returns the number of bytes written,
+/// which for a pipe or file may be fewer than asked, at most
+/// [PIPE_CAPACITY](pipe::PIPE_CAPACITY) or [MAX_FILE_IO]. The console
+/// takes all of them.
+pub const SYS_WRITE: u64 = 1;
+
+/// Blocks for the number of milliseconds in the first argument.
+pub const SYS_SLEEP: u64 = 2;
+
+/// Returns the ID of the calling process.
+pub const SYS_GETPID: u64 = 3;
+
+/// Creates a copy of the calling process. Returns the child's ID in the
+/// parent and 0 in the child.
+pub const SYS_FORK: u64 = 4;
+
+/// Replaces the program of the calling process with the one named by the
+/// string at the first argument, with the length in the second. The third
+/// and fourth arguments point to null terminated arrays of pointers to the
+/// NUL terminated arguments and environment variables of the new program;
+/// a null pointer stands for an empty array. Does not return on success.
+pub const SYS_EXEC: u64 = 5;
+
+/// Waits for a child of the calling process to exit and reaps it. The first
+/// argument is the child's ID, or -1 for any child. Unless null, the second
+/// argument points to where the child's exit status is stored as a 64-bit
+/// value. The third argument holds options such as [WNOHANG]. Returns the ID
+/// of the reaped child.
+pub const SYS_WAITPID: u64 = 6;
+
+/// Makes [SYS_WAITPID] return 0 instead of blocking if no matching child has
+/// exited.
+pub const WNOHANG: u64 = 1;
+
+/// Sends the signal numbered by the second argument to the process with the
+/// ID in the first. A signal number of 0 only checks that the process
+/// exists.
+pub const SYS_KILL: u64 = 7;
+
+/// Creates a message port owned by the calling process and returns its ID.
+/// See [crate::ipc].
+pub const SYS_PORT_CREATE: u64 = 8;
+
+/// Sends the buffer at the second argument, with the length in the third, as
+/// a message to the port with the ID in the first. Does not block; fails with
+/// [Errno::Again] if the port's queue is full.
+pub const SYS_PORT_SEND: u64 = 9;
+
+/// Receives a message from the port with the ID in the first argument, which
+/// the calling process must own, blocking until one arrives. The message is
+/// copied to the buffer at the second argument, with the length in the third.
+/// Unless null, the fourth argument points to where the sender's process ID
+/// is stored as a 64-bit value, or 0 if the kernel sent the message. Returns
+/// the length of the message. If the buffer is too small, fails with
+/// [Errno::MsgSize] and leaves the message queued.
+pub const SYS_PORT_RECEIVE: u64 = 10;
+
+/// Closes the port with the ID in the first argument, which the calling
+/// process must own.
+pub const SYS_PORT_DESTROY: u64 = 11;
+
+/// Creates a pipe and stores the file descriptors of its reading and writing
+/// ends as two 64-bit values at the first argument. See [ipc::pipe].
+pub const SYS_PIPE: u64 = 12;
+
+/// Reads up to the number of bytes in the third argument from the file
+/// descriptor in the first into the buffer at the second. Returns the number
+/// of bytes read, or 0 at end of file. Blocks until data is available.
+pub const SYS_READ: u64 = 13;
+
+/// Closes the file descriptor in the first argument.
+pub const SYS_CLOSE: u64 = 14;
+
+/// Makes the file descriptor in the second argument refer to the same file as
+/// the one in the first, closing it first if it was open. Returns the new
+/// file descriptor.
+pub const SYS_DUP2: u64 = 15;
+
+/// Creates a shared memory region of at least the number of bytes in the first
+/// argument, rounded up to whole pages, and returns its ID. See
+/// [ipc::shm].
+pub const SYS_SHM_CREATE: u64 = 16;
+
+/// Maps the shared memory region with the ID in the first argument into the
+/// calling process at the page aligned address in the second, which must not
+/// be mapped yet. Returns the address.
+pub const SYS_SHM_MAP: u64 = 17;
+
+/// Removes the ID of the shared memory region in the first argument, which
+/// the calling process must have created. Existing mappings are unaffected.
+pub const SYS_SHM_DESTROY: u64 = 18;
+
+/// Moves the program break of the calling process to the address in the
+/// first argument, growing or shrinking its heap. Returns the new program
+/// break, or the current one if the argument is 0 or the break cannot be
+/// moved there. Heap pages are mapped when first accessed.
+pub const SYS_BRK: u64 = 19;
+
+/// Maps anonymous memory into the calling process. Only the length in the
+/// second argument, the protection in the third and the flags in the fourth
+/// are used: the flags must be [MAP_PRIVATE] and [MAP_ANONYMOUS], and the
+/// address hint, file descriptor and offset are ignored. Returns the address
+/// of the mapping, whose pages are mapped zeroed when first accessed.
+pub const SYS_MMAP: u64 = 20;
+
+/// Unmaps the pages covering the number of bytes in the second argument from
+/// the page aligned address in the first.
+pub const SYS_MUNMAP: u64 = 21;
+
+/// Opens the file at the path at the first argument, with the length in the
+/// second, using the flags in the third: one of [O_RDONLY], [O_WRONLY] and
+/// [O_RDWR], combined with any of [O_CREAT], [O_TRUNC] and [O_APPEND].
+/// Returns the lowest free file descriptor, which refers to the file.
+pub const SYS_OPEN: u64 = 22;
+
+/// Moves the position of the file descriptor in the first argument by the
+/// signed offset in the second, relative to the point selected by the third:
+/// [SEEK_SET], [SEEK_CUR] or [SEEK_END]. Returns the new position. Fails with
+/// [Errno::SPipe] on the console and pipes.
+pub const SYS_SEEK: u64 = 23;
+
+/// Powers the machine off if the first argument is [REBOOT_POWER_OFF], or
+/// restarts it if it is [REBOOT_RESTART], and fails with [Errno::Inval]
+/// otherwise. Does not return on success.
+pub const SYS_REBOOT: u64 = 24;
+
+/// Makes [SYS_REBOOT] power the machine off. The value matches Linux.
+pub const REBOOT_POWER_OFF: u64 = 0x4321_fedc;
+
+/// Makes [SYS_REBOOT] restart the machine. The value matches Linux.
+pub const REBOOT_RESTART: u64 = 0x0123_4567;
+
+/// Opens a file for reading only.
+pub const O_RDONLY: u64 = 0;
+
+/// Opens a file for writing only.
+pub const O_WRONLY: u64 = 1;
+
+/// Opens a file for reading and writing.
+pub const O_RDWR: u64 = 2;
+
+/// Bits of the [SYS_OPEN] flags selecting the access mode.
+pub const O_ACCMODE: u64 = 3;
+
+/// Creates the file opened by [SYS_OPEN] if it does not exist.
+pub const O_CREAT: u64 = 0x40;
+
+/// Empties the file opened by [SYS_OPEN] for writing.
+pub const O_TRUNC: u64 = 0x200;
+
+/// Makes every write to the file opened by [SYS_OPEN] go to its end.
+pub const O_APPEND: u64 = 0x400;
+
+/// Makes [SYS_SEEK] offsets relative to the start of the file.
+pub const SEEK_SET: u64 = 0;
+
+/// Makes [SYS_SEEK] offsets relative to the current position.
+pub const SEEK_CUR: u64 = 1;
+
+/// Makes [SYS_SEEK] offsets relative to the end of the file.
+pub const SEEK_END: u64 = 2;
+
+/// Maximum length of a path passed to [SYS_OPEN].
+pub const MAX_PATH_LEN: u64 = 4096;
+
+/// Maximum number of bytes a single [SYS_READ] or [SYS_WRITE] on a file
+/// transfers, bounding the kernel buffer it needs.
+pub const MAX_FILE_IO: u64 = 64 * 1024;
+
+/// Memory protection allowing [SYS_MMAP] mappings to be read.
+pub const PROT_READ: u64 = 1;
+
+/// Memory protection allowing [SYS_MMAP] mappings to be written.
+pub const PROT_WRITE: u64 = 2;
+
+/// Memory protection allowing code in [SYS_MMAP] mappings to be executed.
+pub const PROT_EXEC: u64 = 4;
+
+/// Makes a [SYS_MMAP] mapping private to the calling process.
+pub const MAP_PRIVATE: u64 = 0x02;
+
+/// Makes a [SYS_MMAP] mapping zeroed memory rather than a file.
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Maximum length of a program name passed to [SYS_EXEC].
+pub const MAX_NAME_LEN: u64 = 256;
+
+/// Maximum number of arguments or environment variables passed to
+/// [SYS_EXEC].
+pub const MAX_ARGS: usize = 1024;
+
+/// File descriptor of standard input.
+pub const STDIN: u64 = 0;
+
+/// File descriptor of standard output.
+pub const STDOUT: u64 = 1;
+
+/// File descriptor of standard error.
+pub const STDERR: u64 = 2;
+
+/// Error numbers returned, negated, by failing system calls. The values match
+/// those used by Linux.
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// Operation not permitted.
+    Perm = 1,
+    /// No such file or directory.
+    NoEnt = 2,
+    /// No such process.
+    Srch = 3,
+    /// Interrupted system call.
+    Intr = 4,
+    /// Input/output error.
+    Io = 5,
+    /// Argument list too long.
+    TooBig = 7,
+    /// Executable format error.
+    NoExec = 8,
+    /// Bad file descriptor.
+    BadF = 9,
+    /// No child processes.
+    Child = 10,
+    /// Resource temporarily unavailable.
+    Again = 11,
+    /// Out of memory.
+    NoMem = 12,
+    /// Bad address.
+    Fault = 14,
+    /// Device or resource busy.
+    Busy = 16,
+    /// File exists.
+    Exist = 17,
+    /// Invalid cross-device link.
+    XDev = 18,
+    /// Not a directory.
+    NotDir = 20,
+    /// Is a directory.
+    IsDir = 21,
+    /// Invalid argument.
+    Inval = 22,
+    /// Too many open files.
+    MFile = 24,
+    /// No space left on device.
+    NoSpc = 28,
+    /// Illegal seek.
+    SPipe = 29,
+    /// Read-only file system.
+    RoFs = 30,
+    /// Broken pipe.
+    Pipe = 32,
+    /// File name too long.
+    NameTooLong = 36,
+    /// Function not implemented.
+    NoSys = 38,
+    /// Directory not empty.
+    NotEmpty = 39,
+    /// Message too long.
+    MsgSize = 90,
+    /// Operation not supported.
+    OpNotSupp = 95,
+}
+
+impl Errno {
+    /// Returns the value placed in `rax` to report this error.
+    pub fn to_return_value(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+impl From<process::Error> for Errno {
+    fn from(error: process::Error) -> Self {
+        match error {
+            process::Error::NotFound => Errno::NoEnt,
+            process::Error::File(_) | process::Error::Elf(_) => Errno::NoExec,
+            process::Error::OutOfMemory => Errno::NoMem,
+            process::Error::TooManyThreads => Errno::Again,
+            process::Error::ArgumentsTooLong => Errno::TooBig,
+        }
+    }
+}
+
+impl From<fs::Error> for Errno {
+    fn from(error: fs::Error) -> Self {
+        match error {
+            fs::Error::NotFound => Errno::NoEnt,
+            fs::Error::NotADirectory => Errno::NotDir,
+            fs::Error::IsADirectory => Errno::IsDir,
+            fs::Error::AlreadyExists => Errno::Exist,
+            fs::Error::NotEmpty => Errno::NotEmpty,
+            fs::Error::InvalidPath | fs::Error::InvalidArgument => Errno::Inval,
+            fs::Error::ReadOnly => Errno::RoFs,
+            // Only open files can be write-only, and those are checked before
+            // reading.
+            fs::Error::WriteOnly => Errno::BadF,
+            fs::Error::NotSupported => Errno::OpNotSupp,
+            fs::Error::NoSpace => Errno::NoSpc,
+            fs::Error::Busy => Errno::Busy,
+            fs::Error::CrossDevice => Errno::XDev,
+            fs::Error::Corrupted | fs::Error::Io => Errno::Io,
+        }
+    }
+}
+
+/// The registers of a user mode caller, saved on the kernel stack by the
+/// entry stub. Changes made by a handler are restored on return to user
+/// mode, except for `rax`, which receives the result.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The system call number.
+    pub rax: u64,
+    /// The return address, saved in `rcx` by the `syscall` instruction.
+    pub rip: u64,
+    /// The caller's flags, saved in `r11` by the `syscall` instruction.
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// Returns the six argument registers in order.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Returns the caller's registers as they were after the `syscall`
+    /// instruction.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rip,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rsp: self.rsp,
+            rflags: self.rflags,
+        }
+    }
+
+    /// Makes the system call return to user mode with the given registers.
+    /// `rax` is set to the result of the call instead, and `rcx` and `r11`
+    /// to the instruction pointer and flags.
+    pub fn set_registers(&mut self, registers: &Registers) {
+        *self = SyscallFrame {
+            r15: registers.r15,
+            r14: registers.r14,
+            r13: registers.r13,
+            r12: registers.r12,
+            rbp: registers.rbp,
+            rbx: registers.rbx,
+            r9: registers.r9,
+            r8: registers.r8,
+            r10: registers.r10,
+            rdx: registers.rdx,
+            rsi: registers.rsi,
+            rdi: registers.rdi,
+            rax: self.rax,
+            rip: registers.rip,
+            rflags: registers.rflags,
+            rsp: registers.rsp,
+        };
+    }
+}
+
+/// Result of a system call handler.
+pub type SyscallResult = Result<u64, Errno>;
+
+type Handler = fn(&mut SyscallFrame) -> SyscallResult;
+
+/// Handlers indexed by system call number.
+static TABLE: [Handler; 25] = {
+    let mut table: [Handler; 25] = [sys_unknown; 25];
+    table[SYS_EXIT as usize] = sys_exit;
+    table[SYS_WRITE as usize] = sys_write;
+    table[SYS_SLEEP as usize] = sys_sleep;
+    table[SYS_GETPID as usize] = sys_getpid;
+    table[SYS_FORK as usize] = sys_fork;
+    table[SYS_EXEC as usize] = sys_exec;
+    table[SYS_WAITPID as usize] = sys_waitpid;
+    table[SYS_KILL as usize] = sys_kill;
+    table[SYS_PORT_CREATE as usize] = sys_port_create;
+    table[SYS_PORT_SEND as usize] = sys_port_send;
+    table[SYS_PORT_RECEIVE as usize] = sys_port_receive;
+    table[SYS_PORT_DESTROY as usize] = sys_port_destroy;
+    table[SYS_PIPE as usize] = sys_pipe;
+    table[SYS_READ as usize] = sys_read;
+    table[SYS_CLOSE as usize] = sys_close;
+    table[SYS_DUP2 as usize] = sys_dup2;
+    table[SYS_SHM_CREATE as usize] = sys_shm_create;
+    table[SYS_SHM_MAP as usize] = sys_shm_map;
+    table[SYS_SHM_DESTROY as usize] = sys_shm_destroy;
+    table[SYS_BRK as usize] = sys_brk;
+    table[SYS_MMAP as usize] = sys_mmap;
+    table[SYS_MUNMAP as usize] = sys_munmap;
+    table[SYS_OPEN as usize] = sys_open;
+    table[SYS_SEEK as usize] = sys_seek;
+    table[SYS_REBOOT as usize] = sys_reboot;
+    table
+};
+
+global_asm!(
+    ".pushsection .text",
+    ".global toyos_syscall_entry",
+    "toyos_syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    // Build a `SyscallFrame`. Its sixteen fields keep the stack 16 byte
+    // aligned.
+    "push qword ptr gs:[{user_stack}]",
+    "push r11",
+    "push rcx",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "sti",
+    "call {dispatch}",
+    // The user stack pointer is loaded before `sysretq` returns to ring 3, so
+    // nothing may interrupt the kernel on it.
+    "cli",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    // Skip the call number; the result is already in `rax`.
+    "add rsp, 8",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    ".popsection",
+    user_stack = const USER_STACK_OFFSET,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn toyos_syscall_entry();
+}
+
+/// Enables the `syscall` instruction on the calling CPU and points it at the
+/// entry stub.
+///
+/// Must be called on every CPU after its GDT has been loaded.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout is not compatible with syscall");
+    LStar::write(VirtAddr::new(toyos_syscall_entry as *const () as u64));
+
+    // Enter the kernel with interrupts disabled until the stack has been
+    // switched, and with the flags the compiler expects.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Called by the entry stub with the caller's saved registers.
+extern "C" fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    let handler = TABLE
+        .get(frame.rax as usize)
+        .copied()
+        .unwrap_or(sys_unknown);
+    let result = match handler(frame) {
+        Ok(value) => value,
+        Err(errno) => errno.to_return_value(),
+    };
+
+    process::deliver_signals();
+    result
+}
+
+fn sys_unknown(_frame: &mut SyscallFrame) -> SyscallResult {
+    Err(Errno::NoSys)
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    unsafe { usermode::return_to_kernel(frame.rdi) }
+}
+
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, buffer, len, ..] = frame.args();
+    // Writes to pipes and files may be short, leaving the rest to the
+    // caller, so no more is copied than they take at a time.
+    match descriptor(fd)? {
+        Descriptor::Console => write_console(buffer, len),
+        Descriptor::PipeWriter(writer) => {
+            let len = len.min(pipe::PIPE_CAPACITY as u64);
+            let bytes = usermode::copy_from_user(buffer, len).ok_or(Errno::Fault)?;
+            match block_on(writer.write(&bytes))? {
+                Ok(written) => Ok(written as u64),
+                Err(BrokenPipe) => Err(Errno::Pipe),
+            }
+        }
+        Descriptor::File(file) if file.flags().contains(OpenFlags::WRITE) => {
+            let bytes =
+                usermode::copy_from_user(buffer, len.min(MAX_FILE_IO)).ok_or(Errno::Fault)?;
+            Ok(file.write(&bytes)? as u64)
+        }
+        Descriptor::PipeReader(_) | Descriptor::File(_) => Err(Errno::BadF),
+    }
+}
+
+/// Prints `len` bytes from `buffer` to the console, copying at most
+/// [MAX_FILE_IO] of them at a time.
+fn write_console(buffer: u64, len: u64) -> SyscallResult {
+    if !usermode::is_user_range(buffer, len, false) {
+        return Err(Errno::Fault);
+    }
+    let mut written = 0;
+    while written < len {
+        let chunk = (len - written).min(MAX_FILE_IO);
+        let bytes = usermode::copy_from_user(buffer + written, chunk).ok_or(Errno::Fault)?;
+        vt::print(
+            vt::SHELL,
+            format_args!("{}", String::from_utf8_lossy(&bytes)),
+        );
+        written += chunk;
+    }
+    Ok(len)
+}
+
+fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, buffer, len, ..] = frame.args();
+    let descriptor = descriptor(fd)?;
+    if !usermode::is_user_range(buffer, len, true) {
+        return Err(Errno::Fault);
+    }
+    match descriptor {
+        Descriptor::PipeReader(reader) => {
+            let mut data = vec![0; (len as usize).min(pipe::PIPE_CAPACITY)];
+            let read = block_on(reader.read(&mut data))?;
+            if !usermode::copy_to_user(buffer, &data[..read]) {
+                return Err(Errno::Fault);
+            }
+            Ok(read as u64)
+        }
+        Descriptor::File(file) if file.flags().contains(OpenFlags::READ) => {
+            let mut data = vec![0; len.min(MAX_FILE_IO) as usize];
+            let read = file.read(&mut data)?;
+            if !usermode::copy_to_user(buffer, &data[..read]) {
+                return Err(Errno::Fault);
+            }
+            Ok(read as u64)
+        }
+        Descriptor::Console => Ok(0),
+        Descriptor::PipeWriter(_) | Descriptor::File(_) => Err(Errno::BadF),
+    }
+}
+
+/// Returns what `fd` refers to in the calling process.
+fn descriptor(fd: u64) -> Result<Descriptor, Errno> {
+    process::with_files(|files| files.get(fd)).ok_or(Errno::BadF)
+}
+
+fn sys_open(frame: &mut SyscallFrame) -> SyscallResult {
+    let [path, len, flags, ..] = frame.args();
+    if len > MAX_PATH_LEN {
+        return Err(Errno::NameTooLong);
+    }
+    if flags & !(O_ACCMODE | O_CREAT | O_TRUNC | O_APPEND) != 0 {
+        return Err(Errno::Inval);
+    }
+    let mut open_flags = match flags & O_ACCMODE {
+        O_RDONLY => OpenFlags::READ,
+        O_WRONLY => OpenFlags::WRITE,
+        O_RDWR => OpenFlags::READ | OpenFlags::WRITE,
+        _ => return Err(Errno::Inval),
+    };
+    for (flag, open_flag) in [
+        (O_CREAT, OpenFlags::CREATE),
+        (O_TRUNC, OpenFlags::TRUNCATE),
+        (O_APPEND, OpenFlags::APPEND),
+    ] {
+        if flags & flag != 0 {
+            open_flags = open_flags | open_flag;
+        }
+    }
+
+    let path = usermode::copy_from_user(path, len).ok_or(Errno::Fault)?;
+    let path = core::str::from_utf8(&path).map_err(|_| Errno::Inval)?;
+    let file = fs::open(path, open_flags)?;
+    process::with_files(|files| files.insert(Descriptor::File(Arc::new(file)))).ok_or(Errno::MFile)
+}
+
+fn sys_seek(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, offset, whence, ..] = frame.args();
+    let file = match descriptor(fd)? {
+        Descriptor::File(file) => file,
+        _ => return Err(Errno::SPipe),
+    };
+    let from = match whence {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset as i64).map_err(|_| Errno::Inval)?),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::Inval),
+    };
+    Ok(file.seek(from)?)
+}
+
+fn sys_reboot(frame: &mut SyscallFrame) -> SyscallResult {
+    match frame.rdi {
+        REBOOT_POWER_OFF => power::shutdown(),
+        REBOOT_RESTART => power::reboot(),
+        _ => Err(Errno::Inval),
+    }
+}
+
+fn sys_sleep(frame: &mut SyscallFrame) -> SyscallResult {
+    let ticks = time::duration_to_ticks(Duration::from_millis(frame.rdi));
+    let deadline = time::ticks().saturating_add(ticks);
+    while time::ticks() < deadline {
+        if process::is_interrupted() {
+            return Err(Errno::Intr);
+        }
+        task::thread::park_until(deadline);
+    }
+    Ok(0)
+}
+
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    // User code run directly with `usermode::run` is identified by its
+    // thread.
+    match process::current() {
+        Some(process) => Ok(process.pid().as_u64()),
+        None => Ok(task::thread::current().as_u64()),
+    }
+}
+
+fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    match process::fork(frame).ok_or(Errno::Perm)? {
+        Ok(pid) => Ok(pid.as_u64()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn sys_exec(frame: &mut SyscallFrame) -> SyscallResult {
+    let [name, len, args, env, ..] = frame.args();
+    if len > MAX_NAME_LEN {
+        return Err(Errno::NameTooLong);
+    }
+    let name = usermode::copy_from_user(name, len).ok_or(Errno::Fault)?;
+    let name = core::str::from_utf8(&name).map_err(|_| Errno::Inval)?;
+    let args = string_array(args)?;
+    let env = string_array(env)?;
+
+    let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+    let env: Vec<&[u8]> = env.iter().map(Vec::as_slice).collect();
+    process::exec(frame, name, &args, &env).ok_or(Errno::Perm)??;
+    Ok(0)
+}
+
+/// Copies the strings of the null terminated array of string pointers at
+/// `addr` in user memory. A null `addr` stands for an empty array.
+fn string_array(addr: u64) -> Result<Vec<Vec<u8>>, Errno> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        if strings.len() == MAX_ARGS {
+            return Err(Errno::TooBig);
+        }
+        let entry = addr + strings.len() as u64 * 8;
+        let pointer = usermode::copy_from_user(entry, 8).ok_or(Errno::Fault)?;
+        let pointer = u64::from_ne_bytes(pointer.try_into().unwrap());
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        let string = usermode::copy_string_from_user(pointer, process::stack::MAX_ARGUMENTS_SIZE)
+            .ok_or(Errno::Fault)?;
+        strings.push(string);
+    }
+}
+
+fn sys_waitpid(frame: &mut SyscallFrame) -> SyscallResult {
+    let [pid, status, options, ..] = frame.args();
+    if options & !WNOHANG != 0 {
+        return Err(Errno::Inval);
+    }
+    let target = match pid as i64 {
+        -1 => WaitFor::Any,
+        pid if pid > 0 => WaitFor::Pid(Pid::from_u64(pid as u64)),
+        _ => return Err(Errno::Inval),
+    };
+    if status != 0 && !usermode::is_user_range(status, 8, true) {
+        return Err(Errno::Fault);
+    }
+
+    let block = options & WNOHANG == 0;
+    let reaped = process::wait_child(target, block)
+        .ok_or(Errno::Perm)?
+        .map_err(|error| match error {
+            WaitError::NoChildren => Errno::Child,
+            WaitError::Interrupted => Errno::Intr,
+        })?;
+    match reaped {
+        Some((pid, exit_status)) => {
+            if status != 0 && !usermode::copy_to_user(status, &exit_status.to_ne_bytes()) {
+                return Err(Errno::Fault);
+            }
+            Ok(pid.as_u64())
+        }
+        None => Ok(0),
+    }
+}
+
+fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult {
+    let [pid, signal, ..] = frame.args();
+    let pid = Pid::from_u64(pid);
+    if signal == 0 {
+        return process::get(pid).map(|_| 0).ok_or(Errno::Srch);
+    }
+
+    let signal = Signal::from_number(signal).ok_or(Errno::Inval)?;
+    process::kill(pid, signal).map_err(|_| Errno::Srch)?;
+    Ok(0)
+}
+
+fn sys_pipe(frame: &mut SyscallFrame) -> SyscallResult {
+    let fds = frame.rdi;
+    if !usermode::is_user_range(fds, 16, true) {
+        return Err(Errno::Fault);
+    }
+
+    let (reader, writer) = pipe::pipe();
+    let (read_fd, write_fd) = process::with_files(|files| {
+        let read_fd = files
+            .insert(Descriptor::PipeReader(Arc::new(reader)))
+            .ok_or(Errno::MFile)?;
+        match files.insert(Descriptor::PipeWriter(Arc::new(writer))) {
+            Some(write_fd) => Ok((read_fd, write_fd)),
+            None => {
+                files.remove(read_fd);
+                Err(Errno::MFile)
+            }
+        }
+    })?;
+
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&read_fd.to_ne_bytes());
+    bytes[8..].copy_from_slice(&write_fd.to_ne_bytes());
+    if !usermode::copy_to_user(fds, &bytes) {
+        return Err(Errno::Fault);
+    }
+    Ok(0)
+}
+
+fn sys_close(frame: &mut SyscallFrame) -> SyscallResult {
+    process::with_files(|files| files.remove(frame.rdi)).ok_or(Errno::BadF)?;
+    Ok(0)
+}
+
+fn sys_dup2(frame: &mut SyscallFrame) -> SyscallResult {
+    let [old, new, ..] = frame.args();
+    process::with_files(|files| {
+        if old == new {
+            return files.get(old).map(|_| new).ok_or(Errno::BadF);
+        }
+        files
+            .duplicate(old, new)
+            .map_err(|BadDescriptor| Errno::BadF)?;
+        Ok(new)
+    })
+}
+
+fn sys_port_create(_frame: &mut SyscallFrame) -> SyscallResult {
+    let process = process::current().ok_or(Errno::Perm)?;
+    Ok(ipc::create(Some(process.pid())).id().as_u64())
+}
+
+fn sys_port_send(frame: &mut SyscallFrame) -> SyscallResult {
+    let [port, buffer, len, ..] = frame.args();
+    let port = ipc::get(PortId::from_u64(port)).ok_or(Errno::BadF)?;
+    if len > ipc::MAX_MESSAGE_SIZE as u64 {
+        return Err(Errno::MsgSize);
+    }
+
+    let message = Message {
+        sender: process::current().map(|process| process.pid()),
+        data: usermode::copy_from_user(buffer, len).ok_or(Errno::Fault)?,
+    };
+    port.send(message).map_err(|error| match error {
+        SendError::TooLarge => Errno::MsgSize,
+        SendError::Full => Errno::Again,
+        SendError::Closed => Errno::BadF,
+    })?;
+    Ok(0)
+}
+
+fn sys_port_receive(frame: &mut SyscallFrame) -> SyscallResult {
+    let [port, buffer, len, sender, ..] = frame.args();
+    let port = owned_port(port)?;
+    if !usermode::is_user_range(buffer, len, true)
+        || (sender != 0 && !usermode::is_user_range(sender, 8, true))
+    {
+        return Err(Errno::Fault);
+    }
+
+    // The calling process is the only receiver, so a message seen by
+    // `readable` is still queued when it is removed.
+    block_on(port.readable())?.map_err(|_| Errno::BadF)?;
+    if port.peek_len().is_some_and(|size| size as u64 > len) {
+        return Err(Errno::MsgSize);
+    }
+    let message = match port.try_receive() {
+        Ok(Some(message)) => message,
+        Ok(None) | Err(_) => return Err(Errno::BadF),
+    };
+
+    let sender_pid = message.sender.map_or(0, Pid::as_u64);
+    if !usermode::copy_to_user(buffer, &message.data)
+        || (sender != 0 && !usermode::copy_to_user(sender, &sender_pid.to_ne_bytes()))
+    {
+        return Err(Errno::Fault);
+    }
+    Ok(message.data.len() as u64)
+}
+
+fn sys_port_destroy(frame: &mut SyscallFrame) -> SyscallResult {
+    owned_port(frame.rdi)?.close();
+    Ok(0)
+}
+
+/// Returns the open port with the ID `port`, provided the calling process
+/// owns it.
+fn owned_port(port: u64) -> Result<Arc<Port>, Errno> {
+    let port = ipc::get(PortId::from_u64(port)).ok_or(Errno::BadF)?;
+    let process = process::current().ok_or(Errno::Perm)?;
+    if port.owner() != Some(process.pid()) {
+        return Err(Errno::Perm);
+    }
+    Ok(port)
+}
+
+fn sys_shm_create(frame: &mut SyscallFrame) -> SyscallResult {
+    let process = process::current().ok_or(Errno::Perm)?;
+    match shm::create(Some(process.pid()), frame.rdi) {
+        Ok(region) => Ok(region.id().as_u64()),
+        Err(shm::Error::InvalidSize) => Err(Errno::Inval),
+        Err(shm::Error::OutOfMemory) => Err(Errno::NoMem),
+    }
+}
+
+fn sys_shm_map(frame: &mut SyscallFrame) -> SyscallResult {
+    let [id, addr, ..] = frame.args();
+    let region = shm::get(ShmId::from_u64(id)).ok_or(Errno::Inval)?;
+    let start = VirtAddr::try_new(addr).map_err(|_| Errno::Inval)?;
+    let in_range = addr
+        .checked_add(region.size())
+        .is_some_and(|end| end <= USER_END);
+    if !start.is_aligned(4096u64) || !usermode::is_user_address(start) || !in_range {
+        return Err(Errno::Inval);
+    }
+
+    let process = process::current().ok_or(Errno::Perm)?;
+    let mut address_space = process.address_space();
+    let address_space = address_space.as_mut().ok_or(Errno::Perm)?;
+    match address_space.map_shared(start, &region, PageTableFlags::WRITABLE) {
+        Ok(()) => Ok(addr),
+        Err(MapToError::PageAlreadyMapped(_)) => Err(Errno::Exist),
+        Err(_) => Err(Errno::NoMem),
+    }
+}
+
+fn sys_shm_destroy(frame: &mut SyscallFrame) -> SyscallResult {
+    let id = ShmId::from_u64(frame.rdi);
+    let region = shm::get(id).ok_or(Errno::Inval)?;
+    let process = process::current().ok_or(Errno::Perm)?;
+    if region.owner() != Some(process.pid()) {
+        return Err(Errno::Perm);
+    }
+    shm::remove(id);
+    Ok(0)
+}
+
+fn sys_brk(frame: &mut SyscallFrame) -> SyscallResult {
+    let process = process::current().ok_or(Errno::Perm)?;
+    let mut address_space = process.address_space();
+    let address_space = address_space.as_mut().ok_or(Errno::Perm)?;
+
+    let end = frame.rdi;
+    if end != 0 && end <= USER_END {
+        address_space.set_break(VirtAddr::new(end));
+    }
+    Ok(address_space.program_break().as_u64())
+}
+
+fn sys_mmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let [_, len, prot, flags, ..] = frame.args();
+    if flags != MAP_PRIVATE | MAP_ANONYMOUS
+        || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        || len == 0
+    {
+        return Err(Errno::Inval);
+    }
+    let flags = if prot & PROT_WRITE != 0 {
+        PageTableFlags::WRITABLE
+    } else {
+        PageTableFlags::empty()
+    };
+
+    let process = process::current().ok_or(Errno::Perm)?;
+    let mut address_space = process.address_space();
+    let address_space = address_space.as_mut().ok_or(Errno::Perm)?;
+    let start = address_space.reserve(len, flags).ok_or(Errno::NoMem)?;
+    Ok(start.as_u64())
+}
+
+fn sys_munmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let [addr, len, ..] = frame.args();
+    let in_range = addr.checked_add(len).is_some_and(|end| end <= USER_END);
+    if addr % 4096 != 0 || addr < USER_START || !in_range {
+        return Err(Errno::Inval);
+    }
+
+    let process = process::current().ok_or(Errno::Perm)?;
+    let mut address_space = process.address_space();
+    let address_space = address_space.as_mut().ok_or(Errno::Perm)?;
+    address_space.unmap(VirtAddr::new(addr), len);
+    Ok(0)
+}
+
+/// Runs `future` to completion on the calling thread, which is parked until
+/// the future is woken. Fails with [Errno::Intr] if a signal which terminates
+/// the process arrives first; [process::kill] unparks the thread.
+fn block_on<F: Future>(future: F) -> Result<F::Output, Errno> {
+    let waker = task::thread::waker();
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return Ok(output);
+        }
+        if process::is_interrupted() {
+            return Err(Errno::Intr);
+        }
+        task::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::usermode::user_program;
+
+    global_asm!(
+        ".pushsection .text",
+        // Writes a message, sleeps for 20ms and exits with the sum of its PID
+        // and the results of the two calls.
+        "toyos_test_syscalls:",
+        "mov eax, {write}",
+        "mov edi, {stdout}",
+        "lea rsi, [rip + 2f]",
+        "mov edx, 7",
+        "syscall",
+        "mov rbx, rax",
+        "mov eax, {sleep}",
+        "mov edi, 20",
+        "syscall",
+        "add rbx, rax",
+        "mov eax, {getpid}",
+        "syscall",
+        "add rbx, rax",
+        "mov eax, {exit}",
+        "mov rdi, rbx",
+        "syscall",
+        "2:",
+        ".ascii \"[user] \"",
+        "toyos_test_syscalls_end:",
+        // Exits with the results of an unknown call and a write from a
+        // kernel address.
+        "toyos_test_syscall_errors:",
+        "mov eax, 1000",
+        "syscall",
+        "mov rbx, rax",
+        "mov eax, {write}",
+        "mov edi, {stdout}",
+        "mov rsi, 0x1000",
+        "mov edx, 1",
+        "syscall",
+        "shl rbx, 8",
+        "or bl, al",
+        "mov eax, {exit}",
+        "mov rdi, rbx",
+        "syscall",
+        "toyos_test_syscall_errors_end:",
+        // Loads its data segment selector into GS, which clears the GS base,
+        // then makes a system call and spins long enough to be interrupted by
+        // the timer. Exits with the result of a second system call.
+        "toyos_test_user_gs:",
+        "mov ax, ss",
+        "mov gs, ax",
+        "mov eax, {getpid}",
+        "syscall",
+        "mov ecx, 50000000",
+        "2:",
+        "dec ecx",
+        "jnz 2b",
+        "mov eax, {getpid}",
+        "syscall",
+        "mov rdi, rax",
+        "mov eax, {exit}",
+        "syscall",
+        "toyos_test_user_gs_end:",
+        // Creates a file, writes "xyz" to it, seeks back to the second byte
+        // and reads the rest into the stack. Exits with the number of bytes
+        // read shifted left by 8, ORed with the first of them.
+        "toyos_test_files:",
+        "mov eax, {open}",
+        "lea rdi, [rip + 2f]",
+        "mov esi, 12",
+        "mov edx, {rdwr_creat}",
+        "syscall",
+        "mov r12, rax",
+        "mov eax, {write}",
+        "mov rdi, r12",
+        "lea rsi, [rip + 3f]",
+        "mov edx, 3",
+        "syscall",
+        "mov eax, {seek}",
+        "mov rdi, r12",
+        "mov esi, 1",
+        "mov edx, {seek_set}",
+        "syscall",
+        "sub rsp, 16",
+        "mov eax, {read}",
+        "mov rdi, r12",
+        "mov rsi, rsp",
+        "mov edx, 16",
+        "syscall",
+        "mov rbx, rax",
+        "shl rbx, 8",
+        "mov bl, [rsp]",
+        "mov eax, {close}",
+        "mov rdi, r12",
+        "syscall",
+        "mov eax, {exit}",
+        "mov rdi, rbx",
+        "syscall",
+        "2:",
+        ".ascii \"/tmp/fd-test\"",
+        "3:",
+        ".ascii \"xyz\"",
+        "toyos_test_files_end:",
+        ".popsection",
+        open = const SYS_OPEN,
+        read = const SYS_READ,
+        seek = const SYS_SEEK,
+        close = const SYS_CLOSE,
+        rdwr_creat = const O_RDWR | O_CREAT,
+        seek_set = const SEEK_SET,
+        write = const SYS_WRITE,
+        sleep = const SYS_SLEEP,
+        getpid = const SYS_GETPID,
+        exit = const SYS_EXIT,
+        stdout = const STDOUT,
+    );
+
+    #[test_case]
+    fn test_syscalls() {
+        let code = user_program!(toyos_test_syscalls);
+        let start = time::uptime();
+        let status = usermode::run(code).ok();
+
+        let pid = task::thread::current().as_u64();
+        assert_eq!(status, Some(7 + pid));
+        assert!(time::uptime() - start >= Duration::from_millis(20));
+    }
+
+    #[test_case]
+    fn test_syscall_errors() {
+        let code = user_program!(toyos_test_syscall_errors);
+        let status = usermode::run(code).expect("failed to map program");
+
+        let nosys = Errno::NoSys.to_return_value();
+        let fault = Errno::Fault.to_return_value() as u8;
+        assert_eq!(status, (nosys << 8) | fault as u64);
+    }
+
+    #[test_case]
+    fn test_user_gs_base_does_not_affect_kernel() {
+        let code = user_program!(toyos_test_user_gs);
+        let ticks = time::ticks();
+        let status = usermode::run(code).ok();
+
+        assert_eq!(status, Some(task::thread::current().as_u64()));
+        assert!(time::ticks() > ticks);
+    }
+
+    #[test_case]
+    fn test_file_descriptors() {
+        let code = user_program!(toyos_test_files);
+        let status = usermode::run(code).ok();
+
+        assert_eq!(status, Some((2 << 8) | b'y' as u64));
+        assert_eq!(fs::read("/tmp/fd-test").unwrap(), b"xyz");
+        fs::remove("/tmp/fd-test").unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Test results in the Test Anything Protocol, printed to serial.
+//!
+//! The [test runner](crate::test_runner) prints the plan, then a line for
+//! every test as it finishes, followed by a YAML block with how long it ran
+//! and, for a failure, the panic message:
+//!
+//! ```text
+//! TAP version 13
+//! 1..2
+//! ok 1 - toyos::vga::test::test_println
+//!   ---
+//!   duration_us: 152
+//!   ...
+//! not ok 2 - toyos::vga::test::test_scroll
+//!   ---
+//!   duration_us: 48
+//!   message: |
+//!     panicked at src/vga.rs:812:9:
+//!     assertion failed: writer.row == 0
+//!   ...
+//! ```
+//!
+//! A panic outside of a test prints `Bail out!` with the message instead.
+//!
+//! See: https://testanything.org/tap-version-13-specification.html
+
+use core::fmt::{self, Write};
+
+use crate::{serial_print, serial_println, sync::IrqSpinlock, time::Instant};
+
+/// The test which is running.
+struct Test {
+    number: usize,
+    name: &'static str,
+    start: Instant,
+}
+
+/// The running test, and the number of tests started.
+static CURRENT: IrqSpinlock<(Option<Test>, usize)> = IrqSpinlock::new((None, 0));
+
+/// Prints the header and the number of tests which are about to run.
+pub fn plan(count: usize) {
+    serial_println!("TAP version 13");
+    serial_println!("1..{}", count);
+}
+
+/// Starts timing the next test.
+pub fn start(name: &'static str) {
+    let mut current = CURRENT.lock();
+    current.1 += 1;
+    current.0 = Some(Test {
+        number: current.1,
+        name,
+        start: Instant::now(),
+    });
+}
+
+/// Reports that the running test passed.
+pub fn pass() {
+    let test = CURRENT.lock().0.take();
+    if let Some(test) = test {
+        serial_println!("ok {} - {}", test.number, test.name);
+        diagnostics(&test, None);
+    }
+}
+
+/// Reports that the running test failed with `message`, or bails out if no
+/// test is running.
+pub fn fail(message: &dyn fmt::Display) {
+    let test = CURRENT.lock().0.take();
+    match test {
+        Some(test) => {
+            serial_println!("not ok {} - {}", test.number, test.name);
+            diagnostics(&test, Some(message));
+        }
+        None => {
+            serial_println!("Bail out! {}", message);
+        }
+    }
+}
+
+/// Prints the YAML block following the result of a test.
+fn diagnostics(test: &Test, message: Option<&dyn fmt::Display>) {
+    serial_println!("  ---");
+    serial_println!("  duration_us: {}", test.start.elapsed().as_micros());
+    if let Some(message) = message {
+        serial_println!("  message: |");
+        _ = write!(Indented { start: true }, "{}", message);
+        serial_println!();
+    }
+    serial_println!("  ...");
+}
+
+/// Prints text to serial as the lines of a YAML block scalar.
+struct Indented {
+    /// Whether the next character starts a line.
+    start: bool,
+}
+
+impl Write for Indented {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                serial_println!();
+                self.start = true;
+            }
+            if self.start && !line.is_empty() {
+                serial_print!("    ");
+                self.start = false;
+            }
+            serial_print!("{}", line);
+        }
+        Ok(())
+    }
+}
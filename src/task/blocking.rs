@@ -0,0 +1,148 @@
+//! Offloading of blocking work onto a pool of kernel threads.
+//!
+//! Async tasks must return quickly from each poll, so work which spins or
+//! computes for a long time, such as checksumming a large buffer or polling a
+//! disk controller, would stall every other task on the executor. Closures
+//! passed to [spawn_blocking] instead run on a kernel [thread], which the
+//! timer preempts like any other, while the calling task awaits the returned
+//! [JoinHandle].
+//!
+//! Worker threads are started on demand, up to [MAX_WORKERS], and exit after
+//! being idle for [KEEP_ALIVE]. Idle workers are parked on a
+//! [WaitQueue](thread::WaitQueue) until a job is pushed or their keep-alive
+//! runs out, so they take no time from other threads while waiting.
+
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Context,
+};
+
+use crossbeam_queue::SegQueue;
+use futures_util::task::noop_waker;
+
+use super::{join, thread, JoinHandle};
+use crate::time::{Duration, Instant};
+
+/// Maximum number of worker threads. Further jobs wait for a worker to
+/// become free.
+pub const MAX_WORKERS: usize = 4;
+
+/// How long an idle worker waits for a new job before exiting.
+pub const KEEP_ALIVE: Duration = Duration::from_secs(1);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+static JOBS: SegQueue<Job> = SegQueue::new();
+
+/// Number of live worker threads.
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of worker threads waiting for a job.
+static IDLE_WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Idle workers wait here for new jobs.
+static IDLE: thread::WaitQueue = thread::WaitQueue::new();
+
+/// Runs `f` on a worker thread, returning a handle which resolves with its
+/// result.
+///
+/// Aborting the handle before a worker picks up the job prevents it from
+/// running; once running, `f` always runs to completion.
+///
+/// # Panics
+///
+/// Panics if a worker must be started but [thread::MAX_THREADS] threads are
+/// already running.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (future, handle) = join::joinable(async move { f() });
+    JOBS.push(Box::new(move || {
+        // The wrapped future has no await points besides the abort check, so
+        // a single poll always completes it.
+        let waker = noop_waker();
+        let _ = pin!(future).poll(&mut Context::from_waker(&waker));
+    }));
+    IDLE.wake_one();
+
+    let start_worker = IDLE_WORKERS.load(Ordering::SeqCst) == 0
+        && WORKERS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                (workers < MAX_WORKERS).then_some(workers + 1)
+            })
+            .is_ok();
+    if start_worker {
+        IDLE_WORKERS.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(worker);
+    }
+    handle
+}
+
+/// Returns the number of live worker threads.
+pub fn workers() -> usize {
+    WORKERS.load(Ordering::Relaxed)
+}
+
+fn worker() {
+    let mut idle_since = Instant::now();
+    loop {
+        if let Some(job) = JOBS.pop() {
+            IDLE_WORKERS.fetch_sub(1, Ordering::SeqCst);
+            job();
+            IDLE_WORKERS.fetch_add(1, Ordering::SeqCst);
+            idle_since = Instant::now();
+            continue;
+        }
+
+        if idle_since.elapsed() >= KEEP_ALIVE {
+            IDLE_WORKERS.fetch_sub(1, Ordering::SeqCst);
+            WORKERS.fetch_sub(1, Ordering::SeqCst);
+            // A job pushed after the last check may have seen this worker as
+            // idle and not started another.
+            if JOBS.is_empty() {
+                return;
+            }
+            WORKERS.fetch_add(1, Ordering::SeqCst);
+            IDLE_WORKERS.fetch_add(1, Ordering::SeqCst);
+            idle_since = Instant::now();
+            continue;
+        }
+
+        // Parks the thread rather than yielding, until spawn_blocking wakes
+        // it or the keep-alive deadline passes.
+        IDLE.wait_until_timeout(
+            || !JOBS.is_empty(),
+            KEEP_ALIVE.saturating_sub(idle_since.elapsed()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+    use spin::Mutex;
+
+    #[test_case]
+    fn test_spawn_blocking_returns_result() {
+        static RESULT: Mutex<Option<u64>> = Mutex::new(None);
+
+        let mut executor = Executor::new();
+        let handle = executor.spawn_with_handle(async {
+            let sum = spawn_blocking(|| (1..=1000u64).sum::<u64>()).await;
+            *RESULT.lock() = Some(sum.unwrap());
+        });
+        while !handle.is_finished() {
+            executor.run_until_idle();
+            thread::yield_now();
+        }
+
+        assert_eq!(*RESULT.lock(), Some(500500));
+        assert!(workers() >= 1);
+    }
+}
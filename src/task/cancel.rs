@@ -0,0 +1,110 @@
+//! Cooperative cancellation shared between tasks.
+//!
+//! A [CancellationToken] is cloned into every task that should stop together,
+//! for example all the subtasks of a shell command. Tasks either poll
+//! [CancellationToken::is_cancelled] at convenient points or await
+//! [CancellationToken::cancelled] alongside their real work.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+struct Inner {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// A cloneable flag which wakes every waiting task when set.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                wakers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Cancels the token, waking all tasks awaiting [Self::cancelled].
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Release);
+        let wakers = core::mem::take(&mut *self.inner.wakers.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Returns a future which resolves once the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled { token: self }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by [CancellationToken::cancelled].
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = self.token.inner.wakers.lock();
+        // Checked again under the lock so that a concurrent `cancel` cannot
+        // miss the waker registered below.
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+
+    #[test_case]
+    fn test_cancel_wakes_waiters() {
+        let token = CancellationToken::new();
+        let mut executor = Executor::new();
+        let handles = [(); 3].map(|_| {
+            let token = token.clone();
+            executor.spawn_with_handle(async move { token.cancelled().await })
+        });
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| !handle.is_finished()));
+
+        token.cancel();
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| handle.is_finished()));
+    }
+}
@@ -1,96 +1,520 @@
-use core::task::{Context, Poll, Waker};
+use core::{
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, rc::Rc, string::String, sync::Arc, task::Wake, vec::Vec};
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
 
-use super::{Task, TaskId};
+use super::{idle, join, preempt, JoinHandle, Priority, Task, TaskId};
+use crate::{
+    interrupts::deferred,
+    percpu,
+    time::{tsc, Duration},
+};
+
+/// Capacity of each priority's ready queue.
+const QUEUE_CAPACITY: usize = 100;
+
+/// Number of times a non-empty queue may be passed over in favour of higher
+/// priority work before it is served regardless.
+const STARVATION_LIMIT: usize = 16;
+
+/// Number of times a task may be polled in a single round of
+/// [Executor::run_ready_tasks]. A task woken again after exhausting its budget
+/// is held back until the next round.
+const POLL_BUDGET: usize = 8;
+
+/// Ready queues for each priority level, shared between the executor and the
+/// wakers of its tasks.
+struct ReadyQueue {
+    queues: [ArrayQueue<TaskId>; Priority::COUNT],
+    /// The CPU running the executor, to wake when a task is queued.
+    cpu: usize,
+}
+
+impl ReadyQueue {
+    fn new() -> Self {
+        ReadyQueue {
+            queues: core::array::from_fn(|_| ArrayQueue::new(QUEUE_CAPACITY)),
+            cpu: percpu::cpu_id(),
+        }
+    }
+
+    fn push(&self, task_id: TaskId, priority: Priority) -> Result<(), TaskId> {
+        self.queues[priority as usize].push(task_id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(ArrayQueue::is_empty)
+    }
+}
+
+/// Picks the next ready task, tracking how often each priority is passed
+/// over.
+///
+/// The highest priority non-empty queue is served, unless a lower priority
+/// queue has been passed over [STARVATION_LIMIT] times, in which case that
+/// queue is served instead.
+struct Scheduler {
+    passed_over: [usize; Priority::COUNT],
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        Scheduler {
+            passed_over: [0; Priority::COUNT],
+        }
+    }
+
+    fn next(&mut self, ready: &ReadyQueue) -> Option<TaskId> {
+        let starved = Priority::ALL.iter().rev().find(|&&priority| {
+            let index = priority as usize;
+            self.passed_over[index] >= STARVATION_LIMIT && !ready.queues[index].is_empty()
+        });
+
+        let priority = match starved {
+            Some(&priority) => priority,
+            None => *Priority::ALL
+                .iter()
+                .find(|&&priority| !ready.queues[priority as usize].is_empty())?,
+        };
+
+        for lower in Priority::ALL.iter().filter(|&&p| p != priority) {
+            let index = *lower as usize;
+            if !ready.queues[index].is_empty() {
+                self.passed_over[index] += 1;
+            }
+        }
+        self.passed_over[priority as usize] = 0;
+
+        ready.queues[priority as usize].pop()
+    }
+}
+
+/// Capacity of the queue of tasks spawned through a [Spawner] but not yet
+/// picked up by the executor.
+const SPAWN_QUEUE_CAPACITY: usize = 100;
+
+/// Scheduling state of a task, as reported by [task_list].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken and waiting to be polled.
+    Ready = 0,
+    /// Currently being polled.
+    Running = 1,
+    /// Waiting to be woken.
+    Waiting = 2,
+}
+
+impl TaskState {
+    fn from_u8(value: u8) -> TaskState {
+        match value {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            _ => TaskState::Waiting,
+        }
+    }
+}
+
+/// Information about a spawned task shared between its executor, its waker
+/// and the global task list.
+struct TaskInfo {
+    id: TaskId,
+    name: Option<String>,
+    priority: Priority,
+    state: AtomicU8,
+    polls: AtomicU64,
+    /// TSC cycles spent polling the task.
+    poll_cycles: AtomicU64,
+    wakes: AtomicU64,
+}
+
+impl TaskInfo {
+    fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TaskSummary {
+        TaskSummary {
+            id: self.id,
+            name: self.name.clone(),
+            priority: self.priority,
+            state: TaskState::from_u8(self.state.load(Ordering::Relaxed)),
+            polls: self.polls.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            poll_time: tsc::cycles_to_duration(self.poll_cycles.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Every task spawned on any executor which has not yet completed.
+static TASKS: Mutex<BTreeMap<TaskId, Arc<TaskInfo>>> = Mutex::new(BTreeMap::new());
+
+/// The task currently being polled, or `u64::MAX` if none.
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// A snapshot of a task returned by [task_list].
+#[derive(Debug, Clone)]
+pub struct TaskSummary {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub priority: Priority,
+    pub state: TaskState,
+    /// Number of times the task has been polled.
+    pub polls: u64,
+    /// Number of times the task has been woken.
+    pub wakes: u64,
+    /// Total time spent polling the task.
+    pub poll_time: Duration,
+}
+
+impl fmt::Display for TaskSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns a snapshot of every live task, ordered by ID.
+pub fn task_list() -> Vec<TaskSummary> {
+    TASKS.lock().values().map(|info| info.snapshot()).collect()
+}
+
+/// Returns the number of live tasks.
+pub fn task_count() -> usize {
+    TASKS.lock().len()
+}
+
+/// Returns the task currently being polled.
+///
+/// This function is intended for panic diagnostics. It returns `None` rather
+/// than blocking if the task list is locked.
+pub fn current_task() -> Option<TaskSummary> {
+    let id = CURRENT_TASK.load(Ordering::Relaxed);
+    if id == u64::MAX {
+        return None;
+    }
+
+    TASKS
+        .try_lock()?
+        .get(&TaskId(id))
+        .map(|info| info.snapshot())
+}
+
+/// Prints every live task and its state to serial, marking the one being
+/// polled.
+///
+/// Neither blocks nor allocates, so that it can run in interrupt context
+/// while the executor hangs. Returns `false`, printing nothing, if the task
+/// list is locked.
+pub(crate) fn dump_tasks() -> bool {
+    let Some(tasks) = TASKS.try_lock() else {
+        return false;
+    };
+    let current = CURRENT_TASK.load(Ordering::Relaxed);
+    for info in tasks.values() {
+        crate::serial_println!(
+            "{} {:?} {:?} polls={} wakes={} {}{}",
+            info.id,
+            TaskState::from_u8(info.state.load(Ordering::Relaxed)),
+            info.priority,
+            info.polls.load(Ordering::Relaxed),
+            info.wakes.load(Ordering::Relaxed),
+            info.name.as_deref().unwrap_or("-"),
+            if info.id.0 == current {
+                " <- polling"
+            } else {
+                ""
+            },
+        );
+    }
+    true
+}
+
+/// A task owned by an [Executor].
+struct Spawned {
+    task: Task,
+    info: Arc<TaskInfo>,
+}
+
+/// Counters describing how an [Executor] has shared time between its tasks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutorStats {
+    /// Rounds of polling completed.
+    pub rounds: u64,
+    /// Total number of task polls.
+    pub polls: u64,
+    /// Times a task was held back to the next round because it exhausted its
+    /// poll budget.
+    pub throttled: u64,
+}
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    tasks: BTreeMap<TaskId, Spawned>,
+    ready_queue: Arc<ReadyQueue>,
+    spawn_queue: Rc<ArrayQueue<Task>>,
+    scheduler: Scheduler,
     waker_cache: BTreeMap<TaskId, Waker>,
+    /// Polls of each task during the current round.
+    round_polls: BTreeMap<TaskId, usize>,
+    /// Ready tasks held back until the next round.
+    throttled: Vec<TaskId>,
+    stats: ExecutorStats,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            ready_queue: Arc::new(ReadyQueue::new()),
+            spawn_queue: Rc::new(ArrayQueue::new(SPAWN_QUEUE_CAPACITY)),
+            scheduler: Scheduler::new(),
             waker_cache: BTreeMap::new(),
+            round_polls: BTreeMap::new(),
+            throttled: Vec::new(),
+            stats: ExecutorStats::default(),
         }
     }
 
+    /// Spawns a task built with its own name and priority.
+    ///
+    /// A [Task] has already erased the output of its future, so there is
+    /// nothing for a [JoinHandle] to resolve with; futures whose output is
+    /// wanted are spawned with [spawn_with_handle](Self::spawn_with_handle)
+    /// instead.
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
-        if self.tasks.insert(task_id, task).is_some() {
+        let priority = task.priority;
+        let info = Arc::new(TaskInfo {
+            id: task_id,
+            name: task.name.clone(),
+            priority,
+            state: AtomicU8::new(TaskState::Ready as u8),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+        });
+        TASKS.lock().insert(task_id, info.clone());
+        if self.tasks.insert(task_id, Spawned { task, info }).is_some() {
             panic!("task with same ID already in tasks");
         }
 
-        self.task_queue.push(task_id).expect("queue full");
+        self.ready_queue
+            .push(task_id, priority)
+            .expect("queue full");
+    }
+
+    /// Spawns a future with [Priority::Normal], returning a handle which
+    /// resolves with the future's output.
+    ///
+    /// This is separate from [spawn](Self::spawn) because the handle needs
+    /// the future before it is erased into a [Task], which also keeps the
+    /// many spawns of named or prioritized tasks which ignore their output
+    /// from paying for a handle.
+    pub fn spawn_with_handle<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        self.spawn(Task::new(future));
+        handle
     }
 
+    /// Returns a handle which tasks can use to spawn further tasks onto this
+    /// executor.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    /// Moves tasks queued by [Spawner]s into the executor.
+    fn spawn_queued(&mut self) {
+        while let Some(task) = self.spawn_queue.pop() {
+            self.spawn(task);
+        }
+    }
+
+    /// Returns counters describing how tasks have been scheduled.
+    pub fn stats(&self) -> ExecutorStats {
+        self.stats
+    }
+
+    /// Polls ready tasks until none remain, as a single round.
+    ///
+    /// Each task may be polled at most [POLL_BUDGET] times per round so that a
+    /// future which repeatedly wakes itself cannot starve the rest of the
+    /// ready queue. Tasks which exceed their budget are re-queued once the
+    /// round ends.
     fn run_ready_tasks(&mut self) {
+        self.spawn_queued();
+
         let Self {
             tasks,
-            task_queue,
+            ready_queue,
+            scheduler,
             waker_cache,
+            round_polls,
+            throttled,
+            stats,
+            ..
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
+        while let Some(task_id) = scheduler.next(ready_queue) {
+            let Spawned { task, info } = match tasks.get_mut(&task_id) {
+                Some(spawned) => spawned,
                 None => continue, // task no longer exists
             };
 
+            let polls = round_polls.entry(task_id).or_insert(0);
+            if *polls >= POLL_BUDGET {
+                if !throttled.contains(&task_id) {
+                    throttled.push(task_id);
+                    stats.throttled += 1;
+                }
+                continue;
+            }
+            *polls += 1;
+            stats.polls += 1;
+
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(info.clone(), ready_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            info.set_state(TaskState::Running);
+            info.polls.fetch_add(1, Ordering::Relaxed);
+            CURRENT_TASK.store(task_id.0, Ordering::Relaxed);
+            preempt::start_slice();
+            let start = tsc::rdtsc();
+            let result = task.poll(&mut context);
+            info.poll_cycles
+                .fetch_add(tsc::rdtsc().wrapping_sub(start), Ordering::Relaxed);
+            CURRENT_TASK.store(u64::MAX, Ordering::Relaxed);
+            match result {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    TASKS.lock().remove(&task_id);
+                }
+
+                Poll::Pending => {
+                    // Leave the state alone if the task woke itself while
+                    // being polled.
+                    let _ = info.state.compare_exchange(
+                        TaskState::Running as u8,
+                        TaskState::Waiting as u8,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
                 }
+            }
+        }
 
-                Poll::Pending => {}
+        for task_id in throttled.drain(..) {
+            if let Some(spawned) = tasks.get(&task_id) {
+                ready_queue
+                    .push(task_id, spawned.task.priority)
+                    .expect("queue full");
             }
         }
+        round_polls.clear();
+        stats.rounds += 1;
     }
 
     pub fn run(&mut self) -> ! {
         loop {
+            deferred::run_pending();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
+    /// Runs tasks until none are ready, returning instead of waiting for
+    /// further wake-ups. Useful for tests.
+    pub fn run_until_idle(&mut self) {
+        loop {
+            deferred::run_pending();
+            self.run_ready_tasks();
+            if self.is_idle() {
+                return;
+            }
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.ready_queue.is_empty() && self.spawn_queue.is_empty() && !deferred::is_pending()
+    }
+
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts;
+        idle::sleep(|| self.is_idle());
+    }
+}
 
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            interrupts::enable_and_hlt();
-        } else {
-            interrupts::enable();
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let mut list = TASKS.lock();
+        for task_id in self.tasks.keys() {
+            list.remove(task_id);
         }
     }
 }
 
+/// A cloneable handle for spawning tasks onto an [Executor] from within
+/// running tasks.
+///
+/// Spawned tasks are queued and picked up by the executor before it next
+/// polls ready tasks.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Rc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    /// Spawns a task built with its own name and priority, like
+    /// [Executor::spawn].
+    pub fn spawn(&self, task: Task) {
+        if self.spawn_queue.push(task).is_err() {
+            panic!("spawn queue full");
+        }
+    }
+
+    /// Spawns a future with [Priority::Normal], returning a handle which
+    /// resolves with the future's output, like [Executor::spawn_with_handle].
+    pub fn spawn_with_handle<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        self.spawn(Task::new(future));
+        handle
+    }
+}
+
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    info: Arc<TaskInfo>,
+    ready_queue: Arc<ReadyQueue>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn new(info: Arc<TaskInfo>, ready_queue: Arc<ReadyQueue>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { info, ready_queue }))
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.info.set_state(TaskState::Ready);
+        self.info.wakes.fetch_add(1, Ordering::Relaxed);
+        self.ready_queue
+            .push(self.info.id, self.info.priority)
+            .expect("ready_queue full");
+        idle::wake(self.ready_queue.cpu);
     }
 }
 
@@ -103,3 +527,127 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_higher_priority_first() {
+        let ready = ReadyQueue::new();
+        let mut scheduler = Scheduler::new();
+        ready.push(TaskId(1), Priority::Low).unwrap();
+        ready.push(TaskId(2), Priority::Normal).unwrap();
+        ready.push(TaskId(3), Priority::High).unwrap();
+
+        assert_eq!(scheduler.next(&ready), Some(TaskId(3)));
+        assert_eq!(scheduler.next(&ready), Some(TaskId(2)));
+        assert_eq!(scheduler.next(&ready), Some(TaskId(1)));
+        assert_eq!(scheduler.next(&ready), None);
+    }
+
+    #[test_case]
+    fn test_low_priority_is_not_starved() {
+        let ready = ReadyQueue::new();
+        let mut scheduler = Scheduler::new();
+        ready.push(TaskId(0), Priority::Low).unwrap();
+
+        for polls in 0.. {
+            // Keep the high priority queue permanently busy.
+            ready.push(TaskId(1), Priority::High).unwrap();
+            if scheduler.next(&ready) == Some(TaskId(0)) {
+                assert!(polls <= STARVATION_LIMIT);
+                break;
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_spawner_spawns_from_task() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        static RESULT: AtomicU32 = AtomicU32::new(0);
+
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+        executor.spawn(Task::new(async move {
+            let child = spawner.spawn_with_handle(async { 7 });
+            RESULT.store(child.await.unwrap() * 6, Ordering::Relaxed);
+        }));
+        executor.run_until_idle();
+
+        assert_eq!(RESULT.load(Ordering::Relaxed), 42);
+    }
+
+    /// Wakes itself every time it is polled and never completes.
+    struct Spin;
+
+    impl Future for Spin {
+        type Output = ();
+
+        fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test_case]
+    fn test_self_waking_task_is_throttled() {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(Spin));
+        let handle = executor.spawn_with_handle(async {});
+
+        executor.run_ready_tasks();
+        assert!(handle.is_finished());
+        let stats = executor.stats();
+        assert_eq!(stats.rounds, 1);
+        assert_eq!(stats.polls, POLL_BUDGET as u64 + 1);
+        assert_eq!(stats.throttled, 1);
+
+        // The spinning task is carried over to the next round.
+        assert!(!executor.ready_queue.is_empty());
+        executor.run_ready_tasks();
+        assert_eq!(executor.stats().polls, 2 * POLL_BUDGET as u64 + 1);
+    }
+
+    #[test_case]
+    fn test_task_list_reports_named_tasks() {
+        let mut executor = Executor::new();
+        let task = Task::new(core::future::pending()).named("idle");
+        let id = task.id();
+        executor.spawn(task);
+
+        let summary = |id| task_list().into_iter().find(|task| task.id == id);
+        assert_eq!(summary(id).unwrap().state, TaskState::Ready);
+
+        executor.run_ready_tasks();
+        let idle = summary(id).unwrap();
+        assert_eq!(idle.name.as_deref(), Some("idle"));
+        assert_eq!(idle.state, TaskState::Waiting);
+        assert_eq!(idle.polls, 1);
+
+        drop(executor);
+        assert!(summary(id).is_none());
+    }
+
+    #[test_case]
+    fn test_metrics_count_polls_and_wakes() {
+        let mut executor = Executor::new();
+        let mut remaining = 3;
+        let task = Task::new(core::future::poll_fn(move |cx| {
+            if remaining > 0 {
+                remaining -= 1;
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }));
+        let id = task.id();
+        executor.spawn(task);
+        executor.run_ready_tasks();
+
+        let metrics = crate::task::metrics();
+        let task = metrics.iter().find(|task| task.id == id).unwrap();
+        assert_eq!(task.polls, 4);
+        assert_eq!(task.wakes, 3);
+    }
+}
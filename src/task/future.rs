@@ -0,0 +1,375 @@
+//! Helpers for composing futures.
+//!
+//! [yield_now] lets a long running task give other tasks a turn. The
+//! [join!](crate::join) and [select!](crate::select) macros run several
+//! futures concurrently within a single task, resolving once all of them or
+//! the first of them completes respectively. [select2] and [race] do the same
+//! without macros, and [timeout] bounds how long a future may take:
+//!
+//! ```no_run
+//! # use toyos::task::future::{select2, Either};
+//! # use toyos::task::timer::sleep;
+//! # use toyos::time::Duration;
+//! # async fn f(irq: impl core::future::Future<Output = u8> + Unpin) {
+//! match select2(irq, sleep(Duration::from_millis(500))).await {
+//!     Either::Left(status) => { /* the device completed the request */ }
+//!     Either::Right(()) => { /* no response within 500 ms */ }
+//! }
+//! # }
+//! ```
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::timer::Timeout;
+use crate::time::Duration;
+
+/// Yields once to the executor, letting other ready tasks run before the
+/// calling task continues.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by [yield_now].
+#[must_use = "futures do nothing unless awaited"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Fails with [Elapsed](super::timer::Elapsed) if `future` does not complete
+/// within `duration`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout::new(future, duration)
+}
+
+/// The output of [select2], identifying which future completed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Polls two futures concurrently, resolving with the output of whichever
+/// completes first. The other future is dropped with the [Select2].
+///
+/// `a` is polled first, so it wins if both are ready at once.
+pub fn select2<A: Future, B: Future>(a: A, b: B) -> Select2<A, B> {
+    Select2 { a, b }
+}
+
+/// Future returned by [select2].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select2<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: Future, B: Future> Future for Select2<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: both futures are structurally pinned; they are never moved
+        // out of `self` and `Select2` does not implement `Drop` or `Unpin`
+        // manually.
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        if let Poll::Ready(output) = a.poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        b.poll(cx).map(Either::Right)
+    }
+}
+
+/// Polls any number of futures of the same type concurrently, resolving with
+/// the index and output of the first to complete. The others are dropped with
+/// the [Race].
+///
+/// # Panics
+///
+/// The returned future panics when polled if `futures` was empty.
+pub fn race<F: Future>(futures: impl IntoIterator<Item = F>) -> Race<F> {
+    Race {
+        futures: futures.into_iter().map(Box::pin).collect(),
+    }
+}
+
+/// Future returned by [race].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Race<F> {
+    futures: Vec<Pin<Box<F>>>,
+}
+
+impl<F: Future> Future for Race<F> {
+    type Output = (usize, F::Output);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.futures.is_empty(), "race of no futures");
+
+        for (index, future) in self.futures.iter_mut().enumerate() {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready((index, output));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Awaits several futures concurrently, evaluating to a tuple of their
+/// outputs once all of them have completed.
+///
+/// Must be used within an async context.
+///
+/// ```no_run
+/// # async fn f() {
+/// let (a, b) = toyos::join!(async { 1 }, async { "two" });
+/// # }
+/// ```
+#[macro_export]
+macro_rules! join {
+    ($($future:expr),+ $(,)?) => {
+        $crate::__join!([] $($future,)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __join {
+    // Each step pins one future next to a slot for its output. Identifiers
+    // introduced by different expansions are distinct, so the accumulated
+    // `future`s and `output`s don't shadow each other.
+    ([$($acc:tt)*] $head:expr, $($rest:expr,)*) => {{
+        let mut future = ::core::pin::pin!($head);
+        let mut output = ::core::option::Option::None;
+        $crate::__join!([$($acc)* (future output)] $($rest,)*)
+    }};
+
+    ([$(($future:ident $output:ident))*]) => {{
+        ::core::future::poll_fn(|cx| {
+            let mut done = true;
+            $(
+                if $output.is_none() {
+                    match ::core::future::Future::poll($future.as_mut(), cx) {
+                        ::core::task::Poll::Ready(value) => $output = ::core::option::Option::Some(value),
+                        ::core::task::Poll::Pending => done = false,
+                    }
+                }
+            )*
+            if done {
+                ::core::task::Poll::Ready(())
+            } else {
+                ::core::task::Poll::Pending
+            }
+        })
+        .await;
+        ($($output.unwrap(),)*)
+    }};
+}
+
+/// Awaits several futures concurrently and evaluates the branch of the first
+/// one to complete. The remaining futures are dropped.
+///
+/// Branches are polled in the order they are written, so earlier branches
+/// take priority when several futures are ready at once. Each branch's
+/// pattern must be irrefutable. Branch bodies are evaluated outside of any
+/// closure, so they may `return`, `break` or `.await`.
+///
+/// Must be used within an async context.
+///
+/// ```no_run
+/// # async fn f(
+/// #     irq: impl core::future::Future<Output = u8>,
+/// #     timeout: impl core::future::Future<Output = ()>,
+/// # ) -> Option<u8> {
+/// toyos::select! {
+///     status = irq => Some(status),
+///     _ = timeout => None,
+/// }
+/// # }
+/// ```
+#[macro_export]
+macro_rules! select {
+    ($($pattern:pat = $future:expr => $body:expr),+ $(,)?) => {
+        $crate::__select!([] $($pattern = $future => $body,)+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __select {
+    ([$($acc:tt)*] $pattern:pat = $head:expr => $body:expr, $($rest:tt)*) => {{
+        let mut future = ::core::pin::pin!($head);
+        let mut output = ::core::option::Option::None;
+        $crate::__select!([$($acc)* (future output ($pattern) ($body))] $($rest)*)
+    }};
+
+    ([$(($future:ident $output:ident ($pattern:pat) ($body:expr)))*]) => {{
+        ::core::future::poll_fn(|cx| {
+            $(
+                if let ::core::task::Poll::Ready(value) =
+                    ::core::future::Future::poll($future.as_mut(), cx)
+                {
+                    $output = ::core::option::Option::Some(value);
+                    return ::core::task::Poll::Ready(());
+                }
+            )*
+            ::core::task::Poll::Pending
+        })
+        .await;
+
+        $(
+            if let ::core::option::Option::Some($pattern) = $output {
+                $body
+            } else
+        )*
+        {
+            ::core::unreachable!()
+        }
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{executor::Executor, sync::oneshot, timer, Task};
+    use spin::Mutex;
+
+    /// Runs the executor until `handle` resolves, waiting for interrupts in
+    /// between so that timers can fire.
+    fn run_until_finished<T>(executor: &mut Executor, handle: &crate::task::JoinHandle<T>) {
+        while !handle.is_finished() {
+            executor.run_until_idle();
+            x86_64::instructions::hlt();
+        }
+    }
+
+    #[test_case]
+    fn test_yield_now_lets_others_run() {
+        static ORDER: Mutex<[u8; 3]> = Mutex::new([0; 3]);
+        static NEXT: Mutex<usize> = Mutex::new(0);
+
+        fn record(id: u8) {
+            let mut next = NEXT.lock();
+            ORDER.lock()[*next] = id;
+            *next += 1;
+        }
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            yield_now().await;
+            record(1);
+        }));
+        executor.spawn(Task::new(async { record(2) }));
+        executor.spawn(Task::new(async { record(3) }));
+        executor.run_until_idle();
+
+        assert_eq!(*ORDER.lock(), [2, 3, 1]);
+    }
+
+    #[test_case]
+    fn test_join_waits_for_all() {
+        static RESULT: Mutex<Option<(u32, &str)>> = Mutex::new(None);
+
+        let (sender, receiver) = oneshot::channel();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            let (a, b) = crate::join!(async { receiver.await.unwrap() }, async { "ready" });
+            *RESULT.lock() = Some((a, b));
+        }));
+        executor.run_until_idle();
+        assert_eq!(*RESULT.lock(), None);
+
+        sender.send(7).unwrap();
+        executor.run_until_idle();
+        assert_eq!(*RESULT.lock(), Some((7, "ready")));
+    }
+
+    #[test_case]
+    fn test_select_takes_first() {
+        static RESULT: Mutex<Option<u32>> = Mutex::new(None);
+
+        let (_sender, receiver) = oneshot::channel::<u32>();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            let value = crate::select! {
+                value = receiver => value.unwrap(),
+                value = async {
+                    yield_now().await;
+                    2
+                } => value * 10,
+            };
+            *RESULT.lock() = Some(value);
+        }));
+        executor.run_until_idle();
+
+        assert_eq!(*RESULT.lock(), Some(20));
+    }
+
+    #[test_case]
+    fn test_select2_resolves_with_completed_future() {
+        static RESULT: Mutex<Option<bool>> = Mutex::new(None);
+
+        let mut executor = Executor::new();
+        let (_sender, receiver) = oneshot::channel::<()>();
+        let handle = executor.spawn_with_handle(async {
+            let result = select2(receiver, timer::sleep(Duration::from_millis(5))).await;
+            *RESULT.lock() = Some(matches!(result, Either::Right(())));
+        });
+        run_until_finished(&mut executor, &handle);
+
+        assert_eq!(*RESULT.lock(), Some(true));
+    }
+
+    #[test_case]
+    fn test_timeout_elapses() {
+        static RESULT: Mutex<Option<Result<(), timer::Elapsed>>> = Mutex::new(None);
+
+        let mut executor = Executor::new();
+        let handle = executor.spawn_with_handle(async {
+            *RESULT.lock() = Some(timeout(Duration::from_millis(5), core::future::pending()).await);
+        });
+        run_until_finished(&mut executor, &handle);
+
+        assert_eq!(*RESULT.lock(), Some(Err(timer::Elapsed)));
+    }
+
+    #[test_case]
+    fn test_race_returns_first_index() {
+        static RESULT: Mutex<Option<(usize, u32)>> = Mutex::new(None);
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            let delays = [3u32, 1, 2];
+            let result = race(delays.map(|delay| async move {
+                for _ in 0..delay {
+                    yield_now().await;
+                }
+                delay * 10
+            }))
+            .await;
+            *RESULT.lock() = Some(result);
+        }));
+        executor.run_until_idle();
+
+        assert_eq!(*RESULT.lock(), Some((1, 10)));
+    }
+}
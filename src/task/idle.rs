@@ -0,0 +1,198 @@
+//! Putting a CPU to sleep while it has no work.
+//!
+//! [sleep] checks for work and halts with interrupts disabled in between, so a
+//! wake-up from an interrupt handler can never slip in after the check and
+//! leave the CPU asleep with work pending. Where the processor supports it,
+//! `mwait` is used instead of `hlt`. Besides interrupts, `mwait` also wakes on
+//! a write to a monitored per-CPU word, which [wake] performs, so a task woken
+//! on another CPU is picked up without waiting for the next timer tick.
+//!
+//! Each CPU counts the time spent asleep, from which [stats] derives how busy
+//! the CPU has been.
+
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    percpu::{self, PerCpu},
+    time::{tsc, Duration},
+};
+
+/// Per-CPU sleep state and counters.
+struct IdleState {
+    /// Written by [wake] to end an `mwait` on this CPU.
+    wake: AtomicU64,
+    /// TSC value when counting started.
+    started: u64,
+    idle_cycles: AtomicU64,
+    sleeps: AtomicU64,
+}
+
+static IDLE_STATES: PerCpu<IdleState> = PerCpu::new(|_| IdleState {
+    wake: AtomicU64::new(0),
+    started: tsc::rdtsc(),
+    idle_cycles: AtomicU64::new(0),
+    sleeps: AtomicU64::new(0),
+});
+
+/// Whether the processor supports `mwait`, detected on first use.
+static HAS_MWAIT: Once<bool> = Once::new();
+
+/// Set by [set_mwait_enabled] to fall back to `hlt`.
+static MWAIT_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if [sleep] uses `mwait`.
+pub fn mwait_enabled() -> bool {
+    *HAS_MWAIT.call_once(crate::cpu::has_mwait) && !MWAIT_DISABLED.load(Ordering::Relaxed)
+}
+
+/// Selects whether [sleep] uses `mwait` when the processor supports it.
+/// Enabled by default.
+pub fn set_mwait_enabled(enabled: bool) {
+    MWAIT_DISABLED.store(!enabled, Ordering::Relaxed);
+}
+
+/// Puts the calling CPU to sleep until the next interrupt, or a [wake] of
+/// this CPU, unless `is_idle` returns `false`. Returns `true` if the CPU
+/// slept.
+///
+/// `is_idle` is called with interrupts disabled and must not block.
+/// Interrupts are enabled when this function returns.
+pub fn sleep(is_idle: impl FnOnce() -> bool) -> bool {
+    let state = IDLE_STATES.get();
+    let mwait = mwait_enabled();
+
+    interrupts::disable();
+    if mwait {
+        // Armed before checking for work, so that a `wake` after the check
+        // ends the `mwait` immediately.
+        unsafe {
+            asm!(
+                "monitor",
+                in("rax") state.wake.as_ptr(),
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+    if !is_idle() {
+        interrupts::enable();
+        return false;
+    }
+
+    let start = tsc::rdtsc();
+    if mwait {
+        // ECX bit 0 makes pending interrupts end the `mwait` even though they
+        // are masked; they are handled once interrupts are enabled below.
+        unsafe {
+            asm!("mwait", in("eax") 0, in("ecx") 1, options(nostack, preserves_flags));
+        }
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+    state
+        .idle_cycles
+        .fetch_add(tsc::rdtsc() - start, Ordering::Relaxed);
+    state.sleeps.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Wakes `cpu` if it is sleeping in `mwait`. CPUs halted with `hlt` only wake
+/// on interrupts.
+pub fn wake(cpu: usize) {
+    if cpu != percpu::cpu_id() {
+        IDLE_STATES
+            .get_for(cpu)
+            .wake
+            .fetch_add(1, Ordering::Release);
+    }
+}
+
+/// How a CPU has spent its time since it first slept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStats {
+    /// Time spent asleep.
+    pub idle: Duration,
+    /// Time spent running.
+    pub busy: Duration,
+    /// Number of times the CPU went to sleep.
+    pub sleeps: u64,
+}
+
+impl IdleStats {
+    /// Returns the percentage of time the CPU was busy.
+    pub fn utilization(&self) -> u64 {
+        let total = (self.idle + self.busy).as_micros();
+        match total {
+            0 => 0,
+            total => (self.busy.as_micros() * 100 / total) as u64,
+        }
+    }
+}
+
+impl fmt::Display for IdleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}% busy ({:?} busy, {:?} idle, {} sleeps)",
+            self.utilization(),
+            self.busy,
+            self.idle,
+            self.sleeps
+        )
+    }
+}
+
+/// Returns the counters of the given CPU.
+pub fn stats(cpu: usize) -> IdleStats {
+    let state = IDLE_STATES.get_for(cpu);
+    let idle = state.idle_cycles.load(Ordering::Relaxed);
+    let total = tsc::rdtsc().saturating_sub(state.started);
+    IdleStats {
+        idle: tsc::cycles_to_duration(idle),
+        busy: tsc::cycles_to_duration(total.saturating_sub(idle)),
+        sleeps: state.sleeps.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_sleep_skipped_when_busy() {
+        let before = stats(percpu::cpu_id()).sleeps;
+        assert!(!sleep(|| false));
+        assert!(interrupts::are_enabled());
+        assert_eq!(stats(percpu::cpu_id()).sleeps, before);
+    }
+
+    #[test_case]
+    fn test_sleep_counts_idle_time() {
+        let before = stats(percpu::cpu_id());
+        // The timer interrupt ends the sleep.
+        assert!(sleep(|| true));
+        let after = stats(percpu::cpu_id());
+
+        assert_eq!(after.sleeps, before.sleeps + 1);
+        assert!(after.idle > before.idle);
+    }
+
+    #[test_case]
+    fn test_utilization() {
+        let stats = IdleStats {
+            idle: Duration::from_millis(75),
+            busy: Duration::from_millis(25),
+            sleeps: 1,
+        };
+        assert_eq!(stats.utilization(), 25);
+    }
+}
@@ -0,0 +1,266 @@
+//! PS/2 keyboard driver.
+//!
+//! The interrupt handler only queues raw scancodes. Deferred work then decodes
+//! them into [KeyEvent]s, tracking which modifiers are held, and publishes
+//! them to the input [events](super::events).
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use futures_util::StreamExt;
+use log::warn;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+
+use super::{ByteQueue, InputEvent, KeyCode, KeyEvent, KeyState, Modifiers};
+use crate::{
+    interrupts::deferred::{self, Work},
+    process::{self, Signal},
+    sync::IrqSpinlock,
+    vt,
+};
+
+/// Maximum number of scancodes buffered between the interrupt handler and the
+/// deferred work decoding them.
+const SCANCODE_QUEUE_CAPACITY: usize = 180;
+
+static SCANCODE_QUEUE: IrqSpinlock<ByteQueue<SCANCODE_QUEUE_CAPACITY>> =
+    IrqSpinlock::new(ByteQueue::new());
+
+/// Deferred work raised by the keyboard interrupt handler when new scancodes
+/// are available.
+static KEYBOARD_WORK: OnceCell<Work> = OnceCell::uninit();
+
+/// Number of scancodes dropped by the interrupt handler since the deferred
+/// work last ran.
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Turns scancodes into [KeyEvent]s.
+struct Decoder {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    modifiers: Modifiers,
+    /// Which of the modifier keys on either side are held, as a modifier
+    /// stays held until the keys on both sides are released.
+    shift_left: bool,
+    shift_right: bool,
+    ctrl_left: bool,
+    ctrl_right: bool,
+    alt_left: bool,
+    alt_right: bool,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Decoder {
+            keyboard: Keyboard::new(HandleControl::Ignore),
+            // Matches the initial state of `pc_keyboard`, which decides what
+            // the numeric keypad produces.
+            modifiers: Modifiers {
+                shift: false,
+                ctrl: false,
+                alt: false,
+                caps_lock: false,
+                num_lock: true,
+            },
+            shift_left: false,
+            shift_right: false,
+            ctrl_left: false,
+            ctrl_right: false,
+            alt_left: false,
+            alt_right: false,
+        }
+    }
+
+    /// Feeds a scancode to the decoder, calling `emit` with any resulting
+    /// events.
+    fn add_scancode(&mut self, scancode: u8, mut emit: impl FnMut(KeyEvent)) {
+        let event = match self.keyboard.add_byte(scancode) {
+            Ok(Some(event)) => event,
+            _ => return,
+        };
+
+        let code = event.code;
+        let states: &[KeyState] = match event.state {
+            pc_keyboard::KeyState::Down => &[KeyState::Pressed],
+            pc_keyboard::KeyState::Up => &[KeyState::Released],
+            pc_keyboard::KeyState::SingleShot => &[KeyState::Pressed, KeyState::Released],
+        };
+        let unicode = match self.keyboard.process_keyevent(event) {
+            Some(DecodedKey::Unicode(character)) => Some(character),
+            _ => None,
+        };
+
+        for &state in states {
+            self.update_modifiers(code, state);
+            emit(KeyEvent {
+                code,
+                state,
+                modifiers: self.modifiers,
+                unicode: unicode.filter(|_| state == KeyState::Pressed),
+            });
+        }
+    }
+
+    fn update_modifiers(&mut self, code: KeyCode, state: KeyState) {
+        let pressed = state == KeyState::Pressed;
+        let modifiers = &mut self.modifiers;
+        match code {
+            KeyCode::ShiftLeft => self.shift_left = pressed,
+            KeyCode::ShiftRight => self.shift_right = pressed,
+            KeyCode::ControlLeft => self.ctrl_left = pressed,
+            KeyCode::ControlRight => self.ctrl_right = pressed,
+            KeyCode::AltLeft => self.alt_left = pressed,
+            KeyCode::AltRight => self.alt_right = pressed,
+            KeyCode::CapsLock if pressed => modifiers.caps_lock = !modifiers.caps_lock,
+            KeyCode::NumpadLock if pressed => modifiers.num_lock = !modifiers.num_lock,
+            _ => {}
+        }
+        modifiers.shift = self.shift_left || self.shift_right;
+        modifiers.ctrl = self.ctrl_left || self.ctrl_right;
+        modifiers.alt = self.alt_left || self.alt_right;
+    }
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+/// Registers the deferred work used to decode scancodes queued by the
+/// keyboard interrupt handler.
+pub(crate) fn init() {
+    KEYBOARD_WORK.init_once(|| deferred::register(scancodes_ready));
+}
+
+/// Called by the keyboard interrupt handler.
+///
+/// This function runs in interrupt context and must not block or print; the
+/// scancodes are decoded and errors are reported by deferred work instead.
+pub(crate) fn add_scancode(scancode: u8) {
+    if !SCANCODE_QUEUE.lock().push(scancode) {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if let Ok(work) = KEYBOARD_WORK.try_get() {
+        work.raise();
+    }
+}
+
+/// Deferred half of the keyboard interrupt handler.
+fn scancodes_ready() {
+    let dropped = DROPPED_SCANCODES.swap(0, Ordering::Relaxed);
+    if dropped != 0 {
+        warn!("scancode queue full; dropped {} keyboard input(s)", dropped);
+    }
+
+    let mut decoder = DECODER.lock();
+    loop {
+        // Popped one at a time so that the interrupt handler is never held
+        // off for longer than a single pop.
+        let scancode = match SCANCODE_QUEUE.lock().pop() {
+            Some(scancode) => scancode,
+            None => break,
+        };
+        decoder.add_scancode(scancode, |event| super::publish(InputEvent::Key(event)));
+    }
+}
+
+/// Returns the virtual terminal to show for a pressed key, if it is one of
+/// Alt+F1 to Alt+F4.
+fn terminal_key(key: &KeyEvent) -> Option<usize> {
+    if !key.modifiers.alt {
+        return None;
+    }
+    let keys = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+    keys.iter().position(|&code| code == key.code)
+}
+
+/// Prints the characters typed on the keyboard to the
+/// [shell terminal](vt::SHELL). Ctrl+C sends [Signal::Interrupt] to the
+/// foreground process instead, Shift+PageUp and Shift+PageDown scroll the
+/// shown terminal through its history, and Alt+F1 to Alt+F4 switch terminals.
+pub async fn print_keypresses() {
+    let mut events = super::events();
+
+    while let Some(event) = events.next().await {
+        match event {
+            InputEvent::Key(key)
+                if key.is_pressed() && key.modifiers.ctrl && key.code == KeyCode::C =>
+            {
+                if let Some(pid) = process::foreground() {
+                    vt::print(vt::SHELL, format_args!("^C"));
+                    let _ = process::kill(pid, Signal::Interrupt);
+                }
+            }
+            InputEvent::Key(key)
+                if key.is_pressed() && key.modifiers.shift && key.code == KeyCode::PageUp =>
+            {
+                vt::with_active(|writer| writer.page_up())
+            }
+            InputEvent::Key(key)
+                if key.is_pressed() && key.modifiers.shift && key.code == KeyCode::PageDown =>
+            {
+                vt::with_active(|writer| writer.page_down())
+            }
+            InputEvent::Key(key) if key.is_pressed() && !key.is_modifier() => {
+                match (terminal_key(&key), key.unicode) {
+                    (Some(tty), _) => _ = vt::switch(tty),
+                    (None, Some(character)) => vt::print(vt::SHELL, format_args!("{}", character)),
+                    (None, None) => vt::print(vt::SHELL, format_args!("{:?}", key.code)),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn decode(scancodes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = Decoder::new();
+        let mut events = Vec::new();
+        for &scancode in scancodes {
+            decoder.add_scancode(scancode, |event| events.push(event));
+        }
+        events
+    }
+
+    #[test_case]
+    fn test_press_and_release() {
+        // 'a' down, 'a' up.
+        let events = decode(&[0x1e, 0x9e]);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].code, KeyCode::A);
+        assert_eq!(events[0].state, KeyState::Pressed);
+        assert_eq!(events[0].unicode, Some('a'));
+        assert_eq!(events[1].state, KeyState::Released);
+        assert_eq!(events[1].unicode, None);
+    }
+
+    #[test_case]
+    fn test_modifiers_are_tracked() {
+        // Left shift down, 'a' down, left shift up, 'a' up.
+        let events = decode(&[0x2a, 0x1e, 0xaa, 0x9e]);
+
+        assert_eq!(events.len(), 4);
+        assert!(events[0].modifiers.shift);
+        assert_eq!(events[1].unicode, Some('A'));
+        assert!(events[1].modifiers.shift);
+        assert!(!events[2].modifiers.shift);
+        assert!(!events[3].modifiers.shift);
+    }
+
+    #[test_case]
+    fn test_modifier_held_on_either_side() {
+        // Left ctrl down, right ctrl down, left ctrl up, 'c' down, right
+        // ctrl up.
+        let events = decode(&[0x1d, 0xe0, 0x1d, 0x9d, 0x2e, 0xe0, 0x9d]);
+
+        assert_eq!(events.len(), 5);
+        assert!(events[2].modifiers.ctrl);
+        assert_eq!(events[3].code, KeyCode::C);
+        assert!(events[3].modifiers.ctrl);
+        assert!(!events[4].modifiers.ctrl);
+    }
+}
@@ -0,0 +1,266 @@
+//! Typed input events shared between any number of readers.
+//!
+//! Input drivers decode their device's raw data into [InputEvent]s and
+//! [publish] them. Every [EventStream] obtained from [events] receives its
+//! own copy of each event published after it was created, so a shell and a
+//! debugging task can both watch the keyboard.
+//!
+//! Events are published outside of interrupt context, from the drivers'
+//! deferred work, and queued per stream. A stream which falls behind by more
+//! than [STREAM_CAPACITY] events loses the newest ones.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use spin::Mutex;
+
+pub mod keyboard;
+pub mod mouse;
+
+pub use pc_keyboard::KeyCode;
+
+/// Maximum number of events buffered for each [EventStream].
+pub const STREAM_CAPACITY: usize = 64;
+
+/// An event produced by an input device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InputEvent {
+    Key(KeyEvent),
+    Mouse(MouseEvent),
+}
+
+/// Whether a key went down or came up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+/// Modifier keys held, or lock keys toggled on, when a key event occurred.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+/// A key press or release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    /// The character the key produces under the current layout and
+    /// modifiers. Only set for presses of keys which produce one.
+    pub unicode: Option<char>,
+}
+
+impl KeyEvent {
+    pub fn is_pressed(&self) -> bool {
+        self.state == KeyState::Pressed
+    }
+
+    /// Returns `true` if the key is one of those tracked in [Modifiers].
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.code,
+            KeyCode::ShiftLeft
+                | KeyCode::ShiftRight
+                | KeyCode::ControlLeft
+                | KeyCode::ControlRight
+                | KeyCode::AltLeft
+                | KeyCode::AltRight
+                | KeyCode::CapsLock
+                | KeyCode::NumpadLock
+        )
+    }
+}
+
+/// Mouse buttons held when a mouse event occurred.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// A movement of the mouse or a change of its buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Horizontal movement, positive to the right.
+    pub dx: i16,
+    /// Vertical movement, positive downwards like screen coordinates.
+    pub dy: i16,
+    pub buttons: MouseButtons,
+}
+
+/// A fixed capacity FIFO of bytes received from a device, filled by its
+/// interrupt handler and drained by deferred work.
+///
+/// The queue is statically allocated so that the interrupt handler never
+/// touches the heap.
+pub(crate) struct ByteQueue<const N: usize> {
+    buffer: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteQueue<N> {
+    pub(crate) const fn new() -> Self {
+        ByteQueue {
+            buffer: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends a byte, returning `false` if the queue is full.
+    pub(crate) fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+
+        self.buffer[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Removes the oldest byte.
+    pub(crate) fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.buffer[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// The queue of a single [EventStream].
+struct Subscriber {
+    events: ArrayQueue<InputEvent>,
+    waker: AtomicWaker,
+    dropped: AtomicUsize,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Weak<Subscriber>>> = Mutex::new(Vec::new());
+
+/// Returns a stream of every input event published from now on.
+pub fn events() -> EventStream {
+    let subscriber = Arc::new(Subscriber {
+        events: ArrayQueue::new(STREAM_CAPACITY),
+        waker: AtomicWaker::new(),
+        dropped: AtomicUsize::new(0),
+    });
+    SUBSCRIBERS.lock().push(Arc::downgrade(&subscriber));
+    EventStream { subscriber }
+}
+
+/// Delivers `event` to every [EventStream].
+///
+/// Must not be called from interrupt context.
+pub(crate) fn publish(event: InputEvent) {
+    let mut subscribers = SUBSCRIBERS.lock();
+    subscribers.retain(|subscriber| match subscriber.upgrade() {
+        Some(subscriber) => {
+            if subscriber.events.push(event).is_err() {
+                subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            subscriber.waker.wake();
+            true
+        }
+        None => false,
+    });
+}
+
+/// A stream of [InputEvent]s returned by [events].
+pub struct EventStream {
+    subscriber: Arc<Subscriber>,
+}
+
+impl EventStream {
+    /// Returns the number of events lost because this stream was full, and
+    /// resets the count.
+    pub fn take_dropped(&self) -> usize {
+        self.subscriber.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Stream for EventStream {
+    type Item = InputEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<InputEvent>> {
+        if let Some(event) = self.subscriber.events.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        self.subscriber.waker.register(cx.waker());
+        match self.subscriber.events.pop() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::{task::noop_waker, StreamExt};
+
+    fn key(code: KeyCode) -> InputEvent {
+        InputEvent::Key(KeyEvent {
+            code,
+            state: KeyState::Pressed,
+            modifiers: Modifiers::default(),
+            unicode: None,
+        })
+    }
+
+    fn next(stream: &mut EventStream) -> Option<InputEvent> {
+        let waker = noop_waker();
+        match stream.poll_next_unpin(&mut Context::from_waker(&waker)) {
+            Poll::Ready(event) => event,
+            Poll::Pending => None,
+        }
+    }
+
+    #[test_case]
+    fn test_every_stream_receives_events() {
+        let mut first = events();
+        let mut second = events();
+        publish(key(KeyCode::A));
+
+        assert_eq!(next(&mut first), Some(key(KeyCode::A)));
+        assert_eq!(next(&mut second), Some(key(KeyCode::A)));
+        assert_eq!(next(&mut first), None);
+    }
+
+    #[test_case]
+    fn test_full_stream_counts_dropped_events() {
+        let mut stream = events();
+        for _ in 0..STREAM_CAPACITY + 2 {
+            publish(key(KeyCode::B));
+        }
+
+        assert_eq!(stream.take_dropped(), 2);
+        assert_eq!(stream.take_dropped(), 0);
+        for _ in 0..STREAM_CAPACITY {
+            assert!(next(&mut stream).is_some());
+        }
+        assert_eq!(next(&mut stream), None);
+    }
+}
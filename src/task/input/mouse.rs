@@ -0,0 +1,258 @@
+//! PS/2 mouse driver.
+//!
+//! The mouse sits on the second port of the PS/2 controller and raises
+//! IRQ12 for every byte it sends. As with the keyboard, the interrupt
+//! handler only queues the bytes. Deferred work then assembles them into
+//! three byte packets, decodes those into [MouseEvent]s and publishes them
+//! to the input [events](super::events).
+//!
+//! See: https://wiki.osdev.org/PS/2_Mouse
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use log::warn;
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use super::{ByteQueue, InputEvent, MouseButtons, MouseEvent};
+use crate::{
+    interrupts::{
+        self,
+        deferred::{self, Work},
+    },
+    sync::IrqSpinlock,
+};
+
+const DATA_PORT: u16 = 0x60;
+/// Reads the controller's status, and takes its commands when written.
+const COMMAND_PORT: u16 = 0x64;
+
+/// Bit of the status set when there is a byte to read from the data port.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Bit of the status set while the controller has not taken the last byte
+/// written.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_ENABLE_PORT2: u8 = 0xa8;
+/// Sends the next byte written to the data port to the mouse.
+const CONTROLLER_WRITE_PORT2: u8 = 0xd4;
+
+/// Bit of the configuration enabling IRQ12.
+const CONFIG_PORT2_INTERRUPT: u8 = 1 << 1;
+/// Bit of the configuration stopping the mouse's clock.
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_ACK: u8 = 0xfa;
+
+const IRQ: u8 = 12;
+
+/// Number of times the status is polled before giving up on the controller.
+const POLL_LIMIT: usize = 100_000;
+
+// Bits of the first byte of a packet.
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+/// Always set, which lets a decoder find the start of a packet.
+const PACKET_ALWAYS_SET: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Maximum number of bytes buffered between the interrupt handler and the
+/// deferred work decoding them.
+const BYTE_QUEUE_CAPACITY: usize = 192;
+
+static BYTE_QUEUE: IrqSpinlock<ByteQueue<BYTE_QUEUE_CAPACITY>> = IrqSpinlock::new(ByteQueue::new());
+
+/// Deferred work raised by the interrupt handler when new bytes are
+/// available.
+static MOUSE_WORK: OnceCell<Work> = OnceCell::uninit();
+
+/// Number of bytes dropped by the interrupt handler since the deferred work
+/// last ran.
+static DROPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Turns the bytes sent by the mouse into [MouseEvent]s.
+struct Decoder {
+    packet: [u8; 3],
+    len: usize,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Decoder {
+            packet: [0; 3],
+            len: 0,
+        }
+    }
+
+    /// Feeds a byte to the decoder, returning the event of the packet it
+    /// completes, if any.
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // A byte lost along the way leaves the decoder in the middle of a
+        // packet; skip bytes until one can start a packet.
+        if self.len == 0 && byte & PACKET_ALWAYS_SET == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet.len() {
+            return None;
+        }
+        self.len = 0;
+        Some(decode_packet(self.packet))
+    }
+}
+
+/// Decodes a complete packet. Movements which overflowed are dropped.
+fn decode_packet([flags, x, y]: [u8; 3]) -> MouseEvent {
+    let delta = |value: u8, sign: u8, overflow: u8| {
+        if flags & overflow != 0 {
+            0
+        } else if flags & sign != 0 {
+            value as i16 - 0x100
+        } else {
+            value as i16
+        }
+    };
+    MouseEvent {
+        dx: delta(x, PACKET_X_SIGN, PACKET_X_OVERFLOW),
+        // The mouse reports upward movement as positive.
+        dy: -delta(y, PACKET_Y_SIGN, PACKET_Y_OVERFLOW),
+        buttons: MouseButtons {
+            left: flags & PACKET_LEFT != 0,
+            right: flags & PACKET_RIGHT != 0,
+            middle: flags & PACKET_MIDDLE != 0,
+        },
+    }
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+/// Waits until the controller has a byte for us and reads it.
+fn read_data() -> Option<u8> {
+    let mut status = Port::<u8>::new(COMMAND_PORT);
+    (0..POLL_LIMIT)
+        .find(|_| unsafe { status.read() } & STATUS_OUTPUT_FULL != 0)
+        .map(|_| unsafe { Port::new(DATA_PORT).read() })
+}
+
+/// Waits until the controller can take a byte and writes it to `port`.
+fn write(port: u16, value: u8) -> Option<()> {
+    let mut status = Port::<u8>::new(COMMAND_PORT);
+    (0..POLL_LIMIT).find(|_| unsafe { status.read() } & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::new(port).write(value) };
+    Some(())
+}
+
+/// Sends `command` to the mouse and waits for it to be acknowledged.
+fn send_to_mouse(command: u8) -> Option<()> {
+    write(COMMAND_PORT, CONTROLLER_WRITE_PORT2)?;
+    write(DATA_PORT, command)?;
+    (read_data()? == MOUSE_ACK).then_some(())
+}
+
+/// Enables the second PS/2 port and the mouse on it, then routes its bytes
+/// through IRQ12. Returns `false` if there is no mouse.
+pub fn init() -> bool {
+    MOUSE_WORK.init_once(|| deferred::register(bytes_ready));
+
+    // The keyboard's interrupt handler must not take the replies.
+    let found = without_interrupts(|| {
+        write(COMMAND_PORT, CONTROLLER_ENABLE_PORT2)?;
+        write(COMMAND_PORT, CONTROLLER_READ_CONFIG)?;
+        let config = read_data()?;
+        send_to_mouse(MOUSE_SET_DEFAULTS)?;
+        send_to_mouse(MOUSE_ENABLE_REPORTING)?;
+        write(COMMAND_PORT, CONTROLLER_WRITE_CONFIG)?;
+        write(
+            DATA_PORT,
+            (config | CONFIG_PORT2_INTERRUPT) & !CONFIG_PORT2_CLOCK_DISABLED,
+        )
+    })
+    .is_some();
+    if found {
+        interrupts::add_irq_handler(IRQ, handle_interrupt);
+    }
+    found
+}
+
+/// Handler for IRQ12.
+///
+/// This function runs in interrupt context and must not block or print; the
+/// bytes are decoded and errors are reported by deferred work instead.
+fn handle_interrupt() {
+    let byte: u8 = unsafe { Port::new(DATA_PORT).read() };
+    if !BYTE_QUEUE.lock().push(byte) {
+        DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if let Ok(work) = MOUSE_WORK.try_get() {
+        work.raise();
+    }
+}
+
+/// Deferred half of the interrupt handler.
+fn bytes_ready() {
+    let dropped = DROPPED_BYTES.swap(0, Ordering::Relaxed);
+    if dropped != 0 {
+        warn!("mouse queue full; dropped {} byte(s)", dropped);
+    }
+
+    let mut decoder = DECODER.lock();
+    loop {
+        // Popped one at a time so that the interrupt handler is never held
+        // off for longer than a single pop.
+        let byte = match BYTE_QUEUE.lock().pop() {
+            Some(byte) => byte,
+            None => break,
+        };
+        if let Some(event) = decoder.add_byte(byte) {
+            super::publish(InputEvent::Mouse(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn decode(bytes: &[u8]) -> Vec<MouseEvent> {
+        let mut decoder = Decoder::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.add_byte(byte))
+            .collect()
+    }
+
+    #[test_case]
+    fn test_movement_and_buttons() {
+        // Left button held, 5 to the right and 3 up; then 2 to the left and
+        // 4 down with the right button held.
+        let events = decode(&[0x09, 5, 3, 0x3a, 0xfe, 0xfc]);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].dx, events[0].dy), (5, -3));
+        assert!(events[0].buttons.left && !events[0].buttons.right);
+        assert_eq!((events[1].dx, events[1].dy), (-2, 4));
+        assert!(events[1].buttons.right && !events[1].buttons.left);
+    }
+
+    #[test_case]
+    fn test_resynchronizes() {
+        // A stray byte without the always set bit is skipped, and
+        // overflowed movements are dropped.
+        let events = decode(&[0x00, 0x48, 0xff, 1]);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].dx, events[0].dy), (0, -1));
+    }
+}
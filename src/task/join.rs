@@ -0,0 +1,210 @@
+//! Handles for awaiting the output of spawned tasks.
+//!
+//! [joinable] wraps a future so that its output is stored in state shared
+//! with a [JoinHandle] rather than discarded. The handle is itself a future
+//! which resolves once the task completes. If the task is dropped before it
+//! completes, for example because its executor was dropped or the task was
+//! aborted with [JoinHandle::abort], the handle resolves with
+//! [JoinError::Cancelled].
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::{self, Future},
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Error returned by a [JoinHandle] when its task did not run to completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted or dropped before it completed.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => f.write_str("task was cancelled"),
+        }
+    }
+}
+
+/// State shared between a task and its [JoinHandle].
+struct JoinState<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    /// Wakes the task awaiting the [JoinHandle].
+    waker: AtomicWaker,
+    /// Set by [JoinHandle::abort].
+    aborted: AtomicBool,
+    /// Wakes the task itself so that it observes `aborted`.
+    task_waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, result: Result<T, JoinError>) {
+        *self.result.lock() = Some(result);
+        self.waker.wake();
+    }
+}
+
+/// Owned by the task side. Reports cancellation if dropped before the task
+/// completes.
+struct Completion<T> {
+    state: Option<Arc<JoinState<T>>>,
+}
+
+impl<T> Completion<T> {
+    fn complete(mut self, output: T) {
+        if let Some(state) = self.state.take() {
+            state.finish(Ok(output));
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.finish(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// A future which resolves with the output of a spawned task.
+///
+/// Dropping the handle detaches it from the task; the task keeps running and
+/// its output is discarded. Use [JoinHandle::abort] to stop the task instead.
+#[must_use = "dropping a JoinHandle detaches it from its task"]
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns `true` if the task has finished, either by completing or by
+    /// being cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.state.result.lock().is_some()
+    }
+
+    /// Requests that the task be cancelled.
+    ///
+    /// The task is woken and, the next time the executor polls it, its future
+    /// is dropped without being polled again and the handle resolves with
+    /// [JoinError::Cancelled]. Has no effect if the task already finished.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task_waker.wake();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.state.result.lock().take() {
+            return Poll::Ready(result);
+        }
+
+        self.state.waker.register(cx.waker());
+        match self.state.result.lock().take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Wraps `future` so that its output is delivered to the returned
+/// [JoinHandle]. The wrapped future is suitable for [super::Task::new].
+pub fn joinable<F>(future: F) -> (impl Future<Output = ()> + 'static, JoinHandle<F::Output>)
+where
+    F: Future + 'static,
+{
+    let state = Arc::new(JoinState {
+        result: Mutex::new(None),
+        waker: AtomicWaker::new(),
+        aborted: AtomicBool::new(false),
+        task_waker: AtomicWaker::new(),
+    });
+    let completion = Completion {
+        state: Some(state.clone()),
+    };
+
+    let task_state = state.clone();
+    let task = async move {
+        let mut future = pin!(future);
+        let output = future::poll_fn(|cx| {
+            task_state.task_waker.register(cx.waker());
+            if task_state.aborted.load(Ordering::Acquire) {
+                return Poll::Ready(None);
+            }
+            future.as_mut().poll(cx).map(Some)
+        })
+        .await;
+
+        // Dropping `completion` without completing it reports cancellation.
+        if let Some(output) = output {
+            completion.complete(output);
+        }
+    };
+
+    (task, JoinHandle { state })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{executor::Executor, Task};
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use futures_util::task::noop_waker;
+
+    #[test_case]
+    fn test_join_handle_returns_output() {
+        static RESULT: AtomicU32 = AtomicU32::new(0);
+
+        let mut executor = Executor::new();
+        let handle = executor.spawn_with_handle(async { 21 * 2 });
+        executor.spawn(Task::new(async move {
+            RESULT.store(handle.await.unwrap(), Ordering::Relaxed);
+        }));
+        executor.run_until_idle();
+
+        assert_eq!(RESULT.load(Ordering::Relaxed), 42);
+    }
+
+    #[test_case]
+    fn test_join_handle_reports_cancellation() {
+        let (task, mut handle) = joinable(core::future::pending::<()>());
+        assert!(!handle.is_finished());
+        drop(task);
+        assert!(handle.is_finished());
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(
+            Pin::new(&mut handle).poll(&mut cx),
+            Poll::Ready(Err(JoinError::Cancelled))
+        );
+    }
+
+    #[test_case]
+    fn test_abort_drops_task() {
+        static RESULT: Mutex<Option<Result<(), JoinError>>> = Mutex::new(None);
+
+        let mut executor = Executor::new();
+        let handle = executor.spawn_with_handle(core::future::pending::<()>());
+        executor.run_until_idle();
+        assert!(!handle.is_finished());
+
+        handle.abort();
+        executor.spawn(Task::new(async move {
+            *RESULT.lock() = Some(handle.await);
+        }));
+        executor.run_until_idle();
+
+        assert_eq!(*RESULT.lock(), Some(Err(JoinError::Cancelled)));
+    }
+}
@@ -1,38 +1,125 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, string::String, vec::Vec};
 use core::{
+    fmt,
     future::Future,
     pin::Pin,
     sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
+pub mod blocking;
+pub mod cancel;
 pub mod executor;
-pub mod keyboard;
+pub mod future;
+pub mod idle;
+pub mod input;
+pub mod join;
+pub mod preempt;
+pub mod runtime;
 pub mod simple_executor;
+pub mod status_bar;
+pub mod sync;
+pub mod thread;
+pub mod timer;
+pub mod watchdog;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub use blocking::spawn_blocking;
+pub use cancel::CancellationToken;
+pub use future::yield_now;
+pub use join::{JoinError, JoinHandle};
+
+/// Returns poll and wake statistics for every live task, busiest first.
+pub fn metrics() -> Vec<executor::TaskSummary> {
+    let mut tasks = executor::task_list();
+    tasks.sort_by_key(|task| core::cmp::Reverse(task.poll_time));
+    tasks
+}
+
+/// Uniquely identifies a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Scheduling priority of a task.
+///
+/// The executor always polls ready tasks of a higher priority first, except
+/// that lower priority tasks are guaranteed to be polled periodically so they
+/// can't be starved indefinitely.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    High = 0,
+    #[default]
+    Normal = 1,
+    Low = 2,
+}
+
+impl Priority {
+    /// Number of priority levels.
+    pub const COUNT: usize = 3;
+
+    /// All priorities from highest to lowest.
+    pub const ALL: [Priority; Priority::COUNT] = [Priority::High, Priority::Normal, Priority::Low];
 }
 
 pub struct Task {
     id: TaskId,
+    priority: Priority,
+    name: Option<String>,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
+    /// Creates a task with [Priority::Normal].
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_priority(future, Priority::Normal)
+    }
+
+    /// Creates a task with the given priority.
+    pub fn with_priority(future: impl Future<Output = ()> + 'static, priority: Priority) -> Task {
         Task {
             id: TaskId::new(),
+            priority,
+            name: None,
             future: Box::pin(future),
         }
     }
 
+    /// Names the task, for diagnostics such as [executor::task_list].
+    pub fn named(mut self, name: impl Into<String>) -> Task {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the priority of this task.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -0,0 +1,108 @@
+//! Timer driven preemption points for async tasks.
+//!
+//! Futures are polled cooperatively, so a task which never awaits would
+//! monopolize the executor. To bound how long a task can run, the executor
+//! starts a time slice before every poll and the timer interrupt flags the
+//! slice as expired once [TIME_SLICE_TICKS] have elapsed. Long running tasks
+//! call [checkpoint] in their loops; it completes immediately while the slice
+//! lasts and yields back to the executor once it has expired, letting other
+//! tasks run before the task is polled again.
+//!
+//! ```no_run
+//! # async fn f(items: &[u32]) {
+//! for item in items {
+//!     // ... expensive work ...
+//!     toyos::task::preempt::checkpoint().await;
+//! }
+//! # }
+//! ```
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use crate::time;
+
+/// Number of timer ticks a task may run for before it is asked to yield.
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+/// Tick at which the current time slice started.
+static SLICE_START: AtomicU64 = AtomicU64::new(0);
+
+/// Set by the timer interrupt once the current time slice has expired.
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Number of time slices which expired before the task yielded.
+static EXPIRED_SLICES: AtomicU64 = AtomicU64::new(0);
+
+/// Starts a new time slice. Called by the executor before polling a task.
+pub(crate) fn start_slice() {
+    SLICE_START.store(time::ticks(), Ordering::Relaxed);
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+}
+
+/// Checks whether the current time slice has expired. Called by the timer
+/// interrupt handler.
+pub(crate) fn tick() {
+    let elapsed = time::ticks().wrapping_sub(SLICE_START.load(Ordering::Relaxed));
+    if elapsed >= TIME_SLICE_TICKS && !NEED_RESCHED.swap(true, Ordering::Relaxed) {
+        EXPIRED_SLICES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` if the running task has exhausted its time slice and should
+/// yield.
+#[inline]
+pub fn need_resched() -> bool {
+    NEED_RESCHED.load(Ordering::Relaxed)
+}
+
+/// Returns the number of time slices which have expired since boot.
+pub fn expired_slices() -> u64 {
+    EXPIRED_SLICES.load(Ordering::Relaxed)
+}
+
+/// Returns a future which yields to the executor if the running task has
+/// exhausted its time slice, and completes immediately otherwise.
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint { yielded: false }
+}
+
+/// Future returned by [checkpoint].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Checkpoint {
+    yielded: bool,
+}
+
+impl Future for Checkpoint {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded || !need_resched() {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_slice_expires() {
+        start_slice();
+        assert!(!need_resched());
+        while !need_resched() {
+            x86_64::instructions::hlt();
+        }
+        start_slice();
+        assert!(!need_resched());
+    }
+}
@@ -0,0 +1,256 @@
+//! A multi-core runtime running one executor per CPU with work stealing.
+//!
+//! Unlike [Executor](super::executor::Executor), whose tasks never leave the
+//! CPU running it, tasks spawned here must be [Send]: every CPU which calls
+//! [run] polls tasks from its own local queue and, once that is empty, steals
+//! half of another CPU's queue. A woken task is queued on the CPU which last
+//! polled it, keeping its data warm in that CPU's cache. If that CPU (or any
+//! other CPU running the runtime) is halted for lack of work, it is woken
+//! with a reschedule IPI.
+//!
+//! Application processors enter [run] once started by [crate::smp::init].
+
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+
+use super::{idle, join, JoinHandle};
+use crate::{
+    interrupts::apic,
+    percpu::{self, PerCpu, MAX_CPUS},
+};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use spin::Mutex;
+
+/// Capacity of each CPU's local queue. Tasks overflow into a shared queue.
+const LOCAL_QUEUE_CAPACITY: usize = 256;
+
+/// A spawned task, shared between the queues and its wakers.
+struct TaskCell {
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// Set while the task is in a queue, so that repeated wake-ups queue it
+    /// only once.
+    scheduled: AtomicBool,
+    /// The CPU which last polled the task.
+    home: AtomicUsize,
+}
+
+impl Wake for TaskCell {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            schedule(self.clone(), self.home.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// A CPU's queue of runnable tasks along with statistics about it.
+struct LocalQueue {
+    queue: ArrayQueue<Arc<TaskCell>>,
+    polls: AtomicU64,
+    steals: AtomicU64,
+}
+
+static LOCAL_QUEUES: PerCpu<LocalQueue> = PerCpu::new(|_| LocalQueue {
+    queue: ArrayQueue::new(LOCAL_QUEUE_CAPACITY),
+    polls: AtomicU64::new(0),
+    steals: AtomicU64::new(0),
+});
+
+/// Tasks which did not fit into a local queue.
+static OVERFLOW: SegQueue<Arc<TaskCell>> = SegQueue::new();
+
+/// Bitmask of CPUs running the runtime.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Bitmask of CPUs halted in [run] waiting for work.
+static IDLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Queues a task on `cpu` and wakes a CPU to run it if necessary.
+fn schedule(task: Arc<TaskCell>, cpu: usize) {
+    if let Err(task) = LOCAL_QUEUES.get_for(cpu).queue.push(task) {
+        OVERFLOW.push(task);
+    }
+    notify(cpu);
+}
+
+/// Wakes `cpu` if it is idle, or otherwise any idle CPU, so that newly queued
+/// work is picked up.
+fn notify(cpu: usize) {
+    let idle = IDLE.load(Ordering::SeqCst);
+    if idle == 0 || !apic::is_enabled() {
+        return;
+    }
+
+    let target = if idle & (1 << cpu) != 0 {
+        cpu
+    } else {
+        idle.trailing_zeros() as usize
+    };
+    if target != percpu::cpu_id() {
+        apic::send_reschedule(target);
+    }
+}
+
+/// Spawns a task onto the calling CPU's queue, returning a handle which
+/// resolves with its output.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (future, handle) = join::joinable(future);
+    let cpu = percpu::cpu_id();
+    let task = Arc::new(TaskCell {
+        future: Mutex::new(Some(Box::pin(future))),
+        scheduled: AtomicBool::new(true),
+        home: AtomicUsize::new(cpu),
+    });
+    schedule(task, cpu);
+    handle
+}
+
+/// Moves up to half of the tasks in `victim`'s queue into `thief`'s queue,
+/// returning one of them to run immediately.
+fn steal(victim: &LocalQueue, thief: &LocalQueue) -> Option<Arc<TaskCell>> {
+    let first = victim.queue.pop()?;
+    let count = victim.queue.len() / 2;
+    for _ in 0..count {
+        match victim.queue.pop() {
+            Some(task) => {
+                if let Err(task) = thief.queue.push(task) {
+                    OVERFLOW.push(task);
+                }
+            }
+            None => break,
+        }
+    }
+    thief.steals.fetch_add(1, Ordering::Relaxed);
+    Some(first)
+}
+
+/// Finds the next task for `cpu` to run: from its own queue, then the
+/// overflow queue, then by stealing from the other CPUs in turn.
+fn next_task(cpu: usize) -> Option<Arc<TaskCell>> {
+    let local = LOCAL_QUEUES.get_for(cpu);
+    if let Some(task) = local.queue.pop().or_else(|| OVERFLOW.pop()) {
+        return Some(task);
+    }
+
+    (1..MAX_CPUS)
+        .map(|offset| (cpu + offset) % MAX_CPUS)
+        .find_map(|victim| steal(LOCAL_QUEUES.get_for(victim), local))
+}
+
+fn has_work() -> bool {
+    !OVERFLOW.is_empty() || LOCAL_QUEUES.iter().any(|local| !local.queue.is_empty())
+}
+
+fn poll_task(cpu: usize, task: Arc<TaskCell>) {
+    task.home.store(cpu, Ordering::Relaxed);
+    // Cleared before polling so that a wake-up during the poll queues the
+    // task again.
+    task.scheduled.store(false, Ordering::Release);
+
+    let waker = Waker::from(task.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = task.future.lock();
+    if let Some(inner) = future.as_mut() {
+        LOCAL_QUEUES
+            .get_for(cpu)
+            .polls
+            .fetch_add(1, Ordering::Relaxed);
+        if inner.as_mut().poll(&mut context).is_ready() {
+            *future = None;
+        }
+    }
+}
+
+/// Runs the calling CPU's executor forever, halting whenever there is no
+/// work to run or steal.
+pub fn run() -> ! {
+    let cpu = percpu::cpu_id();
+    let bit = 1 << cpu;
+    RUNNING.fetch_or(bit, Ordering::SeqCst);
+
+    loop {
+        if let Some(task) = next_task(cpu) {
+            poll_task(cpu, task);
+            continue;
+        }
+
+        // Publish that this CPU is idle before checking for work one last
+        // time; `notify` checks in the opposite order, so either the work is
+        // seen here or the IPI is sent.
+        IDLE.fetch_or(bit, Ordering::SeqCst);
+        idle::sleep(|| !has_work());
+        IDLE.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Returns a bitmask of the CPUs running the runtime.
+pub fn running_cpus() -> usize {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// Per-CPU counters of the runtime.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuStats {
+    /// Tasks polled by the CPU.
+    pub polls: u64,
+    /// Successful steals from other CPUs' queues.
+    pub steals: u64,
+}
+
+/// Returns the counters of the given CPU.
+pub fn stats(cpu: usize) -> CpuStats {
+    let local = LOCAL_QUEUES.get_for(cpu);
+    CpuStats {
+        polls: local.polls.load(Ordering::Relaxed),
+        steals: local.steals.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queue() -> LocalQueue {
+        LocalQueue {
+            queue: ArrayQueue::new(LOCAL_QUEUE_CAPACITY),
+            polls: AtomicU64::new(0),
+            steals: AtomicU64::new(0),
+        }
+    }
+
+    fn task() -> Arc<TaskCell> {
+        Arc::new(TaskCell {
+            future: Mutex::new(None),
+            scheduled: AtomicBool::new(true),
+            home: AtomicUsize::new(0),
+        })
+    }
+
+    #[test_case]
+    fn test_steal_takes_half() {
+        let victim = queue();
+        let thief = queue();
+        for _ in 0..9 {
+            victim.queue.push(task()).ok().unwrap();
+        }
+
+        assert!(steal(&victim, &thief).is_some());
+        assert_eq!(victim.queue.len(), 4);
+        assert_eq!(thief.queue.len(), 4);
+        assert_eq!(thief.steals.load(Ordering::Relaxed), 1);
+
+        assert!(steal(&queue(), &thief).is_none());
+    }
+}
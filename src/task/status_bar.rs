@@ -0,0 +1,51 @@
+//! A status bar at the top of the screen, showing the uptime, the heap in
+//! use and the number of tasks.
+
+use alloc::{format, string::String};
+
+use super::{executor, timer, Priority, Task};
+use crate::{
+    allocator,
+    time::{self, Duration},
+    vga,
+};
+
+/// Returns a task which updates the status bar when first polled and then
+/// once every `period`.
+pub fn start(period: Duration) -> Task {
+    let future = async move {
+        loop {
+            vga::set_status_bar(&text());
+            timer::sleep(period).await;
+        }
+    };
+    Task::with_priority(future, Priority::Low).named("status bar")
+}
+
+/// Returns the text of the status bar.
+fn text() -> String {
+    let uptime = time::uptime().as_secs();
+    let heap = allocator::usage();
+    format!(
+        " up {}:{:02}:{:02} | heap {}/{} KiB | {} tasks",
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60,
+        heap.used / 1024,
+        (heap.used + heap.free) / 1024,
+        executor::task_count(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_text() {
+        let text = text();
+        assert!(text.starts_with(" up "));
+        assert!(text.ends_with(" tasks"));
+        assert!(text.len() <= 80);
+    }
+}
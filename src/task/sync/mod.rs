@@ -0,0 +1,16 @@
+//! Synchronization primitives for async tasks.
+//!
+//! Unlike the spinning locks in [crate::sync], these primitives suspend the
+//! waiting task and wake it once it can make progress, so contention never
+//! blocks the executor.
+
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod semaphore;
+mod wait_queue;
+
+pub use mutex::{AsyncMutex, AsyncMutexGuard};
+pub use notify::Notify;
+pub use semaphore::{Semaphore, SemaphorePermit};
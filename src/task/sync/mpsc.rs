@@ -0,0 +1,234 @@
+//! A bounded multi-producer, single-consumer channel.
+//!
+//! [Sender::try_send] never blocks or allocates, so it may be called from
+//! interrupt handlers to feed an async consumer awaiting [Receiver::recv].
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    /// Wakes the receiver when a value is sent or the last sender is dropped.
+    waker: AtomicWaker,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+}
+
+/// Creates a channel able to buffer up to `capacity` values.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Error returned by [Sender::try_send], handing back the unsent value.
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel's buffer is full.
+    Full(T),
+    /// The [Receiver] has been dropped.
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("channel full"),
+            TrySendError::Closed(_) => f.write_str("channel closed"),
+        }
+    }
+}
+
+/// The sending half of a channel. Cloning it adds another producer.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends a value without waiting.
+    ///
+    /// This function neither blocks nor allocates and is safe to call from
+    /// interrupt context.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+
+        self.shared.queue.push(value).map_err(TrySendError::Full)?;
+        self.shared.waker.wake();
+        Ok(())
+    }
+
+    /// Returns `true` if the [Receiver] has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.waker.wake();
+        }
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns a future which resolves with the next value, or `None` once
+    /// the channel is empty and every [Sender] has been dropped.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Receives a value if one is immediately available.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.queue.pop()
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(value) = self.shared.queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.shared.waker.register(cx.waker());
+        match self.shared.queue.pop() {
+            Some(value) => {
+                self.shared.waker.take();
+                Poll::Ready(Some(value))
+            }
+
+            // Popped again in case the last sender sent a value just before
+            // being dropped.
+            None if self.shared.senders.load(Ordering::Acquire) == 0 => {
+                Poll::Ready(self.shared.queue.pop())
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+/// Future returned by [Receiver::recv].
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    #[test_case]
+    fn test_values_arrive_in_order() {
+        static RECEIVED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+        let (sender, mut receiver) = channel(4);
+        let mut executor = Executor::new();
+        let consumer = executor.spawn_with_handle(async move {
+            while let Some(value) = receiver.recv().await {
+                RECEIVED.lock().push(value);
+            }
+        });
+        executor.run_until_idle();
+
+        let second = sender.clone();
+        sender.try_send(1).unwrap();
+        second.try_send(2).unwrap();
+        executor.run_until_idle();
+        assert_eq!(*RECEIVED.lock(), [1, 2]);
+
+        drop(sender);
+        drop(second);
+        assert!(!consumer.is_finished());
+        executor.run_until_idle();
+        assert!(consumer.is_finished());
+    }
+
+    #[test_case]
+    fn test_try_send_errors() {
+        let (sender, receiver) = channel(1);
+        sender.try_send(1).unwrap();
+        assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+    }
+}
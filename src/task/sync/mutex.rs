@@ -0,0 +1,238 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use spin::Mutex;
+
+use super::wait_queue::WaitQueue;
+
+/// A mutual exclusion lock for async tasks.
+///
+/// [AsyncMutex::lock] returns a future which resolves once the lock has been
+/// acquired. Waiting tasks are queued in FIFO order and woken one at a time as
+/// the lock is released, and a new [lock](AsyncMutex::lock) does not take the
+/// lock ahead of them.
+pub struct AsyncMutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: Mutex<WaitQueue>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(value: T) -> Self {
+        AsyncMutex {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(WaitQueue::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    /// Returns a future which resolves with a guard once the lock has been
+    /// acquired.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            id: None,
+        }
+    }
+
+    /// Attempts to acquire the lock without waiting.
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| AsyncMutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.wake_next();
+    }
+
+    /// Wakes the task at the head of the queue. It remains queued until it
+    /// acquires the lock or its [Lock] future is dropped.
+    fn wake_next(&self) {
+        self.waiters.lock().wake_front(1);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("AsyncMutex")
+                .field("value", &&*guard)
+                .finish(),
+            None => f.write_str("AsyncMutex { <locked> }"),
+        }
+    }
+}
+
+/// Future returned by [AsyncMutex::lock].
+pub struct Lock<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+    /// Position in the wait queue, if queued.
+    id: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for Lock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut waiters = mutex.waiters.lock();
+        // Only the task at the head of the queue, or a new one if none is
+        // queued, may take the lock. The queue stays locked so that an unlock
+        // between the attempt and queueing cannot be missed.
+        let turn = match self.id {
+            Some(id) => waiters.is_front(id),
+            None => waiters.is_empty(),
+        };
+        if turn {
+            if let Some(guard) = mutex.try_lock() {
+                if let Some(id) = self.id.take() {
+                    waiters.remove(id);
+                }
+                return Poll::Ready(guard);
+            }
+        }
+
+        self.id = Some(waiters.register(self.id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl<T: ?Sized> Lock<'_, T> {
+    fn dequeue(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.mutex.waiters.lock().remove(id);
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        if self.id.is_some() {
+            self.dequeue();
+            // This future may have been woken to take the lock; pass the
+            // wake-up on so that it is not lost.
+            if !self.mutex.is_locked() {
+                self.mutex.wake_next();
+            }
+        }
+    }
+}
+
+/// Releases the [AsyncMutex] and wakes the next waiting task when dropped.
+pub struct AsyncMutexGuard<'a, T: ?Sized> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for AsyncMutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+    use futures_util::task::noop_waker;
+
+    #[test_case]
+    fn test_lock_waits_for_guard() {
+        static COUNTER: AsyncMutex<u32> = AsyncMutex::new(0);
+
+        let guard = COUNTER.try_lock().unwrap();
+        let mut executor = Executor::new();
+        let handles = [(); 3].map(|_| {
+            executor.spawn_with_handle(async {
+                *COUNTER.lock().await += 1;
+            })
+        });
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| !handle.is_finished()));
+
+        drop(guard);
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| handle.is_finished()));
+        assert_eq!(*COUNTER.try_lock().unwrap(), 3);
+    }
+
+    #[test_case]
+    fn test_queued_waiter_goes_first() {
+        static VALUE: AsyncMutex<()> = AsyncMutex::new(());
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let guard = VALUE.try_lock().unwrap();
+        let mut queued = VALUE.lock();
+        assert!(Pin::new(&mut queued).poll(&mut cx).is_pending());
+        drop(guard);
+
+        let mut late = VALUE.lock();
+        assert!(Pin::new(&mut late).poll(&mut cx).is_pending());
+        let guard = match Pin::new(&mut queued).poll(&mut cx) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("queued waiter did not get the lock"),
+        };
+        drop(guard);
+        assert!(Pin::new(&mut late).poll(&mut cx).is_ready());
+    }
+
+    #[test_case]
+    fn test_dropped_waiter_passes_on_wakeup() {
+        static VALUE: AsyncMutex<()> = AsyncMutex::new(());
+
+        let guard = VALUE.try_lock().unwrap();
+        let mut executor = Executor::new();
+        let first = executor.spawn_with_handle(async { drop(VALUE.lock().await) });
+        let second = executor.spawn_with_handle(async { drop(VALUE.lock().await) });
+        executor.run_until_idle();
+
+        first.abort();
+        drop(guard);
+        executor.run_until_idle();
+        assert!(second.is_finished());
+    }
+}
@@ -0,0 +1,165 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::wait_queue::WaitQueue;
+use crate::sync::IrqSpinlock;
+
+/// Wakes tasks waiting for an event.
+///
+/// [Notify::notify_one] wakes the longest waiting task or, if no task is
+/// waiting, stores a permit so that the next call to [Notify::notified]
+/// completes immediately. Both notify methods may be called from interrupt
+/// context.
+pub struct Notify {
+    state: IrqSpinlock<State>,
+}
+
+struct State {
+    waiters: WaitQueue,
+    permit: bool,
+    /// Incremented by [Notify::notify_waiters], so that a dropped
+    /// [Notified] future can tell which method dequeued it.
+    generation: u64,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: IrqSpinlock::new(State {
+                waiters: WaitQueue::new(),
+                permit: false,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Wakes one waiting task, or stores a permit if there is none.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        if !state.waiters.pop_and_wake() {
+            state.permit = true;
+        }
+    }
+
+    /// Wakes every waiting task without storing a permit.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        state.generation += 1;
+        state.waiters.drain_and_wake();
+    }
+
+    /// Returns a future which resolves once this task is notified.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by [Notify::notified].
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// The waiter's ID and the generation at which it was queued.
+    waiter: Option<(u64, u64)>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+        match self.waiter {
+            // Dequeued by a notify method.
+            Some((id, _)) if !state.waiters.contains(id) => {
+                drop(state);
+                self.waiter = None;
+                Poll::Ready(())
+            }
+
+            Some((id, generation)) => {
+                state.waiters.register(Some(id), cx.waker());
+                drop(state);
+                self.waiter = Some((id, generation));
+                Poll::Pending
+            }
+
+            None if state.permit => {
+                state.permit = false;
+                Poll::Ready(())
+            }
+
+            None => {
+                let id = state.waiters.register(None, cx.waker());
+                let generation = state.generation;
+                drop(state);
+                self.waiter = Some((id, generation));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some((id, generation)) = self.waiter {
+            let mut state = self.notify.state.lock();
+            if state.waiters.contains(id) {
+                state.waiters.remove(id);
+            } else if generation == state.generation {
+                // Woken by `notify_one` but dropped before observing it; pass
+                // the notification on rather than losing it.
+                if !state.waiters.pop_and_wake() {
+                    state.permit = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+
+    #[test_case]
+    fn test_notify_one_stores_permit() {
+        static NOTIFY: Notify = Notify::new();
+
+        NOTIFY.notify_one();
+        let mut executor = Executor::new();
+        let handle = executor.spawn_with_handle(NOTIFY.notified());
+        executor.run_until_idle();
+        assert!(handle.is_finished());
+    }
+
+    #[test_case]
+    fn test_notify_waiters_wakes_all() {
+        static NOTIFY: Notify = Notify::new();
+
+        let mut executor = Executor::new();
+        let handles = [(); 3].map(|_| executor.spawn_with_handle(NOTIFY.notified()));
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| !handle.is_finished()));
+
+        NOTIFY.notify_waiters();
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| handle.is_finished()));
+
+        // No permit is stored.
+        let handle = executor.spawn_with_handle(NOTIFY.notified());
+        executor.run_until_idle();
+        assert!(!handle.is_finished());
+        handle.abort();
+        executor.run_until_idle();
+    }
+}
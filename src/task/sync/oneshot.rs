@@ -0,0 +1,163 @@
+//! A channel for handing a single value from one task to another.
+//!
+//! [Sender::send] never blocks or allocates, so a value may be sent from
+//! interrupt context, for example to complete a driver request.
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::task::AtomicWaker;
+
+use crate::sync::IrqSpinlock;
+
+struct Shared<T> {
+    value: IrqSpinlock<Option<T>>,
+    waker: AtomicWaker,
+    /// Set when the [Sender] is consumed or dropped.
+    sender_done: AtomicBool,
+    receiver_dropped: AtomicBool,
+}
+
+/// Creates a oneshot channel.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: IrqSpinlock::new(None),
+        waker: AtomicWaker::new(),
+        sender_done: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Error returned by a [Receiver] whose [Sender] was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sender dropped without sending")
+    }
+}
+
+/// The sending half of a oneshot channel.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, handing it back if the [Receiver] has been dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(value);
+        }
+
+        *self.shared.value.lock() = Some(value);
+        // Dropping `self` marks the sender done and wakes the receiver.
+        Ok(())
+    }
+
+    /// Returns `true` if the [Receiver] has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_dropped.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.sender_done.store(true, Ordering::Release);
+        self.shared.waker.wake();
+    }
+}
+
+/// The receiving half of a oneshot channel. Resolves with the sent value.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns the value if it has already been sent.
+    pub fn try_recv(&mut self) -> Option<Result<T, RecvError>> {
+        if let Some(value) = self.shared.value.lock().take() {
+            return Some(Ok(value));
+        }
+
+        if self.shared.sender_done.load(Ordering::Acquire) {
+            // The value may have been stored just before the flag was set.
+            return Some(self.shared.value.lock().take().ok_or(RecvError));
+        }
+
+        None
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let receiver = self.get_mut();
+        if let Some(result) = receiver.try_recv() {
+            return Poll::Ready(result);
+        }
+
+        receiver.shared.waker.register(cx.waker());
+        match receiver.try_recv() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{executor::Executor, Task};
+    use spin::Mutex;
+
+    #[test_case]
+    fn test_send_wakes_receiver() {
+        static RESULT: Mutex<Option<Result<u32, RecvError>>> = Mutex::new(None);
+
+        let (sender, receiver) = channel();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async move {
+            *RESULT.lock() = Some(receiver.await);
+        }));
+        executor.run_until_idle();
+        assert_eq!(*RESULT.lock(), None);
+
+        sender.send(7).unwrap();
+        executor.run_until_idle();
+        assert_eq!(*RESULT.lock(), Some(Ok(7)));
+    }
+
+    #[test_case]
+    fn test_drop_semantics() {
+        let (sender, mut receiver) = channel::<u32>();
+        assert_eq!(receiver.try_recv(), None);
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Some(Err(RecvError)));
+
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(1), Err(1));
+    }
+}
@@ -0,0 +1,221 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::wait_queue::{Storage, WaitQueue};
+use crate::sync::{IrqSpinlock, IrqSpinlockGuard};
+
+/// A counting semaphore for async tasks.
+///
+/// Tasks waiting in [Semaphore::acquire] are served in FIFO order: a returned
+/// permit is handed directly to the longest waiting task. Permits may be
+/// returned from interrupt context with [Semaphore::add_permits], for example
+/// when a driver completes a request.
+///
+/// Interrupts are masked while the state is locked, so the wait queue is only
+/// grown, and wakers are only woken or dropped, with the lock released.
+pub struct Semaphore {
+    state: IrqSpinlock<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: IrqSpinlock::new(State {
+                permits,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Returns a future which resolves with a permit once one is available.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            id: None,
+        }
+    }
+
+    /// Takes a permit if one is available and no task is waiting for one.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.permits == 0 || !state.waiters.is_empty() {
+            return None;
+        }
+
+        state.permits -= 1;
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Adds `n` permits, handing them to waiting tasks first.
+    pub fn add_permits(&self, n: usize) {
+        for handed in 0..n {
+            let mut state = self.state.lock();
+            let Some(waker) = state.waiters.pop_front() else {
+                state.permits += n - handed;
+                return;
+            };
+            drop(state);
+            waker.wake();
+        }
+    }
+
+    /// Locks the state once the wait queue has room for another waiter,
+    /// growing it with the lock released if needed.
+    fn lock_with_room(&self) -> IrqSpinlockGuard<'_, State> {
+        loop {
+            let state = self.state.lock();
+            if state.waiters.has_room() {
+                return state;
+            }
+
+            let capacity = (state.waiters.capacity() * 2).max(4);
+            drop(state);
+            let storage = Storage::with_capacity(capacity);
+            let unused = self.state.lock().waiters.grow(storage);
+            drop(unused);
+        }
+    }
+}
+
+/// Future returned by [Semaphore::acquire].
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    /// Position in the wait queue, if queued.
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        if let Some(id) = self.id {
+            let mut state = semaphore.state.lock();
+            if !state.waiters.contains(id) {
+                // Dequeued by add_permits, which handed this future a permit.
+                drop(state);
+                self.id = None;
+                return Poll::Ready(SemaphorePermit { semaphore });
+            }
+
+            let old = state.waiters.replace_waker(id, cx.waker());
+            drop(state);
+            drop(old);
+            return Poll::Pending;
+        }
+
+        let mut state = semaphore.lock_with_room();
+        if state.permits > 0 {
+            state.permits -= 1;
+            return Poll::Ready(SemaphorePermit { semaphore });
+        }
+
+        let id = state.waiters.register(None, cx.waker());
+        drop(state);
+        self.id = Some(id);
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.semaphore.state.lock();
+            match state.waiters.take(id) {
+                Some(waker) => {
+                    drop(state);
+                    drop(waker);
+                }
+                None => {
+                    // Handed a permit which was never taken; pass it on.
+                    drop(state);
+                    self.semaphore.add_permits(1);
+                }
+            }
+        }
+    }
+}
+
+/// A permit from a [Semaphore], returned to it when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Consumes the permit without returning it to the semaphore.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+    use futures_util::task::noop_waker;
+
+    #[test_case]
+    fn test_acquire_waits_for_permit() {
+        static SEMAPHORE: Semaphore = Semaphore::new(1);
+
+        let permit = SEMAPHORE.try_acquire().unwrap();
+        assert!(SEMAPHORE.try_acquire().is_none());
+
+        let mut executor = Executor::new();
+        let handles = [(); 2]
+            .map(|_| executor.spawn_with_handle(async { SEMAPHORE.acquire().await.forget() }));
+        executor.run_until_idle();
+        assert!(handles.iter().all(|handle| !handle.is_finished()));
+
+        drop(permit);
+        executor.run_until_idle();
+        assert!(handles[0].is_finished());
+        assert!(!handles[1].is_finished());
+
+        SEMAPHORE.add_permits(1);
+        executor.run_until_idle();
+        assert!(handles[1].is_finished());
+        assert_eq!(SEMAPHORE.available_permits(), 0);
+    }
+
+    #[test_case]
+    fn test_dropped_waiter_passes_permit_on() {
+        static SEMAPHORE: Semaphore = Semaphore::new(0);
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut acquire = SEMAPHORE.acquire();
+        assert!(Pin::new(&mut acquire).poll(&mut cx).is_pending());
+
+        let mut executor = Executor::new();
+        let handle = executor.spawn_with_handle(async { SEMAPHORE.acquire().await.forget() });
+        executor.run_until_idle();
+        assert!(!handle.is_finished());
+
+        // The permit is handed to `acquire`, which is dropped before taking
+        // it, so it moves on to the next waiter.
+        SEMAPHORE.add_permits(1);
+        drop(acquire);
+        executor.run_until_idle();
+        assert!(handle.is_finished());
+        assert_eq!(SEMAPHORE.available_permits(), 0);
+    }
+}
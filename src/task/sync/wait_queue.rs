@@ -0,0 +1,124 @@
+use alloc::collections::VecDeque;
+use core::task::Waker;
+
+/// Storage for the waiters of a [WaitQueue].
+pub(super) type Storage = VecDeque<(u64, Waker)>;
+
+/// A FIFO queue of waiting tasks, each identified by the future which queued
+/// it.
+///
+/// The queue is not synchronized; primitives wrap it in a lock alongside
+/// their own state.
+pub(super) struct WaitQueue {
+    next_id: u64,
+    queue: Storage,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            next_id: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity()
+    }
+
+    /// Returns `true` if another waiter can be queued without allocating.
+    pub fn has_room(&self) -> bool {
+        self.queue.len() < self.queue.capacity()
+    }
+
+    /// Moves the waiters to `storage` if it has room for more of them, so
+    /// that a primitive can allocate it without holding its lock. Returns
+    /// the storage no longer in use, to be dropped once the lock is
+    /// released.
+    pub fn grow(&mut self, mut storage: Storage) -> Storage {
+        if storage.capacity() > self.queue.capacity() {
+            storage.extend(self.queue.drain(..));
+            core::mem::swap(&mut self.queue, &mut storage);
+        }
+        storage
+    }
+
+    /// Queues a waiter or, if `id` is already queued, updates its waker.
+    /// Returns the waiter's ID.
+    pub fn register(&mut self, id: Option<u64>, waker: &Waker) -> u64 {
+        if let Some(id) = id {
+            if let Some(entry) = self.queue.iter_mut().find(|(queued, _)| *queued == id) {
+                entry.1.clone_from(waker);
+                return id;
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push_back((id, waker.clone()));
+        id
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.queue.iter().any(|(queued, _)| *queued == id)
+    }
+
+    pub fn is_front(&self, id: u64) -> bool {
+        self.queue.front().is_some_and(|(queued, _)| *queued == id)
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.queue.retain(|(queued, _)| *queued != id);
+    }
+
+    /// Wakes the first `n` waiters, leaving them queued.
+    pub fn wake_front(&self, n: usize) {
+        for (_, waker) in self.queue.iter().take(n) {
+            waker.wake_by_ref();
+        }
+    }
+
+    /// Replaces the waker of the queued waiter `id`. Returns the old one if
+    /// it was replaced, to be dropped once the lock is released.
+    pub fn replace_waker(&mut self, id: u64, waker: &Waker) -> Option<Waker> {
+        let entry = self.queue.iter_mut().find(|(queued, _)| *queued == id)?;
+        if entry.1.will_wake(waker) {
+            return None;
+        }
+        Some(core::mem::replace(&mut entry.1, waker.clone()))
+    }
+
+    /// Removes the waiter `id` and returns its waker.
+    pub fn take(&mut self, id: u64) -> Option<Waker> {
+        let index = self.queue.iter().position(|(queued, _)| *queued == id)?;
+        self.queue.remove(index).map(|(_, waker)| waker)
+    }
+
+    /// Removes the first waiter and returns its waker.
+    pub fn pop_front(&mut self) -> Option<Waker> {
+        self.queue.pop_front().map(|(_, waker)| waker)
+    }
+
+    /// Removes and wakes the first waiter, returning `false` if there was
+    /// none.
+    pub fn pop_and_wake(&mut self) -> bool {
+        match self.queue.pop_front() {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes and wakes every waiter.
+    pub fn drain_and_wake(&mut self) {
+        for (_, waker) in self.queue.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,822 @@
+//! Preemptively scheduled kernel threads.
+//!
+//! Each thread owns a kernel stack and is switched to by saving the callee
+//! saved registers and stack pointer of the current thread and restoring
+//! those of the next. The timer interrupt switches to the next ready thread
+//! once the current one has used up its time slice, and a thread may give up
+//! the rest of its slice early with [yield_now]. Which thread runs next is
+//! decided by a [Policy], [RoundRobin] unless replaced with [set_policy].
+//!
+//! Threads waiting for something block instead of spinning: [park] takes the
+//! current thread off the CPU until another thread or an interrupt handler
+//! calls [unpark] for it, or until a deadline passes with [park_until].
+//! [WaitQueue]s build on this to block threads until a condition holds, and
+//! [block_on] blocks on a future until its waker is called.
+//!
+//! The thread running [crate::init] becomes the boot thread, which typically
+//! goes on to run the async [Executor](super::executor::Executor). Kernel
+//! threads therefore share the CPU with all async tasks.
+//!
+//! There is a single scheduler, and only the bootstrap processor's timer
+//! preempts threads, so threads only run on the bootstrap processor.
+//! [current], [yield_now], [park], [park_until], [block_on] and the waits of
+//! a [WaitQueue] act on the running thread and panic if called on another
+//! CPU, such as from a task of the [runtime](super::runtime) running there.
+//! [spawn] and [unpark] may be called from any CPU.
+//!
+//! Threads which run user code also carry the page table and the kernel stack
+//! of their user mode session, which are switched along with their
+//! registers. So are the FPU, SSE and AVX registers, see [crate::fpu].
+//!
+//! The scheduler is entered from interrupt context, so it never allocates or
+//! frees memory: stacks are allocated by [spawn] and the stacks of exited
+//! threads are only freed by a later call to [spawn].
+
+use alloc::{boxed::Box, sync::Arc, task::Wake, vec};
+use core::{
+    arch::global_asm,
+    fmt,
+    future::Future,
+    pin::pin,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::PhysFrame,
+    VirtAddr,
+};
+
+use crate::{
+    fpu::FpuState,
+    mem, percpu,
+    sync::{IrqSpinlock, IrqSpinlockGuard},
+    time, usermode,
+};
+
+/// Maximum number of threads, including the boot thread.
+pub const MAX_THREADS: usize = 32;
+
+/// Size of each thread's kernel stack.
+pub const STACK_SIZE: usize = 64 * 1024;
+
+/// Number of timer ticks a thread may run for before it is preempted, unless
+/// the [Policy] says otherwise.
+pub const THREAD_SLICE_TICKS: u64 = 10;
+
+/// Uniquely identifies a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// The thread which booted the kernel.
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    /// Parked until unparked or until its wake-up tick.
+    Blocked,
+    /// Exited; the stack is freed by the next [spawn].
+    Dead,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    /// Saved stack pointer while the thread is not running.
+    rsp: u64,
+    /// Level 4 page table the thread runs on, or `None` for the kernel's.
+    page_table: Option<PhysFrame>,
+    /// Kernel stack of the thread's user mode session, if any.
+    kernel_stack: VirtAddr,
+    /// Set by [unpark] and cleared by [park], so that a thread unparked
+    /// before it parks does not block.
+    unparked: bool,
+    /// Tick at which the thread is made ready again while blocked.
+    wake_at: Option<u64>,
+    /// Saved FPU, SSE and AVX registers while the thread is not running.
+    fpu: FpuState,
+    /// Keeps the stack alive. `None` for the boot thread, which runs on the
+    /// bootloader's stack.
+    _stack: Option<Box<[u8]>>,
+}
+
+/// Decides which thread runs next.
+///
+/// Threads are identified by their slot in the scheduler's table, from 0 to
+/// [MAX_THREADS]. Policies are called with the scheduler lock held and
+/// interrupts disabled, so they must not block or allocate.
+pub trait Policy: Sync {
+    /// Returns the slot of the thread to switch to from the one in slot
+    /// `current`, among the slots for which `is_ready` returns `true`. The
+    /// current thread is never ready.
+    fn pick_next(&self, current: usize, is_ready: &dyn Fn(usize) -> bool) -> Option<usize>;
+
+    /// Returns the number of ticks a thread may run for before it is
+    /// preempted.
+    fn time_slice(&self) -> u64 {
+        THREAD_SLICE_TICKS
+    }
+}
+
+/// Runs the ready threads in turn, each for [THREAD_SLICE_TICKS].
+pub struct RoundRobin;
+
+impl Policy for RoundRobin {
+    fn pick_next(&self, current: usize, is_ready: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (1..MAX_THREADS)
+            .map(|offset| (current + offset) % MAX_THREADS)
+            .find(|&slot| is_ready(slot))
+    }
+}
+
+struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    /// Index of the running thread in `threads`.
+    current: usize,
+    /// Ticks the running thread has been running for.
+    slice_ticks: u64,
+    policy: &'static dyn Policy,
+}
+
+impl Scheduler {
+    /// Returns the slot of the ready thread to run next, as chosen by the
+    /// policy.
+    fn next_ready(&self) -> Option<usize> {
+        let is_ready = |slot: usize| {
+            self.threads[slot]
+                .as_ref()
+                .is_some_and(|thread| thread.state == State::Ready)
+        };
+        self.policy.pick_next(self.current, &is_ready)
+    }
+
+    fn current_thread(&mut self) -> &mut Thread {
+        self.threads[self.current]
+            .as_mut()
+            .expect("current thread missing")
+    }
+}
+
+static SCHEDULER: IrqSpinlock<Scheduler> = IrqSpinlock::new(Scheduler {
+    threads: {
+        let mut threads = [const { None }; MAX_THREADS];
+        let boot = Some(Thread {
+            id: ThreadId::BOOT,
+            state: State::Running,
+            rsp: 0,
+            page_table: None,
+            kernel_stack: VirtAddr::zero(),
+            unparked: false,
+            wake_at: None,
+            fpu: FpuState::new(),
+            _stack: None,
+        });
+        // Assigning would drop the old value, which is not allowed in a
+        // constant.
+        core::mem::forget(core::mem::replace(&mut threads[0], boot));
+        threads
+    },
+    current: 0,
+    slice_ticks: 0,
+    policy: &RoundRobin,
+});
+
+global_asm!(
+    ".pushsection .text",
+    // Saves the callee saved registers on the current stack, stores the stack
+    // pointer to `[rdi]` and resumes the thread whose stack pointer is `rsi`.
+    ".global toyos_switch_context",
+    "toyos_switch_context:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    "",
+    // First code run by a new thread. The initial stack built by `spawn`
+    // leaves the entry closure in r12.
+    ".global toyos_thread_trampoline",
+    "toyos_thread_trampoline:",
+    "mov rdi, r12",
+    "call {thread_start}",
+    "ud2",
+    ".popsection",
+    thread_start = sym thread_start,
+);
+
+extern "C" {
+    fn toyos_switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn toyos_thread_trampoline();
+}
+
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+extern "C" fn thread_start(entry: *mut Entry) -> ! {
+    // The switch to a new thread always happens with interrupts disabled.
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit();
+}
+
+/// Spawns a kernel thread running `f`.
+///
+/// # Panics
+///
+/// Panics if [MAX_THREADS] threads are already running.
+pub fn spawn<F>(f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    try_spawn(f).expect("too many threads")
+}
+
+/// Spawns a kernel thread running `f`, or returns `None` if [MAX_THREADS]
+/// threads are already running.
+pub fn try_spawn<F>(f: F) -> Option<ThreadId>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+    if count() >= MAX_THREADS {
+        return None;
+    }
+
+    let entry: *mut Entry = Box::into_raw(Box::new(Box::new(f)));
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+
+    // Build a frame for `toyos_switch_context` to pop: six callee saved
+    // registers followed by the return address. The return address sits just
+    // below the 16 byte aligned top so that the stack is aligned when the
+    // trampoline calls `thread_start`.
+    let top = (stack.as_mut_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+    let frame = [
+        0,                                                                 // r15
+        0,                                                                 // r14
+        0,                                                                 // r13
+        entry as u64,                                                      // r12
+        0,                                                                 // rbp
+        0,                                                                 // rbx
+        toyos_thread_trampoline as unsafe extern "C" fn() as usize as u64, // return address
+    ];
+    let rsp = top - 8 * frame.len() as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+    }
+
+    let id = ThreadId::new();
+    let thread = Thread {
+        id,
+        state: State::Ready,
+        rsp,
+        page_table: None,
+        kernel_stack: VirtAddr::zero(),
+        unparked: false,
+        wake_at: None,
+        fpu: FpuState::new(),
+        _stack: Some(stack),
+    };
+
+    let mut scheduler = SCHEDULER.lock();
+    let slot = scheduler.threads.iter_mut().find(|slot| slot.is_none());
+    match slot {
+        Some(slot) => *slot = Some(thread),
+        None => {
+            // Another thread took the last slot. As in `reap`, nothing may be
+            // freed while the lock is held.
+            drop(scheduler);
+            unsafe { drop(Box::from_raw(entry)) };
+            return None;
+        }
+    }
+    Some(id)
+}
+
+/// Frees the stacks of exited threads.
+///
+/// The scheduler lock disables interrupts, so stacks are dropped after
+/// releasing it; otherwise freeing could spin forever on a heap lock held by
+/// a preempted thread.
+fn reap() {
+    loop {
+        let dead = {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler
+                .threads
+                .iter_mut()
+                .find(|slot| slot.as_ref().is_some_and(|t| t.state == State::Dead))
+                .and_then(Option::take)
+        };
+
+        match dead {
+            Some(thread) => drop(thread),
+            None => return,
+        }
+    }
+}
+
+/// Panics unless called on the bootstrap processor, the only CPU threads run
+/// on.
+fn assert_on_bsp() {
+    assert_eq!(
+        percpu::cpu_id(),
+        0,
+        "kernel threads only run on the bootstrap processor"
+    );
+}
+
+/// Returns the ID of the running thread.
+pub fn current() -> ThreadId {
+    assert_on_bsp();
+    let scheduler = SCHEDULER.lock();
+    scheduler.threads[scheduler.current]
+        .as_ref()
+        .expect("current thread missing")
+        .id
+}
+
+/// Returns the number of live threads, including the boot thread.
+pub fn count() -> usize {
+    SCHEDULER
+        .lock()
+        .threads
+        .iter()
+        .flatten()
+        .filter(|thread| thread.state != State::Dead)
+        .count()
+}
+
+/// Switches the current thread to the level 4 page table in `page_table`, or
+/// back to the kernel's if `None`.
+///
+/// # Safety
+///
+/// The page table must contain the kernel's mappings and must stay alive
+/// until the thread switches away from it again.
+pub unsafe fn set_page_table(page_table: Option<PhysFrame>) {
+    assert_on_bsp();
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current;
+    scheduler.threads[current].as_mut().unwrap().page_table = page_table;
+    load_page_table(page_table);
+}
+
+/// Loads the level 4 page table in `page_table`, or the kernel's if `None`.
+fn load_page_table(page_table: Option<PhysFrame>) {
+    let frame = page_table.unwrap_or_else(mem::kernel_page_table);
+    if Cr3::read().0 != frame {
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
+/// Replaces the policy which decides which thread runs next.
+pub fn set_policy(policy: &'static dyn Policy) {
+    SCHEDULER.lock().policy = policy;
+}
+
+/// Gives up the rest of the current time slice.
+pub fn yield_now() {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| switch(State::Ready));
+}
+
+/// Blocks the current thread until [unpark] is called for it. Returns
+/// immediately if it was unparked since it last parked.
+///
+/// Like a condition variable, this may return without the event the caller
+/// waits for having happened, so callers check for it in a loop.
+pub fn park() {
+    block(None);
+}
+
+/// Like [park], but also returns once the tick counter reaches `deadline`.
+pub fn park_until(deadline: u64) {
+    block(Some(deadline));
+}
+
+fn block(deadline: Option<u64>) {
+    assert_on_bsp();
+    interrupts::without_interrupts(|| loop {
+        let mut scheduler = SCHEDULER.lock();
+        let thread = scheduler.current_thread();
+        let expired = deadline.is_some_and(|deadline| time::ticks() >= deadline);
+        if core::mem::take(&mut thread.unparked) || expired {
+            thread.wake_at = None;
+            return;
+        }
+
+        thread.wake_at = deadline;
+        if scheduler.next_ready().is_some() {
+            switch_locked(scheduler, State::Blocked);
+        } else {
+            // There is no other thread to run, so only an interrupt handler
+            // can unpark this one.
+            drop(scheduler);
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+    });
+}
+
+/// Makes the thread with the given ID ready to run if it is parked, or makes
+/// its next [park] return immediately otherwise. May be called from
+/// interrupt handlers.
+pub fn unpark(id: ThreadId) {
+    let mut scheduler = SCHEDULER.lock();
+    let thread = scheduler.threads.iter_mut().flatten().find(|t| t.id == id);
+    if let Some(thread) = thread {
+        thread.unparked = true;
+        if thread.state == State::Blocked {
+            thread.state = State::Ready;
+        }
+    }
+}
+
+/// Unparks a thread when woken.
+struct Unparker(ThreadId);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        unpark(self.0);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        unpark(self.0);
+    }
+}
+
+/// Returns a waker which unparks the current thread.
+pub fn waker() -> Waker {
+    Waker::from(Arc::new(Unparker(current())))
+}
+
+/// Runs `future` to completion on the current thread, which is parked
+/// whenever the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = waker();
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        park();
+    }
+}
+
+/// Threads blocked until a condition holds.
+///
+/// Code which changes the state a condition depends on calls
+/// [WaitQueue::wake_one] or [WaitQueue::wake_all] afterwards, which unparks
+/// waiting threads so they check their conditions again. Waking does not
+/// allocate, so it is safe in interrupt handlers.
+pub struct WaitQueue {
+    waiters: IrqSpinlock<Waiters>,
+}
+
+/// Threads in a [WaitQueue], each with the ticket it drew when it started
+/// waiting. Every thread waits at most once, so [MAX_THREADS] entries always
+/// suffice.
+struct Waiters {
+    next_ticket: u64,
+    entries: [Option<(u64, ThreadId)>; MAX_THREADS],
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinlock::new(Waiters {
+                next_ticket: 0,
+                entries: [None; MAX_THREADS],
+            }),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns `true`.
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.wait(condition, None);
+    }
+
+    /// Blocks the current thread until `condition` returns `true` or
+    /// `timeout` has elapsed. Returns the last result of `condition`.
+    pub fn wait_until_timeout(&self, condition: impl FnMut() -> bool, timeout: Duration) -> bool {
+        let deadline = time::ticks().saturating_add(time::duration_to_ticks(timeout));
+        self.wait(condition, Some(deadline))
+    }
+
+    fn wait(&self, mut condition: impl FnMut() -> bool, deadline: Option<u64>) -> bool {
+        let id = current();
+        let satisfied = loop {
+            // Queue before checking, so a wake-up after the check unparks
+            // this thread. Waking removes it, so queue it again each time.
+            self.insert(id);
+            if condition() {
+                break true;
+            }
+            match deadline {
+                Some(deadline) if time::ticks() >= deadline => break false,
+                Some(deadline) => park_until(deadline),
+                None => park(),
+            }
+        };
+        self.remove(id);
+        satisfied
+    }
+
+    fn insert(&self, id: ThreadId) {
+        let mut waiters = self.waiters.lock();
+        if waiters
+            .entries
+            .iter()
+            .flatten()
+            .any(|&(_, queued)| queued == id)
+        {
+            return;
+        }
+        let ticket = waiters.next_ticket;
+        waiters.next_ticket += 1;
+        let free = waiters.entries.iter_mut().find(|entry| entry.is_none());
+        *free.expect("more waiters than threads") = Some((ticket, id));
+    }
+
+    fn remove(&self, id: ThreadId) {
+        let mut waiters = self.waiters.lock();
+        for entry in &mut waiters.entries {
+            if entry.is_some_and(|(_, queued)| queued == id) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Wakes the thread which has waited longest, if any.
+    pub fn wake_one(&self) {
+        let mut waiters = self.waiters.lock();
+        let first = waiters
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.is_some())
+            .min_by_key(|entry| entry.unwrap().0);
+        if let Some(entry) = first {
+            let (_, id) = entry.take().unwrap();
+            unpark(id);
+        }
+    }
+
+    /// Wakes every waiting thread.
+    pub fn wake_all(&self) {
+        let mut waiters = self.waiters.lock();
+        for entry in &mut waiters.entries {
+            if let Some((_, id)) = entry.take() {
+                unpark(id);
+            }
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Terminates the current thread.
+///
+/// # Panics
+///
+/// Panics if called on the boot thread.
+pub fn exit() -> ! {
+    assert_ne!(current(), ThreadId::BOOT, "the boot thread cannot exit");
+
+    interrupts::disable();
+    switch(State::Dead);
+    unreachable!("dead thread was rescheduled");
+}
+
+/// Switches to the next ready thread, leaving the current thread in `state`.
+/// Returns once the current thread is scheduled again, or immediately if no
+/// other thread is ready.
+///
+/// Must be called with interrupts disabled.
+fn switch(state: State) {
+    switch_locked(SCHEDULER.lock(), state);
+}
+
+/// Like [switch], with the scheduler lock already held.
+fn switch_locked(mut scheduler: IrqSpinlockGuard<'_, Scheduler>, state: State) {
+    let (old_rsp, new_rsp) = {
+        let next = match scheduler.next_ready() {
+            Some(next) => next,
+            None => {
+                scheduler.slice_ticks = 0;
+                return;
+            }
+        };
+
+        let current = scheduler.current;
+        let current_thread = scheduler.threads[current].as_mut().unwrap();
+        current_thread.state = state;
+        current_thread.kernel_stack = usermode::kernel_stack();
+        current_thread.fpu.save();
+        let page_table = current_thread.page_table;
+
+        let next_thread = scheduler.threads[next].as_mut().unwrap();
+        next_thread.state = State::Running;
+        let new_rsp = next_thread.rsp;
+        // The kernel does not use the FPU, so its registers can be loaded
+        // before the switch.
+        next_thread.fpu.restore();
+        usermode::set_kernel_stack(next_thread.kernel_stack);
+        if next_thread.page_table != page_table {
+            load_page_table(next_thread.page_table);
+        }
+        scheduler.current = next;
+        scheduler.slice_ticks = 0;
+
+        // The threads live in a static, so the pointer remains valid after
+        // the lock is released. Interrupts stay disabled until the switch.
+        let old_rsp = addr_of_mut!(scheduler.threads[current].as_mut().unwrap().rsp);
+        (old_rsp, new_rsp)
+    };
+    drop(scheduler);
+
+    unsafe { toyos_switch_context(old_rsp, new_rsp) };
+}
+
+/// Wakes threads whose [park_until] deadline has passed and preempts the
+/// running thread once its time slice has expired. Called by the timer
+/// interrupt handler after acknowledging the interrupt.
+pub(crate) fn tick() {
+    let expired = {
+        let mut scheduler = SCHEDULER.lock();
+        let now = time::ticks();
+        for thread in scheduler.threads.iter_mut().flatten() {
+            if thread.state == State::Blocked && thread.wake_at.is_some_and(|tick| now >= tick) {
+                thread.state = State::Ready;
+                thread.wake_at = None;
+            }
+        }
+        scheduler.slice_ticks += 1;
+        scheduler.slice_ticks >= scheduler.policy.time_slice()
+    };
+
+    if expired {
+        switch(State::Ready);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+    use spin::Mutex;
+
+    #[test_case]
+    fn test_spawned_thread_runs() {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        for _ in 0..3 {
+            spawn(|| {
+                COUNTER.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        while COUNTER.load(Ordering::Relaxed) < 3 {
+            yield_now();
+        }
+
+        // Let the threads exit, then reclaim their stacks.
+        while count() > 1 {
+            yield_now();
+        }
+        reap();
+    }
+
+    #[test_case]
+    fn test_threads_are_preempted() {
+        static STARTED: AtomicBool = AtomicBool::new(false);
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        spawn(|| {
+            STARTED.store(true, Ordering::Relaxed);
+            while !STOP.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        });
+
+        // Neither thread yields, so this only finishes if the timer switches
+        // between them.
+        while !STARTED.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+        STOP.store(true, Ordering::Relaxed);
+        while count() > 1 {
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_unpark_wakes_parked_thread() {
+        static WOKEN: AtomicBool = AtomicBool::new(false);
+        static PARKED: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+        spawn(|| {
+            *PARKED.lock() = Some(current());
+            while !WOKEN.load(Ordering::Acquire) {
+                park();
+            }
+        });
+        let id = loop {
+            if let Some(id) = *PARKED.lock() {
+                break id;
+            }
+            yield_now();
+        };
+
+        WOKEN.store(true, Ordering::Release);
+        unpark(id);
+        while count() > 1 {
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_unpark_before_park() {
+        unpark(current());
+        // Returns immediately instead of blocking forever.
+        park();
+    }
+
+    #[test_case]
+    fn test_park_until_deadline() {
+        let deadline = time::ticks() + 5;
+        while time::ticks() < deadline {
+            park_until(deadline);
+        }
+        assert!(time::ticks() >= deadline);
+    }
+
+    #[test_case]
+    fn test_wait_queue() {
+        static QUEUE: WaitQueue = WaitQueue::new();
+        static READY: AtomicBool = AtomicBool::new(false);
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        spawn(|| {
+            QUEUE.wait_until(|| READY.load(Ordering::Acquire));
+            DONE.store(true, Ordering::Release);
+            QUEUE.wake_all();
+        });
+
+        READY.store(true, Ordering::Release);
+        QUEUE.wake_all();
+        QUEUE.wait_until(|| DONE.load(Ordering::Acquire));
+        assert!(!QUEUE.wait_until_timeout(|| false, Duration::from_millis(5)));
+        while count() > 1 {
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_block_on() {
+        let (sender, receiver) = crate::task::sync::oneshot::channel();
+        spawn(move || {
+            let _ = sender.send(42);
+        });
+        assert_eq!(block_on(receiver), Ok(42));
+        while count() > 1 {
+            yield_now();
+        }
+    }
+}
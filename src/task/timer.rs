@@ -0,0 +1,447 @@
+//! Async timers driven by the timer interrupt.
+//!
+//! [sleep] returns a future which completes once the given duration has
+//! elapsed and [Timeout] bounds how long another future may take.
+//!
+//! Pending timers are kept in a hierarchical timer wheel. The wheel has
+//! [LEVELS] levels of [SLOTS] slots each; a slot at level `k` covers
+//! `SLOTS^k` ticks. A timer is placed on the level of the highest group of
+//! [SLOT_BITS] bits in which its deadline differs from the current tick, so
+//! insertion is O(1) regardless of how many timers are pending. Each tick
+//! fires the timers in the current level 0 slot. Whenever the tick crosses a
+//! level `k` slot boundary, the timers in that slot are cascaded down to
+//! lower levels, where they are placed with finer granularity.
+//!
+//! Dropping a pending timer removes it from the wheel.
+//!
+//! The timer interrupt only raises deferred work while timers are pending,
+//! the wheel itself is processed outside of interrupt context.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use crate::{
+    interrupts::deferred::{self, Work},
+    time,
+};
+
+/// Number of bits of the deadline indexing the slots of each level.
+const SLOT_BITS: u32 = 6;
+
+/// Number of slots in each level of the wheel.
+const SLOTS: usize = 1 << SLOT_BITS;
+
+/// Number of levels in the wheel. Deadlines up to `SLOTS^LEVELS` ticks in the
+/// future (over two years at 1000 Hz) are placed precisely; later deadlines
+/// are parked in the top level until they come into range.
+const LEVELS: usize = 6;
+
+/// State shared between a [Sleep] future and its entry in the wheel.
+struct TimerState {
+    waker: AtomicWaker,
+    fired: AtomicBool,
+}
+
+/// A pending timer.
+struct Entry {
+    deadline: u64,
+    state: Arc<TimerState>,
+}
+
+/// A hierarchical timer wheel.
+struct TimerWheel {
+    levels: [[Vec<Entry>; SLOTS]; LEVELS],
+    /// The last tick which has been processed.
+    current: u64,
+    len: usize,
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        TimerWheel {
+            levels: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+            current: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the level and slot a deadline after the last processed tick
+    /// belongs in.
+    ///
+    /// The level is that of the highest group of bits in which the deadline
+    /// differs from the last processed tick, so that the slot is next reached
+    /// on the tick at which the deadline's group at that level begins. An
+    /// entry stays in this position until then.
+    fn position(&self, deadline: u64) -> (usize, usize) {
+        let differing = deadline ^ self.current;
+        let level = ((u64::BITS - 1 - differing.leading_zeros()) / SLOT_BITS) as usize;
+
+        // Park deadlines beyond the range of the wheel in the next slot of the
+        // top level to be cascaded, where they are re-examined.
+        if level >= LEVELS {
+            let shift = SLOT_BITS * (LEVELS - 1) as u32;
+            let slot = ((self.current >> shift) + 1) as usize % SLOTS;
+            return (LEVELS - 1, slot);
+        }
+
+        let slot = (deadline >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        (level, slot)
+    }
+
+    /// Adds an entry, returning it back if its deadline has already been
+    /// processed.
+    fn insert(&mut self, entry: Entry) -> Result<(), Entry> {
+        if entry.deadline <= self.current {
+            return Err(entry);
+        }
+
+        let (level, slot) = self.position(entry.deadline);
+        self.levels[level][slot].push(entry);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the entry with the given state, returning `false` if it is no
+    /// longer in the wheel.
+    fn remove(&mut self, deadline: u64, state: &Arc<TimerState>) -> bool {
+        if deadline <= self.current {
+            return false;
+        }
+
+        let (level, slot) = self.position(deadline);
+        let entries = &mut self.levels[level][slot];
+        match entries
+            .iter()
+            .position(|entry| Arc::ptr_eq(&entry.state, state))
+        {
+            Some(index) => {
+                entries.swap_remove(index);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fast forwards an empty wheel to `now`.
+    ///
+    /// The wheel is only advanced while timers are pending, so it falls
+    /// behind while idle. Nothing can expire in an empty wheel, so there is no
+    /// need to visit every tick in between.
+    fn skip_idle(&mut self, now: u64) {
+        if self.len == 0 {
+            self.current = self.current.max(now);
+        }
+    }
+
+    /// Processes every tick up to and including `now`, moving expired entries
+    /// into `expired`.
+    fn advance(&mut self, now: u64, expired: &mut Vec<Entry>) {
+        self.skip_idle(now);
+        while self.current < now {
+            // Entries are cascaded relative to this tick, so that those due on
+            // it expire at once and the rest land in lower levels.
+            let tick = self.current + 1;
+            self.current = tick;
+
+            // Cascade higher levels first as their entries may land in lower
+            // level slots which are also due to be cascaded on this tick.
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if tick & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+
+                let slot = (tick >> shift) as usize % SLOTS;
+                let entries = core::mem::take(&mut self.levels[level][slot]);
+                self.len -= entries.len();
+                for entry in entries {
+                    if let Err(entry) = self.insert(entry) {
+                        expired.push(entry);
+                    }
+                }
+            }
+
+            let slot = tick as usize % SLOTS;
+            let entries = &mut self.levels[0][slot];
+            self.len -= entries.len();
+            expired.append(entries);
+        }
+    }
+}
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// Number of entries in the wheel, readable from interrupt context.
+static PENDING_TIMERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Deferred work raised by the timer interrupt while timers are pending.
+static TIMER_WORK: OnceCell<Work> = OnceCell::uninit();
+
+/// Registers the deferred work which expires timers.
+pub(crate) fn init() {
+    TIMER_WORK.init_once(|| deferred::register(expire_timers));
+}
+
+/// Called by the timer interrupt handler after the tick counter advances.
+pub(crate) fn tick() {
+    if PENDING_TIMERS.load(Ordering::Relaxed) != 0 {
+        if let Ok(work) = TIMER_WORK.try_get() {
+            work.raise();
+        }
+    }
+}
+
+/// Deferred half of the timer interrupt: wakes every expired timer.
+fn expire_timers() {
+    let mut expired = Vec::new();
+    {
+        let mut wheel = WHEEL.lock();
+        wheel.advance(time::ticks(), &mut expired);
+        PENDING_TIMERS.store(wheel.len, Ordering::Relaxed);
+    }
+
+    // Wake outside of the lock as waking may poll other timers.
+    for entry in expired {
+        entry.state.fired.store(true, Ordering::Release);
+        entry.state.waker.wake();
+    }
+}
+
+/// Returns a future which completes after `duration` has elapsed.
+///
+/// The timer has tick resolution; the future never completes early but may
+/// complete up to one tick late.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::ticks().saturating_add(time::duration_to_ticks(duration)))
+}
+
+/// Returns a future which completes once the tick counter reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        state: None,
+    }
+}
+
+/// Future returned by [sleep] and [sleep_until].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Sleep {
+    deadline: u64,
+    /// State shared with this timer's entry in the wheel, once registered.
+    state: Option<Arc<TimerState>>,
+}
+
+impl Sleep {
+    /// Returns the tick at which this timer completes.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if let Some(state) = &self.state {
+            state.waker.register(cx.waker());
+            return match state.fired.load(Ordering::Acquire) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            };
+        }
+
+        let state = Arc::new(TimerState {
+            waker: AtomicWaker::new(),
+            fired: AtomicBool::new(false),
+        });
+        state.waker.register(cx.waker());
+
+        let entry = Entry {
+            deadline: self.deadline,
+            state: state.clone(),
+        };
+
+        let mut wheel = WHEEL.lock();
+        wheel.skip_idle(time::ticks());
+        if wheel.insert(entry).is_err() {
+            return Poll::Ready(());
+        }
+        PENDING_TIMERS.store(wheel.len, Ordering::Relaxed);
+        drop(wheel);
+
+        self.state = Some(state);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        if state.fired.load(Ordering::Acquire) {
+            return;
+        }
+
+        let mut wheel = WHEEL.lock();
+        if wheel.remove(self.deadline, state) {
+            PENDING_TIMERS.store(wheel.len, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Error returned by [Timeout] when the deadline elapses before the inner
+/// future completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+/// A future which completes with the output of an inner future, or with
+/// [Elapsed] if the inner future takes longer than a given duration.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Timeout<F> {
+    /// Wraps `future` so that it fails with [Elapsed] after `duration`.
+    pub fn new(future: F, duration: Duration) -> Self {
+        Timeout {
+            future,
+            sleep: sleep(duration),
+        }
+    }
+
+    /// Wraps `future` so that it fails with [Elapsed] once the tick counter
+    /// reaches `deadline`.
+    pub fn until(future: F, deadline: u64) -> Self {
+        Timeout {
+            future,
+            sleep: sleep_until(deadline),
+        }
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `future` is structurally pinned; it is never moved out of
+        // `self` and `Timeout` does not implement `Drop` or `Unpin` manually.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(deadline: u64) -> Entry {
+        Entry {
+            deadline,
+            state: Arc::new(TimerState {
+                waker: AtomicWaker::new(),
+                fired: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    #[test_case]
+    fn test_wheel_fires_on_deadline() {
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 5000, 262_144, 300_000];
+        let mut wheel = TimerWheel::new();
+        for &deadline in &deadlines {
+            assert!(wheel.insert(entry(deadline)).is_ok());
+        }
+
+        let mut fired = 0;
+        let mut expired = Vec::new();
+        for tick in 1..=300_000 {
+            wheel.advance(tick, &mut expired);
+            for entry in expired.drain(..) {
+                assert_eq!(entry.deadline, tick);
+                fired += 1;
+            }
+        }
+
+        assert_eq!(fired, deadlines.len());
+        assert_eq!(wheel.len, 0);
+    }
+
+    #[test_case]
+    fn test_wheel_fires_on_deadline_from_later_tick() {
+        let deadlines = [61, 63, 64, 127, 128, 4095, 4096, 4160, 262_143, 262_204];
+        let mut wheel = TimerWheel::new();
+        wheel.current = 60;
+        for &deadline in &deadlines {
+            assert!(wheel.insert(entry(deadline)).is_ok());
+        }
+
+        let mut fired = Vec::new();
+        let mut expired = Vec::new();
+        for tick in 61..=270_000 {
+            wheel.advance(tick, &mut expired);
+            for entry in expired.drain(..) {
+                assert_eq!(entry.deadline, tick);
+                fired.push(entry.deadline);
+            }
+        }
+
+        assert_eq!(fired, deadlines);
+        assert_eq!(wheel.len, 0);
+    }
+
+    #[test_case]
+    fn test_wheel_removes_entries_and_rejects_late_inserts() {
+        let mut wheel = TimerWheel::new();
+        let mut expired = Vec::new();
+        let removed = entry(5000);
+        let state = removed.state.clone();
+        assert!(wheel.insert(removed).is_ok());
+        assert!(wheel.insert(entry(100)).is_ok());
+
+        // The removed entry is found after the wheel moved on.
+        wheel.advance(50, &mut expired);
+        assert!(expired.is_empty());
+        assert!(wheel.remove(5000, &state));
+        assert!(!wheel.remove(5000, &state));
+        assert_eq!(wheel.len, 1);
+        assert!(wheel.insert(entry(50)).is_err());
+
+        wheel.advance(6000, &mut expired);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(wheel.len, 0);
+    }
+}
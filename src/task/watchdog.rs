@@ -0,0 +1,151 @@
+//! A software watchdog catching hangs of the executor.
+//!
+//! Once armed, every timer interrupt counts a tick, and a low priority task
+//! polled by the executor being watched [feeds](feed) the watchdog by
+//! resetting the count. If a task never yields or the executor deadlocks,
+//! the count reaches the timeout and the timer interrupt reports the state of
+//! every task to serial, then carries out the watchdog's [Action].
+//!
+//! ```no_run
+//! # use toyos::task::{executor::Executor, watchdog::{self, Action}};
+//! # use toyos::time::Duration;
+//! # let mut executor = Executor::new();
+//! executor.spawn(watchdog::start(Duration::from_secs(5), Action::Report));
+//! ```
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use super::{executor, timer, Priority, Task};
+use crate::{
+    interrupts, power, serial_println,
+    time::{self, Duration},
+};
+
+/// What the watchdog does after reporting a hang.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Only reports the hang, again after each time the watchdog is fed.
+    Report,
+    /// Restarts the machine, which gets CI runs going again.
+    Reboot,
+}
+
+/// Number of ticks without feeding after which the watchdog fires, or 0 while
+/// it is disarmed.
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Number of ticks since the watchdog was last fed.
+static ELAPSED: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the watchdog has fired, until it is fed again.
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+static ACTION: AtomicU8 = AtomicU8::new(Action::Report as u8);
+
+/// Number of times the watchdog has fired.
+static FIRINGS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a task which arms the watchdog with the given timeout and action
+/// when first polled, then feeds it four times per timeout. The task must be
+/// spawned on the executor to watch.
+pub fn start(timeout: Duration, action: Action) -> Task {
+    let future = async move {
+        arm(time::duration_to_ticks(timeout), action);
+        loop {
+            feed();
+            timer::sleep(timeout / 4).await;
+        }
+    };
+    Task::with_priority(future, Priority::Low).named("watchdog")
+}
+
+fn arm(timeout: u64, action: Action) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+    ELAPSED.store(0, Ordering::Relaxed);
+    TIMEOUT.store(timeout.max(1), Ordering::Relaxed);
+}
+
+/// Disarms the watchdog. It stays disarmed until a task returned by [start]
+/// is polled again.
+pub fn disarm() {
+    TIMEOUT.store(0, Ordering::Relaxed);
+}
+
+/// Resets the count of ticks towards the timeout.
+pub fn feed() {
+    ELAPSED.store(0, Ordering::Relaxed);
+    FIRED.store(false, Ordering::Relaxed);
+}
+
+/// Returns the number of times the watchdog has fired since boot.
+pub fn firings() -> u64 {
+    FIRINGS.load(Ordering::Relaxed)
+}
+
+fn action() -> Action {
+    match ACTION.load(Ordering::Relaxed) {
+        action if action == Action::Reboot as u8 => Action::Reboot,
+        _ => Action::Report,
+    }
+}
+
+/// Counts a tick, firing the watchdog once it times out. Called by the timer
+/// interrupt handler.
+pub(crate) fn tick() {
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 || ELAPSED.fetch_add(1, Ordering::Relaxed) + 1 < timeout {
+        return;
+    }
+    if !FIRED.swap(true, Ordering::Relaxed) {
+        fire(timeout);
+    }
+}
+
+/// Reports the hang and carries out the action. Runs in interrupt context
+/// while the executor may hold any lock, so it only tries locks and does not
+/// allocate.
+fn fire(timeout: u64) {
+    FIRINGS.fetch_add(1, Ordering::Relaxed);
+    serial_println!(
+        "watchdog: not fed for {} ms; tasks:",
+        time::ticks_to_duration(timeout).as_millis()
+    );
+    if !executor::dump_tasks() {
+        serial_println!("watchdog: task list is locked");
+    }
+    serial_println!("watchdog: interrupts:\n{}", interrupts::stats());
+
+    if action() == Action::Reboot {
+        power::reboot();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_watchdog() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let before = firings();
+            arm(3, Action::Report);
+            tick();
+            tick();
+            feed();
+            tick();
+            tick();
+            assert_eq!(firings(), before);
+            tick();
+            tick();
+            assert_eq!(firings(), before + 1);
+
+            feed();
+            (0..3).for_each(|_| tick());
+            assert_eq!(firings(), before + 2);
+            disarm();
+            (0..3).for_each(|_| tick());
+            assert_eq!(firings(), before + 2);
+        });
+    }
+}
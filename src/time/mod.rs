@@ -0,0 +1,174 @@
+//! The `time` module keeps track of the passage of time in the kernel.
+//!
+//! The programmable interval timer (see [pit]) is configured to fire at
+//! [TICK_HZ] and every timer interrupt increments a global tick counter. Other
+//! subsystems, such as sleeping and scheduling, consume this counter via
+//! [ticks] instead of programming hardware timers themselves.
+//!
+//! Once the local APIC is enabled, [use_apic_timer] hands the tick over to the
+//! [local APIC timers](crate::interrupts::apic), which unlike the PIT give
+//! every CPU a timer interrupt of its own. The [TickSource] tells which
+//! drives the counter.
+//!
+//! For higher resolution measurements, [Instant] reads the processor's time
+//! stamp counter which is calibrated against the PIT during [init].
+//!
+//! The wall-clock time is kept as the time of boot, which a clock driver such
+//! as the [RTC](crate::drivers::rtc) sets with [set_wall_clock], plus the
+//! [uptime].
+
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+pub use core::time::Duration;
+pub use tsc::Instant;
+
+pub mod pit;
+pub mod tsc;
+
+use crate::interrupts::{self, apic};
+
+/// The frequency, in Hz, that the timer interrupt is configured to fire at.
+pub const TICK_HZ: u32 = 1000;
+
+/// Number of timer interrupts since the timer was initialized.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The actual frequency of the timer interrupt, which may differ slightly from
+/// the requested frequency due to the PIT's integer divisor.
+static FREQUENCY: AtomicU32 = AtomicU32::new(pit::DEFAULT_FREQUENCY);
+
+/// What drives the tick counter.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    /// The bootstrap processor's local APIC timer.
+    Apic,
+}
+
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+
+/// The Unix time of boot in nanoseconds, or 0 if the wall-clock time has not
+/// been set.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the timer to fire at [TICK_HZ] and calibrates the TSC.
+pub fn init() {
+    let frequency = pit::set_frequency(TICK_HZ);
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    tsc::init();
+}
+
+/// Calibrates the local APIC timers and moves the tick counter from the PIT
+/// to them, masking the PIT's IRQ. Returns `false`, leaving the PIT in
+/// charge, if the local APIC is not enabled or its timer too slow.
+///
+/// Must be called after [apic::init], and before the application processors
+/// are started so that they start their timers too.
+pub fn use_apic_timer(mode: apic::TimerMode) -> bool {
+    if tick_source() == TickSource::Apic {
+        return true;
+    }
+    if apic::calibrate_timer() == 0 {
+        return false;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let Some(frequency) = apic::start_timer(mode, TICK_HZ) else {
+            return false;
+        };
+        interrupts::disable_irq(0);
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        TICK_SOURCE.store(TickSource::Apic as u8, Ordering::Relaxed);
+        true
+    })
+}
+
+/// Returns what drives the tick counter.
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::Relaxed) {
+        source if source == TickSource::Apic as u8 => TickSource::Apic,
+        _ => TickSource::Pit,
+    }
+}
+
+/// Returns the number of timer ticks since boot.
+#[inline]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the frequency, in Hz, at which the tick counter advances.
+#[inline]
+pub fn tick_frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Converts a number of ticks into a [Duration].
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = tick_frequency() as u64;
+    let secs = ticks / frequency;
+    let nanos = (ticks % frequency) * 1_000_000_000 / frequency;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Converts a [Duration] into a number of ticks, rounding up so that waiting
+/// for the returned number of ticks never waits less than `duration`.
+/// Saturates at [u64::MAX] for durations too long to count in ticks.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = tick_frequency() as u128;
+    let ticks = (duration.as_nanos() * frequency).div_ceil(1_000_000_000);
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Returns the time elapsed since the timer was initialized with tick
+/// resolution.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Returns the time elapsed since the timer was initialized with the
+/// resolution of the [TSC](tsc::uptime), or zero before [init].
+pub fn precise_uptime() -> Duration {
+    tsc::uptime()
+}
+
+/// Sets the wall-clock time to `now`, the time since the Unix epoch.
+pub fn set_wall_clock(now: Duration) {
+    let boot = now.saturating_sub(uptime()).as_nanos() as u64;
+    BOOT_TIME.store(boot.max(1), Ordering::Relaxed);
+}
+
+/// Returns the time since the Unix epoch, or `None` if the wall-clock time
+/// has not been set.
+pub fn wall_clock() -> Option<Duration> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(Duration::from_nanos(boot) + uptime()),
+    }
+}
+
+/// Advances the tick counter. Called by the timer interrupt handler.
+#[inline]
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_ticks_advance() {
+        let start = ticks();
+        while ticks() == start {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    #[test_case]
+    fn test_duration_round_trip() {
+        let ticks = duration_to_ticks(Duration::from_secs(2));
+        assert_eq!(ticks_to_duration(ticks).as_secs(), 2);
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+        assert_eq!(duration_to_ticks(Duration::MAX), u64::MAX);
+    }
+}
@@ -0,0 +1,121 @@
+//! Driver for the Intel 8253/8254 programmable interval timer (PIT).
+//!
+//! Channel 0 of the PIT is wired to IRQ 0 and drives the timer interrupt. The
+//! PIT is clocked at roughly 1.193182 MHz and divides this clock by a 16-bit
+//! reload value to produce its output frequency.
+//!
+//! See: https://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+use crate::sync::IrqSpinlock;
+
+/// The frequency of the oscillator driving the PIT in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The frequency channel 0 runs at when left as configured by the BIOS, which
+/// uses a reload value of 65536.
+pub const DEFAULT_FREQUENCY: u32 = BASE_FREQUENCY / 65536;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+
+/// The keyboard controller's port B, which controls the gate of channel 2 and
+/// exposes its output.
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT: u8 = 1 << 5;
+
+/// Command selecting channel 0, lobyte/hibyte access, mode 2 (rate generator)
+/// and binary counting.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+
+/// Command selecting channel 2, lobyte/hibyte access, mode 0 (interrupt on
+/// terminal count) and binary counting.
+const CHANNEL_2_ONE_SHOT: u8 = 0xb0;
+
+/// Serializes access to the PIT's command and data ports.
+static PIT: IrqSpinlock<()> = IrqSpinlock::new(());
+
+/// Returns the reload value which most closely produces the given frequency.
+///
+/// A reload value of 0 is interpreted by the PIT as 65536.
+fn divisor_for(frequency: u32) -> u16 {
+    let divisor = BASE_FREQUENCY / frequency.max(1);
+    match divisor {
+        0 => 1,
+        1..=0xffff => divisor as u16,
+        _ => 0,
+    }
+}
+
+/// Programs channel 0 to fire at (approximately) the given frequency in Hz.
+///
+/// Returns the actual frequency the PIT was configured with.
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = divisor_for(frequency);
+    {
+        let _guard = PIT.lock();
+        let mut command: Port<u8> = Port::new(COMMAND_PORT);
+        let mut data: Port<u8> = Port::new(CHANNEL_0_PORT);
+        unsafe {
+            command.write(CHANNEL_0_RATE_GENERATOR);
+            data.write(divisor as u8);
+            data.write((divisor >> 8) as u8);
+        }
+    }
+
+    match divisor {
+        0 => DEFAULT_FREQUENCY,
+        divisor => BASE_FREQUENCY / divisor as u32,
+    }
+}
+
+/// Busy-waits for the given duration using PIT channel 2.
+///
+/// Channel 2 is independent of the timer interrupt, so this works with
+/// interrupts disabled, making it suitable for calibrating other clocks. The
+/// maximum wait is roughly 55 ms; longer durations are clamped.
+pub fn busy_wait(duration: Duration) {
+    let count = (duration.as_nanos() * BASE_FREQUENCY as u128 / 1_000_000_000).clamp(1, 0xffff);
+
+    let _guard = PIT.lock();
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_PORT);
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    unsafe {
+        // Disable the gate and speaker while programming the counter.
+        let control = port_b.read() & !(PORT_B_GATE | PORT_B_SPEAKER);
+        port_b.write(control);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // Raising the gate starts the countdown. The output goes high once
+        // the counter reaches zero.
+        port_b.write(control | PORT_B_GATE);
+        while port_b.read() & PORT_B_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+
+        port_b.write(control);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_divisor_for() {
+        assert_eq!(divisor_for(1000), 1193);
+        assert_eq!(divisor_for(BASE_FREQUENCY), 1);
+        assert_eq!(divisor_for(u32::MAX), 1);
+        assert_eq!(divisor_for(1), 0);
+    }
+}
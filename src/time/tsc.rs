@@ -0,0 +1,186 @@
+//! The `tsc` module provides a high resolution monotonic clock based on the
+//! processor's time stamp counter (TSC).
+//!
+//! The TSC counts cycles at a processor specific rate which must be measured
+//! at boot. [init] calibrates it against the PIT, after which [Instant] can be
+//! used to measure elapsed time with sub-microsecond resolution.
+//!
+//! On processors without an invariant TSC (see [is_invariant]) the counting
+//! rate may change with power states, making measurements unreliable.
+//!
+//! See: https://wiki.osdev.org/TSC
+
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::pit;
+
+/// How long each calibration round waits for.
+const CALIBRATION_PERIOD: Duration = Duration::from_millis(10);
+
+/// Number of calibration rounds, the fastest of which is used.
+const CALIBRATION_ROUNDS: usize = 3;
+
+/// Calibrated TSC frequency in Hz, or zero if not yet calibrated.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC is invariant, as reported by CPUID.
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// The value of the TSC when [init] was called.
+static START: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the current value of the time stamp counter.
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Calibrates the TSC against the PIT.
+///
+/// This busy-waits for a few tens of milliseconds with interrupts disabled and
+/// should only be called once during boot.
+pub fn init() {
+    use x86_64::instructions::interrupts;
+
+    START.store(rdtsc(), Ordering::Relaxed);
+    INVARIANT.store(crate::cpu::has_invariant_tsc(), Ordering::Relaxed);
+
+    let cycles = interrupts::without_interrupts(|| {
+        (0..CALIBRATION_ROUNDS)
+            .map(|_| {
+                let start = rdtsc();
+                pit::busy_wait(CALIBRATION_PERIOD);
+                rdtsc() - start
+            })
+            .min()
+            .unwrap_or(0)
+    });
+
+    let frequency = cycles as u128 * 1_000_000_000 / CALIBRATION_PERIOD.as_nanos();
+    FREQUENCY.store(frequency as u64, Ordering::Relaxed);
+}
+
+/// Returns the calibrated TSC frequency in Hz, or zero if [init] has not been
+/// called.
+#[inline]
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the TSC ticks at a constant rate.
+#[inline]
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Returns the time elapsed since [init] was called with the resolution of
+/// the TSC, or zero if it has not been called.
+pub fn uptime() -> Duration {
+    cycles_to_duration(rdtsc().saturating_sub(START.load(Ordering::Relaxed)))
+}
+
+/// Converts a number of TSC cycles into a [Duration].
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    match frequency() {
+        0 => Duration::ZERO,
+        frequency => {
+            let nanos = cycles as u128 * 1_000_000_000 / frequency as u128;
+            Duration::from_nanos(nanos as u64)
+        }
+    }
+}
+
+/// Converts a [Duration] into a number of TSC cycles.
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// A measurement of the monotonic TSC clock.
+///
+/// Instants are opaque and are only useful when compared with one another,
+/// mirroring `std::time::Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns an instant corresponding to "now".
+    #[inline]
+    pub fn now() -> Instant {
+        Instant(rdtsc())
+    }
+
+    /// Returns the amount of time elapsed from another instant to this one, or
+    /// zero if that instant is later than this one.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        cycles_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Returns the amount of time elapsed since this instant was created.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the raw TSC value of this instant.
+    pub fn as_cycles(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + duration_to_cycles(rhs))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_tsc_is_calibrated() {
+        assert!(frequency() > 0);
+    }
+
+    #[test_case]
+    fn test_instant_is_monotonic() {
+        let a = Instant::now();
+        let b = Instant::now();
+        assert!(b >= a);
+        assert_eq!(a.duration_since(b), Duration::ZERO);
+    }
+
+    #[test_case]
+    fn test_uptime_is_monotonic() {
+        let a = uptime();
+        let b = uptime();
+        assert!(a > Duration::ZERO);
+        assert!(b >= a);
+    }
+
+    #[test_case]
+    fn test_instant_measures_pit_delay() {
+        let start = Instant::now();
+        pit::busy_wait(Duration::from_millis(5));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(4));
+        assert!(elapsed <= Duration::from_millis(50));
+    }
+}
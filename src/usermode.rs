@@ -0,0 +1,562 @@
+//! Execution of code in ring 3.
+//!
+//! [enter] switches to user mode by building the stack frame of an interrupt
+//! which arrived in ring 3 and returning from it with `iretq`. User code gets
+//! back into the kernel through interrupts; [EXIT_VECTOR] is the one gate it
+//! is allowed to raise itself, which ends the user mode session and makes
+//! [enter] return the value user code left in `rdi`.
+//!
+//! Before leaving the kernel, [enter] saves the callee saved registers on the
+//! current stack and points both the TSS ring 0 stack and the per-CPU kernel
+//! stack used by [system calls](crate::syscall) just below them. Interrupts
+//! and system calls made while in user mode are therefore handled on the
+//! stack of the thread which called [enter], and [return_to_kernel] finds the
+//! saved registers at a known location. Since every thread has its own stack,
+//! each user mode session gets its own kernel stack, which the scheduler
+//! switches to along with the thread.
+//!
+//! User code runs with its own GS base; see [percpu] for how the kernel's is
+//! swapped back in on every entry.
+//!
+//! User code and stacks live in the range from [USER_START] to [USER_END],
+//! which the kernel itself never maps.
+//!
+//! See: https://wiki.osdev.org/Getting_to_Ring_3
+
+use alloc::{vec, vec::Vec};
+use core::{arch::global_asm, mem::offset_of, sync::atomic::Ordering};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        rflags::RFlags,
+        segmentation::{Segment, DS, ES, SS},
+    },
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    gdt,
+    mem::{self, GlobalFrameAllocator},
+    percpu::{self, KERNEL_STACK_OFFSET},
+    process,
+};
+
+/// Lowest address available to user mode.
+///
+/// The kernel's code lies in the first level 4 page table entry. The
+/// physical memory mapping, the boot stack and the boot information are
+/// pinned to the upper half in `[package.metadata.bootloader]` in
+/// `Cargo.toml`, and the bootloader removes its recursive mapping before
+/// starting the kernel, so nothing else lives in the lower half.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+
+/// End of the range available to user mode, below the kernel heap.
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+/// Initial top of the stack of a user program. The page above it is left
+/// unmapped.
+pub const USER_STACK_TOP: u64 = USER_END - 4096;
+
+/// Size of the stack mapped for a user program.
+pub const USER_STACK_SIZE: u64 = 16 * 4096;
+
+/// Interrupt vector through which user code leaves user mode.
+pub const EXIT_VECTOR: u8 = 0x80;
+
+/// The registers of user code, with which [resume] enters user mode.
+#[repr(C)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// Returns registers which start executing at `entry` with the stack
+    /// pointer `stack` and every other register cleared.
+    pub fn new(entry: VirtAddr, stack: VirtAddr) -> Self {
+        Registers {
+            rip: entry.as_u64(),
+            rsp: stack.as_u64(),
+            ..Registers::default()
+        }
+    }
+}
+
+/// Flags which user code may set. Interrupts are always enabled.
+const USER_FLAGS: RFlags = RFlags::CARRY_FLAG
+    .union(RFlags::PARITY_FLAG)
+    .union(RFlags::AUXILIARY_CARRY_FLAG)
+    .union(RFlags::ZERO_FLAG)
+    .union(RFlags::SIGN_FLAG)
+    .union(RFlags::DIRECTION_FLAG)
+    .union(RFlags::OVERFLOW_FLAG);
+
+global_asm!(
+    ".pushsection .text",
+    // Enters user mode with the registers at `rdi`, code segment `rsi` and
+    // stack segment `rdx`. `rcx` points at the TSS ring 0 stack pointer.
+    // Returns the status the session ends with.
+    ".global toyos_enter_user",
+    "toyos_enter_user:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The CPU aligns the ring 0 stack to 16 bytes before pushing the
+    // interrupt frame, so it must already be aligned for the frame to end up
+    // directly below the saved registers.
+    "sub rsp, 8",
+    "mov [rcx], rsp",
+    "mov gs:[{kernel_stack}], rsp",
+    "mov ds, dx",
+    "mov es, dx",
+    "push rdx", // ss
+    "push qword ptr [rdi + {rsp}]",
+    "push qword ptr [rdi + {rflags}]",
+    "push rsi", // cs
+    "push qword ptr [rdi + {rip}]",
+    "mov rax, [rdi + {rax}]",
+    "mov rbx, [rdi + {rbx}]",
+    "mov rcx, [rdi + {rcx}]",
+    "mov rdx, [rdi + {rdx}]",
+    "mov rsi, [rdi + {rsi}]",
+    "mov rbp, [rdi + {rbp}]",
+    "mov r8, [rdi + {r8}]",
+    "mov r9, [rdi + {r9}]",
+    "mov r10, [rdi + {r10}]",
+    "mov r11, [rdi + {r11}]",
+    "mov r12, [rdi + {r12}]",
+    "mov r13, [rdi + {r13}]",
+    "mov r14, [rdi + {r14}]",
+    "mov r15, [rdi + {r15}]",
+    "mov rdi, [rdi + {rdi}]",
+    "swapgs",
+    "iretq",
+    "",
+    // Ends the user mode session, making `toyos_enter_user` return `rdi`.
+    ".global toyos_user_return",
+    "toyos_user_return:",
+    "cli",
+    "mov rax, rdi",
+    "mov rsp, gs:[{kernel_stack}]",
+    // Skip the alignment padding.
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    "",
+    // Handler of the exit gate.
+    ".global toyos_user_exit",
+    "toyos_user_exit:",
+    // Ignore the gate if raised from ring 0; there is no session to end.
+    "test qword ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "jmp toyos_user_return",
+    "2:",
+    "iretq",
+    ".popsection",
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    rax = const offset_of!(Registers, rax),
+    rbx = const offset_of!(Registers, rbx),
+    rcx = const offset_of!(Registers, rcx),
+    rdx = const offset_of!(Registers, rdx),
+    rsi = const offset_of!(Registers, rsi),
+    rdi = const offset_of!(Registers, rdi),
+    rbp = const offset_of!(Registers, rbp),
+    r8 = const offset_of!(Registers, r8),
+    r9 = const offset_of!(Registers, r9),
+    r10 = const offset_of!(Registers, r10),
+    r11 = const offset_of!(Registers, r11),
+    r12 = const offset_of!(Registers, r12),
+    r13 = const offset_of!(Registers, r13),
+    r14 = const offset_of!(Registers, r14),
+    r15 = const offset_of!(Registers, r15),
+    rip = const offset_of!(Registers, rip),
+    rsp = const offset_of!(Registers, rsp),
+    rflags = const offset_of!(Registers, rflags),
+);
+
+extern "C" {
+    fn toyos_enter_user(
+        registers: *const Registers,
+        cs: u64,
+        ss: u64,
+        kernel_stack: *mut VirtAddr,
+    ) -> u64;
+
+    fn toyos_user_return(status: u64) -> !;
+
+    /// Entry point of the [EXIT_VECTOR] interrupt gate.
+    pub(crate) fn toyos_user_exit();
+}
+
+/// Runs user code starting at `entry` with the stack pointer `stack`, until
+/// it raises [EXIT_VECTOR]. Returns the value of `rdi` at that point.
+///
+/// # Safety
+///
+/// The code and stack must be mapped user accessible, and the code must not
+/// be able to reach kernel memory through any other user accessible mapping.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr) -> u64 {
+    resume(&Registers::new(entry, stack))
+}
+
+/// Runs user code with the given registers until the session ends, either
+/// through [EXIT_VECTOR] or [return_to_kernel]. Returns the session's exit
+/// status.
+///
+/// Only the flags in [USER_FLAGS] are taken from `registers`.
+///
+/// # Safety
+///
+/// See [enter].
+pub unsafe fn resume(registers: &Registers) -> u64 {
+    let selectors = gdt::selectors();
+    let kernel_stack = gdt::kernel_stack();
+    let interrupts_enabled = interrupts::are_enabled();
+
+    let mut registers = registers.clone();
+    registers.rflags =
+        (RFlags::from_bits_truncate(registers.rflags) & USER_FLAGS | RFlags::INTERRUPT_FLAG).bits()
+            | 0x2;
+
+    // No other thread may enter user mode on this CPU between the ring 0
+    // stack being set and the switch.
+    interrupts::disable();
+    let status = toyos_enter_user(
+        &registers,
+        selectors.user_code.0 as u64,
+        selectors.user_data.0 as u64,
+        gdt::kernel_stack_slot(),
+    );
+
+    // Sessions end with interrupts disabled and user data segments loaded.
+    SS::set_reg(selectors.kernel_data);
+    DS::set_reg(selectors.kernel_data);
+    ES::set_reg(selectors.kernel_data);
+    gdt::set_kernel_stack(kernel_stack);
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    status
+}
+
+/// Returns the stack interrupts and system calls arriving from user mode are
+/// handled on by the calling CPU.
+pub(crate) fn kernel_stack() -> VirtAddr {
+    gdt::kernel_stack()
+}
+
+/// Sets the stack interrupts and system calls arriving from user mode are
+/// handled on by the calling CPU. Used by the scheduler to switch between
+/// threads with user mode sessions.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    gdt::set_kernel_stack(top);
+    percpu::kernel_stack().store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Ends the user mode session running on this CPU, making [enter] return
+/// `status`. The stack this is called on is abandoned without running any
+/// destructors.
+///
+/// # Safety
+///
+/// Must be called while handling a system call or interrupt which arrived in
+/// user mode, on the thread that called [enter].
+pub unsafe fn return_to_kernel(status: u64) -> ! {
+    toyos_user_return(status)
+}
+
+/// Returns `true` if `addr` lies in the range reserved for user mode.
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_START..USER_END).contains(&addr.as_u64())
+}
+
+/// Maps zeroed, user accessible pages covering `len` bytes from `start` in
+/// the active address space.
+///
+/// # Panics
+///
+/// Panics if the range is not within the user range.
+pub fn map_user_pages(
+    start: VirtAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let pages = user_pages(start, len);
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut frames = GlobalFrameAllocator;
+    mem::with_page_table(|page_table| {
+        for page in pages {
+            let frame = frames
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                core::ptr::write_bytes(
+                    mem::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                    0,
+                    4096,
+                );
+                page_table.map_to(page, frame, flags, &mut frames)?.flush();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmaps the pages covering `len` bytes from `start` and frees their frames.
+/// Pages which are not mapped are skipped.
+///
+/// # Panics
+///
+/// Panics if the range is not within the user range.
+pub fn unmap_user_pages(start: VirtAddr, len: u64) {
+    let pages = user_pages(start, len);
+    mem::with_page_table(|page_table| {
+        for page in pages {
+            if let Ok((frame, flush)) = page_table.unmap(page) {
+                flush.flush();
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            }
+        }
+    });
+}
+
+/// Returns `true` if every byte from `addr` to `addr + len` lies in user
+/// space and is mapped user accessible, and writable if `write` is set.
+/// Pages the current process reserved but has not accessed yet are mapped.
+pub fn is_user_range(addr: u64, len: u64, write: bool) -> bool {
+    use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+
+    let end = match addr.checked_add(len) {
+        Some(end) if addr >= USER_START && end <= USER_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let flags = |page: Page| {
+        mem::with_page_table(
+            |page_table| match page_table.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => Some(flags),
+                _ => None,
+            },
+        )
+    };
+    user_pages(VirtAddr::new(addr), end - addr).all(|page| {
+        let flags =
+            flags(page).or_else(|| process::populate(page.start_address()).then(|| flags(page))?);
+        flags.is_some_and(|flags| flags.contains(required))
+    })
+}
+
+/// Copies `len` bytes from user memory at `addr`, or returns `None` if the
+/// range is not readable by user mode.
+pub fn copy_from_user(addr: u64, len: u64) -> Option<Vec<u8>> {
+    if !is_user_range(addr, len, false) {
+        return None;
+    }
+
+    let mut buffer = vec![0; len as usize];
+    unsafe { core::ptr::copy_nonoverlapping(addr as *const u8, buffer.as_mut_ptr(), buffer.len()) };
+    Some(buffer)
+}
+
+/// Copies the NUL terminated string at `addr` in user memory, without the
+/// terminator. Returns `None` if the string is not readable by user mode or
+/// is longer than `max_len` bytes.
+pub fn copy_string_from_user(addr: u64, max_len: u64) -> Option<Vec<u8>> {
+    // Chunks never run past the end of user space once they start in it, so
+    // their bounds cannot overflow.
+    if !(USER_START..USER_END).contains(&addr) {
+        return None;
+    }
+    let mut string = Vec::new();
+    let mut chunk_start = addr;
+    loop {
+        // Check one page at a time, so the string may end right before
+        // unmapped memory.
+        let chunk_end = (chunk_start + 1).next_multiple_of(4096);
+        let chunk = copy_from_user(chunk_start, chunk_end - chunk_start)?;
+        let terminator = chunk.iter().position(|&byte| byte == 0);
+        string.extend_from_slice(&chunk[..terminator.unwrap_or(chunk.len())]);
+        if string.len() as u64 > max_len {
+            return None;
+        }
+        if terminator.is_some() {
+            return Some(string);
+        }
+        chunk_start = chunk_end;
+    }
+}
+
+/// Copies `data` to user memory at `addr`, returning `false` if the range is
+/// not writable by user mode.
+pub fn copy_to_user(addr: u64, data: &[u8]) -> bool {
+    if !is_user_range(addr, data.len() as u64, true) {
+        return false;
+    }
+
+    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, data.len()) };
+    true
+}
+
+/// Returns the pages covering `len` bytes from `start`.
+///
+/// # Panics
+///
+/// Panics if the range is not within the user range.
+pub(crate) fn user_pages(start: VirtAddr, len: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    let end = start + len;
+    assert!(
+        is_user_address(start) && end.as_u64() <= USER_END,
+        "{:?}..{:?} is outside of user space",
+        start,
+        end
+    );
+    Page::range(
+        Page::containing_address(start),
+        Page::containing_address(end.align_up(4096u64)),
+    )
+}
+
+/// Copies position independent machine code to [USER_START], runs it in
+/// user mode on a fresh stack and returns its exit status. The code and
+/// stack are unmapped again afterwards.
+pub fn run(code: &[u8]) -> Result<u64, MapToError<Size4KiB>> {
+    let code_start = VirtAddr::new(USER_START);
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+
+    let result = map_user_pages(code_start, code.len() as u64, PageTableFlags::WRITABLE)
+        .and_then(|()| map_user_pages(stack_bottom, USER_STACK_SIZE, PageTableFlags::WRITABLE));
+    let status = result.map(|()| unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), code_start.as_mut_ptr(), code.len());
+        enter(code_start, VirtAddr::new(USER_STACK_TOP))
+    });
+
+    unmap_user_pages(code_start, code.len() as u64);
+    unmap_user_pages(stack_bottom, USER_STACK_SIZE);
+    status
+}
+
+/// Returns the code of a test program as a `&'static [u8]`, given the label
+/// at its start in a `global_asm!` block. The program ends at the label of
+/// the same name with an `_end` suffix.
+#[cfg(test)]
+macro_rules! user_program {
+    ($name:ident) => {{
+        extern "C" {
+            static $name: u8;
+            #[link_name = concat!(stringify!($name), "_end")]
+            static END: u8;
+        }
+        let start = core::ptr::addr_of!($name);
+        let end = core::ptr::addr_of!(END);
+        unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+    }};
+}
+
+#[cfg(test)]
+pub(crate) use user_program;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    global_asm!(
+        ".pushsection .text",
+        // Exits with the privilege level it runs at.
+        "toyos_test_privilege_level:",
+        "mov rdi, cs",
+        "and rdi, 3",
+        "int {exit}",
+        "toyos_test_privilege_level_end:",
+        // Pushes to its stack and spins long enough to be interrupted by the
+        // timer before exiting with 42.
+        "toyos_test_spin:",
+        "push 42",
+        "mov ecx, 50000000",
+        "2:",
+        "dec ecx",
+        "jnz 2b",
+        "pop rdi",
+        "int {exit}",
+        "toyos_test_spin_end:",
+        ".popsection",
+        exit = const EXIT_VECTOR,
+    );
+
+    #[test_case]
+    fn test_runs_in_ring_3() {
+        let code = user_program!(toyos_test_privilege_level);
+        assert_eq!(run(code).ok(), Some(3));
+        assert!(interrupts::are_enabled());
+    }
+
+    #[test_case]
+    fn test_interrupts_in_user_mode() {
+        let code = user_program!(toyos_test_spin);
+        let ticks = crate::time::ticks();
+        assert_eq!(run(code).ok(), Some(42));
+        assert!(crate::time::ticks() > ticks);
+    }
+
+    #[test_case]
+    fn test_user_range_excludes_kernel_mappings() {
+        let physical_memory = mem::phys_to_virt(x86_64::PhysAddr::new(0));
+        assert!(!is_user_address(physical_memory));
+        assert!(!is_user_address(VirtAddr::new(
+            crate::allocator::HEAP_START as u64
+        )));
+        assert!(!is_user_address(VirtAddr::from_ptr(&USER_START)));
+
+        let local = 0u8;
+        assert!(!is_user_address(VirtAddr::from_ptr(&local)));
+    }
+
+    #[test_case]
+    fn test_copy_string_from_kernel_addresses() {
+        assert_eq!(copy_string_from_user(u64::MAX, 16), None);
+        assert_eq!(copy_string_from_user(USER_END - 1, 16), None);
+        assert_eq!(copy_string_from_user(0, 16), None);
+    }
+
+    #[test_case]
+    fn test_exit_gate_ignored_in_ring_0() {
+        unsafe { core::arch::asm!("int {}", const EXIT_VECTOR) };
+    }
+}
@@ -1,23 +1,118 @@
 #![allow(dead_code)]
 
-use core::fmt::Write;
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt::{self, Write},
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::Once;
 use volatile::Volatile;
+use x86_64::{instructions::port::Port, PhysAddr};
 
-/// The width of the VGA buffer in number of `ScreenChar`s.
-const BUFFER_WIDTH: usize = 80;
+use crate::{allocator, console::Console, mem, sync::IrqSpinlock};
 
-/// The height of the VCA buffer in number of lines.
-const BUFFER_HEIGHT: usize = 25;
+/// The most columns of any [TextMode].
+const MAX_WIDTH: usize = 80;
+
+/// The most rows of any [TextMode].
+const MAX_HEIGHT: usize = 50;
+
+/// Physical address of the VGA character buffer.
+const BUFFER_ADDRESS: u64 = 0xb8000;
+
+/// Physical address of the font, while it is mapped by [with_font].
+const FONT_ADDRESS: u64 = 0xa0000;
+
+/// Number of scan lines of a character in the font of the BIOS.
+const BIOS_CHAR_HEIGHT: usize = 16;
+
+/// The default distance between tab stops.
+pub const TAB_WIDTH: usize = 8;
+
+/// The number of lines scrolled off the top of the screen which are kept to
+/// be scrolled back to.
+pub const SCROLLBACK_LINES: usize = 500;
+
+/// Port selecting a register of the CRT controller.
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+
+/// Port reading and writing the selected register of the CRT controller.
+const CRTC_DATA_PORT: u16 = 0x3d5;
+
+/// CRT controller register holding the first scan line of the cursor, and
+/// the bit disabling it.
+const CURSOR_START_REGISTER: u8 = 0x0a;
+
+/// CRT controller register holding the last scan line of the cursor.
+const CURSOR_END_REGISTER: u8 = 0x0b;
+
+const CURSOR_LOCATION_HIGH_REGISTER: u8 = 0x0e;
+const CURSOR_LOCATION_LOW_REGISTER: u8 = 0x0f;
+
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// CRT controller register holding the last scan line of a character.
+const MAX_SCAN_LINE_REGISTER: u8 = 0x09;
+
+/// Ports selecting and accessing a register of the sequencer.
+const SEQUENCER_ADDRESS_PORT: u16 = 0x3c4;
+const SEQUENCER_DATA_PORT: u16 = 0x3c5;
+
+/// Ports selecting and accessing a register of the graphics controller.
+const GRAPHICS_ADDRESS_PORT: u16 = 0x3ce;
+const GRAPHICS_DATA_PORT: u16 = 0x3cf;
+
+/// Sequencer register selecting the planes written to.
+const MAP_MASK_REGISTER: u8 = 0x02;
+
+/// Sequencer register selecting how memory is addressed.
+const MEMORY_MODE_REGISTER: u8 = 0x04;
+
+/// Graphics controller register selecting the plane read from.
+const READ_MAP_REGISTER: u8 = 0x04;
+
+/// Graphics controller register selecting how memory is read and written.
+const GRAPHICS_MODE_REGISTER: u8 = 0x05;
+
+/// Graphics controller register selecting where memory is mapped.
+const MISCELLANEOUS_REGISTER: u8 = 0x06;
+
+/// The plane holding the font.
+const FONT_PLANE: u8 = 2;
+
+/// The line shown in the status bar, or `None` while there is none.
+static STATUS_BAR: IrqSpinlock<Option<Line>> = IrqSpinlock::new(None);
+
+/// Whether the top row is reserved for the status bar.
+static STATUS_BAR_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// The text mode of the screen.
+static MODE: IrqSpinlock<TextMode> = IrqSpinlock::new(TextMode::Text80x25);
+
+/// The shape of the hardware cursor, which is scaled to the height of the
+/// characters.
+static CURSOR_SHAPE: IrqSpinlock<CursorShape> = IrqSpinlock::new(CursorShape::Underline);
+
+/// The font the BIOS loaded, saved when it is first replaced.
+static BIOS_FONT: Once<Font> = Once::new();
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    /// Writes to the screen, as the first [virtual terminal](crate::vt).
+    pub static ref WRITER: IrqSpinlock<Writer> = {
+        let mut writer = Writer::hidden();
+        // Only the first page of the buffer is mapped this low, which holds
+        // the 80x25 mode.
+        writer.screen = Some(unsafe { &mut *(BUFFER_ADDRESS as *mut Buffer) });
+        IrqSpinlock::new(writer)
+    };
 }
 
 /// VGA foreground and background color codes.
@@ -42,6 +137,142 @@ pub enum Color {
     White = 15,
 }
 
+/// The shapes of the hardware cursor, as the scan lines of the character
+/// cell it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    /// The bottom two scan lines.
+    Underline,
+    /// The lower half of the cell.
+    HalfBlock,
+    /// The whole cell.
+    Block,
+}
+
+impl CursorShape {
+    /// Returns the first and last scan line of the shape, for characters of
+    /// the given number of scan lines.
+    fn scan_lines(self, char_height: u8) -> (u8, u8) {
+        let last = char_height - 1;
+        match self {
+            CursorShape::Underline => (last - 1, last),
+            CursorShape::HalfBlock => (char_height / 2, last),
+            CursorShape::Block => (0, last),
+        }
+    }
+}
+
+/// The text modes the screen can be switched to with [set_mode]. They share
+/// the 400 scan lines of the 80x25 mode the BIOS sets up, which shorter
+/// characters fit more rows into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    /// 25 rows of 16 scan lines, the mode at boot.
+    Text80x25,
+    /// 28 rows of 14 scan lines.
+    Text80x28,
+    /// 50 rows of 8 scan lines.
+    Text80x50,
+}
+
+impl TextMode {
+    /// Returns the number of columns of the mode.
+    pub fn columns(self) -> usize {
+        80
+    }
+
+    /// Returns the number of rows of the mode.
+    pub fn rows(self) -> usize {
+        match self {
+            TextMode::Text80x25 => 25,
+            TextMode::Text80x28 => 28,
+            TextMode::Text80x50 => 50,
+        }
+    }
+
+    /// Returns the number of scan lines of a character.
+    fn char_height(self) -> u8 {
+        match self {
+            TextMode::Text80x25 => 16,
+            TextMode::Text80x28 => 14,
+            TextMode::Text80x50 => 8,
+        }
+    }
+}
+
+/// Reads a register of the CRT controller.
+fn crtc_read(register: u8) -> u8 {
+    unsafe {
+        Port::new(CRTC_ADDRESS_PORT).write(register);
+        Port::new(CRTC_DATA_PORT).read()
+    }
+}
+
+/// Writes a register of the CRT controller.
+fn crtc_write(register: u8, value: u8) {
+    unsafe {
+        Port::new(CRTC_ADDRESS_PORT).write(register);
+        Port::new(CRTC_DATA_PORT).write(value);
+    }
+}
+
+/// Reads and writes a register behind a pair of address and data ports, and
+/// returns its previous value.
+fn swap_register(address_port: u16, register: u8, value: u8) -> u8 {
+    unsafe {
+        Port::new(address_port).write(register);
+        let mut data = Port::new(address_port + 1);
+        let previous = data.read();
+        data.write(value);
+        previous
+    }
+}
+
+/// The glyphs of the 256 characters, each 32 scan lines of 8 pixels of
+/// which the first are shown.
+type Font = [[u8; 32]; 256];
+
+/// Calls `f` with the font, which is mapped in place of the character buffer
+/// meanwhile.
+///
+/// # Panics
+///
+/// Panics if called before [mem::init](crate::mem::init).
+fn with_font<R>(f: impl FnOnce(*mut Font) -> R) -> R {
+    let font = mem::phys_to_virt(PhysAddr::new(FONT_ADDRESS)).as_mut_ptr();
+    // The font plane is written alone and read, linearly, at 0xa0000.
+    let saved = [
+        swap_register(SEQUENCER_ADDRESS_PORT, MAP_MASK_REGISTER, 1 << FONT_PLANE),
+        swap_register(SEQUENCER_ADDRESS_PORT, MEMORY_MODE_REGISTER, 0x07),
+        swap_register(GRAPHICS_ADDRESS_PORT, READ_MAP_REGISTER, FONT_PLANE),
+        swap_register(GRAPHICS_ADDRESS_PORT, GRAPHICS_MODE_REGISTER, 0x00),
+        swap_register(GRAPHICS_ADDRESS_PORT, MISCELLANEOUS_REGISTER, 0x04),
+    ];
+    let result = f(font);
+    swap_register(SEQUENCER_ADDRESS_PORT, MAP_MASK_REGISTER, saved[0]);
+    swap_register(SEQUENCER_ADDRESS_PORT, MEMORY_MODE_REGISTER, saved[1]);
+    swap_register(GRAPHICS_ADDRESS_PORT, READ_MAP_REGISTER, saved[2]);
+    swap_register(GRAPHICS_ADDRESS_PORT, GRAPHICS_MODE_REGISTER, saved[3]);
+    swap_register(GRAPHICS_ADDRESS_PORT, MISCELLANEOUS_REGISTER, saved[4]);
+    result
+}
+
+/// Returns the font of the BIOS squeezed into characters of the given
+/// number of scan lines. Each scan line is drawn where any of the scan lines
+/// it takes the place of are, so that thin strokes are kept.
+fn scale_font(bios: &Font, char_height: u8) -> Font {
+    let char_height = char_height as usize;
+    let mut font = [[0; 32]; 256];
+    for (glyph, bios_glyph) in font.iter_mut().zip(bios) {
+        for (line, bits) in glyph[..char_height].iter_mut().enumerate() {
+            let first = line * BIOS_CHAR_HEIGHT / char_height;
+            let end = (line + 1) * BIOS_CHAR_HEIGHT / char_height;
+            *bits = bios_glyph[first..end].iter().fold(0, |bits, &b| bits | b);
+        }
+    }
+    font
+}
+
 /// An 8-bit code containing a foreground and background color.
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -64,54 +295,414 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+impl ScreenChar {
+    const BLANK: ScreenChar = ScreenChar {
+        ascii_char: b' ',
+        color_code: ColorCode(Color::Black as u8),
+    };
+
+    /// Stands for a cell whose character is not known, and is never written
+    /// by a writer.
+    const UNKNOWN: ScreenChar = ScreenChar {
+        ascii_char: 0x00,
+        color_code: ColorCode(0xff),
+    };
+}
+
 /// The VGA character buffer.
 #[repr(transparent)]
 struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [[Volatile<ScreenChar>; MAX_WIDTH]; MAX_HEIGHT],
 }
 
+/// A line of the screen.
+type Line = [ScreenChar; MAX_WIDTH];
+
+/// The characters of a whole screen, in the largest text mode. Smaller
+/// modes use the top left of it.
+type Screen = [Line; MAX_HEIGHT];
+
+/// Writes text at the cursor, which starts on the bottom line of the screen.
+/// Starting a new line moves the cursor down, and scrolls the screen up once
+/// the cursor is on the bottom line. The cursor can be moved with
+/// [set_cursor](Self::set_cursor), and [write_at](Self::write_at) writes
+/// anywhere on the screen without moving it. The blinking hardware cursor,
+/// once [enabled](Self::enable_cursor), follows the cursor.
+///
+/// Besides `\n`, the writer understands `\t`, which moves the cursor to the
+/// next [tab stop](Self::set_tab_width), `\r`, which returns it to the start
+/// of the line, and `\x08`, which erases the character before it.
+///
+/// Lines scrolled off the top are kept in a history of [SCROLLBACK_LINES]
+/// lines, once the heap is initialized. The view can be scrolled back through
+/// them with [scroll_back](Self::scroll_back), and returns to the bottom of
+/// the screen when more text is written.
+///
+/// Text is written to a shadow buffer in memory, and every write ends by
+/// [flushing](Self::flush) the characters which changed to the screen. Only
+/// the writer of the shown [virtual terminal](crate::vt) has the screen to
+/// flush to; the others keep their characters until they are
+/// [shown](Self::show).
 pub struct Writer {
+    /// The number of columns of the screen.
+    width: usize,
+    /// The number of rows of the screen.
+    height: usize,
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    /// The distance between tab stops.
+    tab_width: usize,
+    /// The characters written, as they are to appear on the screen.
+    chars: Screen,
+    /// The screen, if the writer is shown.
+    screen: Option<&'static mut Buffer>,
+    /// The characters on the screen as of the last flush.
+    flushed: Screen,
+    /// The lines scrolled off the top, oldest first.
+    history: VecDeque<Line>,
+    /// The number of lines the view is scrolled back by.
+    scrolled_back: usize,
+    /// The screen as it was before scrolling back, to restore when the view
+    /// returns to the bottom.
+    live_screen: Option<Box<Screen>>,
 }
 
 impl Writer {
-    fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.write_new_line(),
+    /// Returns a blank writer which is not shown, for a virtual terminal.
+    pub(crate) fn hidden() -> Writer {
+        let mode = mode();
+        let color_code = ColorCode::new(Color::Yellow, Color::Black);
+        let blank = ScreenChar {
+            ascii_char: b' ',
+            color_code,
+        };
+        Writer {
+            width: mode.columns(),
+            height: mode.rows(),
+            row_position: mode.rows() - 1,
+            column_position: 0,
+            color_code,
+            tab_width: TAB_WIDTH,
+            chars: [[blank; MAX_WIDTH]; MAX_HEIGHT],
+            screen: None,
+            flushed: [[ScreenChar::BLANK; MAX_WIDTH]; MAX_HEIGHT],
+            history: VecDeque::new(),
+            scrolled_back: 0,
+            live_screen: None,
+        }
+    }
 
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.write_new_line();
-                }
+    /// Returns whether the writer is writing to the screen.
+    pub fn is_shown(&self) -> bool {
+        self.screen.is_some()
+    }
+
+    /// Hands the screen over from the shown writer `from` to `self`, and
+    /// draws what `self` wrote while hidden.
+    pub(crate) fn show(&mut self, from: &mut Writer) {
+        let Some(screen) = from.screen.take() else {
+            return;
+        };
+        // The screen holds what `from` flushed last.
+        self.flushed = from.flushed;
+        self.screen = Some(screen);
+        self.resize(mode());
+        self.flush();
+    }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_char: byte,
-                    color_code: self.color_code,
-                });
+    /// Fits the characters to the size of the given mode. Lines above the
+    /// cursor move up into the history as far as it takes to keep the
+    /// cursor on the screen, and rows the screen gains are blank.
+    fn resize(&mut self, mode: TextMode) {
+        let (width, height) = (mode.columns(), mode.rows());
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+        self.scroll_to_bottom();
+        while self.row_position >= height {
+            self.scroll_up();
+            self.row_position -= 1;
+        }
+        for row in self.height.min(height)..MAX_HEIGHT {
+            self.clear_row(row);
+            // What the screen has there is not known.
+            self.flushed[row] = [ScreenChar::UNKNOWN; MAX_WIDTH];
+        }
+        for line in &mut self.chars[..height] {
+            let blank = ScreenChar {
+                ascii_char: b' ',
+                color_code: self.color_code,
+            };
+            line[width.min(self.width)..].fill(blank);
+        }
+        self.width = width;
+        self.height = height;
+        self.column_position = self.column_position.min(width);
+        self.tab_width = self.tab_width.min(width);
+    }
 
-                self.column_position += 1;
+    /// Returns the number of columns and rows of the screen.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Copies the characters which changed since the last flush to the
+    /// screen, and moves the hardware cursor. Does nothing unless the writer
+    /// is shown.
+    pub fn flush(&mut self) {
+        let Some(screen) = &mut self.screen else {
+            return;
+        };
+        let status_bar = *STATUS_BAR.lock();
+        for (row, line) in self.chars[..self.height].iter().enumerate() {
+            let line = match status_bar {
+                Some(ref status_bar) if row == 0 => status_bar,
+                _ => line,
+            };
+            if *line == self.flushed[row] {
+                continue;
+            }
+            for (col, &c) in line.iter().enumerate() {
+                if c != self.flushed[row][col] {
+                    screen.chars[row][col].write(c);
+                }
             }
+            self.flushed[row] = *line;
+        }
+        self.update_cursor();
+    }
+
+    /// Sets the colors of the text written from now on.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Puts tab stops every `width` columns, clamped to the screen.
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width.clamp(1, self.width);
+    }
+
+    /// Blanks the whole screen and moves the cursor to the top left corner,
+    /// below the status bar if there is one.
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..self.height {
+            self.clear_row(row);
+        }
+        self.set_cursor(0, 0);
+        self.flush();
+    }
+
+    /// Moves the cursor, where the text written next starts. The position is
+    /// clamped to the screen, and kept off the status bar.
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.row_position = row.clamp(first_row(), self.height - 1);
+        self.column_position = col.min(self.width - 1);
+        self.update_cursor();
+    }
+
+    /// Shows the hardware cursor in the given shape.
+    pub fn enable_cursor(&mut self, shape: CursorShape) {
+        *CURSOR_SHAPE.lock() = shape;
+        let (start, end) = shape.scan_lines(mode().char_height());
+        // The upper bits of the registers are left as they are.
+        crtc_write(
+            CURSOR_START_REGISTER,
+            crtc_read(CURSOR_START_REGISTER) & 0xc0 | start,
+        );
+        crtc_write(
+            CURSOR_END_REGISTER,
+            crtc_read(CURSOR_END_REGISTER) & 0xe0 | end,
+        );
+        self.update_cursor();
+    }
+
+    /// Hides the hardware cursor.
+    pub fn disable_cursor(&mut self) {
+        crtc_write(
+            CURSOR_START_REGISTER,
+            crtc_read(CURSOR_START_REGISTER) | CURSOR_DISABLE,
+        );
+    }
+
+    /// Returns whether the hardware cursor is shown.
+    pub fn is_cursor_enabled(&self) -> bool {
+        crtc_read(CURSOR_START_REGISTER) & CURSOR_DISABLE == 0
+    }
+
+    /// Moves the hardware cursor to the cursor if the writer is shown, or off
+    /// the screen while the view is scrolled back.
+    fn update_cursor(&self) {
+        if !self.is_shown() {
+            return;
+        }
+        let position = if self.scrolled_back != 0 {
+            self.height * self.width
+        } else {
+            // A full row wraps only once the next character is written.
+            let col = self.column_position.min(self.width - 1);
+            self.row_position * self.width + col
+        };
+        crtc_write(CURSOR_LOCATION_HIGH_REGISTER, (position >> 8) as u8);
+        crtc_write(CURSOR_LOCATION_LOW_REGISTER, position as u8);
+    }
+
+    /// Returns the row and column of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /// Returns the text on the given rows of the writer's characters.
+    pub fn snapshot(&self, rows: Range<usize>) -> Snapshot {
+        Snapshot::new(rows, self.height, |row| &self.chars[row])
+    }
+
+    /// Writes `s` from the given position on without moving the cursor or
+    /// scrolling. The text is cut off at the end of the row.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        if row >= self.height {
+            return;
+        }
+        self.scroll_to_bottom();
+        for (col, c) in (col..self.width).zip(s.chars()) {
+            self.chars[row][col] = ScreenChar {
+                ascii_char: to_cp437(c),
+                color_code: self.color_code,
+            };
+        }
+        self.flush();
+    }
+
+    /// Scrolls the view back through the history by up to `lines` lines.
+    pub fn scroll_back(&mut self, lines: usize) {
+        let scrolled_back = (self.scrolled_back + lines).min(self.history.len());
+        if scrolled_back == self.scrolled_back {
+            return;
         }
+        if self.live_screen.is_none() {
+            self.live_screen = Some(Box::new(self.chars));
+        }
+        self.scrolled_back = scrolled_back;
+        self.render_view();
+    }
+
+    /// Scrolls the view forward towards the bottom of the screen by up to
+    /// `lines` lines.
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.scrolled_back = self.scrolled_back.saturating_sub(lines);
+        self.render_view();
+    }
+
+    /// Scrolls the view back by most of a screen.
+    pub fn page_up(&mut self) {
+        self.scroll_back(self.height - 1);
+    }
+
+    /// Scrolls the view forward by most of a screen.
+    pub fn page_down(&mut self) {
+        self.scroll_forward(self.height - 1);
+    }
+
+    /// Returns the view to the bottom of the screen.
+    pub fn scroll_to_bottom(&mut self) {
+        if self.live_screen.is_some() {
+            self.scroll_forward(self.scrolled_back);
+        }
+    }
+
+    /// Draws the lines the view is scrolled to, restoring the live screen
+    /// once the view is back at the bottom, and flushes them.
+    fn render_view(&mut self) {
+        let Some(live_screen) = &self.live_screen else {
+            return;
+        };
+        // The history and the live screen below the status bar form one
+        // sequence of lines.
+        let top = first_row();
+        let first = self.history.len() - self.scrolled_back;
+        for (row, line) in self.chars[top..self.height].iter_mut().enumerate() {
+            *line = match self.history.get(first + row) {
+                Some(line) => *line,
+                None => live_screen[top + first + row - self.history.len()],
+            };
+        }
+        if self.scrolled_back == 0 {
+            self.live_screen = None;
+        }
+        self.flush();
+    }
+
+    /// Writes the glyph with the given byte of code page 437 at the cursor.
+    fn write_byte(&mut self, byte: u8) {
+        if self.column_position >= self.width {
+            self.write_new_line();
+        }
+        // The status bar may have appeared over the cursor.
+        self.row_position = self.row_position.max(first_row());
+
+        let row = self.row_position;
+        let col = self.column_position;
+        self.chars[row][col] = ScreenChar {
+            ascii_char: byte,
+            color_code: self.color_code,
+        };
+
+        self.column_position += 1;
+    }
+
+    /// Moves the cursor to the next tab stop, blanking the cells it passes.
+    fn write_tab(&mut self) {
+        let next_stop = (self.column_position / self.tab_width + 1) * self.tab_width;
+        while self.column_position < next_stop.min(self.width) {
+            self.write_byte(b' ');
+        }
+    }
+
+    /// Moves the cursor back over the character before it and blanks it.
+    /// Like a terminal, backspace stops at the start of the line.
+    fn write_backspace(&mut self) {
+        if self.column_position == 0 {
+            return;
+        }
+        self.column_position -= 1;
+        let col = self.column_position.min(self.width - 1);
+        self.chars[self.row_position][col] = ScreenChar {
+            ascii_char: b' ',
+            color_code: self.color_code,
+        };
     }
 
     fn write_new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let c = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(c);
+        self.column_position = 0;
+        if self.row_position < self.height - 1 {
+            self.row_position += 1;
+            return;
+        }
+        self.scroll_up();
+    }
+
+    /// Moves the lines up by one, the top line into the history, and blanks
+    /// the bottom line.
+    fn scroll_up(&mut self) {
+        // Only the rows below the status bar scroll.
+        let top = first_row();
+
+        // The history cannot grow before the heap exists.
+        if allocator::is_initialized() {
+            if self.history.len() == SCROLLBACK_LINES {
+                self.history.pop_front();
             }
+            self.history.push_back(self.chars[top]);
         }
 
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+        self.chars.copy_within(top + 1..self.height, top);
+
+        self.clear_row(self.height - 1);
     }
 
     fn clear_row(&mut self, row: usize) {
-        if row >= BUFFER_HEIGHT {
+        if row >= MAX_HEIGHT {
             return;
         }
 
@@ -120,53 +711,377 @@ impl Writer {
             color_code: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
-        }
+        self.chars[row] = [blank; MAX_WIDTH];
     }
 
+    /// Writes `s` without flushing it. Characters without a glyph in
+    /// [code page 437](to_cp437) are written as blocks.
     fn write_str_lossy(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+        self.scroll_to_bottom();
+        for c in s.chars() {
+            match c {
+                '\n' => self.write_new_line(),
+                '\t' => self.write_tab(),
+                '\r' => self.column_position = 0,
+                '\x08' => self.write_backspace(),
+                c => self.write_byte(to_cp437(c)),
+            }
+        }
+    }
+}
+
+/// Returns the first row text is written to, which is below the status bar
+/// if there is one.
+fn first_row() -> usize {
+    STATUS_BAR_SHOWN.load(Ordering::Relaxed) as usize
+}
+
+/// The glyphs of code page 437, the font of VGA text mode, from 0x01 to 0x1f.
+/// They stand in for control characters in ASCII.
+const CP437_SYMBOLS: &str = "☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+
+/// The glyphs of code page 437 from 0x80 to 0xff.
+const CP437_UPPER: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}",
+);
 
-                // Not part of the printable ASCII range, write block character instead.
-                _ => self.write_byte(0xfe),
+/// Returns the byte of the glyph showing `c` in code page 437, or of a block
+/// if there is none.
+fn to_cp437(c: char) -> u8 {
+    match c {
+        ' '..='~' => c as u8,
+        '⌂' => 0x7f,
+        // Stand-ins for characters without a glyph of their own.
+        '‘' | '’' => b'\'',
+        '“' | '”' => b'"',
+        '‐' | '–' | '—' => b'-',
+        'β' => 0xe1,
+        'μ' => 0xe6,
+        _ => {
+            if let Some(index) = CP437_SYMBOLS.chars().position(|glyph| glyph == c) {
+                index as u8 + 0x01
+            } else if let Some(index) = CP437_UPPER.chars().position(|glyph| glyph == c) {
+                index as u8 + 0x80
+            } else {
+                // Also written for control characters.
+                0xfe
             }
         }
     }
 }
 
+/// Returns the character showing the glyph `byte` of code page 437.
+fn from_cp437(byte: u8) -> char {
+    match byte {
+        0x00 => ' ',
+        0x01..=0x1f => CP437_SYMBOLS.chars().nth(byte as usize - 0x01).unwrap(),
+        0x20..=0x7e => byte as char,
+        0x7f => '⌂',
+        0x80..=0xff => CP437_UPPER.chars().nth(byte as usize - 0x80).unwrap(),
+    }
+}
+
+/// The text on some rows of the screen, for tests to compare with what they
+/// expect.
+///
+/// Each row is a line without its trailing blanks, and colors are left out.
+/// Its [Display] shows the lines one below the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    first_row: usize,
+    lines: Vec<String>,
+}
+
+impl Snapshot {
+    /// Takes the text of the given rows, cut off at the bottom of a screen of
+    /// `height` rows.
+    fn new<'a>(rows: Range<usize>, height: usize, line: impl Fn(usize) -> &'a Line) -> Snapshot {
+        let rows = rows.start.min(height)..rows.end.min(height);
+        let lines = rows
+            .clone()
+            .map(|row| {
+                let text: String = line(row).iter().map(|c| from_cp437(c.ascii_char)).collect();
+                text.trim_end_matches(' ').to_string()
+            })
+            .collect();
+        Snapshot {
+            first_row: rows.start,
+            lines,
+        }
+    }
+
+    /// Returns the rows of the screen the snapshot has.
+    pub fn rows(&self) -> Range<usize> {
+        self.first_row..self.first_row + self.lines.len()
+    }
+
+    /// Returns the text on a row of the screen, or `None` if the snapshot
+    /// does not have the row.
+    pub fn line(&self, row: usize) -> Option<&str> {
+        let index = row.checked_sub(self.first_row)?;
+        self.lines.get(index).map(String::as_str)
+    }
+
+    /// Returns whether any row holds `text`.
+    pub fn contains(&self, text: &str) -> bool {
+        self.lines.iter().any(|line| line.contains(text))
+    }
+
+    /// Compares the snapshot with a later one.
+    pub fn diff<'a>(&'a self, later: &'a Snapshot) -> Diff<'a> {
+        Diff {
+            earlier: self,
+            later,
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            f.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// The rows which differ between two snapshots. A row only one of them has
+/// differs from a blank row.
+///
+/// Its [Display] lists each such row with its earlier text after a `-` and
+/// its later text after a `+`, for a failing test to print.
+pub struct Diff<'a> {
+    earlier: &'a Snapshot,
+    later: &'a Snapshot,
+}
+
+impl Diff<'_> {
+    /// Returns the rows which differ.
+    pub fn rows(&self) -> impl Iterator<Item = usize> + '_ {
+        let (earlier, later) = (self.earlier.rows(), self.later.rows());
+        (earlier.start.min(later.start)..earlier.end.max(later.end)).filter(|&row| {
+            self.earlier.line(row).unwrap_or_default() != self.later.line(row).unwrap_or_default()
+        })
+    }
+
+    /// Returns whether the snapshots have the same text.
+    pub fn is_empty(&self) -> bool {
+        self.rows().next().is_none()
+    }
+}
+
+impl fmt::Display for Diff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in self.rows() {
+            writeln!(
+                f,
+                "{:>2} - {}",
+                row,
+                self.earlier.line(row).unwrap_or_default()
+            )?;
+            writeln!(f, "   + {}", self.later.line(row).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.write_str_lossy(s);
+        self.flush();
         Ok(())
     }
+
+    /// Flushes once all of the text is written, rather than after each of its
+    /// parts.
+    fn write_fmt(&mut self, args: core::fmt::Arguments) -> core::fmt::Result {
+        struct Unflushed<'a>(&'a mut Writer);
+
+        impl Write for Unflushed<'_> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                self.0.write_str_lossy(s);
+                Ok(())
+            }
+        }
+
+        let result = core::fmt::write(&mut Unflushed(self), args);
+        self.flush();
+        result
+    }
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga::_print(format_args!($($arg)*)));
+impl Console for WRITER {
+    fn name(&self) -> &str {
+        "vga"
+    }
+
+    fn print(&self, args: core::fmt::Arguments) {
+        self.lock().write_fmt(args).unwrap();
+    }
+
+    /// Keeps the background, and restores the previous colors under the same
+    /// lock so that other text is not colored.
+    fn print_colored(&self, foreground: Color, args: core::fmt::Arguments) {
+        let mut writer = self.lock();
+        let previous = writer.color_code;
+        // The background is the upper half of the color code.
+        writer.color_code = ColorCode((previous.0 & 0xf0) | foreground as u8);
+        let result = writer.write_fmt(args);
+        writer.color_code = previous;
+        result.unwrap();
+    }
+}
+
+/// Returns the text mode of the screen.
+pub fn mode() -> TextMode {
+    *MODE.lock()
 }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+/// Returns the number of columns and rows of the screen.
+pub fn size() -> (usize, usize) {
+    let mode = mode();
+    (mode.columns(), mode.rows())
 }
 
-#[doc(hidden)]
-pub fn _print(args: core::fmt::Arguments) {
-    use x86_64::instructions::interrupts;
+/// Switches the screen to another text mode, by loading a font of shorter or
+/// taller characters. The shown writer fits its text to the new size at
+/// once, the others when they are next shown.
+///
+/// # Panics
+///
+/// Panics if called before [mem::init](crate::mem::init).
+pub fn set_mode(mode: TextMode) {
+    crate::vt::with_active(|writer| {
+        if self::mode() == mode {
+            return;
+        }
+        let char_height = mode.char_height();
+        with_font(|font| {
+            let bios = BIOS_FONT.call_once(|| unsafe { font.read_volatile() });
+            unsafe { font.write_volatile(scale_font(bios, char_height)) };
+        });
+        crtc_write(
+            MAX_SCAN_LINE_REGISTER,
+            crtc_read(MAX_SCAN_LINE_REGISTER) & 0xe0 | (char_height - 1),
+        );
+        *MODE.lock() = mode;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        // Modes with more rows than 80x25 need more of the buffer than the
+        // page mapped at its physical address.
+        if writer.is_shown() {
+            let buffer = mem::phys_to_virt(PhysAddr::new(BUFFER_ADDRESS));
+            writer.screen = Some(unsafe { &mut *buffer.as_mut_ptr() });
+        }
+        writer.resize(mode);
+        if writer.is_cursor_enabled() {
+            let shape = *CURSOR_SHAPE.lock();
+            writer.enable_cursor(shape);
+        }
+        writer.flush();
     });
 }
 
+/// Blanks the screen, so that printing starts again at the top.
+pub fn clear_screen() {
+    WRITER.lock().clear_screen();
+}
+
+/// Shows the hardware cursor in the given shape at the end of the printed
+/// text.
+pub fn enable_cursor(shape: CursorShape) {
+    WRITER.lock().enable_cursor(shape);
+}
+
+/// Hides the hardware cursor.
+pub fn disable_cursor() {
+    WRITER.lock().disable_cursor();
+}
+
+/// Shows `text` in a status bar on the top row of the screen, reserving the
+/// row on first use so that printed text scrolls below it. The text is cut
+/// off at the width of the screen.
+pub fn set_status_bar(text: &str) {
+    let color_code = ColorCode::new(Color::White, Color::Blue);
+    let mut line = [ScreenChar {
+        ascii_char: b' ',
+        color_code,
+    }; MAX_WIDTH];
+    for (screen_char, c) in line.iter_mut().zip(text.chars()) {
+        screen_char.ascii_char = to_cp437(c);
+    }
+    *STATUS_BAR.lock() = Some(line);
+    STATUS_BAR_SHOWN.store(true, Ordering::Relaxed);
+    crate::vt::with_active(|writer| writer.flush());
+}
+
+/// Removes the status bar, giving its row back to printed text.
+pub fn remove_status_bar() {
+    STATUS_BAR_SHOWN.store(false, Ordering::Relaxed);
+    *STATUS_BAR.lock() = None;
+    crate::vt::with_active(|writer| writer.flush());
+}
+
+/// Returns the text on the given rows of the screen, which shows the active
+/// [virtual terminal](crate::vt) below the status bar.
+pub fn snapshot(rows: Range<usize>) -> Snapshot {
+    let status_bar = *STATUS_BAR.lock();
+    crate::vt::with_active(|writer| {
+        Snapshot::new(rows, writer.height, |row| match status_bar {
+            Some(ref status_bar) if row == 0 => status_bar,
+            _ => &writer.chars[row],
+        })
+    })
+}
+
+/// Returns the text on the given rows of the screen, a line per row.
+///
+/// ```no_run
+/// toyos::println!("hello");
+/// assert_eq!(toyos::vga::capture(23..24), "hello");
+/// ```
+pub fn capture(rows: Range<usize>) -> String {
+    snapshot(rows).to_string()
+}
+
+/// Number of times taking the writer is tried by [show_panic_screen].
+const PANIC_LOCK_ATTEMPTS: usize = 1_000_000;
+
+/// Clears the screen to white on red and writes `report` on it, without the
+/// status bar, for reports after which the kernel stops.
+///
+/// Gives up and returns `false` if the writer stays locked, as it does when
+/// the panic happened while printing.
+pub fn show_panic_screen(report: &dyn core::fmt::Display) -> bool {
+    STATUS_BAR_SHOWN.store(false, Ordering::Relaxed);
+    if let Some(mut status_bar) = STATUS_BAR.try_lock() {
+        *status_bar = None;
+    }
+    let Some(mut writer) = (0..PANIC_LOCK_ATTEMPTS).find_map(|_| WRITER.try_lock()) else {
+        return false;
+    };
+    writer.disable_cursor();
+    writer.set_color(Color::White, Color::Red);
+    writer.clear_screen();
+    // Text which does not fit scrolls the top of the report off the screen.
+    _ = write!(writer, "{}", report);
+    true
+}
+
+/// Sets the colors of the text printed from now on.
+pub fn set_color(foreground: Color, background: Color) {
+    WRITER.lock().set_color(foreground, background);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{cprintln, println};
 
     #[test_case]
     fn test_simple_println() {
@@ -183,17 +1098,253 @@ mod test {
     #[test_case]
     fn test_println_output() {
         use core::fmt::Write;
-        use x86_64::instructions::interrupts;
 
         let s = "Some test string that fits on a single line";
 
-        interrupts::without_interrupts(|| {
-            let mut writer = WRITER.lock();
-            writeln!(writer, "\n{}", s).expect("writeln failed");
-            for (i, c) in s.chars().enumerate() {
-                let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-                assert_eq!(char::from(screen_char.ascii_char), c);
+        let mut writer = WRITER.lock();
+        let height = writer.size().1;
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        let snapshot = writer.snapshot(height - 2..height);
+        assert_eq!(snapshot.line(height - 2), Some(s));
+    }
+
+    #[test_case]
+    fn test_snapshot() {
+        use core::fmt::Write;
+
+        let mut writer = WRITER.lock();
+        let height = writer.size().1;
+        let before = writer.snapshot(0..height);
+        writeln!(writer, "\n♥ snap\tshot").expect("writeln failed");
+        let after = writer.snapshot(0..height + 10);
+        assert_eq!(after.rows(), 0..height);
+        assert_eq!(after.line(height - 2), Some("♥ snap  shot"));
+        assert_eq!(after.line(height), None);
+        assert!(after.contains("snap"));
+        assert!(before.diff(&before).is_empty());
+        assert!(before.diff(&after).rows().any(|row| row == height - 2));
+
+        writeln!(writer, "\none\ntwo").expect("writeln failed");
+        let snapshot = writer.snapshot(height - 3..height - 1);
+        assert_eq!(snapshot.to_string(), "one\ntwo");
+    }
+
+    #[test_case]
+    fn test_cprintln() {
+        cprintln!(Color::LightRed, "in {}", "red");
+        let writer = WRITER.lock();
+        let height = writer.size().1;
+        let screen_char = writer.chars[height - 2][0];
+        assert_eq!(screen_char.ascii_char, b'i');
+        assert_eq!(
+            screen_char.color_code,
+            ColorCode::new(Color::LightRed, Color::Black)
+        );
+        assert_eq!(
+            writer.color_code,
+            ColorCode::new(Color::Yellow, Color::Black)
+        );
+    }
+
+    #[test_case]
+    fn test_cp437() {
+        assert_eq!(CP437_SYMBOLS.chars().count(), 0x1f);
+        assert_eq!(CP437_UPPER.chars().count(), 0x80);
+
+        assert_eq!(to_cp437('A'), b'A');
+        assert_eq!(to_cp437('é'), 0x82);
+        assert_eq!(to_cp437('─'), 0xc4);
+        assert_eq!(to_cp437('→'), 0x1a);
+        assert_eq!(to_cp437('’'), b'\'');
+        assert_eq!(to_cp437('\t'), 0xfe);
+        assert_eq!(to_cp437('😀'), 0xfe);
+    }
+
+    #[test_case]
+    fn test_control_characters() {
+        use core::fmt::Write;
+
+        let mut writer = WRITER.lock();
+        let height = writer.size().1;
+        writeln!(writer).expect("writeln failed");
+        write!(writer, "a\tb").expect("write failed");
+        assert_eq!(writer.cursor(), (height - 1, TAB_WIDTH + 1));
+        write!(writer, "\rc").expect("write failed");
+        assert_eq!(writer.chars[height - 1][0].ascii_char, b'c');
+        write!(writer, "\x08").expect("write failed");
+        assert_eq!(writer.cursor(), (height - 1, 0));
+        assert_eq!(writer.chars[height - 1][0].ascii_char, b' ');
+        write!(writer, "\x08").expect("write failed");
+        assert_eq!(writer.cursor(), (height - 1, 0));
+
+        writer.set_tab_width(4);
+        write!(writer, "\t\t").expect("write failed");
+        assert_eq!(writer.cursor(), (height - 1, 8));
+        writer.set_tab_width(TAB_WIDTH);
+        writeln!(writer).expect("writeln failed");
+    }
+
+    #[test_case]
+    fn test_cursor() {
+        use core::fmt::Write;
+
+        let mut writer = WRITER.lock();
+        let (width, height) = writer.size();
+        writer.clear_screen();
+        assert_eq!(writer.cursor(), (0, 0));
+        assert_eq!(writer.chars[height - 1][0].ascii_char, b' ');
+
+        writeln!(writer, "top").expect("writeln failed");
+        writer.set_cursor(5, 10);
+        write!(writer, "middle").expect("write failed");
+        assert_eq!(writer.cursor(), (5, 16));
+        writer.write_at(height - 1, width - 2, "cut\n");
+        assert_eq!(writer.cursor(), (5, 16));
+
+        assert!(writer.chars[0]
+            .iter()
+            .map(|c| c.ascii_char)
+            .take(3)
+            .eq(*b"top"));
+        let row = writer.chars[5];
+        assert!(row[10..16].iter().map(|c| c.ascii_char).eq(*b"middle"));
+        let row = writer.chars[height - 1];
+        assert!(row[width - 2..].iter().map(|c| c.ascii_char).eq(*b"cu"));
+
+        // Later tests print from the bottom line.
+        writer.set_cursor(height - 1, 0);
+    }
+
+    #[test_case]
+    fn test_scale_font() {
+        let mut bios = [[0; 32]; 256];
+        bios[b'_' as usize][13] = 0xff;
+        bios[b'|' as usize][..BIOS_CHAR_HEIGHT].fill(0x18);
+        let font = scale_font(&bios, 8);
+        assert_eq!(font[b'_' as usize][6], 0xff);
+        assert_eq!(
+            font[b'_' as usize]
+                .iter()
+                .filter(|&&bits| bits != 0)
+                .count(),
+            1
+        );
+        assert_eq!(font[b'|' as usize][..8], [0x18; 8]);
+        assert_eq!(font[b'|' as usize][8..], [0; 24]);
+        assert_eq!(scale_font(&bios, 16), bios);
+    }
+
+    #[test_case]
+    fn test_set_mode() {
+        use core::fmt::Write;
+
+        set_mode(TextMode::Text80x50);
+        assert_eq!(size(), (80, 50));
+        assert_eq!(crtc_read(MAX_SCAN_LINE_REGISTER) & 0x1f, 7);
+        let mut writer = WRITER.lock();
+        assert_eq!(writer.size(), (80, 50));
+        writer.set_cursor(49, 0);
+        writeln!(writer, "on row 48").expect("writeln failed");
+        assert_eq!(writer.snapshot(48..49).to_string(), "on row 48");
+        drop(writer);
+
+        // Shrinking the screen scrolls the text above the cursor up.
+        set_mode(TextMode::Text80x25);
+        assert_eq!(crtc_read(MAX_SCAN_LINE_REGISTER) & 0x1f, 15);
+        let writer = WRITER.lock();
+        assert_eq!(writer.size(), (80, 25));
+        assert_eq!(writer.cursor(), (24, 0));
+        assert_eq!(writer.snapshot(23..24).to_string(), "on row 48");
+    }
+
+    #[test_case]
+    fn test_hardware_cursor() {
+        let mut writer = WRITER.lock();
+        let width = writer.size().0;
+        let (row, col) = writer.cursor();
+        let enabled = writer.is_cursor_enabled();
+
+        writer.set_cursor(3, 7);
+        let position = (crtc_read(CURSOR_LOCATION_HIGH_REGISTER) as usize) << 8
+            | crtc_read(CURSOR_LOCATION_LOW_REGISTER) as usize;
+        assert_eq!(position, 3 * width + 7);
+
+        writer.disable_cursor();
+        assert!(!writer.is_cursor_enabled());
+        writer.enable_cursor(CursorShape::Block);
+        assert!(writer.is_cursor_enabled());
+        assert_eq!(crtc_read(CURSOR_START_REGISTER) & 0x1f, 0);
+
+        writer.set_cursor(row, col);
+        writer.enable_cursor(CursorShape::Underline);
+        if !enabled {
+            writer.disable_cursor();
+        }
+    }
+
+    #[test_case]
+    fn test_flush() {
+        use core::fmt::Write;
+
+        let mut writer = WRITER.lock();
+        writeln!(writer, "flushed").expect("writeln failed");
+        assert_eq!(writer.flushed, writer.chars);
+        if let Some(screen) = &writer.screen {
+            for (row, line) in writer.chars.iter().enumerate() {
+                for (col, &c) in line.iter().enumerate() {
+                    assert_eq!(screen.chars[row][col].read(), c);
+                }
             }
-        });
+        }
+    }
+
+    #[test_case]
+    fn test_status_bar() {
+        use core::fmt::Write;
+
+        set_status_bar("status");
+        let mut writer = WRITER.lock();
+        let height = writer.size().1;
+        writer.set_cursor(0, 0);
+        assert_eq!(writer.cursor(), (1, 0));
+        for _ in 0..height {
+            writeln!(writer, "below").expect("writeln failed");
+        }
+        if let Some(screen) = &writer.screen {
+            assert_eq!(screen.chars[0][0].read().ascii_char, b's');
+        }
+        assert_eq!(writer.chars[1][0].ascii_char, b'b');
+        writer.set_cursor(height - 1, 0);
+        drop(writer);
+
+        remove_status_bar();
+        assert_eq!(first_row(), 0);
+    }
+
+    #[test_case]
+    fn test_scrollback() {
+        use core::fmt::Write;
+
+        let mut writer = WRITER.lock();
+        let height = writer.size().1;
+        writeln!(writer, "scrolled off").expect("writeln failed");
+        for _ in 0..height - 1 {
+            writeln!(writer).expect("writeln failed");
+        }
+        let live = writer.chars[height - 2];
+
+        writer.scroll_back(1);
+        let row = writer.chars[0];
+        assert!(row
+            .iter()
+            .map(|c| c.ascii_char)
+            .take(12)
+            .eq(*b"scrolled off"));
+
+        // Writing returns to the bottom.
+        writer.write_str("").expect("write failed");
+        assert!(writer.live_screen.is_none());
+        assert_eq!(writer.chars[height - 2], live);
+        assert!(writer.history.len() <= SCROLLBACK_LINES);
     }
 }
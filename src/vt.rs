@@ -0,0 +1,122 @@
+//! Virtual terminals sharing the screen.
+//!
+//! Each terminal is a [Writer] with a character buffer of its own, and one of
+//! them is shown at a time. The first terminal, `tty0`, is the
+//! [WRITER](vga::WRITER) behind [println](crate::println) and holds the
+//! kernel log, while programs and the keyboard echo write to the [SHELL]
+//! terminal. The keyboard task switches terminals on Alt+F1 to Alt+F4.
+//!
+//! ```no_run
+//! toyos::vt::print(toyos::vt::SHELL, format_args!("$ "));
+//! toyos::vt::switch(toyos::vt::SHELL);
+//! ```
+
+use alloc::boxed::Box;
+use core::fmt::{self, Write};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    sync::IrqSpinlock,
+    vga::{self, Writer},
+};
+
+/// Number of virtual terminals.
+pub const COUNT: usize = 4;
+
+/// The terminal holding the kernel log.
+pub const LOG: usize = 0;
+
+/// The terminal of programs and the keyboard echo.
+pub const SHELL: usize = 1;
+
+lazy_static! {
+    /// The terminals after the first, which is [vga::WRITER]. They are
+    /// allocated when first used, so this needs the heap.
+    static ref TERMINALS: Box<[IrqSpinlock<Writer>]> = (1..COUNT)
+        .map(|_| IrqSpinlock::new(Writer::hidden()))
+        .collect();
+}
+
+/// The shown terminal, locked while switching.
+static ACTIVE: IrqSpinlock<usize> = IrqSpinlock::new(LOG);
+
+/// Returns the writer of the given terminal.
+///
+/// # Panics
+///
+/// Panics if there is no such terminal.
+pub fn get(tty: usize) -> &'static IrqSpinlock<Writer> {
+    match tty {
+        0 => &vga::WRITER,
+        tty => &TERMINALS[tty - 1],
+    }
+}
+
+/// Returns the shown terminal.
+pub fn active() -> usize {
+    *ACTIVE.lock()
+}
+
+/// Shows the given terminal, returning `false` if there is no such terminal.
+pub fn switch(tty: usize) -> bool {
+    if tty >= COUNT {
+        return false;
+    }
+    let mut active = ACTIVE.lock();
+    if *active == tty {
+        return true;
+    }
+    // Locked in order of their numbers, so that a switch never waits on
+    // another one in the opposite direction.
+    let (first, second) = (tty.min(*active), tty.max(*active));
+    let mut first = get(first).lock();
+    let mut second = get(second).lock();
+    let (to, from) = if tty < *active {
+        (&mut *first, &mut *second)
+    } else {
+        (&mut *second, &mut *first)
+    };
+    to.show(from);
+    *active = tty;
+    true
+}
+
+/// Writes formatted text to the given terminal.
+///
+/// # Panics
+///
+/// Panics if there is no such terminal.
+pub fn print(tty: usize, args: fmt::Arguments) {
+    get(tty).lock().write_fmt(args).unwrap();
+}
+
+/// Calls `f` with the writer of the shown terminal.
+pub fn with_active<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    let active = ACTIVE.lock();
+    f(&mut get(*active).lock())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_switch() {
+        let before = active();
+        assert!(switch(LOG));
+        assert!(vga::WRITER.lock().is_shown());
+
+        print(2, format_args!("\nhidden"));
+        assert!(!get(2).lock().is_shown());
+        assert!(switch(2));
+        assert_eq!(active(), 2);
+        assert!(get(2).lock().is_shown());
+        assert!(!vga::WRITER.lock().is_shown());
+        assert!(with_active(|writer| writer.is_shown()));
+
+        assert!(!switch(COUNT));
+        assert_eq!(active(), 2);
+        assert!(switch(before));
+    }
+}
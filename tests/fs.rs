@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::ToString, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toyos::fs::{self, Error, FileType, OpenFlags, SeekFrom};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toyos::allocator;
+    use toyos::mem::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toyos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { mem::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    fs::init();
+
+    test_main();
+    toyos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toyos::test_panic_handler(info)
+}
+
+fn names(path: &str) -> Vec<alloc::string::String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn mounted_at_boot() {
+    let mounts = fs::mounts();
+    assert!(mounts.contains(&("/".to_string(), "initrd")));
+    assert!(mounts.contains(&("/dev".to_string(), "devfs")));
+    assert!(mounts.contains(&("/proc".to_string(), "procfs")));
+    assert!(mounts.contains(&("/tmp".to_string(), "ramfs")));
+}
+
+#[test_case]
+fn create_write_and_read() {
+    fs::create_dir("/tmp/create").unwrap();
+    fs::write("/tmp/create/file", b"hello world").unwrap();
+    assert_eq!(fs::read("/tmp/create/file").unwrap(), b"hello world");
+
+    let file = fs::open("/tmp/create/file", OpenFlags::READ | OpenFlags::WRITE).unwrap();
+    file.seek(SeekFrom::Start(6)).unwrap();
+    file.write(b"there").unwrap();
+    assert_eq!(fs::read("/tmp/create/file").unwrap(), b"hello there");
+
+    fs::write("/tmp/create/file", b"bye").unwrap();
+    assert_eq!(fs::metadata("/tmp/create/file").unwrap().size, 3);
+}
+
+#[test_case]
+fn rename_across_directories() {
+    fs::create_dir("/tmp/rename").unwrap();
+    fs::create_dir("/tmp/rename/a").unwrap();
+    fs::create_dir("/tmp/rename/b").unwrap();
+    fs::write("/tmp/rename/a/file", b"data").unwrap();
+
+    fs::rename("/tmp/rename/a/file", "/tmp/rename/b/moved").unwrap();
+    assert!(names("/tmp/rename/a").is_empty());
+    assert_eq!(fs::read("/tmp/rename/b/moved").unwrap(), b"data");
+
+    fs::rename("/tmp/rename/b", "/tmp/rename/c").unwrap();
+    assert_eq!(names("/tmp/rename"), ["a", "c"]);
+    assert_eq!(
+        fs::rename("/tmp/rename", "/tmp/rename/a/x"),
+        Err(Error::InvalidPath)
+    );
+    assert_eq!(
+        fs::rename("/tmp/rename/c/moved", "/etc/moved"),
+        Err(Error::CrossDevice)
+    );
+    assert_eq!(fs::rename("/tmp", "/tmp2"), Err(Error::Busy));
+}
+
+#[test_case]
+fn remove_files_and_directories() {
+    fs::create_dir("/tmp/remove").unwrap();
+    fs::write("/tmp/remove/file", b"").unwrap();
+    assert_eq!(fs::remove("/tmp/remove"), Err(Error::NotEmpty));
+
+    fs::remove("/tmp/remove/file").unwrap();
+    fs::remove("/tmp/remove").unwrap();
+    assert_eq!(fs::metadata("/tmp/remove").err(), Some(Error::NotFound));
+}
+
+#[test_case]
+fn open_flags() {
+    assert_eq!(
+        fs::open("/tmp/missing", OpenFlags::READ).err(),
+        Some(Error::NotFound)
+    );
+    let file = fs::open("/tmp/flags", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.metadata().kind, FileType::File);
+    assert_eq!(file.read(&mut [0; 4]), Err(Error::WriteOnly));
+    assert_eq!(
+        fs::open("/tmp", OpenFlags::WRITE).err(),
+        Some(Error::IsADirectory)
+    );
+}
@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use toyos::{
+    interrupts::apic,
+    smp,
+    task::{runtime, sync::oneshot},
+    time::{pit, Duration},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toyos::allocator;
+    use toyos::mem::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toyos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { mem::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    apic::init();
+    smp::init(&mut mapper, &mut frame_allocator);
+
+    test_main();
+    toyos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toyos::test_panic_handler(info)
+}
+
+/// Spins until `done` returns `true`.
+fn wait_for(done: impl Fn() -> bool) {
+    while !done() {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn application_processors_run_runtime() {
+    // Application processors enter the runtime shortly after reporting that
+    // they have started.
+    wait_for(|| runtime::running_cpus().count_ones() as usize == smp::cpu_count() - 1);
+}
+
+#[test_case]
+fn tasks_spawned_on_bsp_are_stolen() {
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    // The bootstrap processor does not run the runtime, so every task must be
+    // stolen by an application processor.
+    let handles: Vec<_> = (0..50)
+        .map(|_| {
+            runtime::spawn(async {
+                COMPLETED.fetch_add(1, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    wait_for(|| COMPLETED.load(Ordering::Relaxed) == 50);
+    assert!(handles.iter().all(|handle| handle.is_finished()));
+    assert!(runtime::stats(1).steals > 0);
+}
+
+#[test_case]
+fn wake_up_reaches_halted_cpu() {
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    let (sender, receiver) = oneshot::channel();
+    let _handle = runtime::spawn(async move {
+        receiver.await.unwrap();
+        RECEIVED.store(true, Ordering::Relaxed);
+    });
+
+    // Give the application processor time to poll the task and halt.
+    pit::busy_wait(Duration::from_millis(10));
+    sender.send(()).unwrap();
+    wait_for(|| RECEIVED.load(Ordering::Relaxed));
+}
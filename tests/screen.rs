@@ -0,0 +1,79 @@
+//! Checks what printing leaves on the screen, as captured by the VGA writer.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toyos::{cprint, println, vga};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toyos::allocator;
+    use toyos::mem::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toyos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { mem::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    toyos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn println_lands_on_the_bottom_row() {
+    let rows = vga::size().1;
+    println!("bottom row");
+    assert_eq!(vga::capture(rows - 2..rows), "bottom row\n");
+}
+
+#[test_case]
+fn colored_text_is_captured_as_text() {
+    let rows = vga::size().1;
+    cprint!(vga::Color::LightGreen, "green");
+    println!(" and not");
+    assert_eq!(vga::capture(rows - 2..rows - 1), "green and not");
+}
+
+#[test_case]
+fn printing_scrolls_the_screen() {
+    let rows = vga::size().1;
+    let before = vga::snapshot(0..rows);
+    println!("scrolled");
+    let after = vga::snapshot(0..rows);
+    assert!(after.contains("scrolled"));
+    assert!(!before.diff(&after).is_empty(), "{}", before.diff(&after));
+    assert_eq!(after.line(rows - 3), before.line(rows - 2));
+}
+
+#[test_case]
+fn status_bar_is_on_the_top_row() {
+    vga::set_status_bar("status");
+    println!("under the status bar");
+    assert_eq!(vga::capture(0..1), "status");
+    vga::remove_status_bar();
+    assert_ne!(vga::capture(0..1), "status");
+}
+
+#[test_case]
+fn switching_to_80x50_adds_rows_below() {
+    vga::set_mode(vga::TextMode::Text80x50);
+    assert_eq!(vga::size(), (80, 50));
+    println!("on row 24");
+    assert_eq!(vga::capture(24..25), "on row 24");
+    vga::set_mode(vga::TextMode::Text80x25);
+    assert_eq!(vga::capture(23..24), "on row 24");
+}
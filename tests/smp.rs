@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toyos::{
+    acpi::madt,
+    interrupts::apic::{self, TimerMode},
+    percpu::MAX_CPUS,
+    smp,
+    time::{self, TickSource},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toyos::allocator;
+    use toyos::mem::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toyos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { mem::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    apic::init();
+    assert!(time::use_apic_timer(TimerMode::OneShot));
+    smp::init(&mut mapper, &mut frame_allocator);
+
+    test_main();
+    toyos::hlt();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_processors_online() {
+    let enabled = madt::processors()
+        .iter()
+        .filter(|processor| processor.enabled)
+        .count();
+    assert_eq!(smp::cpu_count(), enabled.min(MAX_CPUS));
+}
+
+#[test_case]
+fn application_processor_receives_ipi() {
+    if smp::cpu_count() < 2 {
+        return;
+    }
+
+    let before = apic::ipis_received(1);
+    apic::send_reschedule(1);
+    while apic::ipis_received(1) == before {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn apic_timers_tick_on_every_cpu() {
+    assert_eq!(time::tick_source(), TickSource::Apic);
+    assert!(apic::timer_frequency() > 0);
+
+    // The one-shot timers keep firing, as their handler rearms them.
+    let start = time::ticks();
+    while time::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+    for cpu in 0..smp::cpu_count() {
+        let before = apic::timer_ticks(cpu);
+        while apic::timer_ticks(cpu) < before + 2 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -5,12 +5,13 @@
 use core::panic::PanicInfo;
 
 use lazy_static::lazy_static;
-use toyos::{serial_print, serial_println, QemuExitCode};
+use toyos::{tap, QemuExitCode};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow... ");
+    tap::plan(1);
+    tap::start("stack_overflow::stack_overflow");
 
     // Initialize global descriptor table.
     toyos::gdt::init();
@@ -60,6 +61,6 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
+    tap::pass();
     toyos::exit_qemu(QemuExitCode::Success);
 }